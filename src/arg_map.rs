@@ -0,0 +1,131 @@
+use std::iter::Map;
+use std::slice;
+
+/// Arguments given at a call site, keyed by their name. Most invocations only have a handful of
+/// arguments, so they are kept within a vector rather than hashed, in the order they were given
+/// so that diagnostics about them are deterministic.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) struct ArgMap<'a, V> {
+    entries: Vec<(&'a str, V)>,
+}
+
+impl<'a, V> ArgMap<'a, V> {
+    pub(crate) fn new() -> Self {
+        ArgMap {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Sets `key` to `value`, keeping the position of any value it replaces.
+    pub(crate) fn insert(&mut self, key: &'a str, value: V) {
+        match self.entries.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub(crate) fn get_key_value(&self, key: &str) -> Option<(&&'a str, &V)> {
+        self.iter().find(|(name, _)| **name == key)
+    }
+
+    /// The value of `key`, inserting the one returned by `default` first if there is none.
+    pub(crate) fn get_or_insert_with<F>(&mut self, key: &'a str, default: F) -> &mut V
+    where
+        F: FnOnce() -> V,
+    {
+        let index = match self.entries.iter().position(|(name, _)| *name == key) {
+            Some(index) => index,
+            None => {
+                self.entries.push((key, default()));
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[index].1
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|(name, _)| *name == key)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.entries.iter().map(|(name, _)| *name)
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, 'a, V> {
+        self.into_iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+type Iter<'b, 'a, V> =
+    Map<slice::Iter<'b, (&'a str, V)>, fn(&'b (&'a str, V)) -> (&'b &'a str, &'b V)>;
+
+impl<'b, 'a, V> IntoIterator for &'b ArgMap<'a, V> {
+    type Item = (&'b &'a str, &'b V);
+    type IntoIter = Iter<'b, 'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter().map(|(name, value)| (name, value))
+    }
+}
+
+impl<V> Default for ArgMap<'_, V> {
+    fn default() -> Self {
+        ArgMap::new()
+    }
+}
+
+impl<'a, V, const N: usize> From<[(&'a str, V); N]> for ArgMap<'a, V> {
+    fn from(entries: [(&'a str, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<'a, V> FromIterator<(&'a str, V)> for ArgMap<'a, V> {
+    fn from_iter<I: IntoIterator<Item = (&'a str, V)>>(entries: I) -> Self {
+        let mut map = ArgMap::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod arg_map_tests {
+    use crate::arg_map::ArgMap;
+
+    #[test]
+    fn test_insert_keeps_order() {
+        let mut args = ArgMap::from([("title", "Guide"), ("lang", "rust")]);
+        args.insert("year", "2022");
+        args.insert("title", "Book");
+
+        assert_eq!(
+            args.iter().collect::<Vec<_>>(),
+            vec![(&"title", &"Book"), (&"lang", &"rust"), (&"year", &"2022")]
+        );
+        assert_eq!(args.get("lang"), Some(&"rust"));
+        assert_eq!(args.get("missing"), None);
+        assert!(args.contains_key("year"));
+        assert_eq!(args.len(), 3);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut appended = ArgMap::new();
+        appended.get_or_insert_with("links", Vec::new).push("a");
+        appended.get_or_insert_with("tags", Vec::new).push("b");
+        appended.get_or_insert_with("links", Vec::new).push("c");
+
+        assert_eq!(appended.get("links"), Some(&vec!["a", "c"]));
+        assert_eq!(appended.keys().collect::<Vec<_>>(), vec!["links", "tags"]);
+    }
+}
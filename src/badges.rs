@@ -0,0 +1,80 @@
+/// Renders `[[@badge <provider> <value>]]` as a shields.io badge linking to the provider, or as
+/// plain text when `offline`. Returns `None` for unknown providers or a missing value.
+pub(crate) fn render(arg: &str, offline: bool) -> Option<String> {
+    let (provider, value) = arg.split_once(char::is_whitespace)?;
+    let value = value.trim();
+
+    let (label, image, link) = match provider {
+        "crates-io" => (
+            "crates.io",
+            format!("https://img.shields.io/crates/v/{}", value),
+            Some(format!("https://crates.io/crates/{}", value)),
+        ),
+        "docs-rs" => (
+            "docs.rs",
+            format!("https://img.shields.io/docsrs/{}", value),
+            Some(format!("https://docs.rs/{}", value)),
+        ),
+        "license" => (
+            "license",
+            format!(
+                "https://img.shields.io/badge/license-{}-blue",
+                escape(value)
+            ),
+            None,
+        ),
+        _ => return None,
+    };
+
+    let badge = match offline {
+        true => format!("{}: {}", label, value),
+        false => format!("![{}]({})", label, image),
+    };
+    Some(match link {
+        Some(link) => format!("[{}]({})", badge, link),
+        None => badge,
+    })
+}
+
+// Dashes and underscores separate the parts of a static shields.io badge
+fn escape(text: &str) -> String {
+    text.replace('-', "--")
+        .replace('_', "__")
+        .replace(' ', "%20")
+}
+
+#[cfg(test)]
+mod badges_tests {
+    use crate::badges::render;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("crates-io mdbook-template", false).unwrap(),
+            "[![crates.io](https://img.shields.io/crates/v/mdbook-template)](https://crates.io/crates/mdbook-template)"
+        );
+        assert_eq!(
+            render("docs-rs mdbook-template", false).unwrap(),
+            "[![docs.rs](https://img.shields.io/docsrs/mdbook-template)](https://docs.rs/mdbook-template)"
+        );
+        assert_eq!(
+            render("license Apache-2.0", false).unwrap(),
+            "![license](https://img.shields.io/badge/license-Apache--2.0-blue)"
+        );
+    }
+
+    #[test]
+    fn test_render_offline() {
+        assert_eq!(
+            render("crates-io mdbook-template", true).unwrap(),
+            "[crates.io: mdbook-template](https://crates.io/crates/mdbook-template)"
+        );
+        assert_eq!(render("license MIT", true).unwrap(), "license: MIT");
+    }
+
+    #[test]
+    fn test_render_unknown() {
+        assert_eq!(render("npm mdbook-template", false), None);
+        assert_eq!(render("crates-io", false), None);
+    }
+}
@@ -1,20 +1,42 @@
-use std::{io, process};
+use std::path::Path;
+use std::{env, io, process};
 
+use anyhow::bail;
 use clap::{Arg, ArgMatches, Command};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::MDBook;
 use semver::{Version, VersionReq};
 
-use mdbook_template::Template;
+use mdbook_template::config::Config;
+use mdbook_template::diagnostics::Code;
+use mdbook_template::utils::normalize_path;
+use mdbook_template::{
+    check, move_template, rdeps, rename_arg, update, Component, Lockfile, Pack, Preview, Template,
+};
 
 fn main() {
     let matches = make_app().get_matches();
 
     let preprocessor = Template::new();
 
-    if let Some(sub_args) = matches.subcommand_matches("supports") {
-        handle_supports(&preprocessor, sub_args);
-    } else if let Err(e) = handle_preprocessing(&preprocessor) {
+    let result = match matches.subcommand() {
+        Some(("supports", sub_args)) => handle_supports(&preprocessor, sub_args),
+        Some(("explain", sub_args)) => handle_explain(sub_args),
+        Some(("new", sub_args)) => handle_new(sub_args),
+        Some(("mv", sub_args)) => handle_mv(&preprocessor, sub_args),
+        Some(("rename-arg", sub_args)) => handle_rename_arg(&preprocessor, sub_args),
+        Some(("rdeps", sub_args)) => handle_rdeps(&preprocessor, sub_args),
+        Some(("check", sub_args)) => handle_check(&preprocessor, sub_args),
+        Some(("preview", sub_args)) => handle_preview(sub_args),
+        Some(("pack", sub_args)) => handle_pack(sub_args),
+        Some(("install", sub_args)) => handle_install(&preprocessor, sub_args),
+        Some(("update", sub_args)) => handle_update(&preprocessor, sub_args),
+        Some(("matrix", sub_args)) => handle_matrix(&preprocessor, sub_args),
+        _ => handle_preprocessing(&preprocessor),
+    };
+
+    if let Err(e) = result {
         eprintln!("{}", e);
         process::exit(1);
     }
@@ -28,6 +50,169 @@ pub fn make_app() -> Command<'static> {
             .arg(Arg::new("renderer").required(true))
             .about("Check whether a renderer is supported by this preprocessor"),
     )
+    .subcommand(
+        Command::new("explain")
+            .arg(Arg::new("code").required(true).help("Code of the diagnostic, e.g. T002"))
+            .about("Describe a diagnostic and how to fix it"),
+    )
+    .subcommand(
+        Command::new("new")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("component")
+                    .arg(Arg::new("name").required(true).help("Name of the component, e.g. card"))
+                    .arg(
+                        Arg::new("args")
+                            .long("args")
+                            .takes_value(true)
+                            .use_value_delimiter(true)
+                            .help("Comma separated arguments of the component, e.g. title,body,link"),
+                    )
+                    .arg(
+                        Arg::new("dir")
+                            .long("dir")
+                            .default_value("src/templates")
+                            .help("Directory the template is created in"),
+                    )
+                    .about("Create a template with front matter and placeholders, printing an example invocation"),
+            )
+            .about("Create a new template"),
+    )
+    .subcommand(
+        Command::new("mv")
+            .arg(Arg::new("from").required(true).help("Template to move, e.g. src/templates/footer.md"))
+            .arg(Arg::new("to").required(true).help("Where to move it, e.g. src/templates/components/footer.md"))
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("Move a template, rewriting every invocation of it within the book"),
+    )
+    .subcommand(
+        Command::new("rename-arg")
+            .arg(Arg::new("template").required(true).help("Template whose argument is renamed, e.g. src/templates/footer.md"))
+            .arg(Arg::new("rename").required(true).help("Current and new name of the argument, e.g. authors=writers"))
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("Rename an argument within a template and at every call site within the book"),
+    )
+    .subcommand(
+        Command::new("rdeps")
+            .arg(Arg::new("template").required(true).help("Template whose dependents are listed, e.g. templates/footer.md"))
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("List every chapter and template depending on a template, directly or through other templates"),
+    )
+    .subcommand(
+        Command::new("check")
+            .arg(
+                Arg::new("show-effective")
+                    .long("show-effective")
+                    .help("Print the value every placeholder receives at each call site, and where it comes from"),
+            )
+            .arg(
+                Arg::new("changed-only")
+                    .long("changed-only")
+                    .help("Only check the chapters and templates modified in the git working tree, e.g. in a pre-commit hook"),
+            )
+            .arg(Arg::new("dir").default_value(".").help("Root directory of the book"))
+            .about("Check that every template within the book can be expanded"),
+    )
+    .subcommand(
+        Command::new("preview")
+            .arg(Arg::new("template").required(true).help("Template to preview, e.g. src/templates/card.md"))
+            .arg(
+                Arg::new("arg")
+                    .long("arg")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .help("Argument the template is invoked with, e.g. title=Demo"),
+            )
+            .arg(
+                Arg::new("port")
+                    .long("port")
+                    .default_value("3001")
+                    .help("Port the preview is served on, on 127.0.0.1 only"),
+            )
+            .arg(
+                Arg::new("theme")
+                    .long("theme")
+                    .takes_value(true)
+                    .possible_values(["light", "rust", "coal", "navy", "ayu"])
+                    .help("Theme to render the preview with instead of the default theme of the book"),
+            )
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("Serve a single template rendered like the book, reloading whenever it changes"),
+    )
+    .subcommand(
+        Command::new("pack")
+            .arg(
+                Arg::new("pack")
+                    .default_value(".")
+                    .help("Directory of the pack, containing its template-pack.toml"),
+            )
+            .arg(
+                Arg::new("out")
+                    .long("out")
+                    .takes_value(true)
+                    .help("Directory the bundle is written into, the directory of the pack by default"),
+            )
+            .about("Bundle the templates of a pack into a single <name>-<version>.pack.toml file"),
+    )
+    .subcommand(
+        Command::new("install")
+            .arg(Arg::new("bundle").required(true).help("Bundle to install, e.g. cards-1.0.0.pack.toml"))
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("Unpack a bundle into the packs directory of the book source directory"),
+    )
+    .subcommand(
+        Command::new("update")
+            .arg(
+                Arg::new("dry-run")
+                    .long("dry-run")
+                    .help("Only list the updates and the parameter changes they bring"),
+            )
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .default_value(".")
+                    .help("Root directory of the book"),
+            )
+            .about("Update the installed packs to the newest versions found within the pack-sources"),
+    )
+    .subcommand(
+        Command::new("matrix")
+            .arg(
+                Arg::new("profiles")
+                    .long("profiles")
+                    .takes_value(true)
+                    .use_value_delimiter(true)
+                    .required(true)
+                    .help("Comma separated profiles to build, e.g. prod,staging,internal"),
+            )
+            .arg(Arg::new("dir").default_value(".").help("Root directory of the book"))
+            .about("Build the book once per profile, each into its own directory within the build directory"),
+    )
 }
 
 fn handle_preprocessing(pre: &Template) -> Result<(), Error> {
@@ -52,6 +237,226 @@ fn handle_preprocessing(pre: &Template) -> Result<(), Error> {
     Ok(())
 }
 
+fn handle_matrix(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let profiles = sub_args.values_of("profiles").expect("Required argument");
+
+    for profile in profiles {
+        let mut book = MDBook::load(dir)?;
+        let key = format!("preprocessor.{}", pre.name());
+        if book.config.get(&key).is_none() {
+            bail!("The book at {} doesn't use the [{}] preprocessor", dir, key);
+        }
+        book.config.set(format!("{}.profile", key), profile)?;
+        book.config.build.build_dir = book.config.build.build_dir.join(profile);
+
+        println!(
+            "Building the {} profile into {}",
+            profile,
+            book.root.join(&book.config.build.build_dir).display()
+        );
+        book.build()?;
+    }
+
+    Ok(())
+}
+
+fn handle_preview(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let template = sub_args.value_of("template").expect("Required argument");
+    let port = sub_args.value_of("port").expect("Has a default");
+    let args = sub_args
+        .values_of("arg")
+        .map(|args| args.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let cwd = env::current_dir()?;
+    let mut preview = Preview::new(&cwd.join(dir), &cwd.join(template), &args);
+    if let Some(theme) = sub_args.value_of("theme") {
+        preview = preview.theme(theme);
+    }
+    // Only reachable from this machine, the preview serves anything within the build directory
+    preview.serve(&format!("127.0.0.1:{}", port))
+}
+
+fn handle_pack(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = Path::new(sub_args.value_of("pack").expect("Has a default"));
+    let out = sub_args.value_of("out").map_or(dir, Path::new);
+
+    let pack = Pack::from_dir(dir)?;
+    let bundle = pack.write(out)?;
+    println!(
+        "Packed {} {} into {}",
+        pack.pack.name,
+        pack.pack.version,
+        bundle.display()
+    );
+
+    Ok(())
+}
+
+fn handle_install(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let bundle = sub_args.value_of("bundle").expect("Required argument");
+
+    let config = load_config(pre, dir)?;
+    let root = normalize_path(env::current_dir()?.join(dir));
+    let bundle = normalize_path(env::current_dir()?.join(bundle));
+    let pack = Pack::read(&bundle)?;
+    let installed = pack.install(&config.src_dir)?;
+    let mut lockfile = Lockfile::load(&root)?;
+    lockfile.lock(&root, &pack, &bundle)?;
+    lockfile.save(&root)?;
+    println!(
+        "Installed {} {} into {}",
+        pack.pack.name,
+        pack.pack.version,
+        installed.display()
+    );
+
+    Ok(())
+}
+
+fn handle_update(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+
+    let config = load_config(pre, dir)?;
+    let root = normalize_path(env::current_dir()?.join(dir));
+    let sources = config
+        .pack_sources
+        .iter()
+        .map(|source| root.join(source))
+        .collect::<Vec<_>>();
+    print!(
+        "{}",
+        update(
+            &root,
+            &config.src_dir,
+            &sources,
+            sub_args.is_present("dry-run")
+        )?
+    );
+
+    Ok(())
+}
+
+fn handle_new(sub_args: &ArgMatches) -> Result<(), Error> {
+    let sub_args = sub_args
+        .subcommand_matches("component")
+        .expect("Required subcommand");
+    let name = sub_args.value_of("name").expect("Required argument");
+    let args = sub_args
+        .values_of("args")
+        .map(|args| args.collect::<Vec<_>>())
+        .unwrap_or_default();
+    let dir = Path::new(sub_args.value_of("dir").expect("Has a default"));
+
+    let component = Component::new(name, &args)?;
+    let path = component.create(dir)?;
+    // Chapters usually live at the root of the book source directory
+    let relative = path.strip_prefix("src").unwrap_or(&path);
+    println!("Created {}, use it with:\n", path.display());
+    println!("{}", component.invocation(relative));
+
+    Ok(())
+}
+
+fn handle_mv(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let from = sub_args.value_of("from").expect("Required argument");
+    let to = sub_args.value_of("to").expect("Required argument");
+
+    let config = load_config(pre, dir)?;
+    let cwd = env::current_dir()?;
+    for file in move_template(&config, &cwd.join(from), &cwd.join(to))? {
+        println!("Rewrote {}", file.display());
+    }
+
+    Ok(())
+}
+
+fn handle_rename_arg(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let template = sub_args.value_of("template").expect("Required argument");
+    let rename = sub_args.value_of("rename").expect("Required argument");
+    let (from, to) = match rename.split_once('=') {
+        Some((from, to)) => (from.trim(), to.trim()),
+        None => bail!("Expected the argument as <from>=<to>, e.g. authors=writers"),
+    };
+
+    let config = load_config(pre, dir)?;
+    let template = env::current_dir()?.join(template);
+    for file in rename_arg(&config, &template, from, to)? {
+        println!("Rewrote {}", file.display());
+    }
+
+    Ok(())
+}
+
+fn handle_rdeps(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    let template = sub_args.value_of("template").expect("Required argument");
+
+    let config = load_config(pre, dir)?;
+    // Templates are usually given relative to the book source directory
+    let template = match env::current_dir()?.join(template) {
+        path if path.is_file() => path,
+        _ => config.src_dir.join(template),
+    };
+    for file in rdeps(&config, &template)? {
+        let file = file.strip_prefix(&config.src_dir).unwrap_or(&file);
+        println!("{}", file.display());
+    }
+
+    Ok(())
+}
+
+/// Returns whether every invocation can be expanded.
+fn handle_check(pre: &Template, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+
+    let config = load_config(pre, dir)?;
+    let report = check(
+        &config,
+        sub_args.is_present("show-effective"),
+        sub_args.is_present("changed-only"),
+    )?;
+    print!("{}", report.text);
+
+    if report.problems > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Configuration of the book at `dir`, as the preprocessor sees it while building the book.
+fn load_config(pre: &Template, dir: &str) -> Result<Config, Error> {
+    let book = MDBook::load(dir)?;
+    let mut config = Config::from_book(&book.config, pre.name())?;
+    config.resolve_paths(&normalize_path(
+        env::current_dir()?
+            .join(&book.root)
+            .join(&book.config.book.src),
+    ))?;
+    Ok(config)
+}
+
+fn handle_explain(sub_args: &ArgMatches) -> ! {
+    let id = sub_args.value_of("code").expect("Required argument");
+
+    match Code::parse(id) {
+        Some(code) => {
+            println!("{}: {}", code, code.explanation());
+            process::exit(0);
+        }
+        None => {
+            eprintln!("Unknown diagnostic code {}", id);
+            process::exit(1);
+        }
+    }
+}
+
 fn handle_supports(pre: &Template, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
     let supported = pre.supports_renderer(renderer);
@@ -62,3 +467,39 @@ fn handle_supports(pre: &Template, sub_args: &ArgMatches) -> ! {
         process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod cli_tests {
+    use std::{env, fs, process};
+
+    use mdbook_template::{check, Template};
+
+    use crate::load_config;
+
+    #[test]
+    fn test_load_config() {
+        let root = env::temp_dir().join(format!("mdbook-template-cli-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(
+            root.join("book.toml"),
+            "[book]\ntitle = \"CLI\"\n\n[preprocessor.template.defaults.\"x.md\"]\nlicence = \"MIT\"\n",
+        )
+        .unwrap();
+        fs::write(root.join("src/SUMMARY.md"), "- [Intro](intro.md)\n").unwrap();
+        fs::write(root.join("src/intro.md"), "{{#template x.md}}").unwrap();
+        fs::write(root.join("src/x.md"), "[[#licence]]").unwrap();
+
+        let config = load_config(&Template::new(), root.to_str().unwrap()).unwrap();
+        let report = check(&config, true, false).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            report.text.lines().collect::<Vec<_>>(),
+            [
+                "intro.md:1:1 {{#template x.md}}",
+                "    licence = MIT (defaults of the configuration)",
+            ]
+        );
+    }
+}
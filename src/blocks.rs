@@ -0,0 +1,160 @@
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+/// Byte ranges of the blockquotes and list items within `contents`.
+pub(crate) fn containers(contents: &str) -> Vec<Range<usize>> {
+    Parser::new(contents)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::BlockQuote | Tag::Item) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The prefix every line but the first of an expansion at `index` needs to stay within the
+/// blockquotes and list items enclosing it: `>` markers are repeated while list markers turn into
+/// the indentation of a continuation line.
+pub(crate) fn continuation(
+    contents: &str,
+    index: usize,
+    containers: &[Range<usize>],
+) -> Option<String> {
+    if !containers.iter().any(|range| range.contains(&index)) {
+        return None;
+    }
+    let (prefix, _) = markers(line_before(contents, index));
+    (!prefix.is_empty()).then_some(prefix)
+}
+
+/// Returns true if the invocation spanning `range` shares its line with text other than the
+/// markers of blockquotes and list items, e.g. `The {{#template product-name.md}} CLI`.
+pub(crate) fn is_inline(contents: &str, range: Range<usize>) -> bool {
+    let after = &contents[range.end..];
+    let line_end = after.find('\n').unwrap_or(after.len());
+    let (_, before) = markers(line_before(contents, range.start));
+    !before.trim().is_empty() || !after[..line_end].trim().is_empty()
+}
+
+fn line_before(contents: &str, index: usize) -> &str {
+    let line_start = contents[..index]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    &contents[line_start..index]
+}
+
+/// Splits the blockquote and list markers off the start of `line`, turning them into the prefix
+/// of a continuation line.
+fn markers(line: &str) -> (String, &str) {
+    let mut rest = line;
+    let mut prefix = String::new();
+    loop {
+        let trimmed = rest.trim_start_matches([' ', '\t']);
+        prefix.push_str(&rest[..rest.len() - trimmed.len()]);
+        rest = trimmed;
+        if let Some(quoted) = rest.strip_prefix('>') {
+            prefix.push('>');
+            rest = quoted;
+            continue;
+        }
+        match list_marker(rest) {
+            Some(length) => {
+                prefix.extend(std::iter::repeat_n(' ', length));
+                rest = &rest[length..];
+            }
+            None => return (prefix, rest),
+        }
+    }
+}
+
+/// Length of the bullet, e.g. `-`, or ordered list marker, e.g. `12.`, that `text` starts with.
+fn list_marker(text: &str) -> Option<usize> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let length = match digits {
+        0 if text.starts_with(['-', '*', '+']) => 1,
+        1..=9 if text[digits..].starts_with(['.', ')']) => digits + 1,
+        _ => return None,
+    };
+    // A marker is always followed by whitespace, or ends the line
+    match text[length..].chars().next() {
+        None | Some(' ' | '\t') => Some(length),
+        _ => None,
+    }
+}
+
+/// Prefixes every line but the first of `text` with `prefix`, without trailing whitespace on
+/// blank lines.
+pub(crate) fn indent(text: &str, prefix: &str) -> String {
+    let mut lines = text.split('\n');
+    let mut indented = String::with_capacity(text.len());
+    indented.push_str(lines.next().unwrap_or_default());
+    for line in lines {
+        indented.push('\n');
+        match line.trim().is_empty() {
+            true => indented.push_str(prefix.trim_end()),
+            false => indented.push_str(prefix),
+        }
+        indented.push_str(line);
+    }
+    indented
+}
+
+#[cfg(test)]
+mod blocks_tests {
+    use crate::blocks::{containers, continuation, indent, is_inline};
+
+    fn prefix_of(contents: &str) -> Option<String> {
+        let index = contents.find("{{").unwrap();
+        continuation(contents, index, &containers(contents))
+    }
+
+    #[test]
+    fn test_continuation() {
+        assert_eq!(prefix_of("> {{#template a.md}}").as_deref(), Some("> "));
+        assert_eq!(
+            prefix_of("> Quoted\n>\n> > {{#template a.md}}").as_deref(),
+            Some("> > ")
+        );
+        assert_eq!(prefix_of("- {{#template a.md}}").as_deref(), Some("  "));
+        assert_eq!(
+            prefix_of("1. First\n10. {{#template a.md}}").as_deref(),
+            Some("    ")
+        );
+        assert_eq!(
+            prefix_of("- Item\n\n  > {{#template a.md}}").as_deref(),
+            Some("  > ")
+        );
+        assert_eq!(
+            prefix_of("> - Read {{#template a.md}}").as_deref(),
+            Some(">   ")
+        );
+        assert_eq!(prefix_of("Some text {{#template a.md}}"), None);
+        assert_eq!(prefix_of("-5 {{#template a.md}}"), None);
+    }
+
+    #[test]
+    fn test_is_inline() {
+        let inline = |contents: &str| {
+            let start = contents.find("{{").unwrap();
+            let end = contents.find("}}").unwrap() + 2;
+            is_inline(contents, start..end)
+        };
+
+        assert!(inline("The {{#template product-name.md}} CLI"));
+        assert!(inline("Intro\n{{#template product-name.md}} CLI"));
+        assert!(inline("> - Use {{#template product-name.md}}"));
+        assert!(!inline("Intro\n\n{{#template footer.md}}\n\nOutro"));
+        assert!(!inline("> - {{#template footer.md}}  "));
+    }
+
+    #[test]
+    fn test_indent() {
+        assert_eq!(
+            indent("First\nSecond\n\nThird\n", "> "),
+            "First\n> Second\n>\n> Third\n>"
+        );
+        assert_eq!(indent("First\n\nSecond", "  "), "First\n\n  Second");
+        assert_eq!(indent("Single", "> "), "Single");
+    }
+}
@@ -0,0 +1,8 @@
+document.querySelectorAll('.mdbook-tabs').forEach((tabs) => {
+    const titles = tabs.querySelectorAll(':scope > .mdbook-tab-titles > button');
+    const panels = tabs.querySelectorAll(':scope > .mdbook-tab');
+    titles.forEach((title, index) => title.addEventListener('click', () => {
+        titles.forEach((other, i) => other.classList.toggle('active', i === index));
+        panels.forEach((panel, i) => panel.classList.toggle('active', i === index));
+    }));
+});
@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+
+use crate::config::Config;
+use crate::front_matter;
+use crate::globals;
+use crate::links::{self, LinkType};
+use crate::refactor::book_files;
+use crate::utils::{normalize_path, BuiltinFileReader, SystemFileReader};
+
+/// Outcome of checking every invocation within the book source directory.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Report {
+    /// A line per invocation that can't be expanded, followed by the effective arguments of every
+    /// invocation when asked for
+    pub text: String,
+    /// Number of invocations that can't be expanded
+    pub problems: usize,
+    /// Number of invocations of archived templates, or of templates not modified since
+    /// `stale-before`
+    pub stale: usize,
+}
+
+/// Checks that the template of every invocation within the book source directory can be read
+/// and its arguments resolved. With `show_effective`, the report lists the value every
+/// placeholder receives at each call site, so the impact of changing a default can be audited
+/// before publishing. Invocations of stale templates are flagged with a warning.
+///
+/// With `changed_only`, only the invocations within chapters modified in the git working tree,
+/// or of templates modified there, are checked.
+pub fn check(config: &Config, show_effective: bool, changed_only: bool) -> Result<Report> {
+    if let Some(ref date) = config.stale_before {
+        if !is_date(date) {
+            bail!(
+                "stale-before must be a date such as 2024-01-31, not \"{}\"",
+                date
+            );
+        }
+    }
+    let file_reader = BuiltinFileReader::new(SystemFileReader::new(config.max_file_size));
+    let keywords = config.keywords();
+    let changed = match changed_only {
+        true => Some(changed_files(&config.src_dir)?),
+        false => None,
+    };
+    let mut report = Report::default();
+    for file in book_files(&config.src_dir, &config.extensions, config.symlinks)? {
+        let file_changed = changed
+            .as_ref()
+            .is_none_or(|changed| changed.contains(&file));
+        if !file_changed && changed.as_ref().is_some_and(HashSet::is_empty) {
+            continue;
+        }
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let base = file.parent().expect("Files have a parent");
+        let name = file.strip_prefix(&config.src_dir).unwrap_or(&file);
+
+        for link in links::extract_template_links(&contents)
+            .keywords(&keywords)
+            .directives(&config.directives)
+            .src_dir(&config.src_dir)
+        {
+            if !matches!(
+                link.link_type,
+                LinkType::Template(_)
+                    | LinkType::Rooted(..)
+                    | LinkType::Chapter(_)
+                    | LinkType::Pack(..)
+            ) {
+                continue;
+            }
+            let target = link
+                .link_type
+                .target(base)
+                .expect("Templates have a target");
+            if !file_changed
+                && !changed
+                    .as_ref()
+                    .is_some_and(|changed| changed.contains(&normalize_path(&target)))
+            {
+                continue;
+            }
+            let (line, column) = links::location(&contents, link.start_index);
+            let invocation = link.link_text.split_whitespace().collect::<Vec<_>>();
+            let location = format!("{}:{}:{}", name.display(), line, column);
+
+            let checked = link
+                .read_template(&target, &file_reader, config)
+                .and_then(|template| {
+                    let (front_matter, template) = front_matter::split(&template)?;
+                    let effective = link
+                        .effective_args(&target, template, config)?
+                        .into_iter()
+                        .map(|(name, value, origin)| (name.to_owned(), value, origin))
+                        .collect::<Vec<_>>();
+                    Ok((front_matter.archived, effective))
+                });
+            let stale = match checked {
+                Ok((true, _)) => Some("is archived".to_string()),
+                Ok(_) => config.stale_before.as_ref().and_then(|date| {
+                    let modified = fs::metadata(&target).and_then(|metadata| metadata.modified());
+                    let modified = modified.ok().map(date_of)?;
+                    (modified < *date).then(|| format!("hasn't been modified since {}", modified))
+                }),
+                Err(_) => None,
+            };
+            match checked.map(|(_, effective)| effective) {
+                Ok(effective) if show_effective => {
+                    let _ = writeln!(report.text, "{} {}", location, invocation.join(" "));
+                    for (name, value, origin) in effective {
+                        let _ = match value {
+                            Some(value) => {
+                                writeln!(report.text, "    {} = {} ({})", name, value, origin)
+                            }
+                            None => writeln!(report.text, "    {} ({})", name, origin),
+                        };
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    report.problems += 1;
+                    let _ = writeln!(report.text, "{} error: {:#}", location, err);
+                }
+            }
+            if let Some(stale) = stale {
+                report.stale += 1;
+                let template = target.strip_prefix(&config.src_dir).unwrap_or(&target);
+                let _ = writeln!(
+                    report.text,
+                    "{} warning: {} {}",
+                    location,
+                    template.display(),
+                    stale
+                );
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Files within `dir` that are modified, added, deleted or untracked in the git working tree,
+/// according to `git status`.
+fn changed_files(dir: &Path) -> Result<HashSet<PathBuf>> {
+    let git = |args: &[&str]| -> Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .context("Could not run git")?;
+        if !output.status.success() {
+            bail!(
+                "Could not list the changed files, {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    // Paths are relative to the top of the repository, which git gives without symlinks
+    let toplevel = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim());
+    let canonical_dir = fs::canonicalize(dir)?;
+    let status = git(&["status", "--porcelain", "-z", "--untracked-files=all"])?;
+    let mut changed = HashSet::new();
+    let mut entries = status.split('\0').filter(|entry| !entry.is_empty());
+    while let Some(entry) = entries.next() {
+        let (code, path) = match (entry.get(..2), entry.get(3..)) {
+            (Some(code), Some(path)) => (code, path),
+            _ => continue,
+        };
+        // Renamed and copied files are followed by their original path
+        if code.contains(['R', 'C']) {
+            entries.next();
+        }
+        let path = toplevel.join(path);
+        if let Ok(relative) = path.strip_prefix(&canonical_dir) {
+            changed.insert(normalize_path(dir.join(relative)));
+        }
+    }
+    Ok(changed)
+}
+
+/// Returns true if `text` is a date such as `2024-01-31`.
+fn is_date(text: &str) -> bool {
+    let parts = text.split('-').collect::<Vec<_>>();
+    matches!(parts[..], [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2)
+        && parts
+            .iter()
+            .all(|part| part.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// The UTC date, e.g. `2024-01-31`, of `time`.
+fn date_of(time: SystemTime) -> String {
+    let timestamp = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    globals::date(timestamp)
+}
+
+#[cfg(test)]
+mod check_tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::process::Command;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::check::{check, Report};
+    use crate::config::Config;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_check() {
+        let book = Fixture::new("check")
+            .file(
+                "intro.md",
+                "# Intro\n{{#template templates/footer.md authors=Hazel authors+=Goudham}}\n{{#template templates/missing.md}}",
+            )
+            .file(
+                "templates/footer.md",
+                "+++\ncss = []\n+++\n[[#authors]] [[#year 2022]] [[#licence]] [[#title]] [[#authors]]",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            defaults: HashMap::from([(
+                dir.join("templates/footer.md"),
+                HashMap::from([("licence".to_string(), "MIT".to_string())]),
+            )]),
+            ..Default::default()
+        };
+
+        let report = check(&config, true, false).unwrap();
+
+        assert_eq!(report.problems, 1);
+        let lines = report.text.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..5],
+            [
+                "intro.md:2:1 {{#template templates/footer.md authors=Hazel authors+=Goudham}}",
+                "    authors = Hazel, Goudham (call site)",
+                "    year = 2022 (default of the placeholder)",
+                "    licence = MIT (defaults of the configuration)",
+                "    title (not given)",
+            ]
+        );
+        assert!(lines[5].starts_with("intro.md:3:1 error: Could not read template file"));
+        assert_eq!(
+            check(&config, false, false).unwrap().text.lines().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_check_stale() {
+        let book = Fixture::new("check-stale")
+            .file(
+                "intro.md",
+                "{{#template old.md}}\n{{#template archived.md}}\n{{#template fresh.md}}",
+            )
+            .file("old.md", "Old")
+            .file("archived.md", "+++\narchived = true\n+++\nArchived")
+            .file("fresh.md", "Fresh")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        fs::File::options()
+            .write(true)
+            .open(dir.join("old.md"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_500_000_000))
+            .unwrap();
+        let mut config = Config {
+            src_dir: dir.clone(),
+            stale_before: Some("2021-01-01".to_string()),
+            ..Default::default()
+        };
+
+        let report = check(&config, false, false).unwrap();
+
+        assert_eq!((report.problems, report.stale), (0, 2));
+        assert_eq!(
+            report.text,
+            "intro.md:1:1 warning: old.md hasn't been modified since 2017-07-14
+intro.md:2:1 warning: archived.md is archived
+"
+        );
+
+        config.stale_before = Some("last year".to_string());
+        assert!(check(&config, false, false).is_err());
+    }
+
+    #[test]
+    fn test_check_changed_only() {
+        let book = Fixture::new("check-changed")
+            .file("intro.md", "{{#template missing.md}}")
+            .file("usage.md", "{{#template footer.md}}")
+            .file("other.md", "{{#template header.md}}")
+            .file("footer.md", "Footer")
+            .file("header.md", "Header")
+            .write()
+            .unwrap();
+        let dir = book.root();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(dir)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "Book"]);
+        let config = Config {
+            src_dir: dir.join("src"),
+            ..Default::default()
+        };
+
+        assert_eq!(check(&config, false, false).unwrap().problems, 1);
+        assert_eq!(check(&config, false, true).unwrap(), Report::default());
+
+        fs::remove_file(dir.join("src/footer.md")).unwrap();
+        fs::write(dir.join("src/new.md"), "{{#template header.md}}").unwrap();
+        let report = check(&config, true, true).unwrap();
+        assert_eq!(report.problems, 1);
+        let lines = report.text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "new.md:1:1 {{#template header.md}}");
+        assert!(lines[1].starts_with("usage.md:1:1 error: Could not read template file"));
+    }
+}
@@ -0,0 +1,116 @@
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+
+/// Size, in bytes, chunks of large chapters grow to before looking for a place to end.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    // Blocks expanded as a whole, which a chunk must never end within
+    static ref BLOCK_OPENING: Regex =
+        Regex::new(r"\{\{\s*\#(?:if-var|tabs)(?=[\s}])|\[\[\s*\#match\s").unwrap();
+    static ref BLOCK_CLOSING: Regex =
+        Regex::new(r"\{\{\s*/(?:if-var|tabs)\s*\}\}|\[\[\s*/match\s*\]\]").unwrap();
+}
+
+/// Splits `contents` into chunks of roughly `size` bytes that can each be expanded on their own.
+/// A chunk only ends at a blank line outside of fenced code blocks, unterminated invocations and
+/// the `{{#if-var}}`, `{{#tabs}}` and `[[#match]]` blocks, so a chunk may grow past `size`.
+pub(crate) fn split(contents: &str, size: usize) -> Chunks<'_> {
+    Chunks {
+        contents,
+        start: 0,
+        size,
+    }
+}
+
+pub(crate) struct Chunks<'a> {
+    contents: &'a str,
+    start: usize,
+    size: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.contents[self.start..];
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut fence: Option<&str> = None;
+        let mut invocations = 0usize;
+        let mut blocks = 0usize;
+        let mut end = 0;
+        for line in rest.split_inclusive('\n') {
+            end += line.len();
+            let trimmed = line.trim();
+            match fence {
+                Some(marker) if trimmed.starts_with(marker) => fence = None,
+                Some(_) => continue,
+                None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                    fence = Some(&trimmed[..3]);
+                    continue;
+                }
+                None => {}
+            }
+
+            invocations = (invocations + line.matches("{{").count())
+                .saturating_sub(line.matches("}}").count());
+            blocks =
+                (blocks + count(&BLOCK_OPENING, line)).saturating_sub(count(&BLOCK_CLOSING, line));
+            if end >= self.size && trimmed.is_empty() && invocations == 0 && blocks == 0 {
+                break;
+            }
+        }
+
+        self.start += end;
+        Some(&rest[..end])
+    }
+}
+
+fn count(regex: &Regex, line: &str) -> usize {
+    regex.find_iter(line).filter(|mat| mat.is_ok()).count()
+}
+
+#[cfg(test)]
+mod chunks_tests {
+    use crate::chunks::split;
+
+    #[test]
+    fn test_split_at_blank_lines() {
+        let contents = "# API\n\nfirst\n\nsecond\nthird\n\nfourth\n";
+
+        let chunks = split(contents, 4).collect::<Vec<_>>();
+
+        assert_eq!(
+            chunks,
+            vec!["# API\n\n", "first\n\n", "second\nthird\n\n", "fourth\n"]
+        );
+        assert_eq!(chunks.concat(), contents);
+    }
+
+    #[test]
+    fn test_split_keeps_blocks_whole() {
+        let contents = "```\na\n\nb\n```\n\n{{#template\n\nfooter.md}}\n\n{{#if-var beta}}\n\nbeta\n{{/if-var}}\n\n[[#match os]]\n\n[[/match]]\n\nend";
+
+        let chunks = split(contents, 1).collect::<Vec<_>>();
+
+        assert_eq!(
+            chunks,
+            vec![
+                "```\na\n\nb\n```\n\n",
+                "{{#template\n\nfooter.md}}\n\n",
+                "{{#if-var beta}}\n\nbeta\n{{/if-var}}\n\n",
+                "[[#match os]]\n\n[[/match]]\n\n",
+                "end"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_small_contents() {
+        assert_eq!(split("a\n\nb", 1024).collect::<Vec<_>>(), vec!["a\n\nb"]);
+        assert_eq!(split("", 1024).count(), 0);
+    }
+}
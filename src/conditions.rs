@@ -0,0 +1,79 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // r"(?xs)\\\\(?=\\*\{\{)|\\\{\{|\{\{\s*\#if-var\s+([^}\s]+)\s*\}\}(.*?)\{\{\s*/if-var\s*\}\}"
+    static ref IF_VAR: Regex = Regex::new(
+        r"(?xs)                                 # insignificant whitespace, `.` matches newlines
+
+        \\\\(?=\\*\{\{)                         # escaped backslash before an opening parens
+
+        |                                       # or
+
+        \\\{\{                                  # escaped opening parens
+
+        |                                       # or
+
+        \{\{\s*\#if-var\s+([^}\s]+)\s*\}\}      # conditional block opening with its variable
+        (.*?)                                   # content of the block
+        \{\{\s*/if-var\s*\}\}                   # conditional block closing"
+    )
+    .unwrap();
+}
+
+/// Returns true if the variable `name` is set to anything but an empty string or `false`.
+pub(crate) fn is_set<K>(name: &str, variables: &HashMap<K, String>) -> bool
+where
+    K: Borrow<str> + Hash + Eq,
+{
+    variables
+        .get(name)
+        .is_some_and(|value| !value.is_empty() && value != "false")
+}
+
+/// Keeps the content of every `{{#if-var <name>}} ... {{/if-var}}` block whose variable is set,
+/// removing the whole block otherwise.
+pub(crate) fn replace(contents: &str, variables: &HashMap<String, String>) -> String {
+    // Must keep track of indices as they will not correspond after string substitution
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(contents.len());
+
+    for cap in IF_VAR.captures_iter(contents).filter_map(|cap| cap.ok()) {
+        let (mat, name, body) = match (cap.get(0), cap.get(1), cap.get(2)) {
+            (Some(mat), Some(name), Some(body)) => (mat, name.as_str(), body.as_str()),
+            // Escaped blocks are left for the template links to unescape
+            _ => continue,
+        };
+
+        replaced.push_str(&contents[previous_end_index..mat.start()]);
+        if is_set(name, variables) {
+            replaced.push_str(body);
+        }
+        previous_end_index = mat.end();
+    }
+
+    replaced.push_str(&contents[previous_end_index..]);
+    replaced
+}
+
+#[cfg(test)]
+mod conditions_tests {
+    use std::collections::HashMap;
+
+    use crate::conditions::replace;
+
+    #[test]
+    fn test_replace() {
+        let variables = HashMap::from([
+            ("internal".to_string(), "true".to_string()),
+            ("beta".to_string(), "false".to_string()),
+        ]);
+        let start = "A{{#if-var internal}} internal{{/if-var}}{{#if-var beta}} beta{{/if-var}}{{ #if-var missing }}\nmissing\n{{/if-var}} \\{{#if-var beta}}";
+
+        assert_eq!(replace(start, &variables), "A internal \\{{#if-var beta}}");
+    }
+}
@@ -0,0 +1,812 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+use mdbook::preprocess::PreprocessorContext;
+use serde::{de, Deserialize};
+use toml::value::Map;
+use toml::Value;
+
+use crate::diagnostics;
+use crate::links::{LinkType, DEFAULT_DIRECTIVE};
+use crate::utils::{self, normalize_path, DEFAULT_MAX_FILE_SIZE};
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Keep `[[@counter ...]]` values running across the whole book instead of per chapter
+    pub global_counters: bool,
+    /// Chapter, relative to the book source directory, defining the terms used by `[[@gloss ...]]`
+    pub glossary: Option<PathBuf>,
+    /// Renderers explicitly enabled through mdbook's own `renderers` option
+    pub renderers: Option<Vec<String>>,
+    /// What happens to templates when building with a renderer that isn't enabled
+    pub passthrough: Passthrough,
+    /// Text replacing every template when `passthrough` is `note`
+    pub passthrough_note: String,
+    /// What happens to invocations that can't be expanded, unless overridden with `on-error=`
+    pub on_error: OnError,
+    /// How warnings and errors are written: `log`, `json` or `github` annotations
+    pub diagnostics: diagnostics::Format,
+    /// Append a chapter summarising which templates are used, where and with which arguments
+    pub usage_appendix: bool,
+    /// Append a chapter listing every source from outside the book, e.g. diagram commands, with hashes
+    pub provenance: bool,
+    /// Named sets of arguments applied to a template with `preset=<name>`
+    pub presets: HashMap<String, HashMap<String, String>>,
+    /// Ignore arguments that try to override the names of the builtin namespaces, e.g. `chapter.*`
+    pub read_only_globals: bool,
+    /// Builtin namespaces, e.g. `env` or `git`, that are neither provided nor reserved
+    pub disable_builtins: Vec<String>,
+    /// Environment variables exposed as `env.<name>`, none are unless listed here
+    pub env_allow: Vec<String>,
+    /// Leave escape backslashes in the output for a downstream tool to unescape
+    pub preserve_escapes: bool,
+    /// Chapters larger than this many bytes fail the build instead of being expanded
+    pub max_chapter_length: usize,
+    /// Chapters larger than this many bytes are expanded in chunks to bound peak memory usage
+    pub stream_threshold: usize,
+    /// Templates expanded within a single chapter, including nested ones, before failing the build
+    pub max_expansions: usize,
+    /// Invocations spanning more than this many bytes are ignored with an error
+    pub max_invocation_length: usize,
+    /// Invocations without a closing `}}` within this many lines are ignored with a warning
+    pub max_invocation_lines: usize,
+    /// Whether symlinked template files are followed
+    pub symlinks: Symlinks,
+    /// Template files larger than this many bytes are refused
+    pub max_file_size: u64,
+    /// Replace invalid UTF-8 within template files instead of failing to read them
+    pub lossy: bool,
+    /// Encodings of the template files within directories relative to the book source directory
+    pub encodings: HashMap<PathBuf, String>,
+    /// Extensions tried, in order, for templates given without one
+    pub extensions: Vec<String>,
+    /// Match argument names regardless of their case, e.g. `title=` fills `[[#Title]]`
+    pub case_insensitive_args: bool,
+    /// Text inserted between expansions of templates that directly follow each other
+    pub separator: String,
+    /// Trim the whitespace around the expansions of templates invoked within a line of text
+    pub trim_inline: bool,
+    /// Default arguments of specific templates, keyed by their path relative to the book source
+    /// directory
+    pub defaults: HashMap<PathBuf, HashMap<String, String>>,
+    /// Book-wide variables available to every template, e.g. `version` for `[[#version]]`
+    pub variables: HashMap<String, String>,
+    /// Also replace the `[[#name]]` of the variables written directly within chapters
+    pub interpolate: bool,
+    /// Named sets of variables overriding the book-wide ones, e.g. for internal and public builds
+    pub profiles: HashMap<String, HashMap<String, String>>,
+    /// Profile whose variables are used by this build
+    pub profile: Option<String>,
+    /// Integrity digests, e.g. `sha256-<base64>`, that templates must match for the build to succeed,
+    /// keyed by their path relative to the book source directory
+    pub integrity: HashMap<PathBuf, String>,
+    /// Expand every chapter before resolving references and totals, so they can point forward
+    pub two_pass: bool,
+    /// Append a collapsed block to every chapter listing its expansions and their timings
+    pub debug_overlay: bool,
+    /// Keyword of the invocations expanded by the preprocessor, e.g. `component` for `{{#component ...}}`
+    pub directive: String,
+    /// Additional directives, e.g. `{{#snippet ...}}`, whose templates are found within their root
+    pub directives: HashMap<String, Directive>,
+    /// Book source directory, which `chapter:` templates are relative to
+    #[serde(skip)]
+    pub src_dir: PathBuf,
+    /// Settings of the directories within the book source directory that have a `.template.toml`
+    #[serde(skip)]
+    pub directories: HashMap<PathBuf, DirectoryConfig>,
+    /// Cargo.toml, relative to the book root, whose `[package]` provides the `cargo.*` names
+    pub cargo_manifest: Option<PathBuf>,
+    /// Render `[[@badge ...]]` as plain text rather than images fetched from shields.io
+    pub offline_badges: bool,
+    /// Commands rendering `{{#diagram ...}}` files by extension, reading the diagram from stdin
+    pub diagram_commands: HashMap<String, String>,
+    /// Languages of fenced code blocks left untouched, e.g. those showing other templating systems
+    pub skip_langs: Vec<String>,
+    /// Directories, relative to the book root, searched for newer bundles of the installed packs
+    pub pack_sources: Vec<PathBuf>,
+    /// File, relative to the book root, the expansion metrics of every build are written to
+    pub metrics: Option<PathBuf>,
+    /// Date, e.g. `2024-01-31`, before which templates last modified are flagged as stale by `check`
+    pub stale_before: Option<String>,
+    /// Fail the build on placeholders without a value or default instead of leaving them out
+    pub strict_args: bool,
+    /// Leave placeholders without a value or default as they are, for another tool to fill
+    pub keep_placeholders: bool,
+    /// Warn about templates whose output leaves HTML tags unclosed, or closes tags it never opened
+    pub validate_html: bool,
+    /// Whether the urls within the output of templates are checked
+    pub check_links: CheckLinks,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Directive {
+    /// Directory, relative to the book source directory, the templates of this directive are in
+    pub root: PathBuf,
+    /// Default arguments of every template of this directive
+    pub defaults: HashMap<String, String>,
+}
+
+// Prefix of the environment variables overriding options of the preprocessor table
+const ENV_PREFIX: &str = "MDBOOK_TEMPLATE__";
+
+// Keys of every preprocessor table that mdbook itself reads
+const MDBOOK_KEYS: &[&str] = &["command", "before", "after", "optional"];
+
+/// Settings a chapter overrides for itself within the `[template]` table of its front matter, e.g.
+///
+/// ```text
+/// +++
+/// [template]
+/// on-error = "fail"
+/// variables = { audience = "operators" }
+/// +++
+/// ```
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ChapterConfig {
+    pub on_error: Option<OnError>,
+    pub interpolate: Option<bool>,
+    pub case_insensitive_args: Option<bool>,
+    pub strict_args: Option<bool>,
+    pub skip_langs: Option<Vec<String>>,
+    pub max_expansions: Option<usize>,
+    pub max_invocation_length: Option<usize>,
+    pub max_invocation_lines: Option<usize>,
+    /// Variables of the chapter, taking precedence over those of the book
+    pub variables: HashMap<String, String>,
+}
+
+/// Name of the file carrying the settings of every template within its directory, and below it.
+pub const DIRECTORY_CONFIG: &str = ".template.toml";
+
+/// Settings shared by the templates of a directory, so a vendored component library can carry
+/// its own settings into any book that includes it.
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DirectoryConfig {
+    /// Default arguments of every template within the directory
+    pub defaults: HashMap<String, String>,
+    /// Encoding of the template files within the directory when they aren't UTF-8
+    pub encoding: Option<String>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Passthrough {
+    /// Leave the chapters untouched
+    #[default]
+    Keep,
+    /// Remove every `{{#template ...}}`
+    Strip,
+    /// Replace every `{{#template ...}}` with the `passthrough-note`
+    Note,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnError {
+    /// Log an error and leave the invocation as is
+    #[default]
+    Keep,
+    /// Quietly leave out the invocation
+    Ignore,
+    /// Log a warning and leave out the invocation
+    Warn,
+    /// Fail the build once every chapter is expanded
+    Fail,
+}
+
+impl OnError {
+    /// Parses the value of `on-error=` given at a call site.
+    pub(crate) fn parse(value: &str) -> Option<OnError> {
+        match value {
+            "keep" => Some(OnError::Keep),
+            "ignore" => Some(OnError::Ignore),
+            "warn" => Some(OnError::Warn),
+            "fail" => Some(OnError::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    /// Follow symlinks wherever they point
+    #[default]
+    Follow,
+    /// Follow symlinks as long as they resolve to a file within the book root, templates reached
+    /// without any symlink are read wherever they are
+    WithinRoot,
+    /// Refuse to read symlinked template files
+    Deny,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckLinks {
+    /// Leave urls unchecked
+    #[default]
+    Off,
+    /// Check the syntax of urls, without ever sending a request
+    Syntax,
+    /// Check the syntax of urls, and send a `HEAD` request to every external one through `curl`
+    Head,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            global_counters: false,
+            glossary: None,
+            renderers: None,
+            passthrough: Passthrough::default(),
+            passthrough_note: "_This content is not available in this format._".to_string(),
+            on_error: OnError::default(),
+            diagnostics: diagnostics::Format::default(),
+            usage_appendix: false,
+            provenance: false,
+            presets: HashMap::new(),
+            read_only_globals: false,
+            disable_builtins: Vec::new(),
+            env_allow: Vec::new(),
+            preserve_escapes: false,
+            max_chapter_length: 10 * 1024 * 1024,
+            stream_threshold: 1024 * 1024,
+            max_expansions: 10_000,
+            max_invocation_length: 64 * 1024,
+            max_invocation_lines: 50,
+            symlinks: Symlinks::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            lossy: false,
+            encodings: HashMap::new(),
+            extensions: vec!["md".to_string()],
+            case_insensitive_args: false,
+            separator: String::new(),
+            trim_inline: false,
+            defaults: HashMap::new(),
+            variables: HashMap::new(),
+            interpolate: false,
+            profiles: HashMap::new(),
+            profile: None,
+            integrity: HashMap::new(),
+            two_pass: false,
+            debug_overlay: false,
+            directive: DEFAULT_DIRECTIVE.to_string(),
+            directives: HashMap::new(),
+            src_dir: PathBuf::new(),
+            directories: HashMap::new(),
+            cargo_manifest: None,
+            offline_badges: false,
+            diagram_commands: HashMap::new(),
+            skip_langs: Vec::new(),
+            pack_sources: Vec::new(),
+            metrics: None,
+            stale_before: None,
+            strict_args: false,
+            keep_placeholders: false,
+            validate_html: false,
+            check_links: CheckLinks::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Every directive keyword expanded by the preprocessor, starting with `directive`.
+    pub fn keywords(&self) -> Vec<&str> {
+        let mut keywords = self
+            .directives
+            .keys()
+            .map(String::as_str)
+            .filter(|keyword| *keyword != self.directive)
+            .collect::<Vec<_>>();
+        keywords.sort_unstable();
+        keywords.insert(0, &self.directive);
+        keywords
+    }
+
+    /// Book-wide variables, overridden by those of the selected `profile`.
+    pub fn variables(&self) -> Result<HashMap<String, String>> {
+        let mut variables = self.variables.clone();
+        if let Some(ref profile) = self.profile {
+            let overrides = self
+                .profiles
+                .get(profile)
+                .with_context(|| format!("Unknown profile {}", profile))?;
+            variables.extend(overrides.clone());
+        }
+        Ok(variables)
+    }
+
+    /// The configuration of a chapter, with the settings its front matter overrides.
+    pub fn for_chapter(&self, chapter: &ChapterConfig) -> Cow<'_, Config> {
+        if *chapter == ChapterConfig::default() {
+            return Cow::Borrowed(self);
+        }
+        let mut config = self.clone();
+        config.on_error = chapter.on_error.unwrap_or(config.on_error);
+        config.interpolate = chapter.interpolate.unwrap_or(config.interpolate);
+        config.case_insensitive_args = chapter
+            .case_insensitive_args
+            .unwrap_or(config.case_insensitive_args);
+        config.strict_args = chapter.strict_args.unwrap_or(config.strict_args);
+        if let Some(ref skip_langs) = chapter.skip_langs {
+            config.skip_langs = skip_langs.clone();
+        }
+        config.max_expansions = chapter.max_expansions.unwrap_or(config.max_expansions);
+        config.max_invocation_length = chapter
+            .max_invocation_length
+            .unwrap_or(config.max_invocation_length);
+        config.max_invocation_lines = chapter
+            .max_invocation_lines
+            .unwrap_or(config.max_invocation_lines);
+        Cow::Owned(config)
+    }
+
+    /// Settings of the directories `target` is within, starting from the outermost one.
+    pub fn directories_of(&self, target: &Path) -> Vec<&DirectoryConfig> {
+        let mut directories = self
+            .directories
+            .iter()
+            .filter(|(dir, _)| target.starts_with(dir))
+            .collect::<Vec<_>>();
+        directories.sort_by_key(|(dir, _)| dir.components().count());
+        directories.into_iter().map(|(_, config)| config).collect()
+    }
+
+    pub fn from_context(ctx: &PreprocessorContext, name: &str) -> Result<Config> {
+        Config::from_book(&ctx.config, name)
+    }
+
+    /// Reads the `[preprocessor.<name>]` table of the book configuration, e.g. for the
+    /// subcommands that run outside of a build.
+    pub fn from_book(book: &mdbook::Config, name: &str) -> Result<Config> {
+        let mut table = book.get_preprocessor(name).cloned().unwrap_or_default();
+        for key in MDBOOK_KEYS {
+            table.remove(*key);
+        }
+        apply_env(
+            &mut table,
+            std::env::vars_os().filter_map(|(name, value)| {
+                Some((
+                    name.into_string().ok()?,
+                    value.to_string_lossy().into_owned(),
+                ))
+            }),
+        );
+        validate(&table)
+            .and_then(|_| Ok(Value::Table(table).try_into()?))
+            .with_context(|| format!("Invalid configuration for [preprocessor.{}]", name))
+    }
+
+    /// Resolves the configuration against the book source directory `src_dir`, as every build
+    /// does: `defaults` and `integrity` are keyed by the path templates are read from, directive
+    /// roots are joined onto it and the `.template.toml` of its directories are read.
+    pub fn resolve_paths(&mut self, src_dir: &Path) -> Result<()> {
+        let target = |template: PathBuf| {
+            LinkType::Template(template)
+                .target(src_dir)
+                .expect("Templates have a target")
+        };
+        self.src_dir = src_dir.to_path_buf();
+        self.defaults = std::mem::take(&mut self.defaults)
+            .into_iter()
+            .map(|(template, defaults)| (target(template), defaults))
+            .collect();
+        self.integrity = std::mem::take(&mut self.integrity)
+            .into_iter()
+            .map(|(template, integrity)| (target(template), integrity))
+            .collect();
+        for directive in self.directives.values_mut() {
+            directive.root = src_dir.join(&directive.root);
+        }
+        self.directories = read_directories(src_dir, self.symlinks)?;
+        Ok(())
+    }
+}
+
+/// Overrides the options of `table` with the `MDBOOK_TEMPLATE__<KEY>` environment variables, e.g.
+/// `MDBOOK_TEMPLATE__ON_ERROR=fail` or `MDBOOK_TEMPLATE__VARIABLES__HOST=localhost`. Values are
+/// read as TOML when the option they override isn't a string, e.g. `MAX_EXPANSIONS=100`, and as
+/// strings otherwise, so `MDBOOK_TEMPLATE__VARIABLES__VERSION=2` stays `"2"`.
+///
+/// Only the option name is turned into kebab-case. Keys nested within it, such as the names of
+/// variables, are kept as written unless they match an existing key regardless of case, so both
+/// `HOST` and `host` override `host` and `API_VERSION` overrides `api_version`.
+fn apply_env<I>(table: &mut Map<String, Value>, vars: I)
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let mut keys = path.split("__").map(str::to_owned).collect::<Vec<_>>();
+        keys[0] = keys[0].to_lowercase().replace('_', "-");
+        let parsed = toml::from_str::<Map<String, Value>>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"));
+
+        let (option, _) = keys.split_first().expect("split always yields a key");
+        if let Some(parsed) = parsed {
+            let mut overridden = table
+                .get(option)
+                .cloned()
+                .map_or_else(Map::new, |value| Map::from_iter([(option.clone(), value)]));
+            insert(&mut overridden, &keys, parsed.clone());
+            if Value::Table(overridden).try_into::<Config>().is_ok() {
+                insert(table, &keys, parsed);
+                continue;
+            }
+        }
+        insert(table, &keys, Value::String(value));
+    }
+}
+
+/// Sets the value at the path of nested `keys` within `table`, replacing whatever isn't a table
+/// along the way. Keys matching an existing one regardless of case refer to that one.
+fn insert(table: &mut Map<String, Value>, keys: &[String], value: Value) {
+    let existing = |table: &Map<String, Value>, key: &String| {
+        table
+            .keys()
+            .find(|existing| existing.eq_ignore_ascii_case(key))
+            .unwrap_or(key)
+            .clone()
+    };
+    let (last, parents) = keys.split_last().expect("split always yields a key");
+    let mut current = table;
+    for key in parents {
+        let entry = current
+            .entry(existing(current, key))
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Map::new());
+        }
+        current = entry.as_table_mut().expect("entry was just made a table");
+    }
+    current.insert(existing(current, last), value);
+}
+
+/// Checks every key of the preprocessor table on its own, so that each unknown key, or value of
+/// the wrong type, is reported by name rather than silently ignored.
+fn validate(table: &Map<String, Value>) -> Result<()> {
+    let keys = keys_of::<Config>();
+    let mut errors = Vec::new();
+    for (key, value) in table {
+        if !keys.contains(&key.as_str()) {
+            match closest(key, keys) {
+                Some(similar) => errors.push(format!(
+                    "Unknown key `{}`, did you mean `{}`?",
+                    key, similar
+                )),
+                None => errors.push(format!("Unknown key `{}`", key)),
+            }
+            continue;
+        }
+        let single = Value::Table(Map::from_iter([(key.clone(), value.clone())]));
+        if let Err(err) = single.try_into::<Config>() {
+            errors.push(err.to_string());
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => bail!(errors.join("\n")),
+    }
+}
+
+/// The known key most similar to `key`, if any is close enough to be a typo of it.
+fn closest<'a>(key: &str, keys: &[&'a str]) -> Option<&'a str> {
+    keys.iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, known)| *distance <= known.len() / 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Names of the fields of `T`, as asked for by its `Deserialize` implementation.
+fn keys_of<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct Keys<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for Keys<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: de::Visitor<'de>>(
+            self,
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            Err(de::Error::custom("Only structs have keys"))
+        }
+
+        fn deserialize_struct<V: de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("Only the keys are read"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut keys: &'static [&'static str] = &[];
+    let _ = T::deserialize(Keys(&mut keys));
+    keys
+}
+
+/// Reads every `.template.toml` within `dir` and its subdirectories, keyed by the directory it
+/// configures. Hidden directories are skipped, symlinked ones followed as `symlinks` allows.
+pub(crate) fn read_directories(
+    dir: &Path,
+    symlinks: Symlinks,
+) -> Result<HashMap<PathBuf, DirectoryConfig>> {
+    let mut directories = HashMap::new();
+    for file in utils::walk(dir, symlinks)? {
+        if file.file_name().is_none_or(|name| name != DIRECTORY_CONFIG) {
+            continue;
+        }
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid configuration in {}", file.display()))?;
+        let dir = file.parent().expect("Files are within a directory");
+        directories.insert(normalize_path(dir), config);
+    }
+    Ok(directories)
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    use toml::Value;
+
+    use crate::config::{
+        apply_env, read_directories, validate, ChapterConfig, Config, DirectoryConfig, OnError,
+        Symlinks,
+    };
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_profile_variables() {
+        let mut config = Config {
+            variables: HashMap::from([
+                ("host".to_string(), "docs.example.com".to_string()),
+                ("internal".to_string(), "false".to_string()),
+            ]),
+            profiles: HashMap::from([(
+                "internal".to_string(),
+                HashMap::from([("internal".to_string(), "true".to_string())]),
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(config.variables().unwrap(), config.variables);
+
+        config.profile = Some("internal".to_string());
+        assert_eq!(
+            config.variables().unwrap(),
+            HashMap::from([
+                ("host".to_string(), "docs.example.com".to_string()),
+                ("internal".to_string(), "true".to_string()),
+            ])
+        );
+
+        config.profile = Some("staging".to_string());
+        assert!(config.variables().is_err());
+    }
+
+    #[test]
+    fn test_read_directories() {
+        let book = Fixture::new("directories")
+            .file(
+                "vendor/.template.toml",
+                "encoding = \"shift_jis\"\n[defaults]\ntheme = \"dark\"\n",
+            )
+            .file(
+                "vendor/components/.template.toml",
+                "[defaults]\ntheme = \"light\"\n",
+            )
+            .file("vendor/components/.git/.template.toml", "invalid")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+
+        let config = Config {
+            directories: read_directories(&dir, Symlinks::Follow).unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.directories.len(), 2);
+        assert_eq!(
+            config.directories_of(&dir.join("vendor/components/card.md")),
+            vec![
+                &DirectoryConfig {
+                    defaults: HashMap::from([("theme".to_string(), "dark".to_string())]),
+                    encoding: Some("shift_jis".to_string()),
+                },
+                &DirectoryConfig {
+                    defaults: HashMap::from([("theme".to_string(), "light".to_string())]),
+                    encoding: None,
+                },
+            ]
+        );
+        assert!(config.directories_of(Path::new("chapter.md")).is_empty());
+        assert!(config
+            .directories_of(&PathBuf::from(&dir).join("card.md"))
+            .is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_directories_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let book = Fixture::new("directories-symlinks")
+            .file("guide/.template.toml", "[defaults]\ntheme = \"dark\"\n")
+            .file(
+                "reference/.template.toml",
+                "[defaults]\ntheme = \"light\"\n",
+            )
+            .file(
+                "../shared/.template.toml",
+                "[defaults]\ntheme = \"shared\"\n",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        // Links back up the tree would be walked endlessly if followed every time
+        symlink("..", dir.join("guide/up")).unwrap();
+        symlink("..", dir.join("reference/up")).unwrap();
+        symlink("../shared", dir.join("shared")).unwrap();
+
+        let read = |symlinks| {
+            let mut directories = read_directories(&dir, symlinks)
+                .unwrap()
+                .into_keys()
+                .map(|directory| directory.strip_prefix(&dir).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            directories.sort();
+            directories
+        };
+
+        assert_eq!(
+            read(Symlinks::Follow),
+            [
+                Path::new("guide"),
+                Path::new("reference"),
+                Path::new("shared")
+            ]
+        );
+        assert_eq!(
+            read(Symlinks::WithinRoot),
+            [Path::new("guide"), Path::new("reference")]
+        );
+        assert_eq!(
+            read(Symlinks::Deny),
+            [Path::new("guide"), Path::new("reference")]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let table = toml::from_str(
+            r#"
+            global-counters = true
+            on-error = "warn"
+            max-chapter-length = 1024
+            "#,
+        )
+        .unwrap();
+        assert!(validate(&table).is_ok());
+
+        let table = toml::from_str(
+            r#"
+            global-counter = true
+            on-error = "explode"
+            max-chapter-length = "1 KiB"
+            colour = "red"
+            "#,
+        )
+        .unwrap();
+        let err = validate(&table).unwrap_err().to_string();
+        let lines = err.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines.contains(&"Unknown key `global-counter`, did you mean `global-counters`?"));
+        assert!(lines.contains(&"Unknown key `colour`"));
+        assert!(err.contains("unknown variant `explode`, expected one of `keep`, `ignore`, `warn`, `fail` for key `on-error`"));
+        assert!(err.contains(
+            "invalid type: string \"1 KiB\", expected usize for key `max-chapter-length`"
+        ));
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut table = toml::from_str(
+            "on-error = \"keep\"\nvariables = { host = \"docs.example.com\", api_version = \"1\" }",
+        )
+        .unwrap();
+
+        apply_env(
+            &mut table,
+            [
+                ("MDBOOK_TEMPLATE__ON_ERROR", "fail"),
+                ("MDBOOK_TEMPLATE__OFFLINE_BADGES", "true"),
+                ("MDBOOK_TEMPLATE__MAX_CHAPTER_LENGTH", "1024"),
+                ("MDBOOK_TEMPLATE__VARIABLES__HOST", "localhost"),
+                ("MDBOOK_TEMPLATE__VARIABLES__API_VERSION", "2"),
+                ("MDBOOK_TEMPLATE__VARIABLES__ApiVersion", "3"),
+                ("MDBOOK_TEMPLATE__VARIABLES__VERSION", "2"),
+                ("MDBOOK_TEMPLATE__VARIABLES__RELEASE", "1.2"),
+                ("MDBOOK_TEMPLATE__VARIABLES__STABLE", "true"),
+                ("MDBOOK_TEMPLATE__SEPARATOR", "[0]"),
+                ("MDBOOK_TEMPLATE__SKIP_LANGS", "[\"jinja\"]"),
+                ("MDBOOK_BUILD__BUILD_DIR", "public"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let config = Value::Table(table).try_into::<Config>().unwrap();
+
+        assert_eq!(config.on_error, OnError::Fail);
+        assert!(config.offline_badges);
+        assert_eq!(config.max_chapter_length, 1024);
+        assert_eq!(
+            config.variables,
+            HashMap::from([
+                ("host".to_string(), "localhost".to_string()),
+                ("api_version".to_string(), "2".to_string()),
+                ("ApiVersion".to_string(), "3".to_string()),
+                ("VERSION".to_string(), "2".to_string()),
+                ("RELEASE".to_string(), "1.2".to_string()),
+                ("STABLE".to_string(), "true".to_string()),
+            ])
+        );
+        assert_eq!(config.separator, "[0]");
+        assert_eq!(config.skip_langs, vec!["jinja".to_string()]);
+    }
+
+    #[test]
+    fn test_for_chapter() {
+        let config = Config::default();
+        assert!(matches!(
+            config.for_chapter(&ChapterConfig::default()),
+            Cow::Borrowed(_)
+        ));
+
+        let chapter = config.for_chapter(&ChapterConfig {
+            on_error: Some(OnError::Warn),
+            skip_langs: Some(vec!["jinja".to_string()]),
+            ..Default::default()
+        });
+
+        assert_eq!(chapter.on_error, OnError::Warn);
+        assert_eq!(chapter.skip_langs, vec!["jinja".to_string()]);
+        assert_eq!(chapter.max_invocation_lines, config.max_invocation_lines);
+    }
+}
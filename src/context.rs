@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::config::Config;
+use crate::stats::Stats;
+
+/// Everything the expansions within a chapter share: the file reader templates are read through,
+/// which caches them for the whole book when wrapped in a
+/// [`CachedFileReader`](crate::utils::CachedFileReader), the configuration, the variables of the
+/// chapter and the [`Stats`] expansions and failures are recorded in.
+///
+/// The context is `Send + Sync` whenever its file reader is, so chapters can be expanded
+/// independently of each other.
+#[derive(Debug)]
+pub struct ExpansionContext<'a, FR> {
+    pub(crate) file_reader: &'a FR,
+    pub(crate) globals: &'a HashMap<String, String>,
+    pub(crate) config: &'a Config,
+    stats: Mutex<Stats>,
+    budget_exhausted: AtomicBool,
+    // Canonical paths of the templates being expanded, from the outermost one
+    expanding: Mutex<Vec<PathBuf>>,
+}
+
+impl<'a, FR> ExpansionContext<'a, FR> {
+    pub fn new(
+        file_reader: &'a FR,
+        globals: &'a HashMap<String, String>,
+        config: &'a Config,
+    ) -> Self {
+        ExpansionContext {
+            file_reader,
+            globals,
+            config,
+            stats: Mutex::new(Stats::default()),
+            budget_exhausted: AtomicBool::new(false),
+            expanding: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Expansions and failures recorded so far.
+    pub fn stats(&self) -> MutexGuard<'_, Stats> {
+        // Stats are only ever appended to, so they remain usable after a panic elsewhere
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Marks the `max-expansions` budget of the chapter as exhausted, returning whether it
+    /// wasn't already.
+    pub(crate) fn exhaust_budget(&self) -> bool {
+        !self.budget_exhausted.swap(true, Ordering::Relaxed)
+    }
+
+    /// Marks the template at the canonical path `template` as being expanded, returning false
+    /// when it already is, i.e. it includes itself.
+    pub(crate) fn enter(&self, template: &Path) -> bool {
+        let mut expanding = self
+            .expanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if expanding.iter().any(|expanded| expanded == template) {
+            return false;
+        }
+        expanding.push(template.to_path_buf());
+        true
+    }
+
+    /// Marks the template entered last as expanded.
+    pub(crate) fn leave(&self) {
+        self.expanding
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+    }
+
+    pub fn into_stats(self) -> Stats {
+        self.stats
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod context_tests {
+    use crate::context::ExpansionContext;
+    use crate::utils::{
+        BuiltinFileReader, CachedFileReader, ExtensionFileReader, RendererFileReader,
+        SymlinkFileReader, SystemFileReader, TestFileReader,
+    };
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_context_is_send_sync() {
+        assert_send_sync::<ExpansionContext<TestFileReader>>();
+        assert_send_sync::<
+            ExpansionContext<
+                CachedFileReader<
+                    BuiltinFileReader<
+                        ExtensionFileReader<
+                            RendererFileReader<SymlinkFileReader<SystemFileReader>>,
+                        >,
+                    >,
+                >,
+            >,
+        >();
+    }
+}
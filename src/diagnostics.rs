@@ -0,0 +1,537 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError, RwLock};
+
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+/// How bad a [`Diagnostic`] is, errors leave the invocation they are about unexpanded.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Kind of a [`Diagnostic`], explained at length by `mdbook-template explain <code>`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Code {
+    MissingFile,
+    Cycle,
+    Integrity,
+    Budget,
+    UnknownVariable,
+    MalformedArguments,
+    UnterminatedInvocation,
+    UnresolvedReference,
+    DuplicateLabel,
+    ReservedName,
+    Encoding,
+    FileCasing,
+    UnknownTerm,
+    UnknownBadge,
+    FrontMatter,
+    UnknownOnError,
+    InvalidKeyword,
+    Expansion,
+    MissingArgument,
+    UnbalancedHtml,
+    InvalidLink,
+    MalformedMatch,
+}
+
+// Every code with its identifier and explanation, identifiers must never be reused
+const CODES: &[(Code, &str, &str)] = &[
+    (
+        Code::MissingFile,
+        "T001",
+        "A template file could not be found.
+
+Paths of `{{#template ...}}` are relative to the chapter, or template, invoking them. Check the
+spelling of the path and that the file exists, or use `on-error=ignore` for optional templates.",
+    ),
+    (
+        Code::Cycle,
+        "T002",
+        "Templates are nested too deeply, most likely because they include each other.
+
+A template that includes itself, directly, through other templates or through a symlink to it,
+never finishes expanding, so it is left out as soon as it does. Expansion also stops after 10
+levels of nesting. Break the cycle, e.g. by moving the shared part
+into a template of its own that includes neither.",
+    ),
+    (
+        Code::Integrity,
+        "T003",
+        "A template doesn't match the digest it is pinned to with `integrity`.
+
+The template changed since it was pinned. Review the change, then update its digest within the
+`integrity` table of the configuration.",
+    ),
+    (
+        Code::Budget,
+        "T004",
+        "A chapter expands more templates than `max-expansions` allows.
+
+This usually means generated content runs away, e.g. a template including itself through data
+files. When the chapter really is that large, raise `max-expansions` within the configuration, or
+for that chapter only within the `[template]` table of its front matter.",
+    ),
+    (
+        Code::UnknownVariable,
+        "T005",
+        "A `{{ name }}` interpolation refers to a variable that doesn't exist.
+
+Define the variable within `variables` of the configuration or of the chapter front matter, or
+escape the braces with `\\{{` when they are meant literally.",
+    ),
+    (
+        Code::MalformedArguments,
+        "T006",
+        "Arguments of an invocation could not be parsed.
+
+Arguments are given as `key=value`, with values spanning several lines wrapped in double quotes.
+Check for a missing `=` or closing quote.",
+    ),
+    (
+        Code::UnterminatedInvocation,
+        "T007",
+        "An invocation has no closing `}}` within `max-invocation-length` or `max-invocation-lines`.
+
+Close the invocation, or escape its opening with `\\{{` when it is meant literally.",
+    ),
+    (
+        Code::UnresolvedReference,
+        "T008",
+        "A `[[@ref ...]]` refers to a label that no chapter defines.
+
+Check the spelling of the label, and that the chapter defining it with `[[@label ...]]` is part of
+SUMMARY.md.",
+    ),
+    (
+        Code::DuplicateLabel,
+        "T009",
+        "The same label is defined more than once.
+
+References to it can only point to one of the definitions. Rename one of the labels.",
+    ),
+    (
+        Code::ReservedName,
+        "T010",
+        "An argument uses a name of a builtin namespace, such as `chapter.title` or `env.HOME`.
+
+Rename the argument, or disable the namespace with `disable-builtins`.",
+    ),
+    (
+        Code::Encoding,
+        "T011",
+        "A template file is not valid in its encoding, so invalid sequences were replaced.
+
+Declare the encoding of the file within its front matter, or of its directory within `encodings`
+or a `.template.toml`.",
+    ),
+    (
+        Code::FileCasing,
+        "T012",
+        "A template file only exists with a different casing.
+
+The book builds on case-insensitive file systems, such as those of macOS and Windows, but not on
+Linux. Fix the casing of the path.",
+    ),
+    (
+        Code::UnknownTerm,
+        "T013",
+        "A `[[@gloss ...]]` refers to a term the glossary doesn't define.
+
+Add the term to the chapter configured as the `glossary`, or check its spelling.",
+    ),
+    (
+        Code::UnknownBadge,
+        "T014",
+        "A `[[@badge ...]]` names a badge that doesn't exist.
+
+The available badges are crates-io, docs-rs and license.",
+    ),
+    (
+        Code::FrontMatter,
+        "T015",
+        "The front matter of a chapter could not be parsed, so it is ignored.
+
+Front matter is TOML between two `+++` lines at the very start of the chapter. Check the TOML and
+that the `[template]` table only overrides the supported settings.",
+    ),
+    (
+        Code::UnknownOnError,
+        "T016",
+        "An invocation has an unknown `on-error=`.
+
+The possible values are keep, ignore, warn and fail.",
+    ),
+    (
+        Code::InvalidKeyword,
+        "T017",
+        "The keyword of a directive is not valid.
+
+Keywords of `directive` and `directives` may only contain letters, digits, `-` and `_`.",
+    ),
+    (
+        Code::Expansion,
+        "T018",
+        "An invocation could not be expanded.
+
+The message, and the causes listed after it, tell why. Use `on-error=` to choose what happens to
+invocations that can't be expanded.",
+    ),
+    (
+        Code::MissingArgument,
+        "T019",
+        "A template was invoked without an argument it needs.
+
+The argument is either marked required within the template, e.g. `[[#title!]]`, or has no default
+while `strict-args` is enabled. Give it at the call site, or give the placeholder a default.",
+    ),
+    (
+        Code::UnbalancedHtml,
+        "T020",
+        "The output of a template leaves HTML tags unclosed, or closes tags it never opened.
+
+Unbalanced tags break the layout of the rest of the page. Check the arguments given to the
+template, as they may contain HTML themselves. Reported only with `validate-html` enabled.",
+    ),
+    (
+        Code::InvalidLink,
+        "T021",
+        "The output of a template contains a link whose url is empty, malformed or can't be reached.
+
+Templates building urls from their arguments, such as badges and links, produce broken links when
+an argument is missing or has an unexpected value. Reported only with `check-links` enabled, and
+`check-links = \"head\"` requests every external url, which needs `curl`.",
+    ),
+    (
+        Code::MalformedMatch,
+        "T022",
+        "A `[[#match]]` block written within a chapter is malformed.
+
+It is missing its `[[/match]]`, or the markers within it are out of place, such as a
+`[[#case]]` after its `[[#default]]`. The block is left as it is.",
+    ),
+];
+
+impl Code {
+    /// Identifier of the code, e.g. `T002`.
+    pub fn id(self) -> &'static str {
+        self.entry().1
+    }
+
+    /// Longer description of the code, including how to fix it.
+    pub fn explanation(self) -> &'static str {
+        self.entry().2
+    }
+
+    /// The code identified by `id`, ignoring its case.
+    pub fn parse(id: &str) -> Option<Code> {
+        CODES
+            .iter()
+            .find(|(_, known, _)| known.eq_ignore_ascii_case(id.trim()))
+            .map(|(code, _, _)| *code)
+    }
+
+    fn entry(self) -> &'static (Code, &'static str, &'static str) {
+        CODES
+            .iter()
+            .find(|(code, _, _)| *code == self)
+            .expect("Every code has an entry")
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+/// A warning or error raised while expanding the book.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    /// Chapter, or template, the diagnostic is about when it is known
+    pub file: Option<PathBuf>,
+}
+
+/// Receives every [`Diagnostic`], so that reports, strict builds and editor integrations all
+/// observe the same events.
+pub trait Diagnostics: Send + Sync {
+    fn report(&self, diagnostic: &Diagnostic);
+}
+
+/// Writes diagnostics through the `log` crate, as mdbook does.
+#[derive(Debug, Default)]
+pub struct Log;
+
+/// Writes each diagnostic to stderr as a line of JSON.
+#[derive(Debug, Default)]
+pub struct Json;
+
+/// Writes diagnostics to stderr as GitHub Actions workflow commands, which annotate the files
+/// they are about within pull requests.
+#[derive(Debug, Default)]
+pub struct GitHub;
+
+/// Keeps every diagnostic in memory.
+#[derive(Debug, Default)]
+pub struct Collect {
+    diagnostics: Mutex<Vec<Diagnostic>>,
+}
+
+/// Format diagnostics are written in, see [`install`].
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    Log,
+    Json,
+    Github,
+}
+
+static SINK: RwLock<Option<Box<dyn Diagnostics>>> = RwLock::new(None);
+
+thread_local! {
+    // Diagnostics of the current thread are collected here instead while within `capture`
+    static CAPTURED: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, code: Code, message: impl fmt::Display) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message: message.to_string(),
+            file: None,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "[{}] {}: {}", self.code, file.display(), self.message),
+            None => write!(f, "[{}] {}", self.code, self.message),
+        }
+    }
+}
+
+impl Diagnostics for Log {
+    fn report(&self, diagnostic: &Diagnostic) {
+        match diagnostic.severity {
+            Severity::Warning => warn!("{}", diagnostic),
+            Severity::Error => error!("{}", diagnostic),
+        }
+    }
+}
+
+impl Diagnostics for Json {
+    fn report(&self, diagnostic: &Diagnostic) {
+        let severity = match diagnostic.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        eprintln!(
+            "{}",
+            json!({
+                "severity": severity,
+                "code": diagnostic.code.id(),
+                "message": diagnostic.message,
+                "file": diagnostic.file,
+            })
+        );
+    }
+}
+
+impl Diagnostics for GitHub {
+    fn report(&self, diagnostic: &Diagnostic) {
+        let command = match diagnostic.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        // Line breaks would end the workflow command early
+        let message = diagnostic
+            .message
+            .replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A");
+        match diagnostic.file {
+            Some(ref file) => eprintln!(
+                "::{} file={},title={}::{}",
+                command,
+                file.display(),
+                diagnostic.code,
+                message
+            ),
+            None => eprintln!("::{} title={}::{}", command, diagnostic.code, message),
+        }
+    }
+}
+
+impl Collect {
+    /// Every diagnostic reported so far, leaving none behind.
+    pub fn take(&self) -> Vec<Diagnostic> {
+        std::mem::take(
+            &mut self
+                .diagnostics
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+impl Diagnostics for Collect {
+    fn report(&self, diagnostic: &Diagnostic) {
+        self.diagnostics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(diagnostic.clone());
+    }
+}
+
+impl Format {
+    pub fn sink(self) -> Box<dyn Diagnostics> {
+        match self {
+            Format::Log => Box::new(Log),
+            Format::Json => Box::new(Json),
+            Format::Github => Box::new(GitHub),
+        }
+    }
+}
+
+/// Sends every diagnostic of the process to `sink` instead of the `log` crate.
+pub fn install(sink: Box<dyn Diagnostics>) {
+    *SINK.write().unwrap_or_else(PoisonError::into_inner) = Some(sink);
+}
+
+/// Runs `f`, returning the diagnostics it reported on the current thread rather than sending
+/// them to the installed sink.
+pub fn capture<F, R>(f: F) -> (R, Vec<Diagnostic>)
+where
+    F: FnOnce() -> R,
+{
+    let previous = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = f();
+    let diagnostics = CAPTURED.with(|captured| captured.replace(previous));
+    (result, diagnostics.unwrap_or_default())
+}
+
+pub(crate) fn report(diagnostic: Diagnostic) {
+    let diagnostic = match CAPTURED.with(|captured| match *captured.borrow_mut() {
+        Some(ref mut diagnostics) => {
+            diagnostics.push(diagnostic.clone());
+            None
+        }
+        None => Some(diagnostic),
+    }) {
+        Some(diagnostic) => diagnostic,
+        None => return,
+    };
+    match *SINK.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(ref sink) => sink.report(&diagnostic),
+        None => Log.report(&diagnostic),
+    }
+}
+
+pub(crate) fn warn(code: Code, message: impl fmt::Display) {
+    report(Diagnostic::new(Severity::Warning, code, message));
+}
+
+pub(crate) fn error(code: Code, message: impl fmt::Display) {
+    report(Diagnostic::new(Severity::Error, code, message));
+}
+
+/// Reports a warning about `file`, e.g. the chapter an invocation is in.
+pub(crate) fn warn_in(file: impl Into<PathBuf>, code: Code, message: impl fmt::Display) {
+    report(Diagnostic {
+        file: Some(file.into()),
+        ..Diagnostic::new(Severity::Warning, code, message)
+    });
+}
+
+/// Reports an error about `file`, e.g. the chapter an invocation couldn't be expanded in.
+pub(crate) fn error_in(file: impl Into<PathBuf>, code: Code, message: impl fmt::Display) {
+    report(Diagnostic {
+        file: Some(file.into()),
+        ..Diagnostic::new(Severity::Error, code, message)
+    });
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use std::path::PathBuf;
+
+    use crate::diagnostics::{
+        self, capture, Code, Collect, Diagnostic, Diagnostics, Format, Severity, CODES,
+    };
+
+    #[test]
+    fn test_capture() {
+        let (result, diagnostics) = capture(|| {
+            diagnostics::warn(
+                Code::MalformedArguments,
+                format_args!("Unknown trim {}", "sides"),
+            );
+            diagnostics::error_in("guide.md", Code::MissingFile, "Missing template");
+            42
+        });
+
+        assert_eq!(result, 42);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic {
+                    severity: Severity::Warning,
+                    code: Code::MalformedArguments,
+                    message: "Unknown trim sides".to_string(),
+                    file: None,
+                },
+                Diagnostic {
+                    severity: Severity::Error,
+                    code: Code::MissingFile,
+                    message: "Missing template".to_string(),
+                    file: Some(PathBuf::from("guide.md")),
+                },
+            ]
+        );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "[T001] guide.md: Missing template"
+        );
+    }
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(Code::parse("t002"), Some(Code::Cycle));
+        assert_eq!(Code::parse("T999"), None);
+        for (i, (code, id, explanation)) in CODES.iter().enumerate() {
+            assert_eq!(*id, format!("T{:03}", i + 1));
+            assert_eq!(Code::parse(id), Some(*code));
+            assert!(!explanation.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let collect = Collect::default();
+        collect.report(&Diagnostic::new(Severity::Warning, Code::Cycle, "first"));
+        collect.report(&Diagnostic::new(Severity::Warning, Code::Cycle, "second"));
+
+        assert_eq!(collect.take().len(), 2);
+        assert!(collect.take().is_empty());
+        assert_eq!(
+            toml::from_str::<toml::Value>("format = \"github\"").unwrap()["format"]
+                .clone()
+                .try_into::<Format>()
+                .unwrap(),
+            Format::Github
+        );
+    }
+}
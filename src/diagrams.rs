@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+
+use crate::stats::Source;
+use crate::utils;
+
+/// Builtin template rendering a diagram when no other `template` is given.
+pub(crate) const DIAGRAM_TEMPLATE: &str = "builtin:diagram";
+
+/// Renders the diagram `source` read from `file` through the command configured for its
+/// extension, or as the fenced code block expected by mdbook-mermaid or mdbook-plantuml.
+/// Commands are recorded within `sources` for the provenance report.
+pub(crate) fn render(
+    file: &Path,
+    source: &str,
+    commands: &HashMap<String, String>,
+    sources: &mut Vec<Source>,
+) -> Result<String> {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    if let Some(command) = commands.get(extension) {
+        let output = run(command, source)
+            .with_context(|| format!("Could not render {} with `{}`", file.display(), command))?;
+        sources.push(Source {
+            provider: command.to_owned(),
+            input: file.to_path_buf(),
+            input_sha256: utils::sha256(source),
+            output_sha256: utils::sha256(&output),
+        });
+        return Ok(output);
+    }
+
+    let lang = match extension {
+        "mmd" | "mermaid" => "mermaid",
+        "puml" | "plantuml" => "plantuml",
+        _ => bail!(
+            "Unknown diagram {}, expected a .mmd or .puml file",
+            file.display()
+        ),
+    };
+    Ok(format!("```{}\n{}\n```", lang, source.trim_end()))
+}
+
+fn run(command: &str, source: &str) -> Result<String> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().context("The command is empty")?;
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Stdin is piped");
+
+    // Commands may write their output while still reading the diagram, so stdin is written
+    // while stdout and stderr are drained, lest both end up waiting on a full pipe
+    let (written, output) = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(source.as_bytes()));
+        let output = child.wait_with_output();
+        (writer.join().expect("Writing stdin never panics"), output)
+    });
+    let output = output?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("{}, {}", output.status, stderr.trim());
+    }
+    match written {
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => bail!(
+            "It exited before reading the whole diagram, {}",
+            stderr.trim()
+        ),
+        written => written?,
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_owned())
+}
+
+#[cfg(test)]
+mod diagrams_tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use crate::diagrams::render;
+    use crate::utils;
+
+    #[test]
+    fn test_render_fenced() {
+        let commands = HashMap::new();
+
+        assert_eq!(
+            render(
+                Path::new("flow.mmd"),
+                "graph TD;\n  A-->B;\n",
+                &commands,
+                &mut vec![]
+            )
+            .unwrap(),
+            "```mermaid\ngraph TD;\n  A-->B;\n```"
+        );
+        assert_eq!(
+            render(
+                Path::new("seq.puml"),
+                "@startuml\n@enduml",
+                &commands,
+                &mut vec![]
+            )
+            .unwrap(),
+            "```plantuml\n@startuml\n@enduml\n```"
+        );
+        assert!(render(Path::new("flow.png"), "", &commands, &mut vec![]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_command() {
+        let commands = HashMap::from([
+            ("puml".to_string(), "tr a-z A-Z".to_string()),
+            ("mmd".to_string(), "false".to_string()),
+        ]);
+
+        let mut sources = vec![];
+
+        assert_eq!(
+            render(
+                Path::new("seq.puml"),
+                "@startuml\n",
+                &commands,
+                &mut sources
+            )
+            .unwrap(),
+            "@STARTUML"
+        );
+        assert!(render(Path::new("flow.mmd"), "graph TD;", &commands, &mut sources).is_err());
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].provider, "tr a-z A-Z");
+        assert_eq!(sources[0].output_sha256, utils::sha256("@STARTUML"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_command_large() {
+        // Larger than the pipe buffers, which a command echoing its input fills on both ends
+        let source = "graph TD;\n".repeat(30_000);
+        let commands = HashMap::from([
+            ("mmd".to_string(), "cat".to_string()),
+            ("puml".to_string(), "head -c 1".to_string()),
+        ]);
+
+        assert_eq!(
+            render(Path::new("flow.mmd"), &source, &commands, &mut vec![]).unwrap(),
+            source.trim_end()
+        );
+        assert!(
+            render(Path::new("seq.puml"), &source, &commands, &mut vec![])
+                .unwrap_err()
+                .root_cause()
+                .to_string()
+                .starts_with("It exited before reading the whole diagram")
+        );
+    }
+}
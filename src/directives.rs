@@ -0,0 +1,648 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use fancy_regex::{CaptureMatches, Captures, Regex};
+use lazy_static::lazy_static;
+
+use crate::badges;
+use crate::diagnostics::{self, Code};
+use crate::fences;
+use crate::glossary::Glossary;
+use crate::html;
+use crate::links::unescape;
+
+lazy_static! {
+    // r"(?x)\\\\(?=\\*\[\[\s*@(counter|total|label|ref|gloss|badge)\s[^]]+\]\])|\\\[\[\s*@(counter|total|label|ref|gloss|badge)\s[^]]+\]\]|\[\[\s*\\@(counter|total|label|ref|gloss|badge)\s[^]]+\]\]|\[\[\s*@(counter|total|label|ref|gloss|badge)\s+([^]]+?)\s*\]\]"
+    static ref DIRECTIVE: Regex = Regex::new(
+        r"(?x)                                  # enable insignificant whitespace mode
+
+        \\\\                                    # escaped backslash
+        (?=\\*\[\[\s*                           # before a directive opening square brackets
+        @(counter|total|label|ref|gloss|badge)             # builtin directive name
+        \s[^]]+\]\])                            # which is a directive
+
+        |                                       # or
+
+        \\\[\[\s*                               # escaped directive opening square brackets
+        @(counter|total|label|ref|gloss|badge)             # builtin directive name
+        \s[^]]+                                 # match everything up until the closing brackets
+        \]\]                                    # escaped directive closing brackets
+
+        |                                       # or
+
+        \[\[\s*                                 # directive opening brackets and whitespace(s)
+        \\@(counter|total|label|ref|gloss|badge)            # builtin directive name with an escaped @
+        \s[^]]+                                 # match everything up until the closing brackets
+        \]\]                                    # directive closing brackets
+
+        |                                       # or
+
+        \[\[\s*                                 # directive opening brackets and whitespace(s)
+        @(counter|total|label|ref|gloss|badge)             # builtin directive name
+        \s+                                     # separating whitespace(s)
+        ([^]]+?)                                # directive argument(s)
+        \s*                                     # optional trailing whitespace(s)
+        \]\]                                    # directive closing brackets"
+    )
+    .unwrap();
+}
+
+/// Returns true if `text` starts with a builtin directive or one of its escapes.
+pub(crate) fn starts_with_directive(text: &str) -> bool {
+    directive_length(text).is_some()
+}
+
+/// Length of the builtin directive, or one of its escapes, that `text` starts with.
+pub(crate) fn directive_length(text: &str) -> Option<usize> {
+    match DIRECTIVE.find(text) {
+        Ok(Some(mat)) if mat.start() == 0 => Some(mat.end()),
+        _ => None,
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct Directive<'a> {
+    start_index: usize,
+    end_index: usize,
+    directive_type: DirectiveType<'a>,
+    directive_text: &'a str,
+}
+
+impl<'a> Directive<'a> {
+    fn from_capture(cap: Captures<'a>) -> Option<Directive<'a>> {
+        let directive_type = match (
+            cap.get(1).or(cap.get(2)).or(cap.get(3)),
+            cap.get(4),
+            cap.get(5),
+        ) {
+            // This looks like \\[[@counter figure]], \[[@counter figure]] or [[\@counter figure]]
+            (Some(name), None, None) => Some(DirectiveType::Escaped(name.as_str())),
+            (None, Some(name), Some(arg)) => match name.as_str() {
+                // This looks like [[@counter figure]]
+                "counter" => Some(DirectiveType::Counter(arg.as_str())),
+                // This looks like [[@total figure]]
+                "total" => Some(DirectiveType::Total(arg.as_str())),
+                // This looks like [[@gloss API]]
+                "gloss" => Some(DirectiveType::Gloss(arg.as_str())),
+                // This looks like [[@badge crates-io mdbook-template]]
+                "badge" => Some(DirectiveType::Badge(arg.as_str())),
+                // This looks like [[@label install-step-3]]
+                "label" => Some(DirectiveType::Label(arg.as_str())),
+                // This looks like [[@ref install-step-3]] or [[@ref install-step-3 Step Three]]
+                "ref" => match arg.as_str().split_once(char::is_whitespace) {
+                    Some((label, text)) => Some(DirectiveType::Ref(label, Some(text.trim()))),
+                    None => Some(DirectiveType::Ref(arg.as_str(), None)),
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+
+        directive_type.and_then(|directive_type| {
+            cap.get(0).map(|mat| Directive {
+                start_index: mat.start(),
+                end_index: mat.end(),
+                directive_type,
+                directive_text: mat.as_str(),
+            })
+        })
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum DirectiveType<'a> {
+    Escaped(&'a str),
+    Counter(&'a str),
+    Total(&'a str),
+    Label(&'a str),
+    Gloss(&'a str),
+    Badge(&'a str),
+    Ref(&'a str, Option<&'a str>),
+}
+
+struct DirectiveIter<'a>(CaptureMatches<'a, 'a>);
+
+impl<'a> Iterator for DirectiveIter<'a> {
+    type Item = Directive<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for cap in &mut self.0 {
+            if let Some(inc) = Directive::from_capture(cap.ok()?) {
+                return Some(inc);
+            }
+        }
+        None
+    }
+}
+
+fn extract_directives(contents: &str) -> DirectiveIter<'_> {
+    DirectiveIter(DIRECTIVE.captures_iter(contents))
+}
+
+/// Moves the labels of `contents`, and its references to them, under the `namespace` of a pack,
+/// e.g. `[[@label figure]]` becomes `[[@label corp:figure]]`. Labels already within a namespace
+/// are left alone.
+pub(crate) fn namespace(contents: &str, namespace: &str) -> String {
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(contents.len());
+    for directive in extract_directives(contents) {
+        let (keyword, label, text) = match directive.directive_type {
+            DirectiveType::Label(label) if !label.contains(':') => ("label", label, None),
+            DirectiveType::Ref(label, text) if !label.contains(':') => ("ref", label, text),
+            _ => continue,
+        };
+        replaced.push_str(&contents[previous_end_index..directive.start_index]);
+        replaced.push_str(&format!("[[@{} {}:{}", keyword, namespace, label));
+        if let Some(text) = text {
+            replaced.push(' ');
+            replaced.push_str(text);
+        }
+        replaced.push_str("]]");
+        previous_end_index = directive.end_index;
+    }
+    replaced.push_str(&contents[previous_end_index..]);
+    replaced
+}
+
+/// Book-wide state of the builtin directives.
+///
+/// Chapters are first passed through [`Registry::collect`], which numbers counters and
+/// registers labels. Once every chapter has been collected, [`Registry::resolve`] turns
+/// references into links to the chapter that defined the label and totals into the final
+/// value of their counter.
+#[derive(Default, Debug)]
+pub(crate) struct Registry {
+    counters: HashMap<String, usize>,
+    totals: HashMap<PathBuf, HashMap<String, usize>>,
+    global_counters: bool,
+    labels: HashMap<String, PathBuf>,
+    skip_langs: HashMap<PathBuf, Vec<String>>,
+    glossary: Option<Glossary>,
+    preserve_escapes: bool,
+    offline_badges: bool,
+}
+
+impl Registry {
+    pub(crate) fn with_glossary(glossary: Glossary) -> Self {
+        Registry {
+            glossary: Some(glossary),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn preserve_escapes(mut self, preserve_escapes: bool) -> Self {
+        self.preserve_escapes = preserve_escapes;
+        self
+    }
+
+    pub(crate) fn offline_badges(mut self, offline_badges: bool) -> Self {
+        self.offline_badges = offline_badges;
+        self
+    }
+
+    pub(crate) fn global_counters(mut self, global_counters: bool) -> Self {
+        self.global_counters = global_counters;
+        self
+    }
+
+    pub(crate) fn reset_counters(&mut self) {
+        self.counters.clear();
+    }
+
+    /// Leaves the directives within fenced code blocks of `langs` untouched in the chapter at
+    /// `chapter_path`, as the `skip-langs` of that chapter do for invocations.
+    pub(crate) fn skip_langs<P: AsRef<Path>>(&mut self, chapter_path: P, langs: &[String]) {
+        self.skip_langs
+            .insert(chapter_path.as_ref().to_path_buf(), langs.to_vec());
+    }
+
+    /// Directives of `contents` outside of the fenced code blocks skipped in the chapter.
+    fn directives<'a>(
+        &self,
+        contents: &'a str,
+        chapter_path: &Path,
+    ) -> impl Iterator<Item = Directive<'a>> {
+        let skipped = self
+            .skip_langs
+            .get(chapter_path)
+            .map(|langs| fences::skipped(contents, langs))
+            .unwrap_or_default();
+        extract_directives(contents).filter(move |directive| {
+            !skipped
+                .iter()
+                .any(|range| range.contains(&directive.start_index))
+        })
+    }
+
+    pub(crate) fn collect<P: AsRef<Path>>(&mut self, contents: &str, chapter_path: P) -> String {
+        let chapter_path = chapter_path.as_ref();
+        // Must keep track of indices as they will not correspond after string substitution
+        let mut previous_end_index = 0;
+        let mut replaced = String::with_capacity(contents.len());
+
+        for directive in self.directives(contents, chapter_path) {
+            replaced.push_str(&contents[previous_end_index..directive.start_index]);
+
+            match directive.directive_type {
+                // References and totals are unescaped during the second pass
+                DirectiveType::Escaped("ref" | "total")
+                | DirectiveType::Ref(..)
+                | DirectiveType::Total(_) => replaced.push_str(directive.directive_text),
+                DirectiveType::Escaped(_) => {
+                    replaced.push_str(&unescape(directive.directive_text, self.preserve_escapes))
+                }
+                DirectiveType::Counter(name) => {
+                    let counter = self.counters.entry(name.to_owned()).or_default();
+                    *counter += 1;
+                    replaced.push_str(&counter.to_string());
+                }
+                DirectiveType::Label(label) => {
+                    match self.labels.get(label) {
+                        Some(existing) => diagnostics::warn(
+                            Code::DuplicateLabel,
+                            format_args!(
+                                "Duplicate label \"{}\" in {}, it is already defined in {}",
+                                label,
+                                chapter_path.display(),
+                                existing.display()
+                            ),
+                        ),
+                        None => {
+                            self.labels
+                                .insert(label.to_owned(), chapter_path.to_path_buf());
+                        }
+                    }
+                    replaced.push_str(&format!("<a id=\"{}\"></a>", html::escape(label)));
+                }
+                DirectiveType::Gloss(term) => match self
+                    .glossary
+                    .as_ref()
+                    .and_then(|glossary| glossary.expand(term, chapter_path))
+                {
+                    Some(expanded) => replaced.push_str(&expanded),
+                    None => {
+                        diagnostics::error(
+                            Code::UnknownTerm,
+                            format_args!(
+                                "Unknown glossary term \"{}\" in {}",
+                                term,
+                                chapter_path.display()
+                            ),
+                        );
+                        replaced.push_str(directive.directive_text);
+                    }
+                },
+                DirectiveType::Badge(arg) => match badges::render(arg, self.offline_badges) {
+                    Some(badge) => replaced.push_str(&badge),
+                    None => {
+                        diagnostics::error(
+                            Code::UnknownBadge,
+                            format_args!(
+                                "Unknown badge \"{}\" in {}, expected one of: crates-io, docs-rs, license",
+                                arg,
+                                chapter_path.display()
+                            ),
+                        );
+                        replaced.push_str(directive.directive_text);
+                    }
+                },
+            }
+
+            previous_end_index = directive.end_index;
+        }
+
+        self.totals
+            .insert(chapter_path.to_path_buf(), self.counters.clone());
+        replaced.push_str(&contents[previous_end_index..]);
+        replaced
+    }
+
+    /// Final value of the counter `name` for the chapter at `chapter_path`, which is the
+    /// highest value reached anywhere in the book when counters are global.
+    fn total(&self, name: &str, chapter_path: &Path) -> usize {
+        if self.global_counters {
+            self.totals
+                .values()
+                .filter_map(|counters| counters.get(name))
+                .copied()
+                .max()
+                .unwrap_or_default()
+        } else {
+            self.totals
+                .get(chapter_path)
+                .and_then(|counters| counters.get(name))
+                .copied()
+                .unwrap_or_default()
+        }
+    }
+
+    pub(crate) fn resolve<P: AsRef<Path>>(&self, contents: &str, chapter_path: P) -> String {
+        let chapter_path = chapter_path.as_ref();
+        let mut previous_end_index = 0;
+        let mut replaced = String::with_capacity(contents.len());
+
+        for directive in self.directives(contents, chapter_path) {
+            replaced.push_str(&contents[previous_end_index..directive.start_index]);
+
+            match directive.directive_type {
+                DirectiveType::Escaped("ref" | "total") => {
+                    replaced.push_str(&unescape(directive.directive_text, self.preserve_escapes))
+                }
+                DirectiveType::Total(name) => {
+                    replaced.push_str(&self.total(name, chapter_path).to_string())
+                }
+                DirectiveType::Ref(label, text) => match self.labels.get(label) {
+                    Some(target) => replaced.push_str(&format!(
+                        "[{}]({}#{})",
+                        text.unwrap_or(label),
+                        relative_link(chapter_path, target),
+                        label
+                    )),
+                    None => {
+                        diagnostics::error(
+                            Code::UnresolvedReference,
+                            format_args!(
+                                "Unresolved reference \"{}\" in {}, no label with that name exists",
+                                label,
+                                chapter_path.display()
+                            ),
+                        );
+                        replaced.push_str(directive.directive_text);
+                    }
+                },
+                _ => replaced.push_str(directive.directive_text),
+            }
+
+            previous_end_index = directive.end_index;
+        }
+
+        replaced.push_str(&contents[previous_end_index..]);
+        replaced
+    }
+}
+
+/// Builds the link from the chapter at `from` to the chapter at `to`, both relative to the
+/// book source directory. Links within the same chapter only need the anchor.
+pub(crate) fn relative_link(from: &Path, to: &Path) -> String {
+    if from == to {
+        return String::new();
+    }
+
+    let depth = from
+        .parent()
+        .map_or(0, |parent| parent.components().count());
+    let mut link = "../".repeat(depth);
+    let target = to
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    link.push_str(&target);
+    link
+}
+
+#[cfg(test)]
+mod directive_tests {
+    use crate::directives::{
+        directive_length, extract_directives, namespace, relative_link, Directive, DirectiveType,
+        Registry,
+    };
+    use crate::glossary::Glossary;
+    use std::path::Path;
+
+    #[test]
+    fn test_extract_directives_counter() {
+        let s = "Figure [[@counter figure]]: Ferris";
+
+        let res = extract_directives(s).collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![Directive {
+                start_index: 7,
+                end_index: 26,
+                directive_type: DirectiveType::Counter("figure"),
+                directive_text: "[[@counter figure]]",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_extract_directives_ref_with_text() {
+        let s = "See [[@ref install-step-3 the third step]]";
+
+        let res = extract_directives(s).collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![Directive {
+                start_index: 4,
+                end_index: 42,
+                directive_type: DirectiveType::Ref("install-step-3", Some("the third step")),
+                directive_text: "[[@ref install-step-3 the third step]]",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_directive_length() {
+        assert_eq!(directive_length("[[@counter figure]]"), Some(19));
+        assert_eq!(directive_length("[[  @counter   table  ]] text"), Some(24));
+        assert_eq!(directive_length("[[@ref install-step-3]]"), Some(23));
+        assert_eq!(directive_length(r"\[[@label top]]"), Some(15));
+        assert_eq!(directive_length("[[@counter]]"), None);
+        assert_eq!(directive_length("[[#counter figure]]"), None);
+        assert_eq!(directive_length("[[#title An Amazing Title]]"), None);
+        assert_eq!(directive_length("Figure [[@counter figure]]"), None);
+    }
+
+    #[test]
+    fn test_counters_increment_per_name() {
+        let start = "[[@counter figure]] [[@counter table]] [[@counter figure]]";
+        let end = "1 1 2";
+
+        assert_eq!(Registry::default().collect(start, "chapter.md"), end);
+    }
+
+    #[test]
+    fn test_counters_escaped() {
+        let start = r"\[[@counter figure]] [[@counter figure]]";
+        let end = "[[@counter figure]] 1";
+
+        assert_eq!(Registry::default().collect(start, "chapter.md"), end);
+    }
+
+    #[test]
+    fn test_counters_partial_escapes() {
+        let start = r"[[\@counter figure]] \\[[@counter figure]] \\\[[@ref install]]";
+        let mut registry = Registry::default();
+
+        let collected = registry.collect(start, "chapter.md");
+
+        assert_eq!(collected, r"[[@counter figure]] \1 \\\[[@ref install]]");
+        assert_eq!(
+            registry.resolve(&collected, "chapter.md"),
+            r"[[@counter figure]] \1 \[[@ref install]]"
+        );
+    }
+
+    #[test]
+    fn test_preserve_escapes() {
+        let start = r"\[[@counter figure]] \[[@ref install]] [[@counter figure]]";
+        let mut registry = Registry::default().preserve_escapes(true);
+
+        let collected = registry.collect(start, "chapter.md");
+
+        assert_eq!(
+            registry.resolve(&collected, "chapter.md"),
+            r"\[[@counter figure]] \[[@ref install]] 1"
+        );
+    }
+
+    #[test]
+    fn test_counters_continue_until_reset() {
+        let mut registry = Registry::default();
+
+        assert_eq!(registry.collect("[[@counter figure]]", "one.md"), "1");
+        assert_eq!(registry.collect("[[@counter figure]]", "two.md"), "2");
+        registry.reset_counters();
+        assert_eq!(registry.collect("[[@counter figure]]", "three.md"), "1");
+    }
+
+    #[test]
+    fn test_badges() {
+        let start = r"[[@badge license MIT]] [[@badge npm x]] \[[@badge license MIT]]";
+
+        assert_eq!(
+            Registry::default().collect(start, "chapter.md"),
+            "![license](https://img.shields.io/badge/license-MIT-blue) [[@badge npm x]] [[@badge license MIT]]"
+        );
+        assert_eq!(
+            Registry::default()
+                .offline_badges(true)
+                .collect(start, "chapter.md"),
+            "license: MIT [[@badge npm x]] [[@badge license MIT]]"
+        );
+    }
+
+    #[test]
+    fn test_skip_langs() {
+        let start = "[[@counter figure]]\n\n```hbs\n[[@counter figure]] [[@ref top]]\n```\n\n```md\n[[@counter figure]]\n```";
+        let mut registry = Registry::default();
+        registry.skip_langs("chapter.md", &["hbs".to_string()]);
+
+        let collected = registry.collect(start, "chapter.md");
+
+        assert_eq!(
+            registry.resolve(&collected, "chapter.md"),
+            "1\n\n```hbs\n[[@counter figure]] [[@ref top]]\n```\n\n```md\n2\n```"
+        );
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut registry = Registry::default();
+
+        let one = registry.collect(
+            r"Figure [[@counter figure]] of [[@total figure]], \[[@total table]]",
+            "one.md",
+        );
+        registry.reset_counters();
+        let two = registry.collect("[[@counter figure]] [[@counter figure]]", "two.md");
+
+        assert_eq!(one, r"Figure 1 of [[@total figure]], \[[@total table]]");
+        assert_eq!(
+            registry.resolve(&one, "one.md"),
+            "Figure 1 of 1, [[@total table]]"
+        );
+        assert_eq!(registry.resolve(&two, "two.md"), "1 2");
+    }
+
+    #[test]
+    fn test_totals_global() {
+        let mut registry = Registry::default().global_counters(true);
+
+        let one = registry.collect("[[@counter figure]] of [[@total figure]]", "one.md");
+        registry.collect("[[@counter figure]] [[@counter figure]]", "two.md");
+
+        assert_eq!(registry.resolve(&one, "one.md"), "1 of 3");
+    }
+
+    #[test]
+    fn test_labels_and_refs_across_chapters() {
+        let mut registry = Registry::default();
+
+        let guide = registry.collect("## Step 3 [[@label install-step-3]]", "guide/install.md");
+        let intro = registry.collect("See [[@ref install-step-3]]", "intro.md");
+        let hazel = registry.collect("See [[@ref install-step-3 step three]]", "friends/hazel.md");
+
+        assert_eq!(guide, r#"## Step 3 <a id="install-step-3"></a>"#);
+
+        let quoted = registry.collect(r#"[[@label a"b<c&]]"#, "quoted.md");
+        assert_eq!(quoted, r#"<a id="a&quot;b&lt;c&amp;"></a>"#);
+        assert_eq!(intro, "See [[@ref install-step-3]]");
+        assert_eq!(
+            registry.resolve(&intro, "intro.md"),
+            "See [install-step-3](guide/install.md#install-step-3)"
+        );
+        assert_eq!(
+            registry.resolve(&hazel, "friends/hazel.md"),
+            "See [step three](../guide/install.md#install-step-3)"
+        );
+    }
+
+    #[test]
+    fn test_refs_escaped_and_unresolved() {
+        let mut registry = Registry::default();
+        let start = r"\[[@ref missing]] [[@ref missing]]";
+
+        let collected = registry.collect(start, "chapter.md");
+
+        assert_eq!(collected, start);
+        assert_eq!(
+            registry.resolve(&collected, "chapter.md"),
+            "[[@ref missing]] [[@ref missing]]"
+        );
+    }
+
+    #[test]
+    fn test_gloss() {
+        let glossary = Glossary::parse(
+            "reference/glossary.md",
+            "## API\nApplication Programming Interface",
+        );
+        let mut registry = Registry::with_glossary(glossary);
+
+        assert_eq!(
+            registry.collect("An [[@gloss API]] and [[@gloss SDK]]", "intro.md"),
+            r#"An [<abbr title="Application Programming Interface">API</abbr>](reference/glossary.md#api) and [[@gloss SDK]]"#
+        );
+        assert_eq!(
+            Registry::default().collect("[[@gloss API]]", "intro.md"),
+            "[[@gloss API]]"
+        );
+    }
+
+    #[test]
+    fn test_relative_link() {
+        let link = |from: &str, to: &str| relative_link(Path::new(from), Path::new(to));
+
+        assert_eq!(link("intro.md", "intro.md"), "");
+        assert_eq!(link("intro.md", "guide/install.md"), "guide/install.md");
+        assert_eq!(
+            link("a/b/c.md", "guide/install.md"),
+            "../../guide/install.md"
+        );
+    }
+
+    #[test]
+    fn test_namespace() {
+        assert_eq!(
+            namespace(
+                "[[@label top]] [[@ref top Top]] [[@ref other:intro]] [[@counter figure]] \\[[@label raw]]",
+                "corp"
+            ),
+            "[[@label corp:top]] [[@ref corp:top Top]] [[@ref other:intro]] [[@counter figure]] \\[[@label raw]]"
+        );
+    }
+}
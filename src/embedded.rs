@@ -0,0 +1,40 @@
+use mdbook::book::Book;
+use mdbook::errors::Result;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+
+use crate::Template;
+
+/// Runs [`Template`] within the process building the book. mdbook would run `mdbook-template` as
+/// a command for the `[preprocessor.template]` table, so the table is taken out of the
+/// configuration and handed to the preprocessor instead.
+pub(crate) struct Embedded {
+    table: Option<toml::Value>,
+}
+
+impl Embedded {
+    pub(crate) fn take(config: &mut mdbook::Config) -> Embedded {
+        let table = config
+            .get_mut("preprocessor")
+            .and_then(|preprocessors| preprocessors.as_table_mut())
+            .and_then(|preprocessors| preprocessors.remove("template"));
+        Embedded { table }
+    }
+}
+
+impl Preprocessor for Embedded {
+    fn name(&self) -> &str {
+        "template"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book> {
+        let mut ctx = ctx.clone();
+        if let Some(ref table) = self.table {
+            ctx.config.set("preprocessor.template", table)?;
+        }
+        Template::new().run(&ctx, book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        Template::new().supports_renderer(renderer)
+    }
+}
@@ -0,0 +1,46 @@
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag};
+
+/// Byte ranges of the fenced code blocks within `contents` whose language is one of `langs`.
+pub(crate) fn skipped(contents: &str, langs: &[String]) -> Vec<Range<usize>> {
+    if langs.is_empty() {
+        return Vec::new();
+    }
+
+    Parser::new(contents)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                // mdbook attributes follow the language, e.g. `rust,ignore` or `hbs editable`
+                let lang = info
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .next()
+                    .unwrap_or_default();
+                langs.iter().any(|skipped| skipped == lang).then_some(range)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod fences_tests {
+    use crate::fences::skipped;
+
+    #[test]
+    fn test_skipped() {
+        let contents = "# Handlebars\n\n```hbs\n{{#if}}\n```\n\n```rust,ignore\nfn main() {}\n```\n\n~~~tera editable\n{{ x }}\n~~~\n";
+        let langs = vec!["hbs".to_string(), "tera".to_string()];
+
+        let ranges = skipped(contents, &langs);
+
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(&contents[ranges[0].clone()], "```hbs\n{{#if}}\n```");
+        assert_eq!(
+            &contents[ranges[1].clone()],
+            "~~~tera editable\n{{ x }}\n~~~"
+        );
+        assert!(skipped(contents, &[]).is_empty());
+    }
+}
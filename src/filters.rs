@@ -0,0 +1,91 @@
+use mdbook::utils::normalize_id;
+
+use crate::diagnostics::{self, Code};
+
+/// Filters a placeholder can pipe its value through, e.g. `[[#title | upper]]`.
+pub(crate) const FILTERS: &[&str] = &["upper", "lower", "trim", "capitalize", "slugify"];
+
+/// Splits the filters off the end of the text of a placeholder, e.g. `name | lower | trim`.
+///
+/// Only filters right after the name are always taken as such, so that a default value
+/// containing a `|`, e.g. `[[#separator a|b]]`, is kept unless it ends with known filters.
+pub(crate) fn split(placeholder: &str) -> (&str, Vec<&str>) {
+    let mut rest = placeholder;
+    let mut filters = Vec::new();
+    while let Some((head, filter)) = rest.rsplit_once('|') {
+        let filter = filter.trim();
+        let after_name = !head.trim().contains(char::is_whitespace);
+        if !FILTERS.contains(&filter) && !after_name {
+            break;
+        }
+        filters.push(filter);
+        rest = head;
+    }
+    filters.reverse();
+    (rest, filters)
+}
+
+/// Pipes `value` through every one of `filters` in order, unknown ones are skipped with a warning.
+pub(crate) fn apply(value: &str, filters: &[&str]) -> String {
+    let mut value = value.to_owned();
+    for filter in filters {
+        value = match *filter {
+            "upper" => value.to_uppercase(),
+            "lower" => value.to_lowercase(),
+            "trim" => value.trim().to_owned(),
+            // The same ids mdBook gives headings, so links built from a title find its heading
+            "slugify" => normalize_id(value.trim()),
+            "capitalize" => {
+                let mut chars = value.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => value,
+                }
+            }
+            _ => {
+                diagnostics::warn(
+                    Code::MalformedArguments,
+                    format_args!(
+                        "Ignoring unknown filter \"{}\", expected one of: {}",
+                        filter,
+                        FILTERS.join(", ")
+                    ),
+                );
+                value
+            }
+        };
+    }
+    value
+}
+
+#[cfg(test)]
+mod filters_tests {
+    use crate::filters::{apply, split};
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("title | upper"), ("title ", vec!["upper"]));
+        assert_eq!(split("name|lower|trim"), ("name", vec!["lower", "trim"]));
+        assert_eq!(
+            split("title Untitled | capitalize"),
+            ("title Untitled ", vec!["capitalize"])
+        );
+        assert_eq!(split("title | uper"), ("title ", vec!["uper"]));
+        assert_eq!(split("separator a|b"), ("separator a|b", vec![]));
+        assert_eq!(split("title"), ("title", vec![]));
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(apply(" Ferris ", &["trim", "upper"]), "FERRIS");
+        assert_eq!(apply("FERRIS", &["lower"]), "ferris");
+        assert_eq!(apply("élan vital", &["capitalize"]), "Élan vital");
+        assert_eq!(apply("", &["capitalize"]), "");
+        assert_eq!(
+            apply(" Getting Started: mdBook's CLI ", &["slugify"]),
+            "getting-started-mdbooks-cli"
+        );
+        assert_eq!(apply("Über Blick", &["slugify"]), "Über-blick");
+        assert_eq!(apply("Ferris", &["unknown", "lower"]), "ferris");
+    }
+}
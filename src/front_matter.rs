@@ -0,0 +1,337 @@
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+use serde::Deserialize;
+
+use crate::config::ChapterConfig;
+
+pub(crate) const DELIMITER: &str = "+++";
+
+/// Settings declared by a template file within a leading TOML block, e.g.
+///
+/// ```text
+/// +++
+/// css = [".tabs { display: flex; }"]
+/// +++
+/// <div class="tabs">[[#content]]</div>
+/// ```
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct FrontMatter {
+    /// CSS snippets the template output depends on
+    pub(crate) css: Vec<String>,
+    /// JavaScript snippets the template output depends on
+    pub(crate) js: Vec<String>,
+    /// Encoding of the template file when it isn't UTF-8, e.g. `shift_jis`
+    pub(crate) encoding: Option<String>,
+    /// Groups of argument names that refer to the same argument, e.g. `[["author", "authors"]]`
+    pub(crate) aliases: Vec<Vec<String>>,
+    /// Variable that must be set for the template, or the chapter, to be included at all
+    pub(crate) requires: Option<String>,
+    /// Oldest version of mdbook-template able to expand the template, e.g. `1.2`
+    pub(crate) min_preprocessor_version: Option<String>,
+    /// Preprocessor settings a chapter overrides for itself, within a `[template]` table
+    pub(crate) template: ChapterConfig,
+    /// Marks a template as retired, so `check` flags every invocation still using it
+    pub(crate) archived: bool,
+    /// Checks the output of the template must pass, within an `[assert]` table
+    #[serde(rename = "assert")]
+    pub(crate) assertions: Assertions,
+}
+
+/// What the output of a template must look like once its arguments are replaced, e.g.
+///
+/// ```text
+/// +++
+/// [assert]
+/// contains = ["</div>"]
+/// max-length = 2000
+/// +++
+/// ```
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct Assertions {
+    /// Text the output must contain
+    pub(crate) contains: Vec<String>,
+    /// Text the output must not contain
+    pub(crate) not_contains: Vec<String>,
+    /// Most bytes the output may be
+    pub(crate) max_length: Option<usize>,
+}
+
+impl Assertions {
+    /// Fails with the first assertion the `output` of the template breaks.
+    pub(crate) fn check(&self, output: &str) -> Result<()> {
+        if let Some(missing) = self
+            .contains
+            .iter()
+            .find(|text| !output.contains(text.as_str()))
+        {
+            bail!("Output of the template doesn't contain \"{}\"", missing);
+        }
+        if let Some(found) = self
+            .not_contains
+            .iter()
+            .find(|text| output.contains(text.as_str()))
+        {
+            bail!("Output of the template contains \"{}\"", found);
+        }
+        match self.max_length {
+            Some(max_length) if output.len() > max_length => bail!(
+                "Output of the template is {} bytes, longer than its max-length of {} bytes",
+                output.len(),
+                max_length
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FrontMatter {
+    /// Refuses templates declaring a `min-preprocessor-version` newer than this preprocessor.
+    pub(crate) fn check_version(&self) -> Result<()> {
+        let required = match self.min_preprocessor_version {
+            Some(ref required) => required,
+            None => return Ok(()),
+        };
+        let current = env!("CARGO_PKG_VERSION");
+        if version_parts(required)? > version_parts(current)? {
+            bail!(
+                "Template requires mdbook-template {} or newer, but this is {}",
+                required,
+                current
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parses a version such as `1.2` or `1.1.1+deprecated` into its numeric parts, padded to
+/// `major.minor.patch` so that `1.2` and `1.2.0` compare as equal.
+fn version_parts(version: &str) -> Result<Vec<u64>> {
+    let release = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['+', '-'])
+        .next()
+        .unwrap_or_default();
+    let mut parts = release
+        .split('.')
+        .map(|part| part.parse::<u64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("\"{}\" is not a valid version", version))?;
+    if parts.len() < 3 {
+        parts.resize(3, 0);
+    }
+    Ok(parts)
+}
+
+/// Splits the front matter from the rest of the template, or chapter, contents. Templates without front
+/// matter are returned untouched.
+pub(crate) fn split(contents: &str) -> Result<(FrontMatter, &str)> {
+    let rest = match contents.strip_prefix(DELIMITER) {
+        Some(rest) if rest.starts_with(['\n', '\r']) => rest,
+        _ => return Ok((FrontMatter::default(), contents)),
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == DELIMITER {
+            let front_matter = toml::from_str(&rest[..offset])
+                .context("Could not parse the front matter of the template")?;
+            return Ok((front_matter, &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+
+    Err(anyhow::anyhow!(
+        "Front matter of the template is missing its closing `{}`",
+        DELIMITER
+    ))
+}
+
+/// CSS and JavaScript required by the templates expanded within a chapter, deduplicated so
+/// that each snippet is only injected once.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Assets {
+    css: Vec<String>,
+    js: Vec<String>,
+}
+
+impl Assets {
+    pub(crate) fn add(&mut self, front_matter: &FrontMatter) {
+        front_matter.css.iter().for_each(|css| self.add_css(css));
+        front_matter.js.iter().for_each(|js| self.add_js(js));
+    }
+
+    pub(crate) fn add_css(&mut self, css: &str) {
+        if !self.css.iter().any(|existing| existing == css) {
+            self.css.push(css.to_owned());
+        }
+    }
+
+    pub(crate) fn add_js(&mut self, js: &str) {
+        if !self.js.iter().any(|existing| existing == js) {
+            self.js.push(js.to_owned());
+        }
+    }
+
+    /// Renders all collected assets as a single block of HTML to append to the chapter.
+    pub fn render(&self) -> Option<String> {
+        if self.css.is_empty() && self.js.is_empty() {
+            return None;
+        }
+
+        let mut block = String::new();
+        if !self.css.is_empty() {
+            block.push_str(&format!("<style>\n{}\n</style>\n", self.css.join("\n")));
+        }
+        if !self.js.is_empty() {
+            block.push_str(&format!("<script>\n{}\n</script>\n", self.js.join("\n")));
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod front_matter_tests {
+    use std::collections::HashMap;
+
+    use crate::config::{ChapterConfig, OnError};
+    use crate::front_matter::{split, version_parts, Assertions, Assets, FrontMatter};
+
+    #[test]
+    fn test_assertions() {
+        let contents = "+++\n[assert]\ncontains = [\"</div>\"]\nnot-contains = [\"[[#\"]\nmax-length = 20\n+++\n<div>[[#title]]</div>";
+        let (front_matter, _) = split(contents).unwrap();
+        let assertions = front_matter.assertions;
+
+        assert_eq!(
+            assertions,
+            Assertions {
+                contains: vec!["</div>".to_string()],
+                not_contains: vec!["[[#".to_string()],
+                max_length: Some(20),
+            }
+        );
+        assert!(assertions.check("<div>Ferris</div>").is_ok());
+        assert_eq!(
+            assertions.check("<div>Ferris").unwrap_err().to_string(),
+            "Output of the template doesn't contain \"</div>\""
+        );
+        assert_eq!(
+            assertions
+                .check("<div>[[#title]]</div>")
+                .unwrap_err()
+                .to_string(),
+            "Output of the template contains \"[[#\""
+        );
+        assert_eq!(
+            assertions
+                .check("<div>Ferris the crab</div>")
+                .unwrap_err()
+                .to_string(),
+            "Output of the template is 26 bytes, longer than its max-length of 20 bytes"
+        );
+    }
+
+    #[test]
+    fn test_split_without_front_matter() {
+        let contents = "+++ not front matter\n[[#title]]";
+
+        let (front_matter, rest) = split(contents).unwrap();
+
+        assert_eq!(front_matter, FrontMatter::default());
+        assert_eq!(rest, contents);
+    }
+
+    #[test]
+    fn test_split_with_front_matter() {
+        let contents = "+++\ncss = [\".tabs {}\"]\njs = [\"tabs()\"]\n+++\n<div>[[#title]]</div>";
+
+        let (front_matter, rest) = split(contents).unwrap();
+
+        assert_eq!(
+            front_matter,
+            FrontMatter {
+                css: vec![".tabs {}".to_string()],
+                js: vec!["tabs()".to_string()],
+                encoding: None,
+                aliases: vec![],
+                requires: None,
+                min_preprocessor_version: None,
+                template: ChapterConfig::default(),
+                archived: false,
+                assertions: Assertions::default(),
+            }
+        );
+        assert_eq!(rest, "<div>[[#title]]</div>");
+    }
+
+    #[test]
+    fn test_split_chapter_overrides() {
+        let contents = "+++\n[template]\non-error = \"fail\"\nvariables = { audience = \"operators\" }\n+++\n# Runbook";
+
+        let (front_matter, rest) = split(contents).unwrap();
+
+        assert_eq!(
+            front_matter.template,
+            ChapterConfig {
+                on_error: Some(OnError::Fail),
+                variables: HashMap::from([("audience".to_string(), "operators".to_string())]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(rest, "# Runbook");
+        assert!(split("+++\n[template]\nprovenance = true\n+++\n").is_err());
+    }
+
+    #[test]
+    fn test_check_version() {
+        let (front_matter, _) = split("+++\nmin-preprocessor-version = \"1.0\"\n+++\n").unwrap();
+        assert!(front_matter.check_version().is_ok());
+
+        let (front_matter, _) = split("+++\nmin-preprocessor-version = \"99.1\"\n+++\n").unwrap();
+        assert!(front_matter.check_version().is_err());
+
+        let (front_matter, _) = split("+++\nmin-preprocessor-version = \"one\"\n+++\n").unwrap();
+        assert!(front_matter.check_version().is_err());
+    }
+
+    #[test]
+    fn test_version_parts() {
+        assert_eq!(version_parts("1.2").unwrap(), vec![1, 2, 0]);
+        assert_eq!(version_parts("v1.2.0").unwrap(), vec![1, 2, 0]);
+        assert_eq!(version_parts("1.1.1+deprecated").unwrap(), vec![1, 1, 1]);
+        assert_eq!(version_parts("2.0.0-beta.1").unwrap(), vec![2, 0, 0]);
+        assert!(version_parts("1.x").is_err());
+    }
+
+    #[test]
+    fn test_split_unterminated_front_matter() {
+        assert!(split("+++\ncss = []\n<div></div>").is_err());
+    }
+
+    #[test]
+    fn test_assets_deduplicated() {
+        let mut assets = Assets::default();
+        let front_matter = FrontMatter {
+            css: vec![".tabs {}".to_string()],
+            js: vec![],
+            encoding: None,
+            aliases: vec![],
+            requires: None,
+            min_preprocessor_version: None,
+            template: ChapterConfig::default(),
+            archived: false,
+            assertions: Assertions::default(),
+        };
+
+        assert_eq!(assets.render(), None);
+        assets.add(&front_matter);
+        assets.add(&front_matter);
+        assert_eq!(
+            assets.render(),
+            Some("<style>\n.tabs {}\n</style>\n".to_string())
+        );
+    }
+}
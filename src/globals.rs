@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use log::debug;
+use mdbook::config::{BookConfig, HtmlConfig};
+use mdbook::errors::Result;
+use mdbook::preprocess::PreprocessorContext;
+
+use crate::config::Config;
+use crate::lookup;
+use crate::utils::SystemFileReader;
+use crate::FileReader;
+
+/// Namespaces of the values provided by the preprocessor itself, e.g. `[[#chapter.section]]`.
+/// Each can be turned off with `disable-builtins`, freeing its names for arguments.
+pub(crate) const NAMESPACES: &[&str] = &["chapter", "book", "cargo", "env", "git", "now", "theme"];
+
+/// Whether `name` is within one of the enabled builtin namespaces, e.g. `chapter.title`. The
+/// name of a namespace on its own, e.g. `book`, is never a builtin value so it stays free.
+pub(crate) fn is_reserved(name: &str, disabled: &[String]) -> bool {
+    enabled(disabled).any(|namespace| {
+        name.strip_prefix(namespace)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .is_some_and(|rest| !rest.is_empty())
+    })
+}
+
+/// Builtin namespaces that aren't disabled.
+pub(crate) fn enabled(disabled: &[String]) -> impl Iterator<Item = &'static str> + '_ {
+    NAMESPACES
+        .iter()
+        .copied()
+        .filter(move |namespace| !disabled.iter().any(|name| name == namespace))
+}
+
+/// Values of the enabled builtin namespaces shared by every chapter, i.e. all but `chapter.*`.
+pub(crate) fn book(ctx: &PreprocessorContext, config: &Config) -> Result<HashMap<String, String>> {
+    for name in &config.disable_builtins {
+        if !NAMESPACES.contains(&name.as_str()) {
+            bail!(
+                "Unknown builtin {} within disable-builtins, expected one of: {}",
+                name,
+                NAMESPACES.join(", ")
+            );
+        }
+    }
+
+    let mut globals = HashMap::new();
+    for namespace in enabled(&config.disable_builtins) {
+        match namespace {
+            "book" => globals.extend(book_config(&ctx.config.book)),
+            "cargo" => {
+                if let Some(ref manifest) = config.cargo_manifest {
+                    let manifest = ctx.root.join(manifest);
+                    let contents = SystemFileReader::new(config.max_file_size)
+                        .read_to_string(&manifest, &manifest.to_string_lossy())?;
+                    globals.extend(lookup::cargo_globals(&manifest, &contents)?);
+                }
+            }
+            "env" => globals.extend(env(&config.env_allow)),
+            "git" => globals.extend(git(&ctx.root)),
+            "now" => {
+                // Reproducible builds pin the time through SOURCE_DATE_EPOCH
+                let timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+                    Ok(epoch) => epoch.trim().parse()?,
+                    Err(_) => SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_secs() as i64),
+                };
+                globals.extend(now(timestamp));
+            }
+            "theme" => globals.extend(theme(ctx.config.html_config().unwrap_or_default())),
+            // Chapter values are provided as each chapter is expanded
+            _ => {}
+        }
+    }
+    Ok(globals)
+}
+
+fn book_config(book: &BookConfig) -> HashMap<String, String> {
+    let mut globals = HashMap::new();
+    if let Some(ref title) = book.title {
+        globals.insert("book.title".to_string(), title.to_owned());
+    }
+    if !book.authors.is_empty() {
+        globals.insert("book.authors".to_string(), book.authors.join(", "));
+    }
+    if let Some(ref description) = book.description {
+        globals.insert("book.description".to_string(), description.to_owned());
+    }
+    if let Some(ref language) = book.language {
+        globals.insert("book.language".to_string(), language.to_owned());
+    }
+    globals
+}
+
+/// The themes the HTML renderer starts with, before readers pick their own.
+fn theme(html: HtmlConfig) -> HashMap<String, String> {
+    HashMap::from([
+        (
+            "theme.name".to_string(),
+            html.default_theme.unwrap_or_else(|| "light".to_string()),
+        ),
+        (
+            "theme.dark".to_string(),
+            html.preferred_dark_theme
+                .unwrap_or_else(|| "navy".to_string()),
+        ),
+    ])
+}
+
+/// The environment variables listed in `env-allow`, so secrets of the build environment, such as
+/// CI tokens, never end up in the book by accident. Values that aren't valid UTF-8 are converted
+/// lossily.
+fn env(allowed: &[String]) -> HashMap<String, String> {
+    allowed
+        .iter()
+        .filter_map(|name| {
+            let value = std::env::var_os(name)?;
+            Some((
+                format!("env.{}", name),
+                value.to_string_lossy().into_owned(),
+            ))
+        })
+        .collect()
+}
+
+/// The commit and branch checked out within `root`, none when it isn't a git repository.
+fn git(root: &Path) -> HashMap<String, String> {
+    let run = |args: &[&str]| -> Option<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(root)
+            .output()
+            .map_err(|err| debug!("Could not run git, {}", err))
+            .ok()?;
+        match output.status.success() {
+            true => Some(String::from_utf8_lossy(&output.stdout).trim().to_owned()),
+            false => None,
+        }
+    };
+
+    let mut globals = HashMap::new();
+    if let Some(commit) = run(&["rev-parse", "HEAD"]) {
+        globals.insert(
+            "git.short-commit".to_string(),
+            commit.chars().take(7).collect(),
+        );
+        globals.insert("git.commit".to_string(), commit);
+    }
+    if let Some(branch) = run(&["rev-parse", "--abbrev-ref", "HEAD"]) {
+        globals.insert("git.branch".to_string(), branch);
+    }
+    globals
+}
+
+/// The UTC date of the `timestamp`, in seconds since the Unix epoch.
+fn now(timestamp: i64) -> HashMap<String, String> {
+    let (year, _, _) = civil_from_days(timestamp.div_euclid(86_400));
+    HashMap::from([
+        ("now.timestamp".to_string(), timestamp.to_string()),
+        ("now.year".to_string(), year.to_string()),
+        ("now.date".to_string(), date(timestamp)),
+    ])
+}
+
+/// The UTC date of the `timestamp`, e.g. `2023-11-14`.
+pub(crate) fn date(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86_400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Converts days since the Unix epoch to a proleptic Gregorian `(year, month, day)`,
+/// see <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod globals_tests {
+    use mdbook::config::{BookConfig, HtmlConfig};
+
+    use crate::globals::{book_config, civil_from_days, env, is_reserved, now, theme};
+
+    #[test]
+    fn test_is_reserved() {
+        assert!(!is_reserved("chapter", &[]));
+        assert!(!is_reserved("book.", &[]));
+        assert!(is_reserved("chapter.title", &[]));
+        assert!(is_reserved("book.version", &[]));
+        assert!(is_reserved("env.HOME", &[]));
+        assert!(!is_reserved("chapters", &[]));
+        assert!(!is_reserved("bookmark.title", &[]));
+        assert!(!is_reserved("env.HOME", &["env".to_string()]));
+    }
+
+    #[test]
+    fn test_env() {
+        let globals = env(&["PATH".to_string(), "MDBOOK_TEMPLATE_NEVER_SET".to_string()]);
+
+        assert_eq!(globals.len(), 1);
+        assert_eq!(globals["env.PATH"], std::env::var("PATH").unwrap());
+        assert!(env(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_now() {
+        let globals = now(1_700_000_000);
+
+        assert_eq!(globals["now.date"], "2023-11-14");
+        assert_eq!(globals["now.year"], "2023");
+        assert_eq!(globals["now.timestamp"], "1700000000");
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_book_config() {
+        let book = BookConfig {
+            title: Some("Guide".to_string()),
+            authors: vec!["Ann".to_string(), "Bo".to_string()],
+            ..BookConfig::default()
+        };
+
+        let globals = book_config(&book);
+
+        assert_eq!(globals["book.title"], "Guide");
+        assert_eq!(globals["book.authors"], "Ann, Bo");
+        assert_eq!(globals["book.language"], "en");
+        assert!(!globals.contains_key("book.description"));
+    }
+
+    #[test]
+    fn test_theme() {
+        let globals = theme(HtmlConfig {
+            default_theme: Some("ayu".to_string()),
+            ..HtmlConfig::default()
+        });
+
+        assert_eq!(globals["theme.name"], "ayu");
+        assert_eq!(globals["theme.dark"], "navy");
+        assert_eq!(theme(HtmlConfig::default())["theme.name"], "light");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use mdbook::utils::{collapse_whitespace, unique_id_from_content};
+
+use crate::directives::relative_link;
+
+/// Terms defined by the book-level glossary chapter.
+///
+/// Every heading within the glossary is a term and the text up until the next heading is its
+/// definition, e.g.
+///
+/// ```markdown
+/// ## API
+/// Application Programming Interface
+/// ```
+#[derive(PartialEq, Eq, Debug, Default)]
+pub(crate) struct Glossary {
+    path: PathBuf,
+    terms: HashMap<String, Term>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct Term {
+    anchor: String,
+    definition: String,
+}
+
+impl Glossary {
+    pub(crate) fn parse<P: Into<PathBuf>>(path: P, contents: &str) -> Glossary {
+        let mut terms = HashMap::new();
+        let mut id_counter = HashMap::new();
+        let mut current: Option<(String, String, Vec<&str>)> = None;
+        let mut in_code_block = false;
+
+        for line in contents.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("```") {
+                in_code_block = !in_code_block;
+                continue;
+            }
+
+            if in_code_block {
+                continue;
+            } else if trimmed.starts_with('#') {
+                let name = trimmed.trim_start_matches('#').trim().to_owned();
+                let anchor = unique_id_from_content(&name, &mut id_counter);
+                if let Some((name, anchor, definition)) = current.replace((name, anchor, vec![])) {
+                    terms.insert(name, Term::new(anchor, &definition));
+                }
+            } else if let Some((_, _, ref mut definition)) = current {
+                definition.push(trimmed);
+            }
+        }
+
+        if let Some((name, anchor, definition)) = current {
+            terms.insert(name, Term::new(anchor, &definition));
+        }
+
+        Glossary {
+            path: path.into(),
+            terms,
+        }
+    }
+
+    /// Expands `term` into a link to its glossary entry, relative to the chapter at
+    /// `chapter_path`, which shows the definition as a tooltip.
+    pub(crate) fn expand(&self, term: &str, chapter_path: &Path) -> Option<String> {
+        let entry = self.terms.get(term).or_else(|| {
+            self.terms
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(term))
+                .map(|(_, entry)| entry)
+        })?;
+
+        Some(format!(
+            "[<abbr title=\"{}\">{}</abbr>]({}#{})",
+            entry.definition,
+            term,
+            relative_link(chapter_path, &self.path),
+            entry.anchor
+        ))
+    }
+}
+
+impl Term {
+    fn new(anchor: String, definition: &[&str]) -> Term {
+        let definition = collapse_whitespace(definition.join(" ").trim())
+            .replace('&', "&amp;")
+            .replace('"', "&quot;");
+        Term { anchor, definition }
+    }
+}
+
+#[cfg(test)]
+mod glossary_tests {
+    use std::path::Path;
+
+    use crate::glossary::Glossary;
+
+    const GLOSSARY: &str = r#"# Glossary
+
+## API
+Application Programming
+Interface
+
+## CLI
+
+A "Command Line" Interface
+
+```markdown
+# Not A Term
+```
+"#;
+
+    #[test]
+    fn test_parse_and_expand() {
+        let glossary = Glossary::parse("glossary.md", GLOSSARY);
+
+        assert_eq!(
+            glossary.expand("API", Path::new("guide/install.md")),
+            Some(
+                r#"[<abbr title="Application Programming Interface">API</abbr>](../glossary.md#api)"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            glossary.expand("cli", Path::new("intro.md")),
+            Some(
+                r#"[<abbr title="A &quot;Command Line&quot; Interface">cli</abbr>](glossary.md#cli)"#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_unknown_term() {
+        let glossary = Glossary::parse("glossary.md", GLOSSARY);
+
+        assert_eq!(glossary.expand("Not A Term", Path::new("intro.md")), None);
+        assert_eq!(glossary.expand("SDK", Path::new("intro.md")), None);
+    }
+}
@@ -0,0 +1,92 @@
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, Parser};
+
+lazy_static! {
+    // r"<(/?)([a-zA-Z][a-zA-Z0-9-]*)(?:\s[^>]*?)?(/?)>"
+    static ref TAG: Regex = Regex::new(
+        r"(?x)                                  # insignificant whitespace mode
+        <(/?)                                   # opening of the tag, and the / of a closing tag
+        ([a-zA-Z][a-zA-Z0-9-]*)                 # name of the tag
+        (?:\s[^>]*?)?                           # attributes
+        (/?)>                                   # the / of a self-closing tag, and its end"
+    )
+    .unwrap();
+}
+
+// Elements that never have a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Tags of the raw HTML within `contents` that are never closed, e.g. `<div>`, or never opened,
+/// e.g. `</div>`, in the order they appear. HTML within code blocks and code spans is ignored.
+pub(crate) fn unbalanced(contents: &str) -> Vec<String> {
+    let html = Parser::new(contents)
+        .filter_map(|event| match event {
+            Event::Html(html) => Some(html.into_string()),
+            _ => None,
+        })
+        .collect::<String>();
+
+    let mut open: Vec<String> = Vec::new();
+    let mut unbalanced = Vec::new();
+    for captures in TAG
+        .captures_iter(&html)
+        .filter_map(|captures| captures.ok())
+    {
+        let name = captures[2].to_ascii_lowercase();
+        if !captures[3].is_empty() || VOID_ELEMENTS.contains(&name.as_str()) {
+            continue;
+        }
+        if captures[1].is_empty() {
+            open.push(name);
+            continue;
+        }
+        match open.iter().rposition(|opened| *opened == name) {
+            Some(index) => {
+                // Tags opened within the one being closed are never closed themselves
+                for unclosed in open.drain(index..).skip(1) {
+                    unbalanced.push(format!("<{}>", unclosed));
+                }
+            }
+            None => unbalanced.push(format!("</{}>", name)),
+        }
+    }
+    unbalanced.extend(open.into_iter().map(|unclosed| format!("<{}>", unclosed)));
+    unbalanced
+}
+
+/// `text` with the characters that are special within HTML, and its attribute values, escaped.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod html_tests {
+    use crate::html::{escape, unbalanced};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("install-step-3"), "install-step-3");
+        assert_eq!(escape(r#"a"b<c>&'d"#), "a&quot;b&lt;c&gt;&amp;&#39;d");
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        assert!(unbalanced("<div class=\"card\">\n\n**Bold**\n\n</div>").is_empty());
+        assert!(unbalanced("<p>Line<br>break <img src=\"a.png\"/> <Span>x</span></p>").is_empty());
+        assert!(unbalanced("`</div>` and\n\n```html\n<div>\n```").is_empty());
+        assert_eq!(unbalanced("<div class=\"card\">\n\nText"), vec!["<div>"]);
+        assert_eq!(unbalanced("Text</span>"), vec!["</span>"]);
+        assert_eq!(
+            unbalanced("<div><span>Text</div></p>"),
+            vec!["<span>", "</p>"]
+        );
+    }
+}
@@ -1,17 +1,80 @@
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use log::{error, warn};
-use mdbook::book::Book;
+use anyhow::{bail, Context};
+use encoding_rs::Encoding;
+use mdbook::book::{Book, Chapter};
 use mdbook::errors::Result;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use mdbook::BookItem;
 
-use crate::utils::{FileReader, SystemFileReader};
+use crate::config::{CheckLinks, Config, OnError, Passthrough};
+use crate::context::ExpansionContext;
+use crate::diagnostics::Code;
+use crate::directives::Registry;
+use crate::front_matter::Assets;
+use crate::glossary::Glossary;
+use crate::links::{LinkType, MissingArgument};
+use crate::stats::{Expansion, Stats};
+use crate::utils::{
+    normalize_path, BuiltinFileReader, CachedFileReader, ExtensionFileReader, FileReader,
+    IntegrityError, RendererFileReader, SymlinkFileReader, SystemFileReader,
+};
 
+pub use crate::check::{check, Report};
+pub use crate::links::{extract_parameters, Parameter};
+pub use crate::pack::{update, Lockfile, Manifest, Pack};
+pub use crate::preview::Preview;
+pub use crate::refactor::{move_template, rdeps, rename_arg};
+pub use crate::scaffold::Component;
+pub use crate::syntax::{escape, parse, unescape, Segment, SegmentKind};
+
+mod arg_map;
+mod badges;
+mod blocks;
+mod check;
+mod chunks;
+mod conditions;
+pub mod config;
+pub mod context;
+pub mod diagnostics;
+mod diagrams;
+mod directives;
+mod embedded;
+mod fences;
+mod filters;
+pub mod front_matter;
+mod globals;
+mod glossary;
+mod html;
 mod links;
+mod lookup;
+#[cfg(feature = "openapi")]
+mod openapi;
+mod pack;
+mod preview;
+mod refactor;
+mod scaffold;
+mod scanner;
+mod sections;
+pub mod stats;
+mod syntax;
+mod tabs;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod urls;
 pub mod utils;
 
 const MAX_LINK_NESTED_DEPTH: usize = 10;
+// `test` is the renderer used by `mdbook test`, so doctests run against the expanded templates
+const SUPPORTED_RENDERERS: &[&str] = &["html", "test"];
+// Renderers of other formats, which only run the preprocessor so that the `passthrough` option
+// can tidy up their chapters
+const PASSTHROUGH_RENDERERS: &[&str] = &["epub", "pdf", "latex", "typst", "markdown"];
 
 #[derive(Default)]
 pub struct Template;
@@ -28,38 +91,284 @@ impl Preprocessor for Template {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+        // A book may be built more than once within the same process, e.g. by `testing::Fixture`
+        let _ = env_logger::try_init_from_env(env_logger::Env::default().default_filter_or("info"));
         let src_dir = ctx.root.join(&ctx.config.book.src);
+        let mut config = Config::from_context(ctx, self.name())?;
+        // Keep any sink installed by whoever embeds the preprocessor unless asked otherwise
+        if config.diagnostics != diagnostics::Format::Log {
+            diagnostics::install(config.diagnostics.sink());
+        }
+        config.resolve_paths(&src_dir)?;
+        // Fail early rather than for every chapter when the directive keyword is invalid
+        links::validate_keywords(&config.keywords())?;
+        let mut system_file_reader =
+            SystemFileReader::new(config.max_file_size).lossy(config.lossy);
+        for (dir, label) in &config.encodings {
+            let encoding = Encoding::for_label(label.as_bytes())
+                .with_context(|| format!("Unknown encoding {} for {}", label, dir.display()))?;
+            system_file_reader = system_file_reader.encoding(src_dir.join(dir), encoding);
+        }
+        for (dir, directory) in &config.directories {
+            if let Some(ref label) = directory.encoding {
+                let encoding = Encoding::for_label(label.as_bytes())
+                    .with_context(|| format!("Unknown encoding {} for {}", label, dir.display()))?;
+                system_file_reader = system_file_reader.encoding(dir, encoding);
+            }
+        }
+        // Templates used by many chapters are only read once
+        let file_reader = CachedFileReader::new(BuiltinFileReader::new(ExtensionFileReader::new(
+            RendererFileReader::new(
+                SymlinkFileReader::new(system_file_reader, config.symlinks, &ctx.root),
+                &ctx.renderer,
+            ),
+            &config.extensions,
+        )));
+
+        let enabled = match config.renderers {
+            Some(ref renderers) => renderers.contains(&ctx.renderer),
+            None => SUPPORTED_RENDERERS.contains(&ctx.renderer.as_str()),
+        };
+        if !enabled {
+            let replacement = match config.passthrough {
+                Passthrough::Keep => return Ok(book),
+                Passthrough::Strip => "",
+                Passthrough::Note => &config.passthrough_note,
+            };
+            book.for_each_mut(|section| {
+                if let BookItem::Chapter(ref mut chapter) = section {
+                    chapter.content = strip_templates(&chapter.content, replacement, &config);
+                }
+            });
+            return Ok(book);
+        }
+
+        // Fail fast rather than stalling on pathologically large chapters
+        for item in book.iter() {
+            if let BookItem::Chapter(ref chapter) = item {
+                if chapter.content.len() > config.max_chapter_length {
+                    bail!(
+                        "Chapter \"{}\" is {} bytes, larger than the max-chapter-length of {} bytes",
+                        chapter.name,
+                        chapter.content.len(),
+                        config.max_chapter_length
+                    );
+                }
+            }
+        }
+
+        // Book-wide variables take precedence over the builtin values of the same name
+        let mut book_globals = globals::book(ctx, &config)?;
+        // Values of the installed packs live under their namespace, e.g. `corp.logo`
+        book_globals.extend(pack::variables(&src_dir)?);
+        book_globals.extend(config.variables()?);
 
+        // Chapters requiring a variable that isn't set are left out of this build altogether
+        drop_chapters(&mut book.sections, &book_globals);
+
+        let mut stats = Stats::default();
+        let mut registry = match config.glossary {
+            Some(ref glossary) => {
+                let contents = SystemFileReader::new(config.max_file_size)
+                    .read_to_string(&src_dir.join(glossary), &glossary.to_string_lossy())?;
+                Registry::with_glossary(Glossary::parse(glossary, &contents))
+            }
+            None => Registry::default(),
+        }
+        .preserve_escapes(config.preserve_escapes)
+        .global_counters(config.global_counters)
+        .offline_badges(config.offline_badges);
+
+        let mut error = None;
         book.for_each_mut(|section| {
             if let BookItem::Chapter(ref mut chapter) = section {
                 if let Some(ref source) = chapter.path {
-                    let base = source
-                        .parent()
-                        .map(|dir| src_dir.join(dir))
-                        .expect("All book items have a parent");
-
-                    let content =
-                        replace_template(&chapter.content, &SystemFileReader, base, source, 0);
-                    chapter.content = content;
+                    let mut globals = match globals::enabled(&config.disable_builtins)
+                        .any(|namespace| namespace == "chapter")
+                    {
+                        true => chapter_globals(chapter, &book_globals),
+                        false => book_globals.clone(),
+                    };
+                    // Invalid front matter is reported once the chapter is processed
+                    let config = match front_matter::split(&chapter.content) {
+                        Ok((front_matter, _)) => {
+                            globals.extend(front_matter.template.variables.clone());
+                            config.for_chapter(&front_matter.template)
+                        }
+                        Err(_) => Cow::Borrowed(&config),
+                    };
+                    let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+                    let processed = process_chapter(source, &chapter.content, &ctx);
+                    stats.extend(ctx.into_stats());
+                    let content = match processed {
+                        Ok(processed) => processed.content,
+                        Err(err) => {
+                            error.get_or_insert(err);
+                            return;
+                        }
+                    };
+                    if !config.global_counters {
+                        registry.reset_counters();
+                    }
+                    registry.skip_langs(source, &config.skip_langs);
+                    chapter.content = registry.collect(&content, source);
+                    if !config.two_pass {
+                        chapter.content = registry.resolve(&chapter.content, source);
+                    }
                 }
             }
         });
 
+        if let Some(err) = error {
+            return Err(err);
+        }
+
+        // Pinned templates that changed upstream, or critical ones that couldn't be expanded,
+        // must never end up in the published book
+        if !stats.failures().is_empty() {
+            bail!(
+                "Could not expand every template:\n{}",
+                stats.failures().join("\n")
+            );
+        }
+
+        // Chapters are expanded in the order of SUMMARY.md, so references and totals can only
+        // point forward once every chapter has been expanded
+        if config.two_pass {
+            book.for_each_mut(|section| {
+                if let BookItem::Chapter(ref mut chapter) = section {
+                    if let Some(ref source) = chapter.path {
+                        chapter.content = registry.resolve(&chapter.content, source);
+                    }
+                }
+            });
+        }
+
+        if let Some(ref metrics) = config.metrics {
+            let path = ctx.root.join(metrics);
+            fs::write(&path, stats.metrics(&src_dir))
+                .with_context(|| format!("Could not write {}", path.display()))?;
+        }
+        if config.provenance {
+            book.push_item(Chapter::new(
+                "Provenance",
+                stats.provenance_report(&src_dir),
+                "template-provenance.md",
+                vec![],
+            ));
+        }
+        if config.usage_appendix {
+            book.push_item(Chapter::new(
+                "Template Usage",
+                stats.usage_appendix(&src_dir),
+                "template-usage.md",
+                vec![],
+            ));
+        }
+
         Ok(book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html"
+        SUPPORTED_RENDERERS.contains(&renderer) || PASSTHROUGH_RENDERERS.contains(&renderer)
+    }
+}
+
+/// A single chapter expanded by [`process_chapter`].
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ProcessedChapter {
+    /// The chapter with every template expanded, before the labels, references and counters of
+    /// the whole book are resolved
+    pub content: String,
+    /// Every template expanded within the chapter, including nested ones, so the chapter can be
+    /// processed again only when one of them changes
+    pub templates: BTreeSet<PathBuf>,
+}
+
+/// Expands the chapter at `chapter_path`, relative to the book source directory, as building the
+/// book would, so that watch and build daemons can re-expand only the chapters affected by a
+/// change. Invocations that failed with `on-error=fail` are recorded in the stats of `ctx`.
+pub fn process_chapter<P, FR>(
+    chapter_path: P,
+    content: &str,
+    ctx: &ExpansionContext<FR>,
+) -> Result<ProcessedChapter>
+where
+    P: AsRef<Path>,
+    FR: FileReader,
+{
+    let (source, config, globals) = (chapter_path.as_ref(), ctx.config, ctx.globals);
+    if content.len() > config.max_chapter_length {
+        bail!(
+            "Chapter \"{}\" is {} bytes, larger than the max-chapter-length of {} bytes",
+            source.display(),
+            content.len(),
+            config.max_chapter_length
+        );
+    }
+    let base = source
+        .parent()
+        .map(|dir| config.src_dir.join(dir))
+        .expect("All book items have a parent");
+
+    let mut assets = Assets::default();
+    let content = match front_matter::split(content) {
+        Ok((front_matter, content)) => {
+            assets.add(&front_matter);
+            content
+        }
+        Err(err) => {
+            diagnostics::warn(
+                Code::FrontMatter,
+                format_args!(
+                    "Ignoring the front matter of \"{}\", {}",
+                    source.display(),
+                    err
+                ),
+            );
+            content
+        }
+    };
+    let mut expand = |content: &str| {
+        let content = links::replace_matches(content, globals);
+        let content = match config.interpolate {
+            true => links::interpolate(&content, globals, config),
+            false => content,
+        };
+        replace_template(&content, &base, source, 0, &mut assets, ctx)
+    };
+    // Machine generated chapters are expanded a chunk at a time, so only the intermediate
+    // copies of a single chunk are held in memory at once
+    let mut content = match content.len() > config.stream_threshold {
+        true => chunks::split(content, chunks::CHUNK_SIZE)
+            .map(expand)
+            .collect(),
+        false => expand(content),
+    };
+    if let Some(block) = assets.render() {
+        content.push_str("\n\n");
+        content.push_str(&block);
+    }
+    let stats = ctx.stats();
+    if config.debug_overlay {
+        if let Some(overlay) = stats.debug_overlay(source, &config.src_dir) {
+            content.push_str("\n\n");
+            content.push_str(&overlay);
+        }
     }
+    Ok(ProcessedChapter {
+        content,
+        templates: stats.templates(source),
+    })
 }
 
 pub fn replace_template<P1, P2, FR>(
     chapter_content: &str,
-    file_reader: &FR,
     base: P1,
     source: P2,
     depth: usize,
+    assets: &mut Assets,
+    ctx: &ExpansionContext<FR>,
 ) -> String
 where
     P1: AsRef<Path>,
@@ -68,45 +377,295 @@ where
 {
     let path = base.as_ref();
     let source = source.as_ref();
+    let ExpansionContext {
+        file_reader,
+        globals,
+        config,
+        ..
+    } = *ctx;
+    let keywords = config.keywords();
+    if let Err(err) = links::validate_keywords(&keywords) {
+        diagnostics::error(Code::InvalidKeyword, format_args!("{}", err));
+        return chapter_content.to_owned();
+    }
+    let chapter_content = &conditions::replace(chapter_content, globals);
+    let chapter_content = &tabs::replace(chapter_content, assets);
+    let skipped = fences::skipped(chapter_content, &config.skip_langs);
+    let containers = blocks::containers(chapter_content);
     // Must keep track of indices as they will not correspond after string substitution
     let mut previous_end_index = 0;
+    let mut previous_expanded = false;
     let mut replaced = String::with_capacity(chapter_content.len());
 
-    for link in links::extract_template_links(chapter_content) {
-        replaced.push_str(&chapter_content[previous_end_index..link.start_index]);
+    for link in links::extract_template_links(chapter_content)
+        .max_length(config.max_invocation_length)
+        .max_lines(config.max_invocation_lines)
+        .keywords(&keywords)
+        .directives(&config.directives)
+        .src_dir(&config.src_dir)
+    {
+        // Left as is, including escapes, so the gap up to the next link still covers it
+        if skipped
+            .iter()
+            .any(|range| range.contains(&link.start_index))
+        {
+            continue;
+        }
+        let gap = &chapter_content[previous_end_index..link.start_index];
+        replaced.push_str(gap);
+        let consecutive = previous_expanded && gap.trim().is_empty();
+        previous_expanded = false;
+
+        if link.link_type != LinkType::Escaped && ctx.stats().expanded() >= config.max_expansions {
+            // Leave the rest of the chapter as is, reporting the exhausted budget only once
+            // rather than at every level of nesting
+            if ctx.exhaust_budget() {
+                let message = format!(
+                    "\"{}\" expands more than {} templates, raise `max-expansions` if that is intended",
+                    source.display(),
+                    config.max_expansions
+                );
+                diagnostics::error_in(source, Code::Budget, &message);
+                ctx.stats().record_failure(message);
+            }
+            previous_end_index = link.start_index;
+            break;
+        }
 
-        match link.replace_args(path, file_reader) {
+        let started = Instant::now();
+        let mut sources = Vec::new();
+        match link.replace_args(path, file_reader, assets, &mut sources, globals, config) {
             Ok(new_content) => {
-                if depth < MAX_LINK_NESTED_DEPTH {
-                    if let Some(rel_path) = link.link_type.relative_path(path) {
-                        replaced.push_str(&replace_template(
+                for external in sources {
+                    ctx.stats().record_source(source, external);
+                }
+                if consecutive && link.link_type != LinkType::Escaped {
+                    replaced.push_str(&link.separator(config));
+                }
+                previous_expanded = link.link_type != LinkType::Escaped;
+                if let Some(template) = link.link_type.target(path) {
+                    ctx.stats().record(Expansion {
+                        template,
+                        chapter: source.to_path_buf(),
+                        args: link.arg_names(),
+                        duration: started.elapsed(),
+                        depth: depth + 1,
+                    });
+                    // Nested invocations are still unexpanded, so each template answers for its own tags
+                    if config.validate_html {
+                        let unbalanced = html::unbalanced(&new_content);
+                        if !unbalanced.is_empty() {
+                            diagnostics::warn_in(
+                                source,
+                                Code::UnbalancedHtml,
+                                format_args!(
+                                    "\"{}\" produces unbalanced HTML: {}",
+                                    link.link_text,
+                                    unbalanced.join(", ")
+                                ),
+                            );
+                        }
+                    }
+                    if config.check_links != CheckLinks::Off {
+                        let head = config.check_links == CheckLinks::Head;
+                        for (url, err) in urls::check(&new_content, head) {
+                            diagnostics::warn_in(
+                                source,
+                                Code::InvalidLink,
+                                format_args!(
+                                    "\"{}\" links to \"{}\", but {}",
+                                    link.link_text, url, err
+                                ),
+                            );
+                        }
+                    }
+                }
+
+                // Symlinks are resolved, so a template including itself through one is caught too
+                let included = link.link_type.target(path).map(|target| {
+                    target
+                        .canonicalize()
+                        .unwrap_or_else(|_| normalize_path(&target))
+                });
+                let cycle = included.as_ref().filter(|included| !ctx.enter(included));
+                if let Some(included) = cycle {
+                    diagnostics::error(
+                        Code::Cycle,
+                        format_args!(
+                            "{} includes {}, which is already being expanded. Check For Cyclic Templates",
+                            source.display(),
+                            included.display()
+                        ),
+                    );
+                } else if depth < MAX_LINK_NESTED_DEPTH {
+                    // Keep multi-line expansions within the blockquote or list item around them
+                    let prefix = match link.link_type {
+                        LinkType::Escaped => None,
+                        _ => blocks::continuation(chapter_content, link.start_index, &containers),
+                    };
+                    let trim = link.trim_inline(chapter_content, config);
+                    let expanded = match link.link_type.relative_path(path) {
+                        Some(rel_path) => Cow::Owned(replace_template(
                             &new_content,
-                            file_reader,
                             rel_path,
                             source,
                             depth + 1,
-                        ));
-                    } else {
-                        replaced.push_str(&new_content);
+                            assets,
+                            ctx,
+                        )),
+                        None => Cow::Borrowed(&new_content),
+                    };
+                    let expanded = match trim {
+                        true => expanded.trim(),
+                        false => &expanded,
+                    };
+                    match prefix {
+                        Some(prefix) => replaced.push_str(&blocks::indent(expanded, &prefix)),
+                        None => replaced.push_str(expanded),
                     }
                 } else {
-                    error!(
-                        "Stack Overflow! {}. Check For Cyclic Templates",
-                        source.display()
+                    diagnostics::error(
+                        Code::Cycle,
+                        format_args!(
+                            "Stack Overflow! {}. Check For Cyclic Templates",
+                            source.display()
+                        ),
                     );
                 }
+                if included.is_some() && cycle.is_none() {
+                    ctx.leave();
+                }
                 previous_end_index = link.end_index;
             }
             Err(err) => {
-                error!("Error updating \"{}\", {}", link.link_text, err);
-                for cause in err.chain().skip(1) {
-                    warn!("Caused By: {}", cause);
+                let on_error = match err.downcast_ref::<IntegrityError>() {
+                    Some(_) => OnError::Fail,
+                    // Undefined arguments are never allowed through in strict mode
+                    None if config.strict_args && err.is::<MissingArgument>() => OnError::Fail,
+                    None => link.on_error(config),
+                };
+                let code = code_of(&err);
+                match on_error {
+                    OnError::Ignore => previous_end_index = link.end_index,
+                    OnError::Warn => {
+                        diagnostics::warn(
+                            code,
+                            format_args!("Leaving out \"{}\", {}", link.link_text, err),
+                        );
+                        previous_end_index = link.end_index;
+                    }
+                    OnError::Keep | OnError::Fail => {
+                        if on_error == OnError::Fail {
+                            ctx.stats()
+                                .record_failure(format!("\"{}\", {}", link.link_text, err));
+                        }
+                        diagnostics::error_in(
+                            source,
+                            code,
+                            format_args!("Error updating \"{}\", {}", link.link_text, err),
+                        );
+                        for cause in err.chain().skip(1) {
+                            diagnostics::warn(code, format_args!("Caused By: {}", cause));
+                        }
+
+                        // Include `{{# ... }}` snippet when errors occur
+                        previous_end_index = link.start_index;
+                    }
                 }
+            }
+        }
+    }
 
-                // Include `{{# ... }}` snippet when errors occur
-                previous_end_index = link.start_index;
+    replaced.push_str(&chapter_content[previous_end_index..]);
+    replaced
+}
+
+/// Removes every chapter, along with its sub chapters, whose front matter `requires` a variable
+/// that isn't set.
+fn drop_chapters(items: &mut Vec<BookItem>, variables: &HashMap<String, String>) {
+    items.retain(|item| match item {
+        BookItem::Chapter(chapter) => match front_matter::split(&chapter.content) {
+            Ok((front_matter, _)) => front_matter
+                .requires
+                .is_none_or(|required| conditions::is_set(&required, variables)),
+            Err(_) => true,
+        },
+        _ => true,
+    });
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            drop_chapters(&mut chapter.sub_items, variables);
+        }
+    }
+}
+
+/// Values of the reserved `chapter.*` names available to every template within the chapter,
+/// alongside the book-wide `globals`.
+fn chapter_globals(
+    chapter: &Chapter,
+    globals: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut globals = globals.clone();
+    // Draft chapters and those outside the numbered part of SUMMARY.md have no number
+    if let Some(ref number) = chapter.number {
+        let section = number
+            .iter()
+            .map(|part| part.to_string())
+            .collect::<Vec<_>>();
+        globals.insert("chapter.number".to_string(), section[0].clone());
+        globals.insert("chapter.section".to_string(), section.join("."));
+    }
+    globals
+}
+
+/// The kind of error an invocation couldn't be expanded with.
+fn code_of(err: &anyhow::Error) -> Code {
+    if err.downcast_ref::<IntegrityError>().is_some() {
+        return Code::Integrity;
+    }
+    if err.is::<MissingArgument>() {
+        return Code::MissingArgument;
+    }
+    let missing = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+    });
+    match missing {
+        true => Code::MissingFile,
+        false => Code::Expansion,
+    }
+}
+
+/// Replaces every template within the chapter with `replacement`, without expanding them.
+pub fn strip_templates(chapter_content: &str, replacement: &str, config: &Config) -> String {
+    let keywords = config.keywords();
+    if let Err(err) = links::validate_keywords(&keywords) {
+        diagnostics::error(Code::InvalidKeyword, format_args!("{}", err));
+        return chapter_content.to_owned();
+    }
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(chapter_content.len());
+
+    for link in links::extract_template_links(chapter_content)
+        .keywords(&keywords)
+        .directives(&config.directives)
+        .src_dir(&config.src_dir)
+    {
+        replaced.push_str(&chapter_content[previous_end_index..link.start_index]);
+        match link.link_type {
+            LinkType::Escaped => {
+                replaced.push_str(&links::unescape(link.link_text, config.preserve_escapes))
             }
+            LinkType::Template(_)
+            | LinkType::Rooted(..)
+            | LinkType::Chapter(_)
+            | LinkType::Pack(..)
+            | LinkType::Data(_)
+            | LinkType::Changelog(_)
+            | LinkType::Diagram(_) => replaced.push_str(replacement),
         }
+        previous_end_index = link.end_index;
     }
 
     replaced.push_str(&chapter_content[previous_end_index..]);
@@ -118,8 +677,65 @@ mod lib_tests {
     use std::collections::HashMap;
     use std::path::PathBuf;
 
-    use crate::replace_template;
-    use crate::utils::TestFileReader;
+    use mdbook::book::{Chapter, SectionNumber};
+    use mdbook::preprocess::Preprocessor;
+    use mdbook::BookItem;
+
+    use crate::config::{CheckLinks, Config, Directive, DirectoryConfig};
+    use crate::context::ExpansionContext;
+    use crate::diagnostics::{self, Code, Severity};
+    use crate::directives::Registry;
+    use crate::front_matter::Assets;
+    use crate::testing::Fixture;
+    use crate::utils::{
+        BuiltinFileReader, ExtensionFileReader, RendererFileReader, SystemFileReader,
+        TestFileReader,
+    };
+    use crate::{
+        chapter_globals, drop_chapters, process_chapter, replace_template, strip_templates,
+        Template,
+    };
+
+    /// Expands `chapter` at the root of a book made of `files`, as a chapter without globals.
+    fn expand(chapter: &str, files: &[(&str, &str)], config: &Config) -> String {
+        let file_reader = TestFileReader::from(
+            files
+                .iter()
+                .map(|(path, contents)| (PathBuf::from(path), contents.to_string()))
+                .collect::<HashMap<_, _>>(),
+        );
+        replace_template(
+            chapter,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(&file_reader, &HashMap::new(), config),
+        )
+    }
+
+    #[test]
+    fn test_supports_renderer() {
+        assert!(Template::new().supports_renderer("html"));
+        assert!(Template::new().supports_renderer("test"));
+        assert!(Template::new().supports_renderer("epub"));
+        assert!(!Template::new().supports_renderer("linkcheck"));
+        assert!(!Template::new().supports_renderer("custom"));
+    }
+
+    #[test]
+    fn test_strip_templates() {
+        let start = r"Intro {{#template footer.md authors=Goudham}} and \{{#template escaped.md}}";
+
+        assert_eq!(
+            strip_templates(start, "", &Config::default()),
+            "Intro  and {{#template escaped.md}}"
+        );
+        assert_eq!(
+            strip_templates(start, "_Not available_", &Config::default()),
+            "Intro _Not available_ and {{#template escaped.md}}"
+        );
+    }
 
     #[test]
     fn test_happy_path_escaped() {
@@ -134,24 +750,41 @@ mod lib_tests {
         {{#template template.md}} << an escaped link!
         ```";
 
-        assert_eq!(
-            replace_template(start, &TestFileReader::default(), "", "", 0),
-            end
-        );
+        assert_eq!(expand(start, &[], &Config::default()), end);
+    }
+
+    #[test]
+    fn test_happy_path_blockquote_and_list() {
+        let start = "> **Note**\n> {{#template note.md}}\n\n- {{#template note.md}}\n- Next";
+        let end =
+            "> **Note**\n> First line\n>\n> Second line\n\n- First line\n\n  Second line\n- Next";
+        let files = [("note.md", "First line\n\nSecond line")];
+
+        assert_eq!(expand(start, &files, &Config::default()), end);
+    }
+
+    #[test]
+    fn test_happy_path_if_else() {
+        let start = "{{#template title.md name=Intro}}\n{{#template title.md}}";
+        let end = "# Intro\n# Untitled";
+        let files = [(
+            "title.md",
+            "[[#if name]]\n# [[#name]]\n[[#else]]\n# Untitled\n[[/if]]",
+        )];
+
+        assert_eq!(expand(start, &files, &Config::default()), end);
     }
 
     #[test]
     fn test_happy_path_simple() {
         let start_chapter_content = "{{#template footer.md}}";
         let end_chapter_content = "Designed & Created With Love From - Goudham & Hazel";
-        let file_name = PathBuf::from("footer.md");
-        let template_file_contents =
-            "Designed & Created With Love From - Goudham & Hazel".to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [(
+            "footer.md",
+            "Designed & Created With Love From - Goudham & Hazel",
+        )];
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
@@ -160,13 +793,12 @@ mod lib_tests {
     fn test_happy_path_with_args() {
         let start_chapter_content = "{{#template footer.md authors=Goudham & Hazel}}";
         let end_chapter_content = "Designed & Created With Love From - Goudham & Hazel";
-        let file_name = PathBuf::from("footer.md");
-        let template_file_contents = "Designed & Created With Love From - [[#authors]]".to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [(
+            "footer.md",
+            "Designed & Created With Love From - [[#authors]]",
+        )];
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
@@ -180,15 +812,13 @@ mod lib_tests {
         Some content...
         - - - -
         Designed & Created With Love From Goudham & Hazel";
-        let file_name = PathBuf::from("footer.md");
-        let template_file_contents = r"- - - -
-        Designed & Created With Love From [[#authors]]"
-            .to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [(
+            "footer.md",
+            r"- - - -
+        Designed & Created With Love From [[#authors]]",
+        )];
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
@@ -206,20 +836,16 @@ mod lib_tests {
         Some content...
         - - - -
         Designed & Created With Love From Goudham & Hazel";
-        let header_file_name = PathBuf::from("header.md");
-        let header_contents = r"# [[#title]]".to_string();
-        let footer_file_name = PathBuf::from("footer.md");
-        let footer_contents = r"- - - -
-        Designed & Created With Love From [[#authors]]"
-            .to_string();
-        let map = HashMap::from([
-            (footer_file_name, footer_contents),
-            (header_file_name, header_contents),
-        ]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [
+            (
+                "footer.md",
+                r"- - - -
+        Designed & Created With Love From [[#authors]]",
+            ),
+            ("header.md", r"# [[#title]]"),
+        ];
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
@@ -228,13 +854,9 @@ mod lib_tests {
     fn test_happy_path_with_default_values() {
         let start_chapter_content = "{{#template footer.md}}";
         let end_chapter_content = "Designed By - Goudham";
-        let file_name = PathBuf::from("footer.md");
-        let template_file_contents = "Designed By - [[#authors Goudham]]".to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [("footer.md", "Designed By - [[#authors Goudham]]")];
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
@@ -243,17 +865,876 @@ mod lib_tests {
     fn test_happy_path_with_overridden_default_values() {
         let start_chapter_content = "{{#template footer.md authors=Hazel}}";
         let end_chapter_content = "Designed By - Hazel";
-        let file_name = PathBuf::from("footer.md");
-        let template_file_contents = "Designed By - [[#authors Goudham]]".to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
+        let files = [("footer.md", "Designed By - [[#authors Goudham]]")];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_with_appended_values() {
+        let start_chapter_content = "{{#template footer.md authors=Goudham authors+=Hazel}}";
+        let end_chapter_content = "Designed By - Goudham, Hazel";
+        let files = [("footer.md", "Designed By - [[#authors]]")];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_with_preset() {
+        let start_chapter_content = "{{#template header.md preset=blog-header author=Hazel}}";
+        let end_chapter_content = "![avatar](ferris.png) Hazel";
+        let files = [("header.md", "![avatar]([[#avatar]]) [[#author]]")];
+        let config = Config {
+            presets: HashMap::from([(
+                "blog-header".to_string(),
+                HashMap::from([
+                    ("author".to_string(), "Goudham".to_string()),
+                    ("avatar".to_string(), "ferris.png".to_string()),
+                ]),
+            )]),
+            ..Default::default()
+        };
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &config);
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_read_only_globals() {
+        let start_chapter_content =
+            "{{#template title.md chapter.title=Hacked name=Ferris book=Rust}}";
+        let files = [(
+            "title.md",
+            "[[#chapter.title Untitled]] [[#name]] [[#book]]",
+        )];
+
+        let (shadowed, diagnostics) =
+            diagnostics::capture(|| expand(start_chapter_content, &files, &Config::default()));
+        assert_eq!(shadowed, "Hacked Ferris Rust");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]
+            .message
+            .starts_with("Argument \"chapter.title\" shadows a reserved name"));
+
+        let config = Config {
+            read_only_globals: true,
+            ..Default::default()
+        };
+        let (ignored, diagnostics) =
+            diagnostics::capture(|| expand(start_chapter_content, &files, &config));
+        assert_eq!(ignored, "Untitled Ferris Rust");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Code::ReservedName);
+        assert!(diagnostics[0]
+            .message
+            .starts_with("Ignoring argument \"chapter.title\", names within the"));
+    }
+
+    #[test]
+    fn test_drop_chapters() {
+        let internal = "+++\nrequires = \"internal\"\n+++\n# Internal";
+        let mut guide = Chapter::new("Guide", "# Guide".to_string(), "guide.md", vec![]);
+        guide.sub_items = vec![BookItem::Chapter(Chapter::new(
+            "Runbook",
+            internal.to_string(),
+            "guide/runbook.md",
+            vec![],
+        ))];
+        let mut items = vec![
+            BookItem::Chapter(guide),
+            BookItem::Chapter(Chapter::new(
+                "Oncall",
+                internal.to_string(),
+                "oncall.md",
+                vec![],
+            )),
+        ];
+        let sub_items = |items: &[BookItem]| {
+            items
+                .iter()
+                .filter_map(|item| match item {
+                    BookItem::Chapter(chapter) => Some(chapter.sub_items.len()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut all = items.clone();
+        drop_chapters(
+            &mut all,
+            &HashMap::from([("internal".to_string(), "true".to_string())]),
+        );
+        assert_eq!(sub_items(&all), vec![1, 0]);
+
+        drop_chapters(&mut items, &HashMap::new());
+        assert_eq!(sub_items(&items), vec![0]);
+    }
+
+    #[test]
+    fn test_directory_defaults() {
+        let files = [("vendor/components/card.md", "[[#theme]] [[#size]]")];
+        let config = Config {
+            directories: HashMap::from([
+                (
+                    PathBuf::from("vendor"),
+                    DirectoryConfig {
+                        defaults: HashMap::from([
+                            ("theme".to_string(), "dark".to_string()),
+                            ("size".to_string(), "large".to_string()),
+                        ]),
+                        encoding: None,
+                    },
+                ),
+                (
+                    PathBuf::from("vendor/components"),
+                    DirectoryConfig {
+                        defaults: HashMap::from([("theme".to_string(), "light".to_string())]),
+                        encoding: None,
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let expanded = expand("{{#template vendor/components/card.md}} {{#template vendor/components/card.md size=small}}", &files, &config);
+
+        assert_eq!(expanded, "light large light small");
+    }
+
+    #[test]
+    fn test_chapter_globals() {
+        let mut chapter = Chapter::new("Install", String::new(), "guide/install.md", vec![]);
+        chapter.number = Some(SectionNumber(vec![3, 2]));
+        let globals = chapter_globals(&chapter, &HashMap::new());
+        let map = HashMap::from([(
+            PathBuf::from("heading.md"),
+            "## [[#chapter.section]] [[#title]] ([[#chapter.number]])".to_string(),
+        )]);
+
+        let expanded = replace_template(
+            "{{#template heading.md title=Install}}",
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(&TestFileReader::from(map), &globals, &Config::default()),
+        );
+
+        assert_eq!(expanded, "## 3.2 Install (3)");
+        chapter.number = None;
+        assert!(chapter_globals(&chapter, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_preserve_escapes() {
+        let start_chapter_content = r"\{{#template name.md}} {{#template name.md}}";
+        let files = [("name.md", r"\[[#name]] [[#name Ferris]]")];
+
+        assert_eq!(
+            expand(start_chapter_content, &files, &Config::default()),
+            "{{#template name.md}} [[#name]] Ferris"
+        );
+        assert_eq!(
+            expand(
+                start_chapter_content,
+                &files,
+                &Config {
+                    preserve_escapes: true,
+                    ..Default::default()
+                }
+            ),
+            r"\{{#template name.md}} \[[#name]] Ferris"
+        );
+    }
+
+    #[test]
+    fn test_argument_aliases_and_case() {
+        let start_chapter_content = "{{#template footer.md authors=Hazel title=Credits}}";
+        let files = [(
+            "footer.md",
+            "+++\naliases = [[\"author\", \"authors\"]]\n+++\n[[#Title Untitled]] by [[#author]]",
+        )];
+
+        assert_eq!(
+            expand(start_chapter_content, &files, &Config::default()),
+            "Untitled by Hazel"
+        );
+        assert_eq!(
+            expand(
+                start_chapter_content,
+                &files,
+                &Config {
+                    case_insensitive_args: true,
+                    ..Default::default()
+                }
+            ),
+            "Credits by Hazel"
+        );
+    }
+
+    #[test]
+    fn test_trim() {
+        let files = [("footer.md", "\n  \n  Made by [[#authors]]\n\n")];
+        let trim = |trim: &str| {
+            expand(
+                &format!("{{{{#template footer.md authors=Hazel{}}}}}|", trim),
+                &files,
+                &Config::default(),
+            )
+        };
+
+        assert_eq!(trim(""), "\n  \n  Made by Hazel\n\n|");
+        assert_eq!(trim(" trim=none"), "\n  \n  Made by Hazel\n\n|");
+        assert_eq!(trim(" trim=start"), "  Made by Hazel\n\n|");
+        assert_eq!(trim(" trim=end"), "\n  \n  Made by Hazel|");
+        assert_eq!(trim(" trim=both"), "  Made by Hazel|");
+        assert_eq!(
+            trim(" trim=sideways"),
+            "{{#template footer.md authors=Hazel trim=sideways}}|"
+        );
+    }
+
+    #[test]
+    fn test_trim_inline() {
+        let start = "The {{#template name.md}} CLI\n\n{{#template name.md}}\n";
+        let files = [("name.md", "mdbook-template\n")];
+
+        assert_eq!(
+            expand(start, &files, &Config::default()),
+            "The mdbook-template\n CLI\n\nmdbook-template\n\n"
+        );
+        assert_eq!(
+            expand(
+                start,
+                &files,
+                &Config {
+                    trim_inline: true,
+                    ..Default::default()
+                }
+            ),
+            "The mdbook-template CLI\n\nmdbook-template\n\n"
+        );
+    }
+
+    #[test]
+    fn test_branch_placeholders() {
+        let files = [("t.md", "v=[[#else]] [[#default]]")];
+        let config = Config::default();
+
+        assert_eq!(expand("{{#template t.md else=X}}", &files, &config), "v=X ");
+        assert_eq!(
+            expand("{{#template t.md default=X}}", &files, &config),
+            "v= X"
+        );
+        assert_eq!(expand("{{#template t.md}}", &files, &config), "v= ");
+    }
+
+    #[test]
+    fn test_separator() {
+        let start_chapter_content = "{{#template a.md}}\n{{#template b.md separator=\\n***\\n}}\n\n{{#template a.md}} and {{#template b.md}}";
+        let files = [("a.md", "A"), ("b.md", "B")];
+        let config = Config {
+            separator: "---\n".to_string(),
+            ..Default::default()
+        };
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &config);
+
+        assert_eq!(actual_chapter_content, "A\n\n***\nB\n\n---\nA and B");
+    }
+
+    #[test]
+    fn test_template_defaults() {
+        let start_chapter_content = "{{#template ../templates/footer.md}} {{#template ../templates/footer.md preset=team authors=Hazel}}";
+        let map = HashMap::from([(
+            PathBuf::from("templates/footer.md"),
+            "[[#authors]] ([[#year]])".to_string(),
+        )]);
         let file_reader = &TestFileReader::from(map);
+        let config = Config {
+            defaults: HashMap::from([(
+                PathBuf::from("templates/footer.md"),
+                HashMap::from([
+                    ("authors".to_string(), "Docs Team".to_string()),
+                    ("year".to_string(), "2022".to_string()),
+                ]),
+            )]),
+            presets: HashMap::from([(
+                "team".to_string(),
+                HashMap::from([("year".to_string(), "2023".to_string())]),
+            )]),
+            ..Default::default()
+        };
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "chapters",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &config),
+        );
+
+        assert_eq!(actual_chapter_content, "Docs Team (2022) Hazel (2023)");
+    }
+
+    #[test]
+    fn test_rooted_directives() {
+        let start_chapter_content =
+            "{{#snippet hello.rs}} {{#snippet hello.rs lang=c}} {{#template hello.rs}}";
+        let map = HashMap::from([
+            (
+                PathBuf::from("snippets/hello.rs"),
+                "[[#lang]]: hello".to_string(),
+            ),
+            (PathBuf::from("chapters/hello.rs"), "chapter".to_string()),
+        ]);
+        let file_reader = &TestFileReader::from(map);
+        let config = Config {
+            directives: HashMap::from([(
+                "snippet".to_string(),
+                Directive {
+                    root: PathBuf::from("snippets"),
+                    defaults: HashMap::from([("lang".to_string(), "rust".to_string())]),
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "chapters",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &config),
+        );
+
+        assert_eq!(actual_chapter_content, "rust: hello c: hello chapter");
+    }
+
+    #[test]
+    fn test_chapter_sections() {
+        let start_chapter_content = "{{#template chapter:guide/install.md#install-rust}}|{{#template chapter:guide/install.md#missing}}";
+        let map = HashMap::from([
+            (
+                PathBuf::from("/book/src/guide/install.md"),
+                "# Install\n## Install Rust\n{{#template rustup.md}}\n## Configure\n".to_string(),
+            ),
+            (
+                PathBuf::from("/book/src/guide/rustup.md"),
+                "Use rustup.".to_string(),
+            ),
+        ]);
+        let config = Config {
+            src_dir: PathBuf::from("/book/src"),
+            ..Default::default()
+        };
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "/book/src/intro",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(&TestFileReader::from(map), &HashMap::new(), &config),
+        );
+
+        assert_eq!(
+            actual_chapter_content,
+            "Use rustup.\n|{{#template chapter:guide/install.md#missing}}"
+        );
+    }
+
+    #[test]
+    fn test_template_data() {
+        let start_chapter_content = "{{#template-data api.json pointer=/info/version}}
+{{#template-data api.json pointer=/paths/~1users/get template=endpoint.md method=GET}}
+{{#template-data api.json pointer=/info}}";
+        let files = [
+            (
+                "api.json",
+                r#"{"info": {"version": "1.0.0"}, "paths": {"/users": {"get": {"summary": "List users"}}}}"#,
+            ),
+            ("endpoint.md", "`[[#method]]` [[#summary]]"),
+        ];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(
+            actual_chapter_content,
+            "1.0.0\n`GET` List users\n```json\n{\n  \"version\": \"1.0.0\"\n}\n```"
+        );
+    }
+
+    #[cfg(feature = "openapi")]
+    #[test]
+    fn test_template_data_openapi() {
+        let start_chapter_content = "{{#template-data api.yaml operation=listUsers}}";
+        let map = HashMap::from([(
+            PathBuf::from("api.yaml"),
+            "paths:\n  /users:\n    get:\n      operationId: listUsers\n      summary: List users\n"
+                .to_string(),
+        )]);
+        let file_reader = BuiltinFileReader::new(TestFileReader::from(map));
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(&file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(
+            actual_chapter_content,
+            "### `GET /users`\n\nList users\n\n\n\n\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_skip_langs() {
+        let start_chapter_content = "{{#template hello.md}}\n\n```hbs\n{{#template hello.md}} \\{{name}}\n```\n\n```md\n{{#template hello.md}}\n```\n";
+        let files = [("hello.md", "Hello")];
+        let config = Config {
+            skip_langs: vec!["hbs".to_string()],
+            ..Default::default()
+        };
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &config);
+
+        assert_eq!(
+            actual_chapter_content,
+            "Hello\n\n```hbs\n{{#template hello.md}} \\{{name}}\n```\n\n```md\nHello\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_show_source() {
+        let start_chapter_content = "{{#template hello.md show-source=true}}";
+        let files = [(
+            "hello.md",
+            "# [[#title]] [[@counter figure]]\n\n{{#template footer.md}} \\{{x}}\n",
+        )];
+        let mut registry = Registry::default();
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+        let collected = registry.collect(&actual_chapter_content, "hello.md");
+
+        assert_eq!(
+            registry.resolve(&collected, "hello.md"),
+            "```md\n# [[#title]] [[@counter figure]]\n\n{{#template footer.md}} \\{{x}}\n```"
+        );
+    }
+
+    #[test]
+    fn test_integrity() {
+        let start_chapter_content = "{{#template hello.md integrity=sha256-GF+NsyJx/iX1Yab8k4suJkMG7DBO2lGAB9F2SCY4GWk=}} {{#template hello.md integrity=sha256-AAAA}} {{#template other.md}}";
+        let map = HashMap::from([
+            (PathBuf::from("hello.md"), "Hello".to_string()),
+            (PathBuf::from("other.md"), "Other".to_string()),
+        ]);
+        let file_reader = TestFileReader::from(map);
+        let config = Config {
+            integrity: HashMap::from([(PathBuf::from("other.md"), "md5-x".to_string())]),
+            ..Default::default()
+        };
+        let globals = HashMap::new();
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ctx,
+        );
+
+        assert_eq!(
+            actual_chapter_content,
+            "Hello {{#template hello.md integrity=sha256-AAAA}} {{#template other.md}}"
+        );
+        assert_eq!(ctx.stats().failures().len(), 2);
+    }
+
+    #[test]
+    fn test_on_error() {
+        let start_chapter_content = "A{{#template missing.md on-error=ignore}} B{{#template missing.md on-error=warn}} C{{#template missing.md}} D{{#template missing.md on-error=fail}}";
+        let file_reader = TestFileReader::from(HashMap::new());
+        let (globals, config) = (HashMap::new(), Config::default());
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (actual_chapter_content, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                start_chapter_content,
+                "",
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        assert_eq!(
+            actual_chapter_content,
+            "A B C{{#template missing.md}} D{{#template missing.md on-error=fail}}"
+        );
+        assert_eq!(ctx.stats().failures().len(), 1);
+        let severities = diagnostics
+            .iter()
+            .filter(|diagnostic| !diagnostic.message.starts_with("Caused By"))
+            .map(|diagnostic| (diagnostic.severity, diagnostic.file.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            severities,
+            vec![
+                (Severity::Warning, None),
+                (Severity::Error, Some(PathBuf::from("chapter.md"))),
+                (Severity::Error, Some(PathBuf::from("chapter.md"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_chapter() {
+        let file_reader = TestFileReader::from(HashMap::from([
+            (
+                PathBuf::from("/book/src/templates/card.md"),
+                "+++\ncss = [\".card {}\"]\n+++\n{{#template footer.md}} [[#title]]".to_string(),
+            ),
+            (
+                PathBuf::from("/book/src/templates/footer.md"),
+                "Footer".to_string(),
+            ),
+        ]));
+        let globals = HashMap::new();
+        let mut config = Config {
+            src_dir: PathBuf::from("/book/src"),
+            ..Default::default()
+        };
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let processed = process_chapter(
+            "guide/intro.md",
+            "+++\n+++\n{{#template ../templates/card.md title=Intro}}",
+            &ctx,
+        )
+        .unwrap();
+
+        assert_eq!(
+            processed.content,
+            "Footer Intro\n\n<style>\n.card {}\n</style>\n"
+        );
+        assert!(processed.templates.iter().eq([
+            &PathBuf::from("/book/src/templates/card.md"),
+            &PathBuf::from("/book/src/templates/footer.md"),
+        ]));
+
+        config.max_chapter_length = 8;
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+        assert!(process_chapter("intro.md", "Too long a chapter", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_required_args() {
+        let start_chapter_content =
+            "{{#template header.md title=Rust}}\n{{#template header.md}}\n{{#template header.md title=}}";
+        let file_reader = TestFileReader::from(HashMap::from([(
+            PathBuf::from("header.md"),
+            "# [[#title!]]".to_string(),
+        )]));
+        let (globals, config) = (HashMap::new(), Config::default());
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (actual_chapter_content, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                start_chapter_content,
+                "",
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        assert_eq!(
+            actual_chapter_content,
+            "# Rust\n{{#template header.md}}\n# "
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, Some(PathBuf::from("chapter.md")));
+        assert!(diagnostics[0]
+            .message
+            .ends_with("Missing the required argument \"title\""));
+    }
+
+    #[test]
+    fn test_strict_args() {
+        let start_chapter_content =
+            "{{#template header.md title=Rust on-error=ignore}}\n{{#template header.md on-error=ignore}}";
+        let file_reader = TestFileReader::from(HashMap::from([(
+            PathBuf::from("header.md"),
+            "# [[#title]] [[#subtitle Guide]]".to_string(),
+        )]));
+        let globals = HashMap::new();
+        let config = Config {
+            strict_args: true,
+            ..Default::default()
+        };
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (actual_chapter_content, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                start_chapter_content,
+                "",
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        assert_eq!(
+            actual_chapter_content,
+            "# Rust Guide\n{{#template header.md on-error=ignore}}"
+        );
+        assert_eq!(ctx.stats().failures().len(), 1);
+        assert_eq!(diagnostics[0].code, Code::MissingArgument);
+        assert!(diagnostics[0]
+            .message
+            .ends_with("Missing the argument \"title\", which has no default (strict-args)"));
+    }
+
+    #[test]
+    fn test_validate_html() {
+        let start_chapter_content = "{{#template card.md}}\n{{#template title.md title=<em>Rust}}";
+        let file_reader = TestFileReader::from(HashMap::from([
+            (
+                PathBuf::from("card.md"),
+                "<div class=\"card\">\n\n{{#template title.md title=Rust}}\n\n</div>".to_string(),
+            ),
+            (PathBuf::from("title.md"), "<h2>[[#title]]</h2>".to_string()),
+        ]));
+        let globals = HashMap::new();
+        let config = Config {
+            validate_html: true,
+            ..Default::default()
+        };
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (_, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                start_chapter_content,
+                "",
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].code, Code::UnbalancedHtml);
+        assert_eq!(diagnostics[0].file, Some(PathBuf::from("chapter.md")));
+        assert_eq!(
+            diagnostics[0].message,
+            "\"{{#template title.md title=<em>Rust}}\" produces unbalanced HTML: <em>"
+        );
+    }
+
+    #[test]
+    fn test_check_links() {
+        let start_chapter_content =
+            "{{#template badge.md label=build url=https://ci.example.com}}\n{{#template badge.md label=docs}}\n{{#template badge.md label=docs url=https://docs..rs}}";
+        let file_reader = TestFileReader::from(HashMap::from([(
+            PathBuf::from("badge.md"),
+            "[![[[#label]]](https://img.shields.io/badge/[[#label]]-[[#status passing]]-green)]([[#url]])"
+                .to_string(),
+        )]));
+        let globals = HashMap::new();
+        let config = Config {
+            check_links: CheckLinks::Syntax,
+            ..Default::default()
+        };
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (_, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                start_chapter_content,
+                "",
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        let messages = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>();
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.code == Code::InvalidLink));
+        assert_eq!(
+            messages,
+            vec![
+                "\"{{#template badge.md label=docs}}\" links to \"\", but it is empty",
+                "\"{{#template badge.md label=docs url=https://docs..rs}}\" links to \"https://docs..rs\", but \"docs..rs\" isn't a valid host",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_expansions() {
+        let start_chapter_content =
+            "{{#template outer.md}} {{#template inner.md}} \\{{#template escaped.md}}";
+        let map = HashMap::from([
+            (
+                PathBuf::from("outer.md"),
+                "A{{#template inner.md}}".to_string(),
+            ),
+            (PathBuf::from("inner.md"), "B".to_string()),
+        ]);
+        let file_reader = TestFileReader::from(map);
+        let globals = HashMap::new();
+        let config = Config {
+            max_expansions: 2,
+            ..Default::default()
+        };
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "chapter.md",
+            0,
+            &mut Assets::default(),
+            &ctx,
+        );
+
+        assert_eq!(
+            actual_chapter_content,
+            "AB {{#template inner.md}} \\{{#template escaped.md}}"
+        );
+        assert_eq!(
+            ctx.stats().failures(),
+            ["\"chapter.md\" expands more than 2 templates, raise `max-expansions` if that is intended"]
+        );
+    }
+
+    #[test]
+    fn test_diagrams() {
+        let start_chapter_content = "{{#diagram flow.mmd title=Flow width=50%}}";
+        let map = HashMap::from([(PathBuf::from("flow.mmd"), "graph TD;\n".to_string())]);
+        let file_reader = BuiltinFileReader::new(TestFileReader::from(map));
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(&file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(
+            actual_chapter_content,
+            "<figure style=\"width: 50%\">\n\n```mermaid\ngraph TD;\n```\n\n<figcaption>Flow</figcaption>\n</figure>\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sad_path_symlinked_cycle() {
+        let book = Fixture::new("symlinked-cycle")
+            .file("card.md", "Card {{#template alias.md}}")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        std::os::unix::fs::symlink(dir.join("card.md"), dir.join("alias.md")).unwrap();
+        let file_reader = SystemFileReader::default();
+        let (globals, config) = (HashMap::new(), Config::default());
+        let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+
+        let (actual_chapter_content, diagnostics) = diagnostics::capture(|| {
+            replace_template(
+                "{{#template card.md}} {{#template card.md}}",
+                &dir,
+                "chapter.md",
+                0,
+                &mut Assets::default(),
+                &ctx,
+            )
+        });
+
+        // Reported as soon as the template includes itself, rather than 10 levels deep
+        assert_eq!(actual_chapter_content, "Card  Card ");
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.code == Code::Cycle));
+        assert!(diagnostics[0]
+            .message
+            .ends_with("card.md, which is already being expanded. Check For Cyclic Templates"));
+    }
+
+    #[test]
+    fn test_sad_path_unknown_preset() {
+        let start_chapter_content = "{{#template header.md preset=missing}}";
+        let files = [("header.md", "[[#author]]")];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(actual_chapter_content, start_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_with_lookup() {
+        let start_chapter_content =
+            "{{#template footer.md authors=lookup(../team.toml, docs.leads)}}";
+        let end_chapter_content = "Designed By - Goudham, Hazel";
+        let map = HashMap::from([
+            (
+                PathBuf::from("chapters/footer.md"),
+                "Designed By - [[#authors]]".to_string(),
+            ),
+            (
+                PathBuf::from("chapters/../team.toml"),
+                "[docs]\nleads = [\"Goudham\", \"Hazel\"]".to_string(),
+            ),
+        ]);
+        let file_reader = &TestFileReader::from(map);
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "chapters",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
 
+    #[test]
+    fn test_happy_path_partial_escapes() {
+        let start = r"\{{ opens, {{\#template x.md}} is literal and \\{{#template name.md}} is not";
+        let end = r"{{ opens, {{#template x.md}} is literal and \Hazel is not";
+        let files = [("name.md", "Hazel")];
+
+        assert_eq!(expand(start, &files, &Config::default()), end);
+    }
+
     #[test]
     fn test_happy_path_nested() {
         let start_chapter_content = r"
@@ -263,32 +1744,175 @@ mod lib_tests {
         # Example Title
         <img src='example.png' alt='Example Title'>
         Some content...";
-        let header_file_name = PathBuf::from("header.md");
-        let header_contents = r"# [[#title]]
-        {{#template image.md title=[[#title]]}}"
-            .to_string();
-        let image_file_name = PathBuf::from("image.md");
-        let image_contents = r"<img src='example.png' alt='[[#title]]'>".to_string();
+        let files = [
+            ("image.md", r"<img src='example.png' alt='[[#title]]'>"),
+            (
+                "header.md",
+                r"# [[#title]]
+        {{#template image.md title=[[#title]]}}",
+            ),
+        ];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_nested_in_args() {
+        let start_chapter_content =
+            r"{{#template header.md title={{#template name.md first=Hazel}} subtitle=Docs}}";
+        let end_chapter_content = r"# Hazel Goudham - Docs";
+        let files = [
+            ("header.md", r"# [[#title]] - [[#subtitle]]"),
+            ("name.md", r"[[#first]] Goudham"),
+        ];
+
+        let actual_chapter_content = expand(start_chapter_content, &files, &Config::default());
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_windows_path() {
+        let start_chapter_content = r"{{#template .\templates\footer.md authors=Hazel}}";
+        let end_chapter_content = "Made by Hazel";
+        let map = HashMap::from([(
+            PathBuf::from("chapters/templates/footer.md"),
+            "Made by [[#authors]]".to_string(),
+        )]);
+        let file_reader = &TestFileReader::from(map);
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "chapters",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_inferred_extension() {
+        let start_chapter_content = r"{{#template footer authors=Hazel}} {{#template note}}";
+        let end_chapter_content = "Made by Hazel <em>Note</em>";
+        let map = HashMap::from([
+            (
+                PathBuf::from("footer.md"),
+                "Made by [[#authors]]".to_string(),
+            ),
+            (PathBuf::from("note.html"), "<em>Note</em>".to_string()),
+        ]);
+        let file_reader = &ExtensionFileReader::new(TestFileReader::from(map), &[".md", "html"]);
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_builtin() {
+        let start_chapter_content = r"{{#template builtin:warning text=Mind the gap}}";
+        let end_chapter_content = "> **Warning**\n>\n> Mind the gap\n";
+        let file_reader = &BuiltinFileReader::new(TestFileReader::default());
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "chapters",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_renderer_variant() {
+        let start_chapter_content = "{{#template footer.md}} {{#template header.md}}";
+        let end_chapter_content = "EPUB Footer HTML Header";
         let map = HashMap::from([
-            (image_file_name, image_contents),
-            (header_file_name, header_contents),
+            (PathBuf::from("footer.md"), "HTML Footer".to_string()),
+            (PathBuf::from("footer.epub.md"), "EPUB Footer".to_string()),
+            (PathBuf::from("header.md"), "HTML Header".to_string()),
         ]);
+        let file_reader = &RendererFileReader::new(TestFileReader::from(map), "epub");
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(actual_chapter_content, end_chapter_content);
+    }
+
+    #[test]
+    fn test_sad_path_unknown_builtin() {
+        let start_chapter_content = "{{#template builtin:caution}}";
+        let file_reader = &BuiltinFileReader::new(TestFileReader::default());
+
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut Assets::default(),
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
+
+        assert_eq!(actual_chapter_content, start_chapter_content);
+    }
+
+    #[test]
+    fn test_happy_path_front_matter_assets() {
+        let start_chapter_content = "{{#template tabs.md}} {{#template tabs.md}}";
+        let end_chapter_content = "<div class='tabs'></div> <div class='tabs'></div>";
+        let file_name = PathBuf::from("tabs.md");
+        let template_file_contents =
+            "+++\ncss = ['.tabs { display: flex; }']\n+++\n<div class='tabs'></div>".to_string();
+        let map = HashMap::from([(file_name, template_file_contents)]);
         let file_reader = &TestFileReader::from(map);
+        let mut assets = Assets::default();
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = replace_template(
+            start_chapter_content,
+            "",
+            "",
+            0,
+            &mut assets,
+            &ExpansionContext::new(file_reader, &HashMap::new(), &Config::default()),
+        );
 
         assert_eq!(actual_chapter_content, end_chapter_content);
+        assert_eq!(
+            assets.render(),
+            Some("<style>\n.tabs { display: flex; }\n</style>\n".to_string())
+        );
     }
 
     #[test]
     fn test_sad_path_invalid_file() {
-        env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+        // Other tests may already have initialised the logger
+        let _ = env_logger::try_init_from_env(env_logger::Env::default().default_filter_or("info"));
 
         let start_chapter_content = "{{#template footer.md}}";
 
-        let actual_chapter_content =
-            replace_template(start_chapter_content, &TestFileReader::default(), "", "", 0);
+        let actual_chapter_content = expand(start_chapter_content, &[], &Config::default());
 
         assert_eq!(actual_chapter_content, start_chapter_content);
     }
@@ -302,13 +1926,9 @@ mod lib_tests {
         ]
         .join("\n");
         let end_chapter_content = "This is valid text";
-        let file_name: PathBuf = PathBuf::from("template.md");
-        let template_file_contents = "[[#text]]".to_string();
-        let map = HashMap::from([(file_name, template_file_contents)]);
-        let file_reader = &TestFileReader::from(map);
+        let files = [("template.md", "[[#text]]")];
 
-        let actual_chapter_content =
-            replace_template(&start_chapter_content, file_reader, "", "", 0);
+        let actual_chapter_content = expand(&start_chapter_content, &files, &Config::default());
 
         assert_eq!(actual_chapter_content, end_chapter_content);
     }
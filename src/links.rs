@@ -1,55 +1,73 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context};
 use fancy_regex::{CaptureMatches, Captures, Regex};
 use lazy_static::lazy_static;
 use mdbook::errors::Result;
-
+use serde_json::Value;
+
+use crate::arg_map::ArgMap;
+use crate::blocks;
+use crate::conditions;
+use crate::config::{Config, Directive, OnError};
+use crate::diagnostics::{self, Code};
+use crate::diagrams;
+use crate::directives;
+use crate::fences;
+use crate::filters;
+use crate::front_matter::{self, Assets};
+use crate::globals;
+use crate::lookup;
+#[cfg(feature = "openapi")]
+use crate::openapi;
+use crate::pack::{self, PACKS_DIR};
+use crate::scanner;
+use crate::sections;
+use crate::stats::Source;
+use crate::syntax;
+use crate::utils::{self, normalize_path, BUILTIN_PREFIX, CHAPTER_PREFIX};
 use crate::FileReader;
 
-const ESCAPE_CHAR: char = '\\';
+pub(crate) const ESCAPE_CHAR: char = '\\';
 const LINE_BREAKS: &[char] = &['\n', '\r'];
+pub(crate) const DEFAULT_DIRECTIVE: &str = "template";
+// Appended to a directive keyword to render a node of a data file, e.g. `{{#template-data ...}}`
+pub(crate) const DATA_SUFFIX: &str = "-data";
+pub(crate) const CHANGELOG_DIRECTIVE: &str = "changelog";
+pub(crate) const DIAGRAM_DIRECTIVE: &str = "diagram";
+// Arguments consumed by data and diagram invocations rather than passed on to their template
+const RENDER_OPTIONS: &[&str] = &["pointer", "template", "operation", "integrity", "on-error"];
 
 lazy_static! {
     // https://stackoverflow.com/questions/22871602/optimizing-regex-to-fine-key-value-pairs-space-delimited
-    static ref TEMPLATE_ARGS: Regex = Regex::new(r"(?<=\s|\A)([^\s=]+)=(.*?)(?=(?:\s[^\s=]+=|$))").unwrap();
-
-    // r"(?x)\\\{\{\#.*\}\}|\{\{\s*\#(template)\s+([\S]+)\s*\}\}|\{\{\s*\#(template)\s+([\S]+)\s+([^}]+)\}\}"
-    static ref TEMPLATE: Regex = Regex::new(
-        r"(?x)                              # enable insignificant whitespace mode
-
-        \\\{\{                              # escaped link opening parens
-        \#.*                                # match any character
-        \}\}                                # escaped link closing parens
-
-        |                                   # or
-
-        \{\{\s*                             # link opening parens and whitespace(s)
-        \#(template)                        # link type - template
-        \s+                                 # separating whitespace
-        ([\S]+)                             # relative path to template file
-        \s*                                 # optional separating whitespaces(s)
-        \}\}                                # link closing parens
-
-        |                                   # or
-
-        \{\{\s*                             # link opening parens and whitespace(s)
-        \#(template)                        # link type - template
-        \s+                                 # separating whitespace
-        ([\S]+)                             # relative path to template file
-        \s+                                 # separating whitespace(s)
-        ([^}]+)                             # get all template arguments
-        \}\}                                # link closing parens"
-    )
-    .unwrap();
+    // Values may be hard wrapped over several lines, so `.` matches line breaks too
+    static ref TEMPLATE_ARGS: Regex = Regex::new(r"(?s)(?<=\s|\A)([^\s=]+)=(.*?)(?=(?:\s[^\s=]+=|$))").unwrap();
 
-    // r"(?x)\\\[\[.*\]\]|\[\[\s*\#([\S]+)\s*\]\]|\[\[\s*\#([\S]+)\s+([^]]+)\]\]"
+    // r"(?x)\\\\(?=\\*\[\[)|\\\[\[|(?s:\[\[!--.*?--\]\])|(?s:\[\[\s*\#raw\s*\]\].*?\[\[\s*/raw\s*\]\])|\[\[\s*\\\#|\[\[\s*\#([\S]+)\s*\]\]|\[\[\s*\#([\S]+)\s+([^]]+)\]\]"
     static ref ARGS: Regex = Regex::new(
         r"(?x)                                  # enable insignificant whitespace mode
 
+        \\\\(?=\\*\[\[)                         # escaped backslash before an opening bracket
+
+        |                                       # or
+
         \\\[\[                                  # escaped link opening square brackets
-        \#.*                                    # match any character
-        \]\]                                    # escaped link closing parens
+
+        |                                       # or
+
+        (?s:\[\[!--.*?--\]\])                   # comment, which may span several lines
+
+        |                                       # or
+
+        (?s:\[\[\s*\#raw\s*\]\].*?\[\[\s*/raw\s*\]\])  # raw block, emitted as written
+
+        |                                       # or
+
+        \[\[\s*\\\#                             # link opening square brackets with an escaped hash
 
         |                                       # or
 
@@ -67,6 +85,38 @@ lazy_static! {
         \]\]                                    # link closing parens"
     )
     .unwrap();
+
+    // r"(?x)\\\\(?=\\*\[\[)|\\\[\[|(?s:\[\[!--.*?--\]\])|(?s:\[\[\s*\#raw\s*\]\].*?\[\[\s*/raw\s*\]\])|\[\[\s*\#(if|else-if|unless|match|case)\s+([^]\s]+)\s*\]\]|\[\[\s*\#(else|default)\s*\]\]|\[\[\s*/(if|unless|match)\s*\]\]"
+    static ref CONDITIONAL: Regex = Regex::new(
+        r"(?x)                                  # enable insignificant whitespace mode
+
+        \\\\(?=\\*\[\[)                         # escaped backslash before an opening bracket
+
+        |                                       # or
+
+        \\\[\[                                  # escaped opening brackets
+
+        |                                       # or
+
+        (?s:\[\[!--.*?--\]\])                   # comment, whose markers are ignored
+
+        |                                       # or
+
+        (?s:\[\[\s*\#raw\s*\]\].*?\[\[\s*/raw\s*\]\])  # raw block, whose markers are ignored too
+
+        |                                       # or
+
+        \[\[\s*\#(if|else-if|unless|match|case)\s+([^]\s]+)\s*\]\]  # branch opening with its argument or value
+
+        |                                       # or
+
+        \[\[\s*\#(else|default)\s*\]\]          # branch taken when no other one is
+
+        |                                       # or
+
+        \[\[\s*/(if|unless|match)\s*\]\]        # conditional block closing"
+    )
+    .unwrap();
 }
 
 #[derive(PartialEq, Debug)]
@@ -75,127 +125,1417 @@ pub(crate) struct Link<'a> {
     pub(crate) end_index: usize,
     pub(crate) link_type: LinkType,
     pub(crate) link_text: &'a str,
-    args: HashMap<&'a str, &'a str>,
+    args: ArgMap<'a, Cow<'a, str>>,
+    appended: ArgMap<'a, Vec<Cow<'a, str>>>,
+    section: Option<&'a str>,
 }
 
 impl<'a> Link<'a> {
-    fn from_capture(cap: Captures<'a>) -> Option<Link<'a>> {
-        let mut all_args = HashMap::with_capacity(20);
-
-        // https://regex101.com/r/OBywLv/1
-        let link_type = match (
-            cap.get(0),
-            cap.get(1),
-            cap.get(2),
-            cap.get(3),
-            cap.get(4),
-            cap.get(5),
-        ) {
-            // This looks like {{#template <file>}}
-            (_, _, Some(file), None, None, None) => {
-                Some(LinkType::Template(PathBuf::from(file.as_str())))
-            }
-            // This looks like \{{#<whatever string>}}
-            (Some(mat), _, _, _, _, _) if mat.as_str().starts_with(ESCAPE_CHAR) => {
-                Some(LinkType::Escaped)
-            }
-            (_, None, None, _, Some(file), Some(args)) => {
-                let split_args = match args.as_str().contains(LINE_BREAKS) {
-                    /*
-                    This looks like
-                       {{#template
-                           <file>
-                           <args>
-                       }}
-                    */
-                    true => args
-                        .as_str()
-                        .split(LINE_BREAKS)
-                        .map(|str| str.trim())
-                        .filter(|trimmed| !trimmed.is_empty())
-                        .filter_map(|mat| {
-                            let mut split_n = mat.splitn(2, '=');
-                            if let Some(key) = split_n.next() {
-                                let key = key.trim();
-                                if let Some(value) = split_n.next() {
-                                    return Some((key, value));
-                                }
-                            }
-                            eprintln!(
-                                "Couldn't find a key/value pair while parsing the argument '{}'",
-                                mat
-                            );
-                            None
-                        })
-                        .collect::<Vec<_>>(),
-
-                    // This looks like {{#template <file> <args>}}
-                    false => TEMPLATE_ARGS
-                        .captures_iter(args.as_str())
-                        .filter_map(|mat| {
-                            let captures = mat.ok()?;
-                            let mut split_n = captures.get(0)?.as_str().splitn(2, '=');
-                            if let Some(key) = split_n.next() {
-                                let key = key.trim();
-                                if let Some(value) = split_n.next() {
-                                    return Some((key.trim(), value));
-                                }
-                            }
-                            eprintln!(
-                                "Couldn't parse key or value while parsing '{:?}'",
-                                &args.as_str()
-                            );
-                            None
-                        })
-                        .collect::<Vec<_>>(),
-                };
+    fn from_opening(
+        contents: &'a str,
+        opening: Range<usize>,
+        max_length: usize,
+        max_lines: usize,
+        directives: Option<&HashMap<String, Directive>>,
+        src_dir: &Path,
+    ) -> Option<Link<'a>> {
+        let opening_text = &contents[opening.clone()];
+        // This looks like \\{{, \{{ or {{\#
+        if opening_text.contains(ESCAPE_CHAR) {
+            return Some(Link {
+                start_index: opening.start,
+                end_index: opening.end,
+                link_type: LinkType::Escaped,
+                link_text: opening_text,
+                args: ArgMap::new(),
+                appended: ArgMap::new(),
+                section: None,
+            });
+        }
 
-                all_args.extend(split_args);
-                Some(LinkType::Template(PathBuf::from(file.as_str())))
+        let rest = &contents[opening.end..];
+        let end_index = match find_closing(rest, max_length) {
+            Some(length) if rest[..length].matches('\n').count() < max_lines => {
+                opening.end + length
             }
-            _ => None,
+            // A closing }} that far away most likely belongs to a later invocation
+            Some(_) => {
+                warn_unterminated(contents, opening.start, max_lines);
+                return None;
+            }
+            None if rest.len() > max_length => {
+                diagnostics::error(
+                    Code::UnterminatedInvocation,
+                    format_args!(
+                        "Ignoring \"{}...\", no closing }}}} within the max-invocation-length of {} bytes",
+                        opening_text.trim_end(),
+                        max_length
+                    ),
+                );
+                return None;
+            }
+            None => {
+                warn_unterminated(contents, opening.start, max_lines);
+                return None;
+            }
+        };
+        let inner = contents[opening.end..end_index - 2].trim_start();
+        let (file, rest) = match inner.strip_prefix('"') {
+            // This looks like {{#template "my templates/card.md" title=Demo}}
+            Some(quoted) => quoted.split_once('"')?,
+            None => inner.split_at(inner.find(char::is_whitespace).unwrap_or(inner.len())),
+        };
+        if file.is_empty() {
+            return None;
+        }
+        let args = rest.trim_start();
+
+        let mut all_args = ArgMap::new();
+        let mut all_appended = ArgMap::new();
+        let split_args = match rest
+            .trim_start_matches([' ', '\t'])
+            .starts_with(LINE_BREAKS)
+        {
+            /*
+            This looks like
+               {{#template
+                   <file>
+                   <args>
+               }}
+            */
+            true => split_lines(args),
+
+            // This looks like {{#template <file> <args>}}, possibly hard wrapped within a paragraph
+            false => split_args(args)
+                .into_iter()
+                .map(|(key, value)| match value.contains(LINE_BREAKS) {
+                    true => (
+                        key,
+                        Cow::Owned(value.split_whitespace().collect::<Vec<_>>().join(" ")),
+                    ),
+                    false => (key, Cow::Borrowed(value)),
+                })
+                .collect(),
+        };
+
+        for (key, value) in split_args {
+            match key.strip_suffix('+') {
+                // This looks like key+=value
+                Some(key) => all_appended
+                    .get_or_insert_with(key.trim_end(), Vec::new)
+                    .push(value),
+                None => {
+                    all_args.insert(key, value);
+                }
+            }
+        }
+
+        // This looks like {{#snippet <file>}} for a directive with its own root
+        let keyword = opening_text
+            .trim_start_matches('{')
+            .trim()
+            .trim_start_matches('#');
+        // This looks like {{#template notes.md#troubleshooting}}
+        let (file, section) = match file.split_once('#') {
+            Some((file, section)) => (file, Some(section)),
+            None => (file, None),
+        };
+        let directive = |keyword: &str| directives.and_then(|directives| directives.get(keyword));
+        let link_type = match directive(keyword) {
+            Some(directive) if !file.starts_with(BUILTIN_PREFIX) => {
+                LinkType::Rooted(keyword.to_owned(), directive.root.join(file))
+            }
+            // This looks like {{#changelog ../CHANGELOG.md version=1.2.0}}
+            None if keyword == CHANGELOG_DIRECTIVE => LinkType::Changelog(PathBuf::from(file)),
+            // This looks like {{#diagram flow.mmd title=Flow}}
+            None if keyword == DIAGRAM_DIRECTIVE => LinkType::Diagram(PathBuf::from(file)),
+            // This looks like {{#template-data api.json pointer=/info/version}}
+            None if keyword.ends_with(DATA_SUFFIX) => {
+                let keyword = &keyword[..keyword.len() - DATA_SUFFIX.len()];
+                match directive(keyword) {
+                    Some(directive) => LinkType::Data(directive.root.join(file)),
+                    None => LinkType::Data(PathBuf::from(file)),
+                }
+            }
+            // This looks like {{#template chapter:guide/install.md}}
+            _ => match (file.strip_prefix(CHAPTER_PREFIX), pack_template(file)) {
+                (Some(chapter), _) => LinkType::Chapter(src_dir.join(chapter)),
+                // This looks like {{#template corp:header.md}}
+                (None, Some((name, template))) => LinkType::Pack(
+                    name.to_owned(),
+                    src_dir.join(PACKS_DIR).join(name).join(template),
+                ),
+                (None, None) => LinkType::Template(PathBuf::from(file)),
+            },
         };
 
-        link_type.and_then(|lnk_type| {
-            cap.get(0).map(|mat| Link {
-                start_index: mat.start(),
-                end_index: mat.end(),
-                link_type: lnk_type,
-                link_text: mat.as_str(),
-                args: all_args,
+        Some(Link {
+            start_index: opening.start,
+            end_index,
+            link_type,
+            link_text: &contents[opening.start..end_index],
+            args: all_args,
+            appended: all_appended,
+            section,
+        })
+    }
+
+    /// Reads the template the link refers to, keeping only the section it asks for, if any.
+    pub(crate) fn read_template<FR: FileReader>(
+        &self,
+        target: &Path,
+        file_reader: &FR,
+        config: &Config,
+    ) -> Result<String> {
+        let contents = file_reader.read_to_string(target, self.link_text)?;
+        self.verify(target, &contents, config)?;
+        match self.section {
+            Some(id) => Ok(sections::extract(&contents, id)
+                .with_context(|| format!("No heading named \"{}\" in {}", id, target.display()))?
+                .to_owned()),
+            None => Ok(contents),
+        }
+    }
+
+    /// Values of the arguments not given at the call site, in increasing order of precedence.
+    fn defaults<'c>(
+        &self,
+        target: &Path,
+        config: &'c Config,
+    ) -> Result<Vec<(&'c str, &'c str, Origin)>> {
+        let mut defaults = Vec::new();
+        // Defaults of the directive are overridden by those of the template itself
+        if let LinkType::Rooted(ref keyword, _) = self.link_type {
+            if let Some(directive) = config.directives.get(keyword) {
+                for (key, value) in &directive.defaults {
+                    defaults.push((key.as_str(), value.as_str(), Origin::Directive));
+                }
+            }
+        }
+        // Defaults of the directories the template is in, the nearest taking precedence
+        for directory in config.directories_of(target) {
+            for (key, value) in &directory.defaults {
+                defaults.push((key.as_str(), value.as_str(), Origin::Directory));
+            }
+        }
+        // The book-wide defaults of the template are overridden by any preset
+        if let Some(values) = config.defaults.get(target) {
+            for (key, value) in values {
+                defaults.push((key.as_str(), value.as_str(), Origin::Config));
+            }
+        }
+        // Arguments given at the call site override the values of the preset
+        if let Some(preset) = self.args.get("preset") {
+            let preset = preset.trim();
+            let values = config
+                .presets
+                .get(preset)
+                .with_context(|| format!("Unknown preset {}{}", preset, self.marker("preset")))?;
+            for (key, value) in values {
+                defaults.push((key.as_str(), value.as_str(), Origin::Preset));
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// The value every placeholder of `template`, the contents of the `target` file without its
+    /// front matter, receives at this call site and where it comes from. Values are shown as
+    /// given, without looking up data files or interpolating them.
+    pub(crate) fn effective_args<'t>(
+        &self,
+        target: &Path,
+        template: &'t str,
+        config: &Config,
+    ) -> Result<Vec<(&'t str, Option<String>, Origin)>> {
+        let defaults = self.defaults(target, config)?;
+        let mut effective: Vec<(&str, Option<String>, Origin)> = Vec::new();
+        for Parameter { name, default, .. } in extract_parameters(template) {
+            if effective.iter().any(|(known, _, _)| *known == name) {
+                continue;
+            }
+            let given = self.args.get(name).map(|value| value.to_string());
+            let appended = self.appended.get(name).map(|values| values.join(", "));
+            let value = match (given, appended) {
+                (Some(given), Some(appended)) => {
+                    Some((format!("{}, {}", given, appended), Origin::CallSite))
+                }
+                (Some(value), None) | (None, Some(value)) => Some((value, Origin::CallSite)),
+                (None, None) => defaults
+                    .iter()
+                    .rev()
+                    .find(|(key, _, _)| *key == name)
+                    .map(|(_, value, origin)| (value.to_string(), *origin)),
+            };
+            effective.push(match (value, default) {
+                (Some((value, origin)), _) => (name, Some(value), origin),
+                (None, Some(default)) => (name, Some(default.to_owned()), Origin::Placeholder),
+                (None, None) => (name, None, Origin::Missing),
+            });
+        }
+        Ok(effective)
+    }
+
+    /// Byte range, within the chapter, of the file the link was given, e.g. `footer.md` of
+    /// `{{#template footer.md#credits}}`.
+    pub(crate) fn file_span(&self) -> Option<Range<usize>> {
+        if self.link_type == LinkType::Escaped {
+            return None;
+        }
+        let inner = self
+            .link_text
+            .strip_suffix("}}")?
+            .trim_start_matches('{')
+            .trim_start()
+            .strip_prefix('#')?
+            .trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            .trim_start();
+        let file = inner.split_whitespace().next()?;
+        let file = file.split_once('#').map_or(file, |(file, _)| file);
+        let start = self.start_index + (file.as_ptr() as usize - self.link_text.as_ptr() as usize);
+        Some(start..start + file.len())
+    }
+
+    /// Byte ranges, within the chapter, of the names of the arguments called `name`, whether
+    /// given as `name=` or appended to with `name+=`.
+    pub(crate) fn arg_spans(&self, name: &str) -> Vec<Range<usize>> {
+        self.args
+            .keys()
+            .chain(self.appended.keys())
+            .filter(|key| *key == name)
+            .map(|key| {
+                let start =
+                    self.start_index + (key.as_ptr() as usize - self.link_text.as_ptr() as usize);
+                start..start + key.len()
             })
+            .collect()
+    }
+
+    pub(crate) fn arg_names(&self) -> Vec<String> {
+        self.args
+            .keys()
+            .chain(
+                self.appended
+                    .keys()
+                    .filter(|name| !self.args.contains_key(name)),
+            )
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// Fails with an [`IntegrityError`](crate::utils::IntegrityError) unless the `contents` of
+    /// `target` match the digest given with `integrity=` or configured for the file.
+    fn verify(&self, target: &Path, contents: &str, config: &Config) -> Result<()> {
+        let expected = match self.args.get("integrity") {
+            Some(integrity) => integrity.trim(),
+            None => match config.integrity.get(target) {
+                Some(integrity) => integrity,
+                None => return Ok(()),
+            },
+        };
+        utils::verify_integrity(target, contents.as_bytes(), expected)
+    }
+
+    /// Byte span of the `key=value` pair given for `key`, relative to the start of the invocation.
+    pub(crate) fn arg_span(&self, key: &str) -> Option<Range<usize>> {
+        let (key, value) = match self.args.get_key_value(key) {
+            Some((key, value)) => (*key, value),
+            None => {
+                let (key, values) = self.appended.get_key_value(key)?;
+                (*key, values.last()?)
+            }
+        };
+        // Arguments are slices of the invocation, so their offsets within it give the span
+        let offset =
+            |part: &str| (part.as_ptr() as usize).checked_sub(self.link_text.as_ptr() as usize);
+        let start = offset(key)?;
+        let end = match value {
+            Cow::Borrowed(value) => offset(value)? + value.len(),
+            // Values continued over several lines are no longer slices of the invocation
+            Cow::Owned(_) => start + key.len(),
+        };
+        (end <= self.link_text.len()).then_some(start..end)
+    }
+
+    /// The line of the invocation giving `key`, with the `key=value` pair underlined, so a
+    /// diagnostic can point at the exact argument rather than the whole invocation.
+    pub(crate) fn marker(&self, key: &str) -> String {
+        let span = match self.arg_span(key) {
+            Some(span) => span,
+            // Not given at the call site, e.g. coming from a preset
+            None => return format!("\n    {}", self.link_text),
+        };
+        let text = self.link_text;
+        let line_start = text[..span.start].rfind('\n').map_or(0, |index| index + 1);
+        let line_end = text[span.start..]
+            .find('\n')
+            .map_or(text.len(), |index| span.start + index);
+        let column = text[line_start..span.start].chars().count();
+        let width = text[span.start..span.end.min(line_end)].chars().count();
+        format!(
+            "\n    {}\n    {}{}",
+            text[line_start..line_end].trim_end(),
+            " ".repeat(column),
+            "^".repeat(width.max(1))
+        )
+    }
+
+    /// What happens when this invocation can't be expanded, as given with `on-error=` or the
+    /// book-wide `on-error`.
+    pub(crate) fn on_error(&self, config: &Config) -> OnError {
+        let on_error = match self.args.get("on-error") {
+            Some(on_error) => on_error.trim(),
+            None => return config.on_error,
+        };
+        OnError::parse(on_error).unwrap_or_else(|| {
+            diagnostics::warn(
+                Code::UnknownOnError,
+                format_args!(
+                    "Unknown on-error={}, expected keep, ignore, warn or fail{}",
+                    on_error,
+                    self.marker("on-error")
+                ),
+            );
+            config.on_error
         })
     }
 
-    pub(crate) fn replace_args<P, FR>(&self, base: P, file_reader: &FR) -> Result<String>
+    /// The text inserted between this expansion and the one directly before it.
+    pub(crate) fn separator(&self, config: &Config) -> String {
+        match self.args.get("separator") {
+            Some(separator) => separator.replace("\\n", "\n"),
+            None => config.separator.to_owned(),
+        }
+    }
+
+    /// Whether the expansion is trimmed for being invoked within a line of text, which giving
+    /// `trim=` opts out of.
+    pub(crate) fn trim_inline(&self, contents: &str, config: &Config) -> bool {
+        config.trim_inline
+            && !self.args.contains_key("trim")
+            && blocks::is_inline(contents, self.start_index..self.end_index)
+    }
+
+    /// Includes the changes of the release given with `version`, e.g. `1.2.0` or `Unreleased`.
+    fn replace_changelog<P, FR>(&self, base: P, file_reader: &FR, config: &Config) -> Result<String>
+    where
+        P: AsRef<Path>,
+        FR: FileReader,
+    {
+        let target = self
+            .link_type
+            .target(&base)
+            .expect("Changelogs have a target");
+        let version = self
+            .args
+            .get("version")
+            .map(|version| version.trim())
+            .context("Changelogs need the version to include, e.g. version=1.2.0")?;
+        let contents = file_reader.read_to_string(&target, self.link_text)?;
+        self.verify(&target, &contents, config)?;
+
+        sections::extract_release(&contents, version)
+            .map(str::to_owned)
+            .with_context(|| {
+                format!(
+                    "No release {} in {}{}",
+                    version,
+                    target.display(),
+                    self.marker("version")
+                )
+            })
+    }
+
+    /// Renders the node of the data file at `pointer`, or the whole file, either as a plain value,
+    /// as a JSON code block or through the template given with `template=`, which receives the
+    /// fields of the node as arguments and the node itself as `value`.
+    fn replace_data<P, FR>(
+        &self,
+        base: P,
+        file_reader: &FR,
+        assets: &mut Assets,
+        config: &Config,
+    ) -> Result<String>
+    where
+        P: AsRef<Path>,
+        FR: FileReader,
+    {
+        let target = self
+            .link_type
+            .target(&base)
+            .expect("Data files have a target");
+        let contents = file_reader.read_to_string(&target, self.link_text)?;
+        self.verify(&target, &contents, config)?;
+        let data = lookup::parse(&target, &contents)
+            .with_context(|| format!("Could not parse {}", target.display()))?;
+        // This looks like {{#template-data api.yaml operation=listUsers}}
+        #[cfg(feature = "openapi")]
+        let operation = match self.args.get("operation") {
+            Some(id) => Some(openapi::operation(&data, id.trim()).with_context(|| {
+                format!(
+                    "Could not find the operation {} within {}{}",
+                    id,
+                    target.display(),
+                    self.marker("operation")
+                )
+            })?),
+            None => None,
+        };
+        #[cfg(not(feature = "openapi"))]
+        let operation: Option<Value> = None;
+        let node = match (&operation, self.args.get("pointer")) {
+            (Some(operation), _) => operation,
+            (None, Some(pointer)) => data.pointer(pointer.trim()).with_context(|| {
+                format!(
+                    "Could not find {} within {}{}",
+                    pointer,
+                    target.display(),
+                    self.marker("pointer")
+                )
+            })?,
+            (None, None) => &data,
+        };
+        let value = lookup::to_string(node).unwrap_or_else(|| {
+            format!(
+                "```json\n{}\n```",
+                serde_json::to_string_pretty(node).expect("Parsed data is valid JSON")
+            )
+        });
+
+        let template = match self.args.get("template") {
+            Some(template) => template.trim(),
+            #[cfg(feature = "openapi")]
+            None if operation.is_some() => openapi::OPENAPI_TEMPLATE,
+            None => return Ok(value),
+        };
+
+        let mut all_args = HashMap::new();
+        if let Value::Object(fields) = node {
+            for (key, field) in fields {
+                if let Some(field) = lookup::to_string(field) {
+                    all_args.insert(key.to_owned(), field);
+                }
+            }
+        }
+        all_args.entry("value".to_string()).or_insert(value);
+        self.render(base, file_reader, assets, config, template, all_args)
+    }
+
+    /// Renders the diagram as `[[#diagram]]` through the builtin `diagram` template, or the
+    /// template given with `template=`, which also receives the `title` and `width` arguments.
+    fn replace_diagram<P, FR>(
+        &self,
+        base: P,
+        file_reader: &FR,
+        assets: &mut Assets,
+        sources: &mut Vec<Source>,
+        config: &Config,
+    ) -> Result<String>
+    where
+        P: AsRef<Path>,
+        FR: FileReader,
+    {
+        let target = self
+            .link_type
+            .target(&base)
+            .expect("Diagrams have a target");
+        let source = file_reader.read_to_string(&target, self.link_text)?;
+        self.verify(&target, &source, config)?;
+        let diagram = diagrams::render(&target, &source, &config.diagram_commands, sources)?;
+
+        let template = self
+            .args
+            .get("template")
+            .map_or(diagrams::DIAGRAM_TEMPLATE, |template| template.trim());
+        let all_args = HashMap::from([("diagram".to_string(), diagram)]);
+        self.render(base, file_reader, assets, config, template, all_args)
+    }
+
+    /// Renders `template`, relative to `base` unless it is builtin, with `all_args` alongside
+    /// every argument of the invocation that isn't one of the [`RENDER_OPTIONS`].
+    fn render<P, FR>(
+        &self,
+        base: P,
+        file_reader: &FR,
+        assets: &mut Assets,
+        config: &Config,
+        template: &str,
+        mut all_args: HashMap<String, String>,
+    ) -> Result<String>
+    where
+        P: AsRef<Path>,
+        FR: FileReader,
+    {
+        let template = LinkType::Template(PathBuf::from(template))
+            .target(&base)
+            .expect("Templates have a target");
+        let contents = file_reader.read_to_string(&template, self.link_text)?;
+        if let Some(expected) = config.integrity.get(&template) {
+            utils::verify_integrity(&template, contents.as_bytes(), expected)?;
+        }
+        let (front_matter, contents) = front_matter::split(&contents)?;
+        front_matter.check_version()?;
+        assets.add(&front_matter);
+
+        for (key, value) in &self.args {
+            if !RENDER_OPTIONS.contains(key) {
+                all_args.insert(key.to_string(), value.to_string());
+            }
+        }
+        let all_args = all_args
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        Ok(Args::replace(
+            contents,
+            &all_args,
+            config.preserve_escapes,
+            config.keep_placeholders,
+        ))
+    }
+
+    pub(crate) fn replace_args<P, FR>(
+        &self,
+        base: P,
+        file_reader: &FR,
+        assets: &mut Assets,
+        sources: &mut Vec<Source>,
+        globals: &HashMap<String, String>,
+        config: &Config,
+    ) -> Result<String>
     where
         P: AsRef<Path>,
         FR: FileReader,
     {
         match self.link_type {
-            LinkType::Escaped => Ok((self.link_text[1..]).to_owned()),
-            LinkType::Template(ref pat) => {
-                let target = base.as_ref().join(pat);
-                let contents = file_reader.read_to_string(&target, self.link_text)?;
-                Ok(Args::replace(contents.as_str(), &self.args))
+            LinkType::Escaped => Ok(unescape(self.link_text, config.preserve_escapes)),
+            LinkType::Data(_) => self.replace_data(base, file_reader, assets, config),
+            LinkType::Changelog(_) => self.replace_changelog(base, file_reader, config),
+            LinkType::Diagram(_) => {
+                self.replace_diagram(base, file_reader, assets, sources, config)
+            }
+            LinkType::Template(_)
+            | LinkType::Rooted(..)
+            | LinkType::Chapter(_)
+            | LinkType::Pack(..) => {
+                let target = self
+                    .link_type
+                    .target(&base)
+                    .expect("Templates have a target");
+                let contents = self.read_template(&target, file_reader, config)?;
+                if self.args.get("show-source").map(|value| value.trim()) == Some("true") {
+                    return Ok(show_source(&contents, &target));
+                }
+                let (front_matter, contents) = front_matter::split(&contents)?;
+                front_matter.check_version()?;
+                if let Some(ref required) = front_matter.requires {
+                    if !conditions::is_set(required, globals) {
+                        return Ok(String::new());
+                    }
+                }
+                assets.add(&front_matter);
+
+                let resolve = |value: &str| -> Result<String> {
+                    Ok(lookup::resolve(value, &base, file_reader)?
+                        .unwrap_or_else(|| value.to_owned()))
+                };
+
+                let mut resolved = HashMap::with_capacity(self.args.len());
+                for (key, value, _) in self.defaults(&target, config)? {
+                    resolved.insert(key, value.to_owned());
+                }
+                let invalid = |key: &str| format!("Invalid argument {}{}", key, self.marker(key));
+                for (key, value) in &self.args {
+                    resolved.insert(*key, resolve(value).with_context(|| invalid(key))?);
+                }
+                // Appended values accumulate after any value assigned with `key=value`
+                for (key, values) in &self.appended {
+                    let mut list = resolved.remove(key).into_iter().collect::<Vec<_>>();
+                    for value in values {
+                        list.push(resolve(value).with_context(|| invalid(key))?);
+                    }
+                    resolved.insert(*key, list.join(", "));
+                }
+                // Placeholders named differently to the given argument still receive its value
+                if config.case_insensitive_args || !front_matter.aliases.is_empty() {
+                    for Parameter { name, .. } in extract_parameters(contents) {
+                        if resolved.contains_key(name) {
+                            continue;
+                        }
+                        let value = resolved
+                            .iter()
+                            .find(|(key, _)| {
+                                same_arg(
+                                    key,
+                                    name,
+                                    config.case_insensitive_args,
+                                    &front_matter.aliases,
+                                )
+                            })
+                            .map(|(_, value)| value.to_owned());
+                        if let Some(value) = value {
+                            resolved.insert(name, value);
+                        }
+                    }
+                }
+                resolved.retain(|key, _| match globals::is_reserved(key, &config.disable_builtins) {
+                    true if config.read_only_globals => {
+                        diagnostics::warn(
+                            Code::ReservedName,
+                            format_args!(
+                                "Ignoring argument \"{}\", names within the {} namespaces are reserved{}",
+                                key,
+                                globals::enabled(&config.disable_builtins)
+                                    .collect::<Vec<_>>()
+                                    .join(", "),
+                                self.marker(key)
+                            ),
+                        );
+                        false
+                    }
+                    true => {
+                        diagnostics::warn(
+                            Code::ReservedName,
+                            format_args!(
+                                "Argument \"{}\" shadows a reserved name{}",
+                                key,
+                                self.marker(key)
+                            ),
+                        );
+                        true
+                    }
+                    false => true,
+                });
+                // Values provided by mdbook fill any reserved name the call site didn't shadow
+                for (key, value) in globals {
+                    resolved
+                        .entry(key.as_str())
+                        .or_insert_with(|| value.to_owned());
+                }
+
+                // Branches are chosen before their markers could be mistaken for arguments
+                let contents = &replace_conditionals(contents, &resolved)?;
+                // Required placeholders fail the invocation rather than silently disappearing
+                if let Some(missing) = extract_parameters(contents).into_iter().find(|parameter| {
+                    (parameter.required || config.strict_args && parameter.default.is_none())
+                        && !resolved.contains_key(parameter.name)
+                }) {
+                    return Err(MissingArgument {
+                        name: missing.name.to_owned(),
+                        required: missing.required,
+                    }
+                    .into());
+                }
+                let all_args = resolved
+                    .iter()
+                    .map(|(key, value)| (*key, value.as_str()))
+                    .collect();
+                let replaced = Args::replace(
+                    contents,
+                    &all_args,
+                    config.preserve_escapes,
+                    config.keep_placeholders,
+                );
+                // Labels of a pack can't collide with those of the book or of other packs
+                let replaced = match self.link_type {
+                    LinkType::Pack(ref name, _) => directives::namespace(&replaced, name),
+                    _ => replaced,
+                };
+                front_matter.assertions.check(&replaced)?;
+                match self.args.get("trim").map(|trim| trim.trim()) {
+                    None | Some("none") => Ok(replaced),
+                    Some("start") => Ok(trim_blank_lines(&replaced, true, false).to_owned()),
+                    Some("end") => Ok(trim_blank_lines(&replaced, false, true).to_owned()),
+                    Some("both") => Ok(trim_blank_lines(&replaced, true, true).to_owned()),
+                    Some(trim) => Err(anyhow::anyhow!(
+                        "Unknown trim {}, expected one of: both, start, end, none",
+                        trim
+                    )),
+                }
             }
         }
     }
 }
 
+/// A placeholder without a value, which is either marked required or has no default while
+/// `strict-args` is enabled.
+#[derive(Debug)]
+pub(crate) struct MissingArgument {
+    pub(crate) name: String,
+    pub(crate) required: bool,
+}
+
+impl fmt::Display for MissingArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.required {
+            true => write!(f, "Missing the required argument \"{}\"", self.name),
+            false => write!(
+                f,
+                "Missing the argument \"{}\", which has no default (strict-args)",
+                self.name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MissingArgument {}
+
+/// A marker of a conditional block within a template.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Marker<'a> {
+    If(&'a str),
+    ElseIf(&'a str),
+    Else,
+    EndIf,
+    Unless(&'a str),
+    EndUnless,
+    Match(&'a str),
+    Case(&'a str),
+    Default,
+    EndMatch,
+}
+
+impl Marker<'_> {
+    /// The marker opening the block this one belongs to.
+    fn opening(&self) -> &'static str {
+        match self {
+            Marker::Unless(_) | Marker::EndUnless => "[[#unless ...]]",
+            Marker::Match(_) | Marker::Case(_) | Marker::Default | Marker::EndMatch => {
+                "[[#match ...]]"
+            }
+            _ => "[[#if ...]]",
+        }
+    }
+}
+
+impl fmt::Display for Marker<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Marker::If(name) => write!(f, "[[#if {}]]", name),
+            Marker::ElseIf(name) => write!(f, "[[#else-if {}]]", name),
+            Marker::Else => write!(f, "[[#else]]"),
+            Marker::EndIf => write!(f, "[[/if]]"),
+            Marker::Unless(name) => write!(f, "[[#unless {}]]", name),
+            Marker::EndUnless => write!(f, "[[/unless]]"),
+            Marker::Match(name) => write!(f, "[[#match {}]]", name),
+            Marker::Case(value) => write!(f, "[[#case {}]]", value),
+            Marker::Default => write!(f, "[[#default]]"),
+            Marker::EndMatch => write!(f, "[[/match]]"),
+        }
+    }
+}
+
+/// A template split into its text, `[[#if <name>]] ... [[/if]]`,
+/// `[[#unless <name>]] ... [[/unless]]` and `[[#match <name>]] ... [[/match]]` blocks.
+#[derive(PartialEq, Eq, Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    /// Every `[[#if]]` and `[[#else-if]]` branch with the argument it depends on, followed by the
+    /// `[[#else]]` branch if there is one
+    If(Vec<(Option<&'a str>, Vec<Node<'a>>)>),
+    /// The body kept only when the argument isn't set
+    Unless(&'a str, Vec<Node<'a>>),
+    /// Every `[[#case]]` branch with its value, and the `[[#default]]` branch without one
+    Match(&'a str, Vec<(Option<&'a str>, Vec<Node<'a>>)>),
+}
+
+/// Every conditional marker within `contents`, escaped ones left out. `[[#else-if]]` and
+/// `[[#else]]` are only markers directly within an `[[#if]]` block, and `[[#case]]` and
+/// `[[#default]]` directly within a `[[#match]]` block, elsewhere they are ordinary placeholders,
+/// e.g. `[[#default]]` filled by `default=`.
+fn markers(contents: &str) -> Vec<(Range<usize>, Marker<'_>)> {
+    let mut open = Vec::new();
+    CONDITIONAL
+        .captures_iter(contents)
+        .filter_map(|cap| cap.ok())
+        .filter_map(|cap| {
+            let marker = match (cap.get(1), cap.get(2), cap.get(3), cap.get(4)) {
+                (Some(keyword), Some(name), _, _) => match keyword.as_str() {
+                    "if" => Marker::If(name.as_str()),
+                    "else-if" => Marker::ElseIf(name.as_str()),
+                    "unless" => Marker::Unless(name.as_str()),
+                    "match" => Marker::Match(name.as_str()),
+                    _ => Marker::Case(name.as_str()),
+                },
+                (_, _, Some(keyword), _) => match keyword.as_str() {
+                    "else" => Marker::Else,
+                    _ => Marker::Default,
+                },
+                (_, _, _, Some(keyword)) => match keyword.as_str() {
+                    "if" => Marker::EndIf,
+                    "unless" => Marker::EndUnless,
+                    _ => Marker::EndMatch,
+                },
+                // Escapes, comments and raw blocks are left for the arguments to replace
+                _ => return None,
+            };
+            cap.get(0).map(|mat| (mat.range(), marker))
+        })
+        .filter(|(_, marker)| match marker {
+            Marker::If(_) | Marker::Unless(_) | Marker::Match(_) => {
+                open.push(*marker);
+                true
+            }
+            Marker::ElseIf(_) | Marker::Else => matches!(open.last(), Some(Marker::If(_))),
+            Marker::EndIf | Marker::EndUnless | Marker::EndMatch => {
+                if open
+                    .last()
+                    .is_some_and(|opening| opening.opening() == marker.opening())
+                {
+                    open.pop();
+                }
+                true
+            }
+            Marker::Case(_) | Marker::Default => matches!(open.last(), Some(Marker::Match(_))),
+        })
+        .collect()
+}
+
+/// Parses `contents` into its text and conditional blocks, failing on markers out of place.
+fn parse_conditionals(contents: &str) -> Result<Vec<Node<'_>>> {
+    let markers = markers(contents);
+    let mut next = 0;
+    let mut offset = 0;
+    match parse_nodes(contents, &markers, &mut next, &mut offset)? {
+        (nodes, None) => Ok(nodes),
+        (_, Some(marker)) => Err(misplaced(contents, markers[next - 1].0.start, marker)),
+    }
+}
+
+fn misplaced(contents: &str, start: usize, marker: Marker) -> anyhow::Error {
+    let (line, column) = location(contents, start);
+    anyhow::anyhow!(
+        "{} at line {}, column {} doesn't belong to any {}",
+        marker,
+        line,
+        column,
+        marker.opening()
+    )
+}
+
+/// Parses the nodes up until the end of `contents`, or the marker ending the branch they are
+/// within, which is returned alongside them.
+fn parse_nodes<'a>(
+    contents: &'a str,
+    markers: &[(Range<usize>, Marker<'a>)],
+    next: &mut usize,
+    offset: &mut usize,
+) -> Result<(Vec<Node<'a>>, Option<Marker<'a>>)> {
+    let mut nodes = Vec::new();
+    while let Some((range, marker)) = markers.get(*next).cloned() {
+        *next += 1;
+        if range.start > *offset {
+            nodes.push(Node::Text(&contents[*offset..range.start]));
+        }
+        *offset = range.end;
+        let name = match marker {
+            Marker::If(name) => name,
+            Marker::Unless(name) => {
+                match parse_nodes(contents, markers, next, offset)? {
+                    (body, Some(Marker::EndUnless)) => nodes.push(Node::Unless(name, body)),
+                    (_, Some(marker)) => {
+                        return Err(misplaced(contents, markers[*next - 1].0.start, marker))
+                    }
+                    (_, None) => {
+                        let (line, column) = location(contents, range.start);
+                        bail!(
+                            "[[#unless {}]] at line {}, column {} is missing its [[/unless]]",
+                            name,
+                            line,
+                            column
+                        )
+                    }
+                }
+                continue;
+            }
+            Marker::Match(name) => {
+                nodes.push(parse_match(
+                    contents,
+                    markers,
+                    next,
+                    offset,
+                    name,
+                    range.start,
+                )?);
+                continue;
+            }
+            _ => return Ok((nodes, Some(marker))),
+        };
+
+        let mut branches = Vec::new();
+        let mut condition = Some(name);
+        loop {
+            let (body, end) = parse_nodes(contents, markers, next, offset)?;
+            branches.push((condition, body));
+            condition = match (end, condition) {
+                (Some(Marker::EndIf), _) => break,
+                (Some(Marker::ElseIf(name)), Some(_)) => Some(name),
+                (Some(Marker::Else), Some(_)) => None,
+                (Some(marker @ (Marker::ElseIf(_) | Marker::Else)), None) => {
+                    let (line, column) = location(contents, markers[*next - 1].0.start);
+                    bail!(
+                        "{} at line {}, column {} comes after the [[#else]] of [[#if {}]]",
+                        marker,
+                        line,
+                        column,
+                        name
+                    )
+                }
+                (Some(marker), _) => {
+                    return Err(misplaced(contents, markers[*next - 1].0.start, marker))
+                }
+                (None, _) => {
+                    let (line, column) = location(contents, range.start);
+                    bail!(
+                        "[[#if {}]] at line {}, column {} is missing its [[/if]]",
+                        name,
+                        line,
+                        column
+                    )
+                }
+            };
+        }
+        nodes.push(Node::If(branches));
+    }
+    if *offset < contents.len() {
+        nodes.push(Node::Text(&contents[*offset..]));
+        *offset = contents.len();
+    }
+    Ok((nodes, None))
+}
+
+/// Parses the cases of the `[[#match <name>]]` starting at `start`, ignoring anything before the
+/// first of them.
+fn parse_match<'a>(
+    contents: &'a str,
+    markers: &[(Range<usize>, Marker<'a>)],
+    next: &mut usize,
+    offset: &mut usize,
+    name: &'a str,
+    start: usize,
+) -> Result<Node<'a>> {
+    let mut branches = Vec::new();
+    let mut label: Option<Option<&str>> = None;
+    loop {
+        let (body, end) = parse_nodes(contents, markers, next, offset)?;
+        if let Some(label) = label {
+            branches.push((label, body));
+        }
+        label = match (end, label) {
+            (Some(Marker::EndMatch), _) => return Ok(Node::Match(name, branches)),
+            (Some(marker @ (Marker::Case(_) | Marker::Default)), Some(None)) => {
+                let (line, column) = location(contents, markers[*next - 1].0.start);
+                bail!(
+                    "{} at line {}, column {} comes after the [[#default]] of [[#match {}]]",
+                    marker,
+                    line,
+                    column,
+                    name
+                )
+            }
+            (Some(Marker::Case(value)), _) => Some(Some(value)),
+            (Some(Marker::Default), _) => Some(None),
+            (Some(marker), _) => {
+                return Err(misplaced(contents, markers[*next - 1].0.start, marker))
+            }
+            (None, _) => {
+                let (line, column) = location(contents, start);
+                bail!(
+                    "[[#match {}]] at line {}, column {} is missing its [[/match]]",
+                    name,
+                    line,
+                    column
+                )
+            }
+        };
+    }
+}
+
+/// Keeps the first branch of every conditional block whose argument is set, or its `[[#else]]`
+/// branch, the body of every `[[#unless]]` block whose argument isn't, and the case of every
+/// `[[#match]]` block equal to its argument or its `[[#default]]`, removing the others.
+fn replace_conditionals<'a>(
+    contents: &'a str,
+    args: &HashMap<&str, String>,
+) -> Result<Cow<'a, str>> {
+    if markers(contents).is_empty() {
+        return Ok(Cow::Borrowed(contents));
+    }
+    let mut replaced = String::with_capacity(contents.len());
+    render_nodes(&parse_conditionals(contents)?, args, &mut replaced);
+    Ok(Cow::Owned(replaced))
+}
+
+/// Keeps the case of every `[[#match <name>]]` block written directly within a chapter equal to
+/// the book-wide variable, or its `[[#default]]`, just like within templates. Blocks that can't be
+/// parsed are left as they are.
+pub(crate) fn replace_matches(contents: &str, variables: &HashMap<String, String>) -> String {
+    let markers = markers(contents);
+    if !markers
+        .iter()
+        .any(|(_, marker)| matches!(marker, Marker::Match(_)))
+    {
+        return contents.to_owned();
+    }
+    let variables = variables
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.to_owned()))
+        .collect();
+
+    // Must keep track of indices as they will not correspond after string substitution
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(contents.len());
+    let mut depth = 0;
+    let mut start = 0;
+    for (range, marker) in &markers {
+        match marker {
+            Marker::Match(_) => {
+                if depth == 0 {
+                    start = range.start;
+                }
+                depth += 1;
+            }
+            Marker::EndMatch if depth > 0 => depth -= 1,
+            _ => continue,
+        }
+        if depth > 0 {
+            continue;
+        }
+        replaced.push_str(&contents[previous_end_index..start]);
+        replaced.push_str(&render_match(&contents[start..range.end], &variables));
+        previous_end_index = range.end;
+    }
+    if depth > 0 {
+        replaced.push_str(&contents[previous_end_index..start]);
+        replaced.push_str(&render_match(&contents[start..], &variables));
+        previous_end_index = contents.len();
+    }
+
+    replaced.push_str(&contents[previous_end_index..]);
+    replaced
+}
+
+fn render_match<'a>(block: &'a str, variables: &HashMap<&str, String>) -> Cow<'a, str> {
+    match replace_conditionals(block, variables) {
+        Ok(rendered) => rendered,
+        Err(err) => {
+            diagnostics::warn(
+                Code::MalformedMatch,
+                format_args!("Leaving a [[#match ...]] block as it is, {}", err),
+            );
+            Cow::Borrowed(block)
+        }
+    }
+}
+
+fn render_nodes(nodes: &[Node], args: &HashMap<&str, String>, replaced: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => replaced.push_str(text),
+            Node::If(branches) => {
+                let taken = branches.iter().find(|(condition, _)| {
+                    condition.is_none_or(|name| conditions::is_set(name, args))
+                });
+                if let Some((_, body)) = taken {
+                    let mut branch = String::new();
+                    render_nodes(body, args, &mut branch);
+                    replaced.push_str(branch.trim_matches(['\n', '\r']));
+                }
+            }
+            Node::Unless(name, body) if !conditions::is_set(name, args) => {
+                let mut branch = String::new();
+                render_nodes(body, args, &mut branch);
+                replaced.push_str(branch.trim_matches(['\n', '\r']));
+            }
+            Node::Unless(..) => {}
+            Node::Match(name, branches) => {
+                let value = args.get(name).map(String::as_str);
+                let taken = branches
+                    .iter()
+                    .find(|(case, _)| case.is_some() && *case == value)
+                    .or_else(|| branches.iter().find(|(case, _)| case.is_none()));
+                if let Some((_, body)) = taken {
+                    let mut branch = String::new();
+                    render_nodes(body, args, &mut branch);
+                    replaced.push_str(branch.trim_matches(['\n', '\r']));
+                }
+            }
+        }
+    }
+}
+
+/// Warns about the invocation starting at `start`, which is missing its closing `}}`.
+fn warn_unterminated(contents: &str, start: usize, max_lines: usize) {
+    let (line, column) = location(contents, start);
+    let opening = contents[start..].lines().next().unwrap_or_default();
+    diagnostics::warn(
+        Code::UnterminatedInvocation,
+        format_args!(
+            "Ignoring \"{}\" at line {}, column {}, no closing }}}} within {} lines",
+            opening.trim_end(),
+            line,
+            column,
+            max_lines
+        ),
+    );
+}
+
+/// The 1-based line and column of the byte `index` within `contents`.
+pub(crate) fn location(contents: &str, index: usize) -> (usize, usize) {
+    let before = &contents[..index];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Removes the backslash escaping a delimiter, e.g. `\{{` becomes `{{` and `\\` becomes `\`,
+/// unless escapes are preserved for a downstream tool to unescape.
+pub(crate) fn unescape(escaped: &str, preserve_escapes: bool) -> String {
+    match preserve_escapes {
+        true => escaped.to_owned(),
+        false => escaped.replacen(ESCAPE_CHAR, "", 1),
+    }
+}
+
+/// Splits the arguments of an invocation spanning several lines, one `key=value` per line. A
+/// trailing backslash continues the value on the next line, while a value starting with a quote
+/// runs up until the closing quote, line breaks included.
+fn split_lines(args: &str) -> Vec<(&str, Cow<'_, str>)> {
+    let mut pairs = Vec::new();
+    let mut rest = args;
+
+    while !rest.is_empty() {
+        let line = take_line(&mut rest).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value),
+            None => {
+                diagnostics::warn(
+                    Code::MalformedArguments,
+                    format_args!(
+                        "Couldn't find a key/value pair while parsing the argument '{}'",
+                        line
+                    ),
+                );
+                continue;
+            }
+        };
+
+        // This looks like key="<line>
+        //                      <line>"
+        if let Some(quoted) = value.strip_prefix('"') {
+            let start = quoted.as_ptr() as usize - args.as_ptr() as usize;
+            if let Some(length) = args[start..].find('"') {
+                pairs.push((key, Cow::Borrowed(&args[start..start + length])));
+                rest = &args[start + length + 1..];
+                let trailing = take_line(&mut rest).trim();
+                if !trailing.is_empty() {
+                    diagnostics::warn(
+                        Code::MalformedArguments,
+                        format_args!(
+                            "Ignoring '{}' after the closing quote of the argument '{}'",
+                            trailing, key
+                        ),
+                    );
+                }
+                continue;
+            }
+        }
+
+        // This looks like key=<text> \
+        //                     <text>
+        match value.ends_with('\\') {
+            true => {
+                let mut joined = value.to_owned();
+                while joined.ends_with('\\') && !rest.is_empty() {
+                    joined.pop();
+                    joined.push_str(take_line(&mut rest).trim());
+                }
+                pairs.push((key, Cow::Owned(joined)));
+            }
+            false => pairs.push((key, Cow::Borrowed(value))),
+        }
+    }
+    pairs
+}
+
+/// Returns the line at the start of `rest`, advancing it past the line break.
+fn take_line<'a>(rest: &mut &'a str) -> &'a str {
+    let (line, next) = match rest.find(LINE_BREAKS) {
+        Some(index) if rest[index..].starts_with("\r\n") => (&rest[..index], &rest[index + 2..]),
+        Some(index) => (&rest[..index], &rest[index + 1..]),
+        None => (*rest, ""),
+    };
+    *rest = next;
+    line
+}
+
+/// Escapes every `opening` delimiter within `contents` for which `delimiter_length` gives the
+/// length of what it opens, doubling the backslashes already before it, so the delimiter ends up
+/// in the output verbatim. Delimiters within an escaped one are left alone, as they are never
+/// looked at.
+pub(crate) fn escape<F>(contents: &str, opening: &str, delimiter_length: F) -> String
+where
+    F: Fn(&str) -> Option<usize>,
+{
+    let mut previous_end_index = 0;
+    let mut delimiter_end_index = 0;
+    let mut escaped = String::with_capacity(contents.len());
+
+    for (index, _) in contents.match_indices(opening) {
+        let length = match delimiter_length(&contents[index..]) {
+            Some(length) if index >= delimiter_end_index => length,
+            _ => continue,
+        };
+        delimiter_end_index = index + length;
+        let gap = &contents[previous_end_index..index];
+        let backslashes = gap.len() - gap.trim_end_matches(ESCAPE_CHAR).len();
+        escaped.push_str(gap);
+        escaped.extend(std::iter::repeat_n(ESCAPE_CHAR, backslashes + 1));
+        escaped.push_str(opening);
+        previous_end_index = index + opening.len();
+    }
+
+    escaped.push_str(&contents[previous_end_index..]);
+    escaped
+}
+
+/// Shows the source of the template `target` within a fenced code block, with every delimiter
+/// escaped so that nothing within it is expanded.
+fn show_source(contents: &str, target: &Path) -> String {
+    let escaped = syntax::escape(contents);
+
+    // The fence must be longer than any run of backticks within the template
+    let mut longest = 0;
+    let mut run = 0;
+    for c in escaped.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    let lang = target
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    format!("{}{}\n{}\n{}", fence, lang, escaped.trim_end(), fence)
+}
+
+/// Returns true if `given` and `placeholder` name the same argument, either because they only
+/// differ in case or because they belong to the same group of `aliases`.
+fn same_arg(
+    given: &str,
+    placeholder: &str,
+    case_insensitive: bool,
+    aliases: &[Vec<String>],
+) -> bool {
+    let eq = |a: &str, b: &str| match case_insensitive {
+        true => a.eq_ignore_ascii_case(b),
+        false => a == b,
+    };
+    eq(given, placeholder)
+        || aliases.iter().any(|group| {
+            group.iter().any(|alias| eq(alias, given))
+                && group.iter().any(|alias| eq(alias, placeholder))
+        })
+}
+
+/// Strips the blank lines at the `start` and/or trailing whitespace at the `end` of an expansion.
+fn trim_blank_lines(contents: &str, start: bool, end: bool) -> &str {
+    let mut trimmed = contents;
+    if start {
+        let first_line = trimmed
+            .split_inclusive('\n')
+            .take_while(|line| line.trim().is_empty())
+            .map(str::len)
+            .sum::<usize>();
+        trimmed = &trimmed[first_line..];
+    }
+    if end {
+        trimmed = trimmed.trim_end();
+    }
+    trimmed
+}
+
+/// Where the value an argument receives comes from.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum Origin {
+    Directive,
+    Directory,
+    Config,
+    Preset,
+    CallSite,
+    /// The default of the placeholder itself, e.g. `[[#year 2022]]`
+    Placeholder,
+    /// Nowhere, the placeholder is left empty
+    Missing,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = match self {
+            Origin::Directive => "defaults of the directive",
+            Origin::Directory => "defaults of a .template.toml",
+            Origin::Config => "defaults of the configuration",
+            Origin::Preset => "preset",
+            Origin::CallSite => "call site",
+            Origin::Placeholder => "default of the placeholder",
+            Origin::Missing => "not given",
+        };
+        write!(f, "{}", origin)
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub(crate) enum LinkType {
     Escaped,
     Template(PathBuf),
+    /// A template of a configured directive, already joined onto the root of that directive
+    Rooted(String, PathBuf),
+    /// The source of another chapter, already joined onto the book source directory
+    Chapter(PathBuf),
+    /// A template of an installed pack, already joined onto the directory of that pack
+    Pack(String, PathBuf),
+    /// A JSON, TOML or YAML file of which a single node is rendered
+    Data(PathBuf),
+    /// A Keep a Changelog style file of which a single version is included
+    Changelog(PathBuf),
+    /// A Mermaid or PlantUML diagram
+    Diagram(PathBuf),
 }
 
 impl LinkType {
+    /// The template file this link refers to, builtin templates are never relative to `base`.
+    pub(crate) fn target<P: AsRef<Path>>(&self, base: P) -> Option<PathBuf> {
+        match self {
+            LinkType::Escaped => None,
+            LinkType::Template(pat) => match pat.to_string_lossy().starts_with(BUILTIN_PREFIX) {
+                true => Some(pat.to_path_buf()),
+                false => Some(normalize_path(base.as_ref().join(pat))),
+            },
+            LinkType::Rooted(_, path) | LinkType::Chapter(path) | LinkType::Pack(_, path) => {
+                Some(normalize_path(path))
+            }
+            LinkType::Data(path) | LinkType::Changelog(path) | LinkType::Diagram(path) => {
+                Some(normalize_path(base.as_ref().join(path)))
+            }
+        }
+    }
+
     pub(crate) fn relative_path<P: AsRef<Path>>(self, base: P) -> Option<PathBuf> {
         match self {
             LinkType::Escaped => None,
-            LinkType::Template(path) => Some(
+            LinkType::Template(path)
+            | LinkType::Rooted(_, path)
+            | LinkType::Chapter(path)
+            | LinkType::Pack(_, path)
+            | LinkType::Data(path)
+            | LinkType::Changelog(path)
+            | LinkType::Diagram(path) => Some(
                 base.as_ref()
-                    .join(path)
+                    .join(normalize_path(path))
                     .parent()
                     .expect("Included file should not be /")
                     .to_path_buf(),
@@ -204,23 +1544,175 @@ impl LinkType {
     }
 }
 
-pub(crate) struct LinkIter<'a>(CaptureMatches<'a, 'a>);
+/// Splits `file` into the name of a pack and the path of a template within it, e.g.
+/// `corp:header.md`. Builtin templates and Windows drive letters aren't packs.
+fn pack_template(file: &str) -> Option<(&str, &str)> {
+    match file.split_once(':') {
+        Some((name, template))
+            if name.len() > 1
+                && BUILTIN_PREFIX.strip_suffix(':') != Some(name)
+                && pack::is_valid_name(name)
+                && !template.is_empty() =>
+        {
+            Some((name, template))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the index just past the `}}` closing an invocation whose opening `{{` precedes
+/// `contents`, skipping over any `{{ }}` pairs nested within its arguments. The scan gives up
+/// after `max_length` bytes.
+fn find_closing(contents: &str, max_length: usize) -> Option<usize> {
+    let bytes = contents.as_bytes();
+    let end = bytes.len().min(max_length);
+    let mut depth = 1;
+    let mut index = 0;
+
+    while index + 1 < end {
+        match &bytes[index..index + 2] {
+            b"{{" => depth += 1,
+            b"}}" => depth -= 1,
+            _ => {
+                index += 1;
+                continue;
+            }
+        }
+        index += 2;
+        if depth == 0 {
+            return Some(index);
+        }
+    }
+    None
+}
+
+/// Splits single line arguments into key/value pairs. Nested invocations are masked beforehand
+/// so that whitespace or `key=value` pairs within them do not split the outer value.
+fn split_args(args: &str) -> Vec<(&str, &str)> {
+    let mut masked = args.as_bytes().to_vec();
+    let mut position = 0;
+    while let Some(start) = args[position..].find("{{").map(|start| position + start) {
+        let end =
+            find_closing(&args[start + 2..], usize::MAX).map_or(args.len(), |end| start + 2 + end);
+        masked[start..end].fill(b'_');
+        position = end;
+    }
+    let masked = String::from_utf8(masked).expect("Masking only replaces whole characters");
+
+    TEMPLATE_ARGS
+        .captures_iter(&masked)
+        .filter_map(|mat| {
+            let mat = mat.ok()?.get(0)?;
+            let pair = &args[mat.start()..mat.end()];
+            let mut split_n = pair.splitn(2, '=');
+            if let Some(key) = split_n.next() {
+                if let Some(value) = split_n.next() {
+                    return Some((key.trim(), value));
+                }
+            }
+            diagnostics::warn(
+                Code::MalformedArguments,
+                format_args!("Couldn't parse key or value while parsing '{:?}'", args),
+            );
+            None
+        })
+        .collect()
+}
+
+pub(crate) struct LinkIter<'a> {
+    contents: &'a str,
+    position: usize,
+    max_length: usize,
+    max_lines: usize,
+    keywords: &'a [&'a str],
+    directives: Option<&'a HashMap<String, Directive>>,
+    src_dir: &'a Path,
+}
+
+impl<'a> LinkIter<'a> {
+    /// Limits how many bytes an invocation may span before it is ignored.
+    pub(crate) fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Limits how many lines an invocation may span before it is considered unterminated.
+    pub(crate) fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines;
+        self
+    }
+
+    /// Matches invocations of these directive keywords instead of `{{#template ...}}`.
+    pub(crate) fn keywords(mut self, keywords: &'a [&'a str]) -> Self {
+        self.keywords = keywords;
+        self
+    }
+
+    /// Resolves the templates of these directives within their root instead of the chapter.
+    pub(crate) fn directives(mut self, directives: &'a HashMap<String, Directive>) -> Self {
+        self.directives = Some(directives);
+        self
+    }
+
+    /// Resolves `chapter:` templates relative to the book source directory.
+    pub(crate) fn src_dir(mut self, src_dir: &'a Path) -> Self {
+        self.src_dir = src_dir;
+        self
+    }
+}
 
 impl<'a> Iterator for LinkIter<'a> {
     type Item = Link<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for cap in &mut self.0 {
-            if let Some(inc) = Link::from_capture(cap.ok()?) {
-                return Some(inc);
+        loop {
+            let opening = scanner::find(self.contents, self.position, self.keywords)?;
+            match Link::from_opening(
+                self.contents,
+                opening.clone(),
+                self.max_length,
+                self.max_lines,
+                self.directives,
+                self.src_dir,
+            ) {
+                Some(link) => {
+                    self.position = link.end_index;
+                    return Some(link);
+                }
+                None => self.position = opening.end,
             }
         }
-        None
     }
 }
 
 pub(crate) fn extract_template_links(contents: &str) -> LinkIter<'_> {
-    LinkIter(TEMPLATE.captures_iter(contents))
+    LinkIter {
+        contents,
+        position: 0,
+        max_length: usize::MAX,
+        max_lines: usize::MAX,
+        keywords: &[DEFAULT_DIRECTIVE],
+        directives: None,
+        src_dir: Path::new(""),
+    }
+}
+
+/// Fails unless every directive keyword, e.g. `component` for `{{#component ...}}`, only consists
+/// of letters, digits, '-' and '_'.
+pub(crate) fn validate_keywords(keywords: &[&str]) -> Result<()> {
+    for keyword in keywords {
+        if keyword.is_empty()
+            || !keyword
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "Invalid directive \"{}\", only letters, digits, '-' and '_' are allowed",
+                keyword
+            );
+        }
+    }
+    Ok(())
 }
 
 #[derive(PartialEq, Debug)]
@@ -229,78 +1721,239 @@ struct Args<'a> {
     end_index: usize,
     args_type: ArgsType<'a>,
     args_text: &'a str,
+    filters: Vec<&'a str>,
+}
+
+/// Replaces the `[[#name]]` placeholders written directly within a chapter with the book-wide
+/// `globals`, outside of the fenced code blocks in `skip-langs`. Unknown names without a default
+/// are left as is.
+pub(crate) fn interpolate(
+    contents: &str,
+    globals: &HashMap<String, String>,
+    config: &Config,
+) -> String {
+    let skipped = fences::skipped(contents, &config.skip_langs);
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(contents.len());
+
+    for captured_arg in extract_args(contents) {
+        if skipped
+            .iter()
+            .any(|range| range.contains(&captured_arg.start_index))
+        {
+            continue;
+        }
+        replaced.push_str(&contents[previous_end_index..captured_arg.start_index]);
+
+        match captured_arg.args_type {
+            // Escaped directives are unescaped once the whole chapter is expanded
+            ArgsType::Escaped
+                if directives::starts_with_directive(&contents[captured_arg.start_index..]) =>
+            {
+                replaced.push_str(captured_arg.args_text)
+            }
+            ArgsType::Escaped => {
+                replaced.push_str(&unescape(captured_arg.args_text, config.preserve_escapes))
+            }
+            // Comments and raw blocks are only processed within templates
+            ArgsType::Directive | ArgsType::Comment | ArgsType::Raw(_) => {
+                replaced.push_str(captured_arg.args_text)
+            }
+            ArgsType::Plain(name) | ArgsType::Required(name) => match globals.get(name) {
+                Some(value) => replaced.push_str(&filters::apply(value, &captured_arg.filters)),
+                None => {
+                    diagnostics::warn(
+                        Code::UnknownVariable,
+                        format_args!("No variable named \"{}\" to interpolate", name),
+                    );
+                    replaced.push_str(captured_arg.args_text);
+                }
+            },
+            ArgsType::Default(name, default_value) => {
+                let value = globals.get(name).map_or(default_value, String::as_str);
+                replaced.push_str(&filters::apply(value, &captured_arg.filters))
+            }
+        }
+
+        previous_end_index = captured_arg.end_index;
+    }
+
+    replaced.push_str(&contents[previous_end_index..]);
+    replaced
 }
 
 impl<'a> Args<'a> {
-    fn replace(contents: &str, all_args: &HashMap<&str, &str>) -> String {
+    fn replace(
+        contents: &str,
+        all_args: &HashMap<&str, &str>,
+        preserve_escapes: bool,
+        keep_placeholders: bool,
+    ) -> String {
         // Must keep track of indices as they will not correspond after string substitution
         let mut previous_end_index = 0;
         let mut replaced = String::with_capacity(contents.len());
 
         for captured_arg in extract_args(contents) {
             replaced.push_str(&contents[previous_end_index..captured_arg.start_index]);
+            previous_end_index = captured_arg.end_index;
 
             match captured_arg.args_type {
-                ArgsType::Escaped => replaced.push_str(&captured_arg.args_text[1..]),
-                ArgsType::Plain(argument) => match all_args.get(argument) {
-                    None => {}
-                    Some(value) => replaced.push_str(value),
-                },
-                ArgsType::Default(argument, default_value) => match all_args.get(argument) {
-                    None => replaced.push_str(default_value),
-                    Some(value) => replaced.push_str(value),
-                },
+                // Escaped directives are unescaped once the whole chapter is expanded
+                ArgsType::Escaped
+                    if directives::starts_with_directive(&contents[captured_arg.start_index..]) =>
+                {
+                    replaced.push_str(captured_arg.args_text)
+                }
+                ArgsType::Escaped => {
+                    replaced.push_str(&unescape(captured_arg.args_text, preserve_escapes))
+                }
+                ArgsType::Directive => replaced.push_str(captured_arg.args_text),
+                // Directives stay escaped until the whole chapter is expanded
+                ArgsType::Raw(raw) => {
+                    replaced.push_str(&escape(raw, "[[", directives::directive_length))
+                }
+                ArgsType::Plain(argument) | ArgsType::Required(argument) => {
+                    match all_args.get(argument) {
+                        Some(value) => {
+                            replaced.push_str(&filters::apply(value, &captured_arg.filters))
+                        }
+                        // Left for another tool to fill later on
+                        None if keep_placeholders => replaced.push_str(captured_arg.args_text),
+                        None => {}
+                    }
+                }
+                ArgsType::Default(argument, default_value) => {
+                    let value = all_args.get(argument).unwrap_or(&default_value);
+                    replaced.push_str(&filters::apply(value, &captured_arg.filters))
+                }
+                // A comment alone on its line takes the whole line with it
+                ArgsType::Comment => {
+                    let line_start = replaced.rfind('\n').map_or(0, |newline| newline + 1);
+                    let rest = &contents[captured_arg.end_index..];
+                    let line_end = rest.find('\n').map_or(rest.len(), |newline| newline + 1);
+                    if replaced[line_start..].trim().is_empty()
+                        && rest[..line_end].trim().is_empty()
+                    {
+                        replaced.truncate(line_start);
+                        previous_end_index += line_end;
+                    }
+                }
             }
-
-            previous_end_index = captured_arg.end_index;
         }
 
         replaced.push_str(&contents[previous_end_index..]);
         replaced
     }
 
-    fn from_capture(cap: Captures<'a>) -> Option<Args<'a>> {
+    fn from_capture(cap: Captures<'a>, markers: &[usize]) -> Option<Args<'a>> {
         // https://regex101.com/r/lKSOOl/4
-        let arg_type = match (cap.get(0), cap.get(1), cap.get(2), cap.get(3)) {
+        let mut arg_type = match (cap.get(0), cap.get(1), cap.get(2), cap.get(3)) {
+            // This looks like [[!-- note to maintainers --]]
+            (Some(mat), _, _, _) if mat.as_str().starts_with("[[!--") => Some(ArgsType::Comment),
+            // This looks like [[#raw]] [[#title]] [[/raw]]
+            (Some(mat), None, None, None) if raw_contents(mat.as_str()).is_some() => {
+                raw_contents(mat.as_str()).map(ArgsType::Raw)
+            }
+            // This looks like [[#if title]]
+            (Some(mat), _, _, _) if markers.contains(&mat.start()) => Some(ArgsType::Directive),
             // This looks like [[#path]]
-            (_, Some(argument), None, None) => Some(ArgsType::Plain(argument.as_str())),
+            // This looks like [[#title!]]
+            (_, Some(argument), None, None) => match argument.as_str().strip_suffix('!') {
+                Some(name) if !name.is_empty() => Some(ArgsType::Required(name)),
+                _ => Some(ArgsType::Plain(argument.as_str())),
+            },
             // This looks like [[#path ../images]]
             (_, _, Some(argument), Some(default_value)) => {
                 Some(ArgsType::Default(argument.as_str(), default_value.as_str()))
             }
-            // This looks like \[[#any string]]
-            (Some(mat), _, _, _) if mat.as_str().starts_with(ESCAPE_CHAR) => {
-                Some(ArgsType::Escaped)
-            }
+            // This looks like \\[[, \[[ or [[\#
+            (Some(mat), _, _, _) if mat.as_str().contains(ESCAPE_CHAR) => Some(ArgsType::Escaped),
             _ => None,
         };
 
+        // This looks like [[#title | upper]] or [[#name Anonymous | lower | trim]]
+        let mut filters = Vec::new();
+        if let (
+            Some(ArgsType::Plain(_) | ArgsType::Required(_) | ArgsType::Default(..)),
+            Some(mat),
+        ) = (&arg_type, cap.get(0))
+        {
+            let placeholder = mat
+                .as_str()
+                .trim_start_matches("[[")
+                .trim_start()
+                .trim_start_matches('#')
+                .trim_end_matches("]]");
+            let (rest, split) = filters::split(placeholder);
+            if !split.is_empty() {
+                filters = split;
+                arg_type = Some(match rest.trim().split_once(char::is_whitespace) {
+                    Some((name, default)) => ArgsType::Default(name, default.trim_start()),
+                    None => match rest.trim().strip_suffix('!') {
+                        Some(name) if !name.is_empty() => ArgsType::Required(name),
+                        _ => ArgsType::Plain(rest.trim()),
+                    },
+                });
+            }
+        }
+
         arg_type.and_then(|arg_type| {
             cap.get(0).map(|capt| Args {
                 start_index: capt.start(),
                 end_index: capt.end(),
                 args_type: arg_type,
                 args_text: capt.as_str(),
+                filters,
             })
         })
     }
 }
 
+/// What is between the markers of a raw block such as `[[#raw]] [[#title]] [[/raw]]`, without
+/// the line breaks right after the opening marker and before the closing one.
+fn raw_contents(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix("[[")?.trim_start().strip_prefix("#raw")?;
+    let rest = rest.trim_start().strip_prefix("]]")?;
+    let end = rest.rfind("[[")?;
+    rest[end + 2..]
+        .trim_start()
+        .starts_with("/raw")
+        .then(|| rest[..end].trim_matches(['\n', '\r']))
+}
+
 #[derive(PartialEq, Debug)]
 enum ArgsType<'a> {
     Escaped,
+    Directive,
+    Comment,
+    Raw(&'a str),
     Plain(&'a str),
+    Required(&'a str),
     Default(&'a str, &'a str),
 }
 
-struct ArgsIter<'a>(CaptureMatches<'a, 'a>);
+struct ArgsIter<'a> {
+    captures: CaptureMatches<'a, 'a>,
+    /// Start of every conditional marker, which is never a placeholder
+    markers: Vec<usize>,
+}
 
 impl<'a> Iterator for ArgsIter<'a> {
     type Item = Args<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for cap in &mut self.0 {
-            if let Some(inc) = Args::from_capture(cap.unwrap()) {
+        for cap in &mut self.captures {
+            let cap = match cap {
+                Ok(cap) => cap,
+                Err(err) => {
+                    diagnostics::error(
+                        Code::MalformedArguments,
+                        format_args!("Stopped scanning for arguments, {}", err),
+                    );
+                    return None;
+                }
+            };
+            if let Some(inc) = Args::from_capture(cap, &self.markers) {
                 return Some(inc);
             }
         }
@@ -309,15 +1962,84 @@ impl<'a> Iterator for ArgsIter<'a> {
 }
 
 fn extract_args(contents: &str) -> ArgsIter<'_> {
-    ArgsIter(ARGS.captures_iter(contents))
+    ArgsIter {
+        captures: ARGS.captures_iter(contents),
+        markers: markers(contents)
+            .into_iter()
+            .map(|(range, _)| range.start)
+            .collect(),
+    }
+}
+
+/// An argument placeholder within a template, e.g. `[[#title]]` or `[[#width 200px]]`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Parameter<'a> {
+    /// Name of the argument filling the placeholder
+    pub name: &'a str,
+    /// Value used when the argument isn't given, exactly as written
+    pub default: Option<&'a str>,
+    /// Whether the argument must be given, as in `[[#title!]]`
+    pub required: bool,
+    /// Byte range of the whole placeholder within the template
+    pub span: Range<usize>,
+}
+
+/// Returns every argument placeholder within `template`, in order of appearance. Escaped
+/// placeholders, comments and directives such as `[[@counter figure]]` are left out.
+pub fn extract_parameters(template: &str) -> Vec<Parameter<'_>> {
+    extract_args(template)
+        .filter_map(|arg| {
+            let (name, default, required) = match arg.args_type {
+                ArgsType::Plain(name) => (name, None, false),
+                ArgsType::Required(name) => (name, None, true),
+                ArgsType::Default(name, default) => (name, Some(default), false),
+                ArgsType::Escaped | ArgsType::Directive | ArgsType::Comment | ArgsType::Raw(_) => {
+                    return None
+                }
+            };
+            Some(Parameter {
+                name,
+                default,
+                required,
+                span: arg.start_index..arg.end_index,
+            })
+        })
+        .collect()
+}
+
+/// Byte ranges of the argument names the conditional blocks within `template` depend on, e.g.
+/// `name` within `[[#if name]]` or `[[#match name]]`, along with the names themselves. The values
+/// of `[[#case]]` aren't names and are left out.
+pub(crate) fn condition_names(template: &str) -> Vec<(Range<usize>, &str)> {
+    markers(template)
+        .into_iter()
+        .filter_map(|(_, marker)| match marker {
+            Marker::If(name)
+            | Marker::ElseIf(name)
+            | Marker::Unless(name)
+            | Marker::Match(name) => Some(name),
+            _ => None,
+        })
+        .map(|name| {
+            let start = name.as_ptr() as usize - template.as_ptr() as usize;
+            (start..start + name.len(), name)
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod link_tests {
     use std::collections::HashMap;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
-    use crate::links::{extract_args, extract_template_links, Args, ArgsType, Link, LinkType};
+    use crate::arg_map::ArgMap;
+    use crate::config::{Config, Directive};
+    use crate::diagnostics::{self, Code};
+    use crate::links::{
+        escape, extract_args, extract_parameters, extract_template_links, interpolate, location,
+        replace_conditionals, replace_matches, validate_keywords, Args, ArgsType, Link, LinkType,
+        Parameter,
+    };
 
     #[test]
     fn test_extract_zero_template_links() {
@@ -325,6 +2047,136 @@ mod link_tests {
         assert_eq!(extract_template_links(s).collect::<Vec<_>>(), vec![])
     }
 
+    #[test]
+    fn test_extract_template_links_pack() {
+        let s = r"{{#template corp:header.md}} {{#template builtin:callout}} {{#template C:\a.md}}";
+
+        let res = extract_template_links(s)
+            .src_dir(Path::new("src"))
+            .map(|link| link.link_type)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                LinkType::Pack(
+                    "corp".to_string(),
+                    PathBuf::from("src/packs/corp/header.md")
+                ),
+                LinkType::Template(PathBuf::from("builtin:callout")),
+                LinkType::Template(PathBuf::from(r"C:\a.md")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_template_links_quoted() {
+        let s = r#"{{#template "my templates/card.md" title=Demo}} {{#template ""}}"#;
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            res[0].link_type,
+            LinkType::Template(PathBuf::from("my templates/card.md"))
+        );
+        assert_eq!(res[0].args.get("title"), Some(&"Demo".into()));
+    }
+
+    #[test]
+    fn test_extract_template_links_custom_directive() {
+        let s = r"{{#template a.md}} {{#component b.md}} \{{#component c.md}}";
+
+        let res = extract_template_links(s)
+            .keywords(&["component"])
+            .map(|link| (link.link_type, link.link_text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                (
+                    LinkType::Template(PathBuf::from("b.md")),
+                    "{{#component b.md}}"
+                ),
+                (LinkType::Escaped, r"\{{"),
+            ]
+        );
+        assert!(validate_keywords(&["template", "component"]).is_ok());
+        assert!(validate_keywords(&["comp onent"]).is_err());
+        assert!(validate_keywords(&[""]).is_err());
+    }
+
+    #[test]
+    fn test_extract_template_links_rooted_directives() {
+        let s =
+            "{{#template a.md}} {{#snippet b.rs}} {{ #layout page.md}} {{#snippet builtin:note}}";
+        let directives = HashMap::from([
+            (
+                "snippet".to_string(),
+                Directive {
+                    root: PathBuf::from("/book/src/snippets"),
+                    ..Default::default()
+                },
+            ),
+            (
+                "layout".to_string(),
+                Directive {
+                    root: PathBuf::from("/book/src/layouts"),
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let res = extract_template_links(s)
+            .keywords(&["template", "layout", "snippet"])
+            .directives(&directives)
+            .map(|link| link.link_type)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                LinkType::Template(PathBuf::from("a.md")),
+                LinkType::Rooted(
+                    "snippet".to_string(),
+                    PathBuf::from("/book/src/snippets/b.rs")
+                ),
+                LinkType::Rooted(
+                    "layout".to_string(),
+                    PathBuf::from("/book/src/layouts/page.md")
+                ),
+                LinkType::Template(PathBuf::from("builtin:note")),
+            ]
+        );
+        assert_eq!(
+            res[1].target("/book/src/guide"),
+            Some(PathBuf::from("/book/src/snippets/b.rs"))
+        );
+    }
+
+    #[test]
+    fn test_extract_template_links_changelog() {
+        let s = "{{#changelog ../CHANGELOG.md version=1.2.0}} {{#changelog-data a.json}}";
+
+        let res = extract_template_links(s)
+            .map(|link| link.link_type)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![LinkType::Changelog(PathBuf::from("../CHANGELOG.md"))]
+        );
+        let res = extract_template_links(s)
+            .keywords(&["component", "snippet"])
+            .map(|link| link.link_type)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            res,
+            vec![LinkType::Changelog(PathBuf::from("../CHANGELOG.md"))]
+        );
+    }
+
     #[test]
     fn test_extract_template_links_partial_match() {
         let s = "Some random text with {{#template...";
@@ -333,8 +2185,28 @@ mod link_tests {
         assert_eq!(extract_template_links(s).collect::<Vec<_>>(), vec![]);
         let s = "Some random text with {{#template footer.md path=../images...";
         assert_eq!(extract_template_links(s).collect::<Vec<_>>(), vec![]);
-        let s = "Some random text with \\{{#template...";
-        assert_eq!(extract_template_links(s).collect::<Vec<_>>(), vec![]);
+    }
+
+    #[test]
+    fn test_extract_template_links_partial_escapes() {
+        let s = r"\{{#template... {{\#template a.md}} \\{{#template b.md}}";
+
+        let res = extract_template_links(s)
+            .map(|link| (link.link_type, link.link_text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                (LinkType::Escaped, r"\{{"),
+                (LinkType::Escaped, r"{{\#"),
+                (LinkType::Escaped, r"\\"),
+                (
+                    LinkType::Template(PathBuf::from("b.md")),
+                    "{{#template b.md}}"
+                ),
+            ]
+        );
     }
 
     #[test]
@@ -362,7 +2234,9 @@ mod link_tests {
                 end_index: 33,
                 link_type: LinkType::Template(PathBuf::from("templates/footer.md")),
                 link_text: "{{#template templates/footer.md}}",
-                args: HashMap::new()
+                args: ArgMap::new(),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -382,14 +2256,18 @@ mod link_tests {
                     end_index: 43,
                     link_type: LinkType::Template(PathBuf::from("file.rs")),
                     link_text: "{{#template file.rs}}",
-                    args: HashMap::new()
+                    args: ArgMap::new(),
+                    appended: ArgMap::new(),
+                    section: None,
                 },
                 Link {
                     start_index: 48,
                     end_index: 79,
                     link_type: LinkType::Template(PathBuf::from("test.rs")),
                     link_text: "{{#template test.rs lang=rust}}",
-                    args: HashMap::from([("lang", "rust")])
+                    args: ArgMap::from([("lang", "rust".into())]),
+                    appended: ArgMap::new(),
+                    section: None,
                 },
             ]
         );
@@ -408,7 +2286,9 @@ mod link_tests {
                 end_index: 63,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template test.rs lang=rust math=2+2=4}}",
-                args: HashMap::from([("lang", "rust"), ("math", "2+2=4")]),
+                args: ArgMap::from([("lang", "rust".into()), ("math", "2+2=4".into())]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -426,7 +2306,12 @@ mod link_tests {
                 end_index: 77,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template test.rs lang=rust authors=Goudham & Hazel}}",
-                args: HashMap::from([("lang", "rust"), ("authors", "Goudham & Hazel")]),
+                args: ArgMap::from([
+                    ("lang", "rust".into()),
+                    ("authors", "Goudham & Hazel".into())
+                ]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -444,7 +2329,12 @@ mod link_tests {
                 end_index: 87,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template      test.rs      lang=rust authors=Goudham & Hazel}}",
-                args: HashMap::from([("lang", "rust"), ("authors", "Goudham & Hazel")]),
+                args: ArgMap::from([
+                    ("lang", "rust".into()),
+                    ("authors", "Goudham & Hazel".into())
+                ]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -462,11 +2352,93 @@ mod link_tests {
                 end_index: 70,
                 link_type: LinkType::Template(PathBuf::from("foo-bar\\-baz/_c++.'.rs")),
                 link_text: "{{#template foo-bar\\-baz/_c++.'.rs path=images}}",
-                args: HashMap::from([("path", "images")]),
+                args: ArgMap::from([("path", "images".into())]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
 
+    #[test]
+    fn test_extract_template_links_nested() {
+        let s = "{{#template header.md title={{#template name.md first=Hazel}} year=2022}} {{#template footer.md}}";
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        assert_eq!(
+            res,
+            vec![
+                Link {
+                    start_index: 0,
+                    end_index: 73,
+                    link_type: LinkType::Template(PathBuf::from("header.md")),
+                    link_text:
+                        "{{#template header.md title={{#template name.md first=Hazel}} year=2022}}",
+                    args: ArgMap::from([
+                        ("title", "{{#template name.md first=Hazel}}".into()),
+                        ("year", "2022".into())
+                    ]),
+                    appended: ArgMap::new(),
+                    section: None,
+                },
+                Link {
+                    start_index: 74,
+                    end_index: 97,
+                    link_type: LinkType::Template(PathBuf::from("footer.md")),
+                    link_text: "{{#template footer.md}}",
+                    args: ArgMap::new(),
+                    appended: ArgMap::new(),
+                    section: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_template_links_unbalanced() {
+        let s = "{{#template header.md title={{#template name.md}}";
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        // Only the inner invocation is closed
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].link_text, "{{#template name.md}}");
+    }
+
+    #[test]
+    fn test_extract_template_links_max_length() {
+        let s = "{{#template header.md title=Hazel}} {{#template footer.md}}";
+
+        let res = extract_template_links(s)
+            .max_length(22)
+            .map(|link| link.link_text)
+            .collect::<Vec<_>>();
+
+        assert_eq!(res, vec!["{{#template footer.md}}"]);
+    }
+
+    #[test]
+    fn test_extract_template_links_appended() {
+        let s = "{{#template
+            footer.md
+            authors=Goudham
+            authors+=Hazel
+            links+=https://github.com
+        }}";
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].args, ArgMap::from([("authors", "Goudham".into())]));
+        assert_eq!(
+            res[0].appended,
+            ArgMap::from([
+                ("authors", vec!["Hazel".into()]),
+                ("links", vec!["https://github.com".into()])
+            ])
+        );
+    }
+
     #[test]
     fn test_extract_template_links_newlines() {
         let s = "{{#template
@@ -485,7 +2457,9 @@ mod link_tests {
                 end_index: 122,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template\n            test.rs\n            lang=rust\n            authors=Goudham & Hazel\n            year=2022\n        }}",
-                args: HashMap::from([("lang", "rust"), ("authors", "Goudham & Hazel"), ("year", "2022")]),
+                args: ArgMap::from([("lang", "rust".into()), ("authors", "Goudham & Hazel".into()), ("year", "2022".into())]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -508,7 +2482,9 @@ year=2022
                 end_index: 78,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template\n    test.rs\nlang=rust\n        authors=Goudham & Hazel\nyear=2022\n}}",
-                args: HashMap::from([("lang", "rust"), ("authors", "Goudham & Hazel"), ("year", "2022")]),
+                args: ArgMap::from([("lang", "rust".into()), ("authors", "Goudham & Hazel".into()), ("year", "2022".into())]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -531,7 +2507,9 @@ year=2022
                 end_index: 58,
                 link_type: LinkType::Template(PathBuf::from("test.rs")),
                 link_text: "{{#template test.rs \n        lang=rust\n        year=2022}}",
-                args: HashMap::from([("lang", "rust"), ("year", "2022")]),
+                args: ArgMap::from([("lang", "rust".into()), ("year", "2022".into())]),
+                appended: ArgMap::new(),
+                section: None,
             },]
         );
     }
@@ -550,8 +2528,17 @@ year=2022
         assert_eq!(extract_args(s).collect::<Vec<_>>(), vec![]);
         let s = "Some random text with [[#width 550...";
         assert_eq!(extract_args(s).collect::<Vec<_>>(), vec![]);
-        let s = "Some random text with \\[[#title...";
-        assert_eq!(extract_args(s).collect::<Vec<_>>(), vec![]);
+    }
+
+    #[test]
+    fn test_replace_args_partial_escapes() {
+        let start = r"\[[ [[\#title]] \\[[#title]] \\\[[#title]] \[[@counter figure]]";
+        let end = r"[[ [[#title]] \Hazel \[[#title]] \[[@counter figure]]";
+
+        assert_eq!(
+            Args::replace(start, &HashMap::from([("title", "Hazel")]), false, false),
+            end
+        );
     }
 
     #[test]
@@ -572,7 +2559,8 @@ year=2022
                 start_index: 30,
                 end_index: 39,
                 args_type: ArgsType::Plain("path"),
-                args_text: "[[#path]]"
+                args_text: "[[#path]]",
+                filters: vec![]
             }]
         );
     }
@@ -587,7 +2575,40 @@ year=2022
         Example Text
         [[#height 200px]] << an escaped argument!
         ";
-        assert_eq!(Args::replace(start, &HashMap::<&str, &str>::new()), end);
+        assert_eq!(
+            Args::replace(start, &HashMap::<&str, &str>::new(), false, false),
+            end
+        );
+    }
+
+    #[test]
+    fn test_extract_parameters() {
+        let s =
+            r"[[#title]] by [[ #author Anonymous ]], \[[#escaped]] [[@counter figure]] [[#title]]";
+
+        assert_eq!(
+            extract_parameters(s),
+            vec![
+                Parameter {
+                    name: "title",
+                    default: None,
+                    required: false,
+                    span: 0..10,
+                },
+                Parameter {
+                    name: "author",
+                    default: Some("Anonymous "),
+                    required: false,
+                    span: 14..37,
+                },
+                Parameter {
+                    name: "title",
+                    default: None,
+                    required: false,
+                    span: 73..83,
+                },
+            ]
+        );
     }
 
     #[test]
@@ -606,7 +2627,8 @@ year=2022
                 start_index: 30,
                 end_index: 51,
                 args_type: ArgsType::Plain("path"),
-                args_text: "[[     #path       ]]"
+                args_text: "[[     #path       ]]",
+                filters: vec![]
             }]
         );
 
@@ -616,7 +2638,8 @@ year=2022
                 start_index: 30,
                 end_index: 46,
                 args_type: ArgsType::Plain("path"),
-                args_text: "[[#path       ]]"
+                args_text: "[[#path       ]]",
+                filters: vec![]
             }]
         );
 
@@ -626,7 +2649,8 @@ year=2022
                 start_index: 30,
                 end_index: 44,
                 args_type: ArgsType::Plain("path"),
-                args_text: "[[     #path]]"
+                args_text: "[[     #path]]",
+                filters: vec![]
             }]
         );
     }
@@ -643,7 +2667,8 @@ year=2022
                 start_index: 30,
                 end_index: 45,
                 args_type: ArgsType::Default("path", "200px"),
-                args_text: "[[#path 200px]]"
+                args_text: "[[#path 200px]]",
+                filters: vec![]
             }]
         );
     }
@@ -661,7 +2686,8 @@ year=2022
                 start_index: 30,
                 end_index: 52,
                 args_type: ArgsType::Default("path", "400px  "),
-                args_text: "[[   #path   400px  ]]"
+                args_text: "[[   #path   400px  ]]",
+                filters: vec![]
             }]
         );
     }
@@ -678,7 +2704,8 @@ year=2022
                 start_index: 0,
                 end_index: 27,
                 args_type: ArgsType::Default("title", "An Amazing Title"),
-                args_text: "[[#title An Amazing Title]]"
+                args_text: "[[#title An Amazing Title]]",
+                filters: vec![]
             }]
         );
     }
@@ -694,7 +2721,7 @@ year=2022
         200px << an argument!
         ";
         assert_eq!(
-            Args::replace(start, &HashMap::from([("height", "200px")])),
+            Args::replace(start, &HashMap::from([("height", "200px")]), false, false),
             end
         );
     }
@@ -709,7 +2736,162 @@ year=2022
         Example Text
         300px << an argument!
         ";
-        assert_eq!(Args::replace(start, &HashMap::<&str, &str>::new()), end);
+        assert_eq!(
+            Args::replace(start, &HashMap::<&str, &str>::new(), false, false),
+            end
+        );
+    }
+
+    #[test]
+    fn test_replace_args_keeps_directives() {
+        let start = r"
+        Figure [[@counter figure]]: [[#title]]
+        \[[@counter figure]] << an escaped directive!
+        ";
+        let end = r"
+        Figure [[@counter figure]]: Ferris
+        \[[@counter figure]] << an escaped directive!
+        ";
+        assert_eq!(
+            Args::replace(
+                start,
+                &HashMap::from([("title", "Ferris"), ("counter", "0")]),
+                false,
+                false
+            ),
+            end
+        );
+    }
+
+    #[test]
+    fn test_replace_args_named_like_directives() {
+        let start = "<button>[[#label Submit]]</button> [[#ref default-ref]] [[#counter]]";
+        assert_eq!(
+            Args::replace(
+                start,
+                &HashMap::from([("label", "Go"), ("counter", "3")]),
+                false,
+                false
+            ),
+            "<button>Go</button> default-ref 3"
+        );
+        assert_eq!(
+            Args::replace(start, &HashMap::new(), false, false),
+            "<button>Submit</button> default-ref "
+        );
+    }
+
+    #[test]
+    fn test_replace_args_named_like_branches() {
+        let args = HashMap::from([("else", "X".to_string())]);
+        let template = "v=[[#else]] [[#unless a]]A[[#else]][[/unless]] [[#if a]]A[[#else]]B[[/if]]";
+        let replaced = replace_conditionals(template, &args).unwrap();
+
+        assert_eq!(replaced, "v=[[#else]] A[[#else]] B");
+        assert_eq!(
+            Args::replace(&replaced, &HashMap::from([("else", "X")]), false, false),
+            "v=X AX B"
+        );
+    }
+
+    #[test]
+    fn test_replace_args_named_like_cases() {
+        let args = HashMap::from([("lang", "go".to_string())]);
+        let template = "[[#default]] [[#case x]] [[#if lang]][[#default]][[/if]] [[#match lang]][[#case rust]]R[[#default]]D[[/match]]";
+        let replaced = replace_conditionals(template, &args).unwrap();
+
+        assert_eq!(replaced, "[[#default]] [[#case x]] [[#default]] D");
+        assert_eq!(
+            Args::replace(&replaced, &HashMap::from([("default", "X")]), false, false),
+            "X x X D"
+        );
+    }
+
+    #[test]
+    fn test_replace_args_strips_comments() {
+        let start = "# [[#title]]\n  [[!-- Keep the title short,\n  it ends up in the sidebar --]]\nBy [[#author]] [[!-- [[#if author]] --]]\n\\[[!-- shown --]]";
+        let end = "# Ferris\nBy Goudham \n[[!-- shown --]]";
+        assert_eq!(
+            Args::replace(
+                start,
+                &HashMap::from([("title", "Ferris"), ("author", "Goudham")]),
+                false,
+                false
+            ),
+            end
+        );
+        assert!(extract_parameters("[[!-- [[#hidden]] --]] [[#shown]]")
+            .iter()
+            .map(|parameter| parameter.name)
+            .eq(["shown"]));
+        assert_eq!(
+            replace_conditionals("[[!-- [[#else]] --]]", &HashMap::new()).unwrap(),
+            "[[!-- [[#else]] --]]"
+        );
+    }
+
+    #[test]
+    fn test_replace_args_filters() {
+        let start = "[[#title | upper]] [[#name|lower|trim]] [[#kind guide | capitalize]] [[#separator a|b]]";
+        assert_eq!(
+            Args::replace(
+                start,
+                &HashMap::from([("title", "Ferris"), ("name", " HAZEL ")]),
+                false,
+                false
+            ),
+            "FERRIS hazel Guide a|b"
+        );
+        assert!(
+            extract_parameters("[[#title! | upper]] [[#kind guide | lower]]")
+                .iter()
+                .map(|parameter| (parameter.name, parameter.default, parameter.required))
+                .eq([("title", None, true), ("kind", Some("guide"), false)])
+        );
+    }
+
+    #[test]
+    fn test_replace_args_keep_placeholders() {
+        let start = "[[#title]] by [[ #author ]] in [[#year 2022]]";
+        let all_args = HashMap::from([("title", "Ferris")]);
+
+        assert_eq!(
+            Args::replace(start, &all_args, false, true),
+            "Ferris by [[ #author ]] in 2022"
+        );
+        assert_eq!(
+            Args::replace(start, &all_args, false, false),
+            "Ferris by  in 2022"
+        );
+    }
+
+    #[test]
+    fn test_replace_args_raw() {
+        let start = "Use [[#raw]][[#title]][[/raw]] for the title\n[[#raw]]\n[[#width 200px]] [[@counter figure]] [[#if title]]\n[[/raw]]\n[[#title]]";
+        let end = "Use [[#title]] for the title\n[[#width 200px]] \\[[@counter figure]] [[#if title]]\nFerris";
+        assert_eq!(
+            Args::replace(start, &HashMap::from([("title", "Ferris")]), false, false),
+            end
+        );
+        assert!(
+            extract_parameters("[[#raw]] [[#hidden]] [[/raw]] [[#shown]]")
+                .iter()
+                .map(|parameter| parameter.name)
+                .eq(["shown"])
+        );
+        assert_eq!(
+            replace_conditionals("[[#raw]][[/if]][[/raw]]", &HashMap::new()).unwrap(),
+            "[[#raw]][[/if]][[/raw]]"
+        );
+        assert_eq!(
+            Args::replace(
+                "[[#raw]] unclosed",
+                &HashMap::from([("raw", "x")]),
+                false,
+                false
+            ),
+            "x unclosed"
+        );
     }
 
     #[test]
@@ -723,8 +2905,274 @@ year=2022
         200px << an argument!
         ";
         assert_eq!(
-            Args::replace(start, &HashMap::from([("height", "200px")])),
+            Args::replace(start, &HashMap::from([("height", "200px")]), false, false),
             end
         );
     }
+
+    #[test]
+    fn test_replace_conditionals() {
+        let template = "[[#if title]]\n# [[#title]]\n[[#else-if name]]\n# [[#name]]\n[[#else]]\n# Untitled\n[[/if]]\n\nText";
+        let replace = |args: &[(&'static str, &str)]| {
+            let args = args
+                .iter()
+                .map(|(key, value)| (*key, value.to_string()))
+                .collect::<HashMap<_, _>>();
+            replace_conditionals(template, &args).unwrap().into_owned()
+        };
+
+        assert_eq!(replace(&[("title", "Intro")]), "# [[#title]]\n\nText");
+        assert_eq!(
+            replace(&[("title", ""), ("name", "Intro")]),
+            "# [[#name]]\n\nText"
+        );
+        assert_eq!(replace(&[("title", "false")]), "# Untitled\n\nText");
+        assert_eq!(
+            replace_conditionals(
+                r"[[#if a]]A[[#if b]]B[[/if]][[/if]] \[[#if a]]",
+                &HashMap::from([("a", "1".to_string())])
+            )
+            .unwrap(),
+            r"A \[[#if a]]"
+        );
+    }
+
+    #[test]
+    fn test_replace_unless() {
+        let template = "[[#unless hide-footer]]\nMade with mdBook\n[[/unless]]";
+        let replace = |args: HashMap<&str, String>| {
+            replace_conditionals(template, &args).unwrap().into_owned()
+        };
+
+        assert_eq!(replace(HashMap::new()), "Made with mdBook");
+        assert_eq!(
+            replace(HashMap::from([("hide-footer", String::new())])),
+            "Made with mdBook"
+        );
+        assert_eq!(
+            replace(HashMap::from([("hide-footer", "true".to_string())])),
+            ""
+        );
+        assert_eq!(
+            replace_conditionals(
+                "[[#if a]][[#unless b]]A[[/unless]][[#else]]B[[/if]]",
+                &HashMap::from([("a", "1".to_string())])
+            )
+            .unwrap(),
+            "A"
+        );
+    }
+
+    #[test]
+    fn test_replace_match() {
+        let template = "[[#match lang]]\n[[#case rust]]\ncargo run\n[[#case python]]\npython main.py\n[[#default]]\nmake run\n[[/match]]";
+        let replace = |args: HashMap<&str, String>| {
+            replace_conditionals(template, &args).unwrap().into_owned()
+        };
+
+        assert_eq!(
+            replace(HashMap::from([("lang", "rust".to_string())])),
+            "cargo run"
+        );
+        assert_eq!(
+            replace(HashMap::from([("lang", "python".to_string())])),
+            "python main.py"
+        );
+        assert_eq!(
+            replace(HashMap::from([("lang", "go".to_string())])),
+            "make run"
+        );
+        assert_eq!(replace(HashMap::new()), "make run");
+        assert_eq!(
+            replace_conditionals(
+                "[[#match a]][[#case 1]]One[[/match]]",
+                &HashMap::from([("a", "2".to_string())])
+            )
+            .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_replace_matches() {
+        let start = "Intro\n[[#match audience]]\n[[#case beginner]]\nSimple text.\n[[#case expert]]\n[[#match os]]\n[[#case linux]]\napt\n[[#default]]\nbrew\n[[/match]]\n[[#default]]\nSome text.\n[[/match]]\nOutro [[#default]]";
+        let expert = HashMap::from([("audience".to_string(), "expert".to_string())]);
+        let other = HashMap::from([("audience".to_string(), "manager".to_string())]);
+
+        assert_eq!(
+            replace_matches(start, &expert),
+            "Intro\nbrew\nOutro [[#default]]"
+        );
+        assert_eq!(
+            replace_matches(start, &other),
+            "Intro\nSome text.\nOutro [[#default]]"
+        );
+        assert_eq!(
+            replace_matches(
+                "[[#match os]][[#case linux]]apt[[/match]]!",
+                &HashMap::new()
+            ),
+            "!"
+        );
+        assert_eq!(
+            replace_matches("\\[[#match os]][[/match]]", &HashMap::new()),
+            "\\[[#match os]][[/match]]"
+        );
+
+        let (replaced, diagnostics) = diagnostics::capture(|| {
+            replace_matches("[[#match os]][[#case linux]]apt", &HashMap::new())
+        });
+        assert_eq!(replaced, "[[#match os]][[#case linux]]apt");
+        assert_eq!(diagnostics[0].code, Code::MalformedMatch);
+    }
+
+    #[test]
+    fn test_replace_conditionals_misplaced() {
+        let error = |template: &str| {
+            replace_conditionals(template, &HashMap::new())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error("Intro\n[[#if title]]\n[[#title]]"),
+            "[[#if title]] at line 2, column 1 is missing its [[/if]]"
+        );
+        assert_eq!(
+            error("[[#if a]]A[[#else]]B[[#else-if b]]C[[/if]]"),
+            "[[#else-if b]] at line 1, column 21 comes after the [[#else]] of [[#if a]]"
+        );
+        assert_eq!(
+            error("[[#unless a]]\n[[#if b]]B[[/unless]]"),
+            "[[/unless]] at line 2, column 11 doesn't belong to any [[#unless ...]]"
+        );
+        assert_eq!(
+            error("[[#unless a]]A"),
+            "[[#unless a]] at line 1, column 1 is missing its [[/unless]]"
+        );
+        assert_eq!(
+            error("[[#match a]][[#default]]A[[#case b]]B[[/match]]"),
+            "[[#case b]] at line 1, column 26 comes after the [[#default]] of [[#match a]]"
+        );
+        assert_eq!(
+            error("[[#match a]][[#case b]]B"),
+            "[[#match a]] at line 1, column 1 is missing its [[/match]]"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        let start = r"{{#template a.md}} \{{#template b.md}} \\{{x}} [[#title]]";
+        let end = r"\{{#template a.md}} \\\{{#template b.md}} \\\\\{{x}} [[#title]]";
+
+        assert_eq!(escape(start, "{{", |_| Some(2)), end);
+        assert_eq!(
+            escape(
+                "[[#title]] [[@counter figure [[@counter table]]",
+                "[[",
+                |text| text.starts_with("[[@c").then_some(text.len())
+            ),
+            r"[[#title]] \[[@counter figure [[@counter table]]"
+        );
+    }
+
+    #[test]
+    fn test_arg_spans() {
+        let s = "{{#template img.md\n    alt=Logo\n    widht=200\n    tags+=a\n}}";
+        let link = extract_template_links(s).next().unwrap();
+
+        assert_eq!(link.arg_span("widht"), Some(36..45));
+        assert_eq!(&s[36..45], "widht=200");
+        assert_eq!(&s[link.arg_span("tags").unwrap()], "tags+=a");
+        assert_eq!(link.arg_span("height"), None);
+        assert_eq!(
+            link.marker("widht"),
+            "\n        widht=200\n        ^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_arg_spans_inline() {
+        let s = "Some text {{#template img.md alt=Logo widht=200}}";
+        let link = extract_template_links(s).next().unwrap();
+
+        assert_eq!(link.arg_span("widht"), Some(28..37));
+        assert_eq!(
+            link.marker("widht"),
+            "\n    {{#template img.md alt=Logo widht=200}}\n                                ^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_extract_unterminated() {
+        let s = "{{#template header.md\n\nSome text\n\n{{#template footer.md}}";
+
+        let res = extract_template_links(s).max_lines(2).collect::<Vec<_>>();
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].link_text, "{{#template footer.md}}");
+        assert_eq!(location(s, 34), (5, 1));
+
+        let s = "{{#template header.md\n\nSome text with }} in it";
+        assert_eq!(extract_template_links(s).max_lines(2).count(), 0);
+        assert_eq!(extract_template_links(s).max_lines(3).count(), 1);
+    }
+
+    #[test]
+    fn test_extract_template_links_continued() {
+        let s = "{{#template
+            footer.md
+            title=A rather long \\
+                  title
+            notes=\"- First
+- Second\"
+            quoted=\"Hello\"
+            year=2022
+        }}";
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            res[0].args,
+            ArgMap::from([
+                ("title", "A rather long title".into()),
+                ("notes", "- First\n- Second".into()),
+                ("quoted", "Hello".into()),
+                ("year", "2022".into())
+            ])
+        );
+        assert_eq!(&s[res[0].arg_span("year").unwrap()], "year=2022");
+        assert_eq!(&s[res[0].arg_span("title").unwrap()], "title");
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let start = "Version [[#version]] of [[#name]] [[#edition 2021]] \\[[#version]] [[@counter figure]]\n\n```hbs\n[[#version]]\n```\n";
+        let end = "Version 1.2.0 of [[#name]] 2021 [[#version]] [[@counter figure]]\n\n```hbs\n[[#version]]\n```\n";
+        let globals = HashMap::from([("version".to_string(), "1.2.0".to_string())]);
+        let config = Config {
+            skip_langs: vec!["hbs".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(interpolate(start, &globals, &config), end);
+    }
+
+    #[test]
+    fn test_extract_template_links_hard_wrapped() {
+        let s = "A paragraph with {{#template test.rs lang=rust authors=Goudham\n& Hazel year=2022\n}} inside.";
+
+        let res = extract_template_links(s).collect::<Vec<_>>();
+
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            res[0].args,
+            ArgMap::from([
+                ("lang", "rust".into()),
+                ("authors", "Goudham & Hazel".into()),
+                ("year", "2022".into())
+            ])
+        );
+    }
 }
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use mdbook::errors::Result;
+use serde_json::Value;
+
+use crate::utils::normalize_path;
+use crate::FileReader;
+
+lazy_static! {
+    // r"^lookup\(\s*([^,]+?)\s*,\s*([^)]+?)\s*\)$"
+    static ref LOOKUP: Regex = Regex::new(
+        r"(?x)                                  # enable insignificant whitespace mode
+
+        ^lookup\(\s*                            # lookup opening parens and whitespace(s)
+        ([^,]+?)                                # relative path to the data file
+        \s*,\s*                                 # separating comma
+        ([^)]+?)                                # dotted path to the value
+        \s*\)$                                  # lookup closing parens"
+    )
+    .unwrap();
+}
+
+/// Resolves an argument value of the form `lookup(<file>, <path>)` to the value found at the
+/// dotted `path` within the JSON, TOML or YAML `file`, relative to `base`. Any other value is
+/// not a lookup and `None` is returned.
+pub(crate) fn resolve<P, FR>(value: &str, base: P, file_reader: &FR) -> Result<Option<String>>
+where
+    P: AsRef<Path>,
+    FR: FileReader,
+{
+    let cap = match LOOKUP.captures(value.trim()) {
+        Ok(Some(cap)) => cap,
+        _ => return Ok(None),
+    };
+    let (file, path) = match (cap.get(1), cap.get(2)) {
+        (Some(file), Some(path)) => (file.as_str(), path.as_str()),
+        _ => return Ok(None),
+    };
+
+    let target = base.as_ref().join(normalize_path(file));
+    let contents = file_reader.read_to_string(&target, value)?;
+    let data = parse(&target, &contents).with_context(|| format!("Could not parse {}", file))?;
+
+    let found = path
+        .split('.')
+        .try_fold(&data, |node, key| match node {
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => node.get(key),
+        })
+        .with_context(|| format!("Could not find {} within {}", path, file))?;
+
+    to_string(found)
+        .map(Some)
+        .with_context(|| format!("{} within {} is not a plain value or list", path, file))
+}
+
+pub(crate) fn parse(file: &Path, contents: &str) -> Result<Value> {
+    let data = match file.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(contents)?,
+        Some("toml") => toml::from_str(contents)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(contents)?,
+        _ => bail!("Lookups are only supported within JSON, TOML or YAML files"),
+    };
+    Ok(data)
+}
+
+/// Reads the `cargo.*` names from the `[package]` table of a Cargo.toml, e.g. `cargo.version`,
+/// with nested tables such as `[package.metadata.docs]` flattened into `cargo.metadata.docs.*`.
+pub(crate) fn cargo_globals(manifest: &Path, contents: &str) -> Result<HashMap<String, String>> {
+    let data = parse(manifest, contents)
+        .with_context(|| format!("Could not parse {}", manifest.display()))?;
+    let package = data
+        .get("package")
+        .with_context(|| format!("{} has no [package] table", manifest.display()))?;
+
+    let mut globals = HashMap::new();
+    flatten("cargo", package, &mut globals);
+    Ok(globals)
+}
+
+fn flatten(prefix: &str, value: &Value, flattened: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields {
+                flatten(&format!("{}.{}", prefix, key), field, flattened);
+            }
+        }
+        _ => {
+            if let Some(value) = to_string(value) {
+                flattened.insert(prefix.to_owned(), value);
+            }
+        }
+    }
+}
+
+/// Renders plain values and lists of them, objects and `null` have no textual form.
+pub(crate) fn to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.to_owned()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(boolean) => Some(boolean.to_string()),
+        Value::Array(items) => items
+            .iter()
+            .map(to_string)
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(", ")),
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod lookup_tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    use crate::lookup::{cargo_globals, resolve};
+    use crate::utils::TestFileReader;
+
+    fn file_reader() -> TestFileReader {
+        TestFileReader::from(HashMap::from([
+            (
+                PathBuf::from("data/team.yaml"),
+                "leads:\n  docs: Hazel\nmembers:\n  - Goudham\n  - Hazel\n".to_string(),
+            ),
+            (
+                PathBuf::from("data/book.toml"),
+                "[release]\nversion = \"1.2.0\"\nyear = 2022".to_string(),
+            ),
+            (
+                PathBuf::from("data/api.json"),
+                r#"{"paths": [{"name": "users"}]}"#.to_string(),
+            ),
+        ]))
+    }
+
+    #[test]
+    fn test_resolve_not_a_lookup() {
+        assert_eq!(resolve("Goudham", "", &file_reader()).unwrap(), None);
+        assert_eq!(resolve("lookup(", "", &file_reader()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resolve_lookups() {
+        let resolve = |value: &str| resolve(value, "data", &file_reader()).unwrap();
+
+        assert_eq!(
+            resolve("lookup(team.yaml, leads.docs)"),
+            Some("Hazel".into())
+        );
+        assert_eq!(
+            resolve("lookup(team.yaml, members)"),
+            Some("Goudham, Hazel".into())
+        );
+        assert_eq!(
+            resolve("lookup( book.toml , release.year )"),
+            Some("2022".into())
+        );
+        assert_eq!(
+            resolve("lookup(api.json, paths.0.name)"),
+            Some("users".into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let resolve = |value: &str| resolve(value, "data", &file_reader());
+
+        assert!(resolve("lookup(team.yaml, leads.design)").is_err());
+        assert!(resolve("lookup(team.yaml, leads)").is_err());
+        assert!(resolve("lookup(missing.yaml, leads)").is_err());
+        assert!(resolve("lookup(team.txt, leads)").is_err());
+    }
+
+    #[test]
+    fn test_cargo_globals() {
+        let manifest = r#"
+[package]
+name = "mdbook-template"
+version = "1.1.1"
+rust-version = "1.60"
+authors = ["Goudham", "Hazel"]
+
+[package.metadata.docs]
+theme = "mocha"
+
+[dependencies]
+mdbook = "0.4.18"
+"#;
+
+        let globals = cargo_globals(Path::new("Cargo.toml"), manifest).unwrap();
+
+        assert_eq!(globals["cargo.name"], "mdbook-template");
+        assert_eq!(globals["cargo.version"], "1.1.1");
+        assert_eq!(globals["cargo.rust-version"], "1.60");
+        assert_eq!(globals["cargo.authors"], "Goudham, Hazel");
+        assert_eq!(globals["cargo.metadata.docs.theme"], "mocha");
+        assert!(!globals.contains_key("cargo.dependencies.mdbook"));
+        assert!(cargo_globals(Path::new("Cargo.toml"), "[workspace]").is_err());
+    }
+}
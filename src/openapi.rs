@@ -0,0 +1,128 @@
+use serde_json::{Map, Value};
+
+/// Builtin template rendering an operation when no other `template` is given.
+pub(crate) const OPENAPI_TEMPLATE: &str = "builtin:openapi";
+
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// Finds the operation with the given `operationId` within an OpenAPI spec. Besides its own
+/// fields, the operation gains its `method`, `path` and markdown tables of its `parameters`
+/// and `responses` for the template rendering it.
+pub(crate) fn operation(spec: &Value, id: &str) -> Option<Value> {
+    spec.get("paths")?
+        .as_object()?
+        .iter()
+        .find_map(|(path, item)| {
+            METHODS.iter().find_map(|method| {
+                let operation = item.get(method)?;
+                match operation.get("operationId").and_then(Value::as_str) == Some(id) {
+                    true => Some(flatten(path, method, operation)),
+                    false => None,
+                }
+            })
+        })
+}
+
+fn flatten(path: &str, method: &str, operation: &Value) -> Value {
+    let mut fields = operation.as_object().cloned().unwrap_or_else(Map::new);
+    fields.insert("path".to_string(), Value::from(path));
+    fields.insert("method".to_string(), Value::from(method.to_uppercase()));
+
+    let parameters = operation
+        .get("parameters")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|parameter| {
+            Some(format!(
+                "| `{}` | {} | {} | {} |",
+                parameter.get("name")?.as_str()?,
+                text(parameter, "in"),
+                parameter
+                    .get("required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or_default(),
+                text(parameter, "description")
+            ))
+        })
+        .collect::<Vec<_>>();
+    fields.insert(
+        "parameters".to_string(),
+        Value::from(table(
+            "| Parameter | In | Required | Description |\n|-----------|----|----------|-------------|",
+            parameters,
+        )),
+    );
+
+    let responses = operation
+        .get("responses")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(status, response)| format!("| `{}` | {} |", status, text(response, "description")))
+        .collect::<Vec<_>>();
+    fields.insert(
+        "responses".to_string(),
+        Value::from(table(
+            "| Status | Description |\n|--------|-------------|",
+            responses,
+        )),
+    );
+
+    Value::Object(fields)
+}
+
+fn text<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn table(header: &str, rows: Vec<String>) -> String {
+    match rows.is_empty() {
+        true => String::new(),
+        false => format!("{}\n{}", header, rows.join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod openapi_tests {
+    use serde_json::json;
+
+    use crate::openapi::operation;
+
+    #[test]
+    fn test_operation() {
+        let spec = json!({
+            "paths": {
+                "/users": {
+                    "get": {
+                        "operationId": "listUsers",
+                        "summary": "List users",
+                        "parameters": [
+                            {"name": "limit", "in": "query", "description": "Page size"}
+                        ],
+                        "responses": {"200": {"description": "The users"}}
+                    },
+                    "post": {"operationId": "createUser"}
+                }
+            }
+        });
+
+        let found = operation(&spec, "listUsers").unwrap();
+
+        assert_eq!(found["method"], "GET");
+        assert_eq!(found["path"], "/users");
+        assert_eq!(found["summary"], "List users");
+        assert_eq!(
+            found["parameters"],
+            "| Parameter | In | Required | Description |\n|-----------|----|----------|-------------|\n| `limit` | query | false | Page size |"
+        );
+        assert_eq!(
+            found["responses"],
+            "| Status | Description |\n|--------|-------------|\n| `200` | The users |"
+        );
+        assert_eq!(operation(&spec, "createUser").unwrap()["parameters"], "");
+        assert_eq!(operation(&spec, "deleteUser"), None);
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Component as PathComponent, Path, PathBuf};
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::front_matter;
+use crate::globals;
+use crate::links::extract_parameters;
+use crate::utils::sha256;
+
+/// Manifest describing a pack of templates, at the root of the directory it is packed from.
+pub const MANIFEST: &str = "template-pack.toml";
+/// Directory of the book source directory packs are installed into, one directory per pack.
+pub const PACKS_DIR: &str = "packs";
+/// Lockfile, at the book root, recording the version and source of every installed pack.
+pub const LOCKFILE: &str = "template-packs.lock";
+
+/// Optional features of the preprocessor a pack can depend on, and whether they were compiled in.
+const FEATURES: &[(&str, bool)] = &[("openapi", cfg!(feature = "openapi"))];
+
+/// The `[pack]` table of `template-pack.toml`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    pub name: String,
+    /// Semantic version of the pack, e.g. `1.2.0`
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Files of the pack, relative to the manifest
+    pub templates: Vec<PathBuf>,
+    /// Features of the preprocessor the templates need, e.g. `openapi`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Values available to every template as `[[#<name>.<key>]]`, e.g. `[[#corp.logo]]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// A library of templates bundled into a single `<name>-<version>.pack.toml` file by
+/// `mdbook-template pack`, and unpacked into a book by `mdbook-template install`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Pack {
+    pub pack: Manifest,
+    /// Contents of every template, keyed by its path relative to the manifest
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    fn validate(&self) -> Result<()> {
+        if self.name.len() < 2 || !is_valid_name(&self.name) {
+            bail!(
+                "Invalid pack name \"{}\", only two or more letters, digits, '-' and '_' are allowed",
+                self.name
+            );
+        }
+        if globals::NAMESPACES.contains(&self.name.as_str()) || self.name == "builtin" {
+            bail!(
+                "Invalid pack name \"{}\", it is the name of a builtin namespace",
+                self.name
+            );
+        }
+        Version::parse(&self.version)
+            .with_context(|| format!("Invalid version \"{}\" of {}", self.version, self.name))?;
+        for feature in &self.features {
+            if !FEATURES.iter().any(|(name, _)| name == feature) {
+                bail!(
+                    "Unknown feature {} required by {}, expected one of: {}",
+                    feature,
+                    self.name,
+                    FEATURES
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pack {
+    /// Reads the manifest within `dir` and every template it lists.
+    pub fn from_dir(dir: &Path) -> Result<Pack> {
+        let manifest = dir.join(MANIFEST);
+        let contents = fs::read_to_string(&manifest)
+            .with_context(|| format!("Could not read {}", manifest.display()))?;
+        let pack = toml::from_str::<Pack>(&contents)
+            .with_context(|| format!("Invalid manifest {}", manifest.display()))?
+            .pack;
+        pack.validate()?;
+
+        let mut files = BTreeMap::new();
+        for template in &pack.templates {
+            let key = relative_key(template)?;
+            let path = dir.join(template);
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            files.insert(key, contents);
+        }
+        Ok(Pack { pack, files })
+    }
+
+    /// Reads a bundle written by [`Pack::write`].
+    pub fn read(bundle: &Path) -> Result<Pack> {
+        let contents = fs::read_to_string(bundle)
+            .with_context(|| format!("Could not read {}", bundle.display()))?;
+        let pack = toml::from_str::<Pack>(&contents)
+            .with_context(|| format!("Invalid pack {}", bundle.display()))?;
+        pack.pack.validate()?;
+        for key in pack.files.keys() {
+            relative_key(Path::new(key))?;
+        }
+        Ok(pack)
+    }
+
+    /// Name of the bundle, e.g. `cards-1.0.0.pack.toml`.
+    pub fn file_name(&self) -> String {
+        format!("{}-{}.pack.toml", self.pack.name, self.pack.version)
+    }
+
+    /// Writes the bundle into `dir`, returning its path.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(self.file_name());
+        let contents = toml::to_string_pretty(self)
+            .with_context(|| format!("Could not serialise {}", self.pack.name))?;
+        fs::write(&path, contents)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Unpacks the templates into `packs/<name>` within `src_dir`, alongside the manifest,
+    /// replacing any version of the pack installed before. Returns the directory of the pack.
+    pub fn install(&self, src_dir: &Path) -> Result<PathBuf> {
+        for feature in &self.pack.features {
+            if !FEATURES.contains(&(feature.as_str(), true)) {
+                bail!(
+                    "{} requires the {} feature, which this build of mdbook-template doesn't include",
+                    self.pack.name,
+                    feature
+                );
+            }
+        }
+
+        let dir = src_dir.join(PACKS_DIR).join(&self.pack.name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Could not remove {}", dir.display()))?;
+        }
+        let manifest = toml::to_string_pretty(&Pack {
+            pack: self.pack.clone(),
+            files: BTreeMap::new(),
+        })
+        .with_context(|| format!("Could not serialise {}", self.pack.name))?;
+        for (path, contents) in [(MANIFEST, &manifest)]
+            .into_iter()
+            .chain(self.files.iter().map(|(key, value)| (key.as_str(), value)))
+        {
+            let path = dir.join(path);
+            let parent = path.parent().expect("Files have a parent");
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create {}", parent.display()))?;
+            fs::write(&path, contents)
+                .with_context(|| format!("Could not write {}", path.display()))?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Returns true if `name` only has letters, digits, '-' and '_', as pack names do.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Values of the packs installed within `src_dir`, each under the namespace of its pack, e.g.
+/// `corp.logo`.
+pub(crate) fn variables(src_dir: &Path) -> Result<HashMap<String, String>> {
+    let mut variables = HashMap::new();
+    let packs_dir = src_dir.join(PACKS_DIR);
+    if !packs_dir.is_dir() {
+        return Ok(variables);
+    }
+    let entries = fs::read_dir(&packs_dir)
+        .with_context(|| format!("Could not read {}", packs_dir.display()))?;
+    for entry in entries {
+        let manifest = entry?.path().join(MANIFEST);
+        if !manifest.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&manifest)
+            .with_context(|| format!("Could not read {}", manifest.display()))?;
+        let pack = toml::from_str::<Pack>(&contents)
+            .with_context(|| format!("Invalid manifest {}", manifest.display()))?
+            .pack;
+        for (key, value) in pack.variables {
+            variables.insert(format!("{}.{}", pack.name, key), value);
+        }
+    }
+    Ok(variables)
+}
+
+/// The installed packs, as recorded within `template-packs.lock`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct Lockfile {
+    #[serde(default, rename = "pack")]
+    pub packs: Vec<Locked>,
+}
+
+/// A pack installed into the book.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Locked {
+    pub name: String,
+    pub version: String,
+    /// Bundle the pack was installed from, relative to the book root when within it
+    pub source: PathBuf,
+    /// Hex encoded SHA-256 digest of the bundle
+    pub checksum: String,
+}
+
+impl Lockfile {
+    /// Reads the lockfile of the book at `root`, empty when no pack was installed yet.
+    pub fn load(root: &Path) -> Result<Lockfile> {
+        let path = root.join(LOCKFILE);
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid lockfile {}", path.display()))
+    }
+
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let path = root.join(LOCKFILE);
+        let contents = toml::to_string_pretty(self).context("Could not serialise the lockfile")?;
+        fs::write(&path, contents).with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    /// Records `pack`, installed from `bundle`, in place of any other version of it.
+    pub fn lock(&mut self, root: &Path, pack: &Pack, bundle: &Path) -> Result<()> {
+        let contents =
+            fs::read(bundle).with_context(|| format!("Could not read {}", bundle.display()))?;
+        let locked = Locked {
+            name: pack.pack.name.clone(),
+            version: pack.pack.version.clone(),
+            source: bundle.strip_prefix(root).unwrap_or(bundle).to_path_buf(),
+            checksum: sha256(contents),
+        };
+        self.packs.retain(|locked| locked.name != pack.pack.name);
+        self.packs.push(locked);
+        self.packs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+}
+
+/// Updates every pack recorded within the lockfile of the book at `root` to the newest version
+/// found within `sources`, unless `dry_run`. Returns a report listing, for each update, the
+/// templates and parameters that were added, removed or given a new default.
+pub fn update(root: &Path, src_dir: &Path, sources: &[PathBuf], dry_run: bool) -> Result<String> {
+    let mut lockfile = Lockfile::load(root)?;
+    let mut report = String::new();
+    for locked in lockfile.packs.clone() {
+        let current = Version::parse(&locked.version).with_context(|| {
+            format!("Invalid version \"{}\" of {}", locked.version, locked.name)
+        })?;
+        let (bundle, newer) = match newest(sources, &locked.name, &current)? {
+            Some(newest) => newest,
+            None => {
+                let _ = writeln!(report, "{} {} is up to date", locked.name, locked.version);
+                continue;
+            }
+        };
+        let _ = writeln!(
+            report,
+            "{} {} -> {} ({})",
+            locked.name,
+            locked.version,
+            newer.pack.version,
+            bundle.display()
+        );
+        let installed = Pack::from_dir(&src_dir.join(PACKS_DIR).join(&locked.name))?;
+        for change in changes(&installed, &newer) {
+            let _ = writeln!(report, "    {}", change);
+        }
+        if !dry_run {
+            newer.install(src_dir)?;
+            lockfile.lock(root, &newer, &bundle)?;
+        }
+    }
+    if !dry_run {
+        lockfile.save(root)?;
+    }
+    Ok(report)
+}
+
+/// The bundle of the newest version of `name` within `sources`, if newer than `current`.
+fn newest(sources: &[PathBuf], name: &str, current: &Version) -> Result<Option<(PathBuf, Pack)>> {
+    let mut newest: Option<(Version, PathBuf, Pack)> = None;
+    for source in sources {
+        let entries = fs::read_dir(source)
+            .with_context(|| format!("Could not read the pack source {}", source.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let is_candidate = path
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy())
+                .is_some_and(|file_name| {
+                    file_name.starts_with(&format!("{}-", name))
+                        && file_name.ends_with(".pack.toml")
+                });
+            if !is_candidate {
+                continue;
+            }
+            let pack = Pack::read(&path)?;
+            let version = Version::parse(&pack.pack.version)?;
+            if pack.pack.name == name
+                && version > *current
+                && newest.as_ref().is_none_or(|(newest, ..)| version > *newest)
+            {
+                newest = Some((version, path, pack));
+            }
+        }
+    }
+    Ok(newest.map(|(_, path, pack)| (path, pack)))
+}
+
+/// The templates and parameters added to, removed from or changed within `new` since `old`.
+fn changes(old: &Pack, new: &Pack) -> Vec<String> {
+    let parameters = |contents: &str| {
+        let template = front_matter::split(contents).map_or(contents, |(_, template)| template);
+        let mut parameters = BTreeMap::new();
+        for parameter in extract_parameters(template) {
+            parameters
+                .entry(parameter.name.to_string())
+                .or_insert(parameter.default.map(str::to_string));
+        }
+        parameters
+    };
+
+    let mut changes = Vec::new();
+    for key in old.files.keys().filter(|key| !new.files.contains_key(*key)) {
+        changes.push(format!("- {}", key));
+    }
+    for (key, contents) in &new.files {
+        let old = match old.files.get(key) {
+            Some(old) => parameters(old),
+            None => {
+                changes.push(format!("+ {}", key));
+                continue;
+            }
+        };
+        let new = parameters(contents);
+        for (name, default) in &new {
+            match old.get(name) {
+                None => changes.push(format!("{}: + {}", key, name)),
+                Some(previous) if previous != default => changes.push(format!(
+                    "{}: ~ {} (default {} -> {})",
+                    key,
+                    name,
+                    previous.as_deref().unwrap_or("none"),
+                    default.as_deref().unwrap_or("none")
+                )),
+                Some(_) => {}
+            }
+        }
+        for name in old.keys().filter(|name| !new.contains_key(*name)) {
+            changes.push(format!("{}: - {}", key, name));
+        }
+    }
+    changes
+}
+
+/// The `/` separated key of a file of a pack, which can't point outside of the pack.
+fn relative_key(path: &Path) -> Result<String> {
+    let components = path
+        .components()
+        .map(|component| match component {
+            PathComponent::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+    match components {
+        Some(components) if !components.is_empty() && path != Path::new(MANIFEST) => {
+            Ok(components.join("/"))
+        }
+        _ => bail!(
+            "Invalid template path {}, it must be relative to the pack",
+            path.display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod pack_tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::pack::{relative_key, update, Lockfile, Manifest, Pack};
+    use crate::testing::Fixture;
+
+    fn pack(version: &str, files: &[(&str, &str)]) -> Pack {
+        Pack {
+            pack: Manifest {
+                name: "cards".to_string(),
+                version: version.to_string(),
+                description: None,
+                templates: files.iter().map(|(key, _)| PathBuf::from(key)).collect(),
+                features: Vec::new(),
+                variables: BTreeMap::new(),
+            },
+            files: files
+                .iter()
+                .map(|(key, contents)| (key.to_string(), contents.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_pack_and_install() {
+        let book = Fixture::new("pack")
+            .file(
+                "cards/template-pack.toml",
+                "[pack]\nname = \"cards\"\nversion = \"1.0.0\"\ntemplates = [\"card.md\", \"callouts/note.md\"]\n",
+            )
+            .file("cards/card.md", "<div class=\"card\">\n[[#title]]\n</div>\n")
+            .file("cards/callouts/note.md", "> [[#body]]")
+            .write()
+            .unwrap();
+        let dir = book.root();
+
+        let pack = Pack::from_dir(&dir.join("src/cards")).unwrap();
+        let bundle = pack.write(dir).unwrap();
+        assert_eq!(bundle, dir.join("cards-1.0.0.pack.toml"));
+        assert_eq!(Pack::read(&bundle).unwrap(), pack);
+
+        let installed = pack.install(&dir.join("src")).unwrap();
+        assert_eq!(installed, dir.join("src/packs/cards"));
+        assert_eq!(
+            fs::read_to_string(installed.join("callouts/note.md")).unwrap(),
+            "> [[#body]]"
+        );
+        let manifest = fs::read_to_string(installed.join("template-pack.toml")).unwrap();
+        assert_eq!(toml::from_str::<Pack>(&manifest).unwrap().pack, pack.pack);
+    }
+
+    #[test]
+    fn test_invalid_packs() {
+        let book = Fixture::new("pack-invalid").write().unwrap();
+        let dir = book.root();
+        let read = |contents: &str| {
+            fs::write(dir.join("bundle.pack.toml"), contents).unwrap();
+            Pack::read(&dir.join("bundle.pack.toml")).map_err(|err| format!("{:#}", err))
+        };
+
+        assert!(
+            read("[pack]\nname = \"cards\"\nversion = \"1\"\ntemplates = []\n")
+                .unwrap_err()
+                .starts_with("Invalid version \"1\" of cards")
+        );
+        assert_eq!(
+            read("[pack]\nname = \"cards\"\nversion = \"1.0.0\"\ntemplates = []\nfeatures = [\"gpu\"]\n").unwrap_err(),
+            "Unknown feature gpu required by cards, expected one of: openapi"
+        );
+        assert_eq!(
+            read("[pack]\nname = \"chapter\"\nversion = \"1.0.0\"\ntemplates = []\n").unwrap_err(),
+            "Invalid pack name \"chapter\", it is the name of a builtin namespace"
+        );
+        assert_eq!(
+            read("[pack]\nname = \"cards\"\nversion = \"1.0.0\"\ntemplates = []\n\n[files]\n\"../book.toml\" = \"\"\n").unwrap_err(),
+            "Invalid template path ../book.toml, it must be relative to the pack"
+        );
+        assert_eq!(relative_key(Path::new("a/b.md")).unwrap(), "a/b.md");
+        assert!(relative_key(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let book = Fixture::new("pack-update").write().unwrap();
+        let dir = book.root().to_path_buf();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        let installed = pack(
+            "1.0.0",
+            &[
+                ("card.md", "[[#title]] [[#footer]] [[#width 10]]"),
+                ("old.md", ""),
+            ],
+        );
+        let bundle = installed.write(&dir.join("shared")).unwrap();
+        installed.install(&dir.join("src")).unwrap();
+        let mut lockfile = Lockfile::default();
+        lockfile.lock(&dir, &installed, &bundle).unwrap();
+        lockfile.save(&dir).unwrap();
+        pack(
+            "1.1.0",
+            &[
+                ("card.md", "[[#title]] [[#subtitle]] [[#width 20]]"),
+                ("new.md", ""),
+            ],
+        )
+        .write(&dir.join("shared"))
+        .unwrap();
+        pack("1.0.5", &[]).write(&dir.join("shared")).unwrap();
+        let sources = [dir.join("shared")];
+
+        let report = update(&dir, &dir.join("src"), &sources, true).unwrap();
+        let bundle = dir.join("shared/cards-1.1.0.pack.toml");
+        assert_eq!(
+            report,
+            format!(
+                "cards 1.0.0 -> 1.1.0 ({})\n    - old.md\n    card.md: + subtitle\n    card.md: ~ width (default 10 -> 20)\n    card.md: - footer\n    + new.md\n",
+                bundle.display()
+            )
+        );
+        assert_eq!(Lockfile::load(&dir).unwrap().packs[0].version, "1.0.0");
+
+        update(&dir, &dir.join("src"), &sources, false).unwrap();
+        let locked = &Lockfile::load(&dir).unwrap().packs[0];
+        assert_eq!(locked.version, "1.1.0");
+        assert_eq!(locked.source, Path::new("shared/cards-1.1.0.pack.toml"));
+        assert!(dir.join("src/packs/cards/new.md").exists());
+        assert!(!dir.join("src/packs/cards/old.md").exists());
+        assert_eq!(
+            update(&dir, &dir.join("src"), &sources, false).unwrap(),
+            "cards 1.1.0 is up to date\n"
+        );
+    }
+}
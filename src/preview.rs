@@ -0,0 +1,317 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component as PathComponent, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use mdbook::book::{Book, Chapter};
+use mdbook::errors::Result;
+use mdbook::{BookItem, MDBook};
+
+use crate::config::Symlinks;
+use crate::embedded::Embedded;
+use crate::refactor::book_files;
+use crate::utils::normalize_path;
+
+const RELOAD_PATH: &str = "/__reload";
+// Previews within the same process each get their own build directory
+static PREVIEWS: AtomicUsize = AtomicUsize::new(0);
+// Polls the server for the version of the build, reloading the page once it changes
+const RELOAD_SCRIPT: &str = r#"<script>
+let version;
+setInterval(async () => {
+    const latest = await fetch("/__reload").then((response) => response.text()).catch(() => version);
+    if (version !== undefined && latest !== version) {
+        location.reload();
+    }
+    version = latest;
+}, 1000);
+</script>
+"#;
+
+/// A single template rendered through the HTML pipeline of the book it belongs to, served by
+/// `mdbook-template preview` and rebuilt whenever the book source changes.
+#[derive(Debug)]
+pub struct Preview {
+    root: PathBuf,
+    template: PathBuf,
+    args: Vec<String>,
+    theme: Option<String>,
+    build_dir: PathBuf,
+    built: Option<SystemTime>,
+}
+
+impl Preview {
+    /// Previews `template` of the book at `root`, invoked with `args` such as `title=Demo`.
+    pub fn new<S: AsRef<str>>(root: &Path, template: &Path, args: &[S]) -> Preview {
+        let name = template
+            .file_stem()
+            .map_or("template".into(), |name| name.to_string_lossy());
+        Preview {
+            root: normalize_path(root),
+            template: normalize_path(template),
+            args: args.iter().map(|arg| arg.as_ref().to_string()).collect(),
+            theme: None,
+            build_dir: std::env::temp_dir().join(format!(
+                "mdbook-template-preview-{}-{}-{}",
+                name,
+                std::process::id(),
+                PREVIEWS.fetch_add(1, Ordering::Relaxed)
+            )),
+            built: None,
+        }
+    }
+
+    /// Renders the preview with `theme`, e.g. `ayu`, instead of the default theme of the book,
+    /// which `[[#theme.name]]` follows.
+    pub fn theme(mut self, theme: &str) -> Preview {
+        self.theme = Some(theme.to_string());
+        self
+    }
+
+    /// The chapter the template is rendered within, with the path of the template quoted in case
+    /// it contains whitespace.
+    pub fn invocation(&self) -> String {
+        let mut invocation = format!("{{{{#template \"{}\"", self.template.display());
+        for arg in &self.args {
+            invocation.push(' ');
+            invocation.push_str(arg);
+        }
+        invocation.push_str("}}");
+        invocation
+    }
+
+    /// Builds the book with the template as its only chapter, into a temporary directory so
+    /// the build directory of the book is left alone.
+    pub fn build(&mut self) -> Result<()> {
+        let version = self.version();
+        let mut config = mdbook::Config::from_disk(self.root.join("book.toml"))?;
+        config.build.build_dir = self.build_dir.clone();
+        config.build.create_missing = false;
+        if let Some(ref theme) = self.theme {
+            config.set("output.html.default-theme", theme)?;
+            config.set("output.html.preferred-dark-theme", theme)?;
+        }
+        // Other renderers would only slow down every rebuild
+        if let Some(outputs) = config
+            .get_mut("output")
+            .and_then(|outputs| outputs.as_table_mut())
+        {
+            let others = outputs
+                .keys()
+                .filter(|name| *name != "html")
+                .cloned()
+                .collect::<Vec<_>>();
+            for name in others {
+                outputs.remove(&name);
+            }
+        }
+        let preprocessor = Embedded::take(&mut config);
+
+        let mut book = MDBook::load_with_config(&self.root, config)?;
+        let mut preview = Book::new();
+        preview.push_item(BookItem::Chapter(Chapter::new(
+            "Preview",
+            self.invocation(),
+            "preview.md",
+            Vec::new(),
+        )));
+        book.book = preview;
+        book.with_preprocessor(preprocessor);
+        book.build()?;
+        self.built = Some(version);
+        Ok(())
+    }
+
+    /// The newest modification time of the template and the files within the book source
+    /// directory, which tells when the preview is out of date.
+    fn version(&self) -> SystemTime {
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+        let src_dir = mdbook::Config::from_disk(self.root.join("book.toml"))
+            .map_or(self.root.join("src"), |config| {
+                self.root.join(config.book.src)
+            });
+        let extensions = ["md", "toml", "json", "yaml", "yml", "css", "js"];
+        book_files(&src_dir, &extensions, Symlinks::default())
+            .unwrap_or_default()
+            .into_iter()
+            .chain([self.template.clone(), self.root.join("book.toml")])
+            .filter_map(|path| modified(&path).ok())
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Status, content type and body of the response to a `GET` of `path`.
+    pub fn respond(&mut self, path: &str) -> (u16, &'static str, Vec<u8>) {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        if path == RELOAD_PATH {
+            let version = self.version();
+            if self.built != Some(version) {
+                if let Err(err) = self.build() {
+                    log::error!("Could not rebuild the preview: {:#}", err);
+                }
+            }
+            let built = self
+                .built
+                .and_then(|built| built.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            return (
+                200,
+                "text/plain",
+                built.as_millis().to_string().into_bytes(),
+            );
+        }
+
+        let relative = match path.trim_start_matches('/') {
+            "" => Path::new("preview.html"),
+            relative => Path::new(relative),
+        };
+        // Never serve anything outside of the build directory
+        if !relative
+            .components()
+            .all(|component| matches!(component, PathComponent::Normal(_)))
+        {
+            return (404, "text/plain", b"Not Found".to_vec());
+        }
+        match fs::read(self.build_dir.join(relative)) {
+            Ok(body)
+                if relative
+                    .extension()
+                    .is_some_and(|extension| extension == "html") =>
+            {
+                let page = String::from_utf8_lossy(&body).replacen(
+                    "</body>",
+                    &format!("{}</body>", RELOAD_SCRIPT),
+                    1,
+                );
+                (200, "text/html; charset=utf-8", page.into_bytes())
+            }
+            Ok(body) => (200, content_type(relative), body),
+            Err(_) => (404, "text/plain", b"Not Found".to_vec()),
+        }
+    }
+
+    /// Serves the preview at `address`, e.g. `127.0.0.1:3001`, until the process is stopped.
+    pub fn serve(&mut self, address: &str) -> Result<()> {
+        self.build()?;
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("Could not listen on {}", address))?;
+        log::info!(
+            "Previewing {} at http://{}",
+            self.template.display(),
+            address
+        );
+        for stream in listener.incoming() {
+            if let Err(err) = stream
+                .map_err(Into::into)
+                .and_then(|stream| self.handle(stream))
+            {
+                log::warn!("Could not answer a request, {:#}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Unread headers would reset the connection once it is closed
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let (status, content_type, body) =
+            match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["GET", path, ..] => self.respond(path),
+                _ => (405, "text/plain", b"Method Not Allowed".to_vec()),
+            };
+        let reason = match status {
+            200 => "OK",
+            404 => "Not Found",
+            _ => "Method Not Allowed",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            status,
+            reason,
+            content_type,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        Ok(())
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.build_dir);
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+    {
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod preview_tests {
+    use crate::preview::Preview;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_preview() {
+        // Within a directory whose name has a space, which the path of the template keeps
+        let book = Fixture::new("preview book")
+            .book_toml("[book]\ntitle = \"Preview\"\n\n[output.html]\n\n[output.epub]\n")
+            .chapter("intro.md", "# Intro")
+            .file(
+                "templates/card.md",
+                "<div class=\"card\">[[#title]] [[#body]]</div>\n<p>[[#theme.name]]</p>",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root();
+
+        let mut preview = Preview::new(
+            dir,
+            &dir.join("src/templates/card.md"),
+            &["title=Demo", "body=Hello there"],
+        )
+        .theme("ayu");
+        assert!(preview
+            .invocation()
+            .ends_with("card.md\" title=Demo body=Hello there}}"));
+        preview.build().unwrap();
+
+        let (status, content_type, body) = preview.respond("/");
+        let page = String::from_utf8(body).unwrap();
+        assert_eq!((status, content_type), (200, "text/html; charset=utf-8"));
+        assert!(page.contains("<div class=\"card\">Demo Hello there</div>\n<p>ayu</p>"));
+        assert!(page.contains("class=\"sidebar-visible no-js ayu\""));
+        assert!(page.contains("fetch(\"/__reload\")"));
+        assert_eq!(preview.respond("/css/general.css").1, "text/css");
+        assert_eq!(preview.respond("/../book.toml").0, 404);
+        assert_eq!(preview.respond("/missing.html").0, 404);
+        assert!(!dir.join("book").exists());
+
+        let (_, _, version) = preview.respond("/__reload");
+        assert_eq!(preview.respond("/__reload").2, version);
+    }
+}
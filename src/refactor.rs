@@ -0,0 +1,566 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+
+use crate::config::{Config, Symlinks};
+use crate::links::{self, LinkType};
+use crate::pack::PACKS_DIR;
+use crate::utils::{self, normalize_path, BUILTIN_PREFIX, CHAPTER_PREFIX};
+
+/// Moves the file `from` to `to`, rewriting every invocation of it within the book source
+/// directory as well as the relative invocations within the file itself. Returns the files that
+/// were rewritten, nothing is written unless every file could be read.
+pub fn move_template(config: &Config, from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    let (from, to) = (normalize_path(from), normalize_path(to));
+    if !from.is_file() {
+        bail!("{} is not a file", from.display());
+    }
+    if to.exists() {
+        bail!("{} already exists", to.display());
+    }
+
+    let keywords = config.keywords();
+    let mut rewrites = Vec::new();
+    for file in book_files(&config.src_dir, &config.extensions, config.symlinks)? {
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let moved = file == from;
+        let base = file.parent().expect("Files have a parent");
+        let new_base = match moved {
+            true => to.parent().expect("Files have a parent"),
+            false => base,
+        };
+
+        let mut replaced = String::with_capacity(contents.len());
+        let mut previous_end_index = 0;
+        for link in links::extract_template_links(&contents)
+            .keywords(&keywords)
+            .directives(&config.directives)
+            .src_dir(&config.src_dir)
+        {
+            let (span, target) = match (link.file_span(), link.link_type.target(base)) {
+                (Some(span), Some(target)) if moved || target == from => (span, target),
+                _ => continue,
+            };
+            let new_target = match target == from {
+                true => &to,
+                false => &target,
+            };
+            let path = match link.link_type {
+                LinkType::Escaped => continue,
+                LinkType::Template(ref path)
+                    if path.to_string_lossy().starts_with(BUILTIN_PREFIX) =>
+                {
+                    continue
+                }
+                LinkType::Template(_)
+                | LinkType::Data(_)
+                | LinkType::Changelog(_)
+                | LinkType::Diagram(_) => relative(new_target, new_base),
+                // Neither depends on where the invoking file is
+                LinkType::Rooted(..) | LinkType::Chapter(_) | LinkType::Pack(..)
+                    if target != from =>
+                {
+                    continue
+                }
+                LinkType::Rooted(ref keyword, _) => {
+                    let root = &config.directives[keyword].root;
+                    match to.strip_prefix(normalize_path(root)) {
+                        Ok(path) => relative(path, Path::new("")),
+                        Err(_) => bail!(
+                            "{} is invoked with {{{{#{} ...}}}}, so it must stay within {}",
+                            from.display(),
+                            keyword,
+                            root.display()
+                        ),
+                    }
+                }
+                LinkType::Pack(ref name, _) => {
+                    let root = config.src_dir.join(PACKS_DIR).join(name);
+                    match to.strip_prefix(normalize_path(&root)) {
+                        Ok(path) => format!("{}:{}", name, relative(path, Path::new(""))),
+                        Err(_) => bail!(
+                            "{} is invoked as a template of the {} pack, so it must stay within {}",
+                            from.display(),
+                            name,
+                            root.display()
+                        ),
+                    }
+                }
+                LinkType::Chapter(_) => match to.strip_prefix(normalize_path(&config.src_dir)) {
+                    Ok(path) => format!("{}{}", CHAPTER_PREFIX, relative(path, Path::new(""))),
+                    Err(_) => bail!(
+                        "{} is invoked as a chapter, so it must stay within {}",
+                        from.display(),
+                        config.src_dir.display()
+                    ),
+                },
+            };
+            if contents[span.clone()] != path {
+                replaced.push_str(&contents[previous_end_index..span.start]);
+                replaced.push_str(&path);
+                previous_end_index = span.end;
+            }
+        }
+        if previous_end_index == 0 && !moved {
+            continue;
+        }
+        replaced.push_str(&contents[previous_end_index..]);
+        rewrites.push((if moved { to.clone() } else { file }, replaced));
+    }
+
+    let parent = to.parent().expect("Files have a parent");
+    fs::create_dir_all(parent).with_context(|| format!("Could not create {}", parent.display()))?;
+    fs::rename(&from, &to)
+        .with_context(|| format!("Could not move {} to {}", from.display(), to.display()))?;
+    let mut rewritten = Vec::with_capacity(rewrites.len());
+    for (file, contents) in rewrites {
+        fs::write(&file, contents)
+            .with_context(|| format!("Could not write {}", file.display()))?;
+        rewritten.push(file);
+    }
+    Ok(rewritten)
+}
+
+/// Renames the argument `from` of `template` to `to`, both its placeholders within the template
+/// and the arguments given at every call site within the book source directory. Returns the files
+/// that were rewritten, nothing is written unless every file could be read.
+pub fn rename_arg(config: &Config, template: &Path, from: &str, to: &str) -> Result<Vec<PathBuf>> {
+    let template = normalize_path(template);
+    if !template.is_file() {
+        bail!("{} is not a file", template.display());
+    }
+    if to.is_empty()
+        || !to
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        bail!(
+            "Invalid argument name \"{}\", only letters, digits, '-', '_' and '.' are allowed",
+            to
+        );
+    }
+
+    let keywords = config.keywords();
+    let mut rewrites = Vec::new();
+    for file in book_files(&config.src_dir, &config.extensions, config.symlinks)? {
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let base = file.parent().expect("Files have a parent");
+
+        let mut spans = links::extract_template_links(&contents)
+            .keywords(&keywords)
+            .directives(&config.directives)
+            .src_dir(&config.src_dir)
+            .filter(|link| link.link_type.target(base).as_ref() == Some(&template))
+            .flat_map(|link| link.arg_spans(from))
+            .collect::<Vec<_>>();
+        if file == template {
+            spans.extend(
+                links::extract_parameters(&contents)
+                    .into_iter()
+                    .filter(|parameter| parameter.name == from)
+                    .map(|parameter| {
+                        let start = parameter.name.as_ptr() as usize - contents.as_ptr() as usize;
+                        start..start + parameter.name.len()
+                    }),
+            );
+            // Conditions must keep testing the argument they tested before
+            spans.extend(
+                links::condition_names(&contents)
+                    .into_iter()
+                    .filter(|(_, name)| *name == from)
+                    .map(|(span, _)| span),
+            );
+        }
+        if spans.is_empty() {
+            continue;
+        }
+
+        spans.sort_by_key(|span| span.start);
+        let mut replaced = String::with_capacity(contents.len());
+        let mut previous_end_index = 0;
+        for span in spans {
+            replaced.push_str(&contents[previous_end_index..span.start]);
+            replaced.push_str(to);
+            previous_end_index = span.end;
+        }
+        replaced.push_str(&contents[previous_end_index..]);
+        rewrites.push((file, replaced));
+    }
+
+    let mut rewritten = Vec::with_capacity(rewrites.len());
+    for (file, contents) in rewrites {
+        fs::write(&file, contents)
+            .with_context(|| format!("Could not write {}", file.display()))?;
+        rewritten.push(file);
+    }
+    Ok(rewritten)
+}
+
+/// Every chapter and template within the book source directory invoking `template`, directly or
+/// through other templates, found by following the invocations of each file back to the files
+/// invoking it.
+pub fn rdeps(config: &Config, template: &Path) -> Result<Vec<PathBuf>> {
+    let template = normalize_path(template);
+    let keywords = config.keywords();
+    let mut dependents = HashMap::<PathBuf, BTreeSet<PathBuf>>::new();
+    for file in book_files(&config.src_dir, &config.extensions, config.symlinks)? {
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let base = file.parent().expect("Files have a parent");
+        for link in links::extract_template_links(&contents)
+            .keywords(&keywords)
+            .directives(&config.directives)
+            .src_dir(&config.src_dir)
+        {
+            if let Some(target) = link.link_type.target(base) {
+                dependents
+                    .entry(normalize_path(target))
+                    .or_default()
+                    .insert(file.clone());
+            }
+        }
+    }
+
+    let mut found = BTreeSet::new();
+    let mut pending = vec![template.clone()];
+    while let Some(file) = pending.pop() {
+        for dependent in dependents.get(&file).into_iter().flatten() {
+            if found.insert(dependent.clone()) {
+                pending.push(dependent.clone());
+            }
+        }
+    }
+    // Templates invoking themselves, e.g. through a cycle, don't depend on themselves
+    found.remove(&template);
+    Ok(found.into_iter().collect())
+}
+
+/// Every chapter and template within `dir` and its subdirectories with one of the `extensions`.
+/// Hidden directories are skipped, symlinked ones followed as `symlinks` allows.
+pub(crate) fn book_files<S: AsRef<str>>(
+    dir: &Path,
+    extensions: &[S],
+    symlinks: Symlinks,
+) -> Result<Vec<PathBuf>> {
+    let mut files = utils::walk(dir, symlinks)?
+        .into_iter()
+        .filter(|path| {
+            path.extension().is_some_and(|extension| {
+                extensions
+                    .iter()
+                    .any(|known| extension.to_string_lossy() == known.as_ref())
+            })
+        })
+        .map(normalize_path)
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// `path` relative to the directory `base`, always separated by `/` so books build anywhere.
+fn relative(path: &Path, base: &Path) -> String {
+    let path = path.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    let common = path
+        .iter()
+        .zip(&base)
+        .take_while(|(path, base)| path == base)
+        .count();
+    let mut parts = vec!["..".to_string(); base.len() - common];
+    parts.extend(
+        path[common..]
+            .iter()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod refactor_tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::config::{Config, Symlinks};
+    use crate::refactor::{book_files, move_template, rdeps, relative, rename_arg};
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(
+            relative(
+                Path::new("/book/src/templates/footer.md"),
+                Path::new("/book/src")
+            ),
+            "templates/footer.md"
+        );
+        assert_eq!(
+            relative(
+                Path::new("/book/src/templates/footer.md"),
+                Path::new("/book/src/guide/install")
+            ),
+            "../../templates/footer.md"
+        );
+    }
+
+    #[test]
+    fn test_move_template() {
+        let book = Fixture::new("move")
+            .file(
+                "intro.md",
+                "{{#template templates/footer.md#credits year=2022}} {{#template templates/header.md}}",
+            )
+            .file(
+                "guide/install.md",
+                "{{#template\n  ../templates/footer.md\n  year=2022\n}} \\{{#template ../templates/footer.md}}",
+            )
+            .file(
+                "templates/footer.md",
+                "{{#template header.md}} {{#template builtin:note text=Hi}}",
+            )
+            .file("templates/header.md", "# Header")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        let rewritten = move_template(
+            &config,
+            &dir.join("templates/footer.md"),
+            &dir.join("templates/components/footer.md"),
+        )
+        .unwrap();
+
+        assert_eq!(rewritten.len(), 3);
+        assert!(!dir.join("templates/footer.md").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("intro.md")).unwrap(),
+            "{{#template templates/components/footer.md#credits year=2022}} {{#template templates/header.md}}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("guide/install.md")).unwrap(),
+            "{{#template\n  ../templates/components/footer.md\n  year=2022\n}} \\{{#template ../templates/footer.md}}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("templates/components/footer.md")).unwrap(),
+            "{{#template ../header.md}} {{#template builtin:note text=Hi}}"
+        );
+        assert!(move_template(
+            &config,
+            &dir.join("templates/header.md"),
+            &dir.join("intro.md")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_rename_arg() {
+        let book = Fixture::new("rename-arg")
+            .file(
+                "intro.md",
+                "{{#template templates/footer.md authors=Hazel authors+=Goudham}} {{#template templates/header.md authors=Hazel}}",
+            )
+            .file(
+                "templates/footer.md",
+                "By [[#authors]], [[#authors-title Authors]] [[#authors Nobody]] \\[[#authors]]",
+            )
+            .file("templates/header.md", "[[#authors]]")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        let rewritten = rename_arg(
+            &config,
+            &dir.join("templates/footer.md"),
+            "authors",
+            "writers",
+        )
+        .unwrap();
+
+        assert_eq!(rewritten.len(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("intro.md")).unwrap(),
+            "{{#template templates/footer.md writers=Hazel writers+=Goudham}} {{#template templates/header.md authors=Hazel}}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("templates/footer.md")).unwrap(),
+            "By [[#writers]], [[#authors-title Authors]] [[#writers Nobody]] \\[[#authors]]"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("templates/header.md")).unwrap(),
+            "[[#authors]]"
+        );
+        assert!(rename_arg(&config, &dir.join("templates/footer.md"), "writers", "a b").is_err());
+    }
+
+    #[test]
+    fn test_rename_arg_conditions() {
+        let book = Fixture::new("rename-arg-conditions")
+            .file("intro.md", "{{#template t.md beta=1}}")
+            .file(
+                "t.md",
+                "[[#if alpha]]A[[#else-if beta]]B [[#beta]] [[#else]]C[[/if]] [[#if beta-x]]X[[/if]]",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        rename_arg(&config, &dir.join("t.md"), "beta", "gamma").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("intro.md")).unwrap(),
+            "{{#template t.md gamma=1}}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("t.md")).unwrap(),
+            "[[#if alpha]]A[[#else-if gamma]]B [[#gamma]] [[#else]]C[[/if]] [[#if beta-x]]X[[/if]]"
+        );
+
+        rename_arg(&config, &dir.join("t.md"), "alpha", "delta").unwrap();
+
+        assert!(fs::read_to_string(dir.join("t.md"))
+            .unwrap()
+            .starts_with("[[#if delta]]A"));
+    }
+
+    #[test]
+    fn test_rename_arg_unless() {
+        let book = Fixture::new("rename-arg-unless")
+            .file("intro.md", "{{#template t.md beta=1}}")
+            .file(
+                "t.md",
+                "[[#unless beta]]No beta[[/unless]] [[#unless betas]]-[[/unless]]",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        rename_arg(&config, &dir.join("t.md"), "beta", "gamma").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("t.md")).unwrap(),
+            "[[#unless gamma]]No beta[[/unless]] [[#unless betas]]-[[/unless]]"
+        );
+    }
+
+    #[test]
+    fn test_rename_arg_match() {
+        let book = Fixture::new("rename-arg-match")
+            .file("intro.md", "{{#template t.md beta=beta}}")
+            .file(
+                "t.md",
+                "[[#match beta]][[#case beta]]B [[#default]]D [[/match]]",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        rename_arg(&config, &dir.join("t.md"), "beta", "gamma").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("intro.md")).unwrap(),
+            "{{#template t.md gamma=beta}}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("t.md")).unwrap(),
+            "[[#match gamma]][[#case beta]]B [[#default]]D [[/match]]"
+        );
+    }
+
+    #[test]
+    fn test_rdeps() {
+        let book = Fixture::new("rdeps")
+            .file("intro.md", "{{#template templates/page.md}}")
+            .file(
+                "guide/install.md",
+                "{{#template ../templates/footer.md}} \\{{#template ../templates/header.md}}",
+            )
+            .file("other.md", "{{#template templates/header.md}}")
+            .file(
+                "templates/page.md",
+                "{{#template footer.md}} {{#template page.md}}",
+            )
+            .file("templates/footer.md", "Footer")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        let dependents = rdeps(&config, &dir.join("templates/footer.md")).unwrap();
+
+        assert_eq!(
+            dependents,
+            [
+                dir.join("guide/install.md"),
+                dir.join("intro.md"),
+                dir.join("templates/page.md"),
+            ]
+        );
+        assert_eq!(
+            rdeps(&config, &dir.join("templates/page.md")).unwrap(),
+            [dir.join("intro.md")]
+        );
+        assert!(rdeps(&config, &dir.join("intro.md")).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_book_files_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let book = Fixture::new("book-files-symlinks")
+            .file("intro.md", "{{#template templates/footer.md}}")
+            .file("guide/install.md", "{{#template ../templates/footer.md}}")
+            .file("templates/footer.md", "Footer")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        // Each link leads back to the whole book, which is only walked once
+        symlink("..", dir.join("guide/up")).unwrap();
+        symlink("..", dir.join("templates/up")).unwrap();
+        let config = Config {
+            src_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            book_files(&dir, &config.extensions, Symlinks::Follow).unwrap(),
+            [
+                dir.join("SUMMARY.md"),
+                dir.join("guide/install.md"),
+                dir.join("intro.md"),
+                dir.join("templates/footer.md"),
+            ]
+        );
+        assert_eq!(
+            rdeps(&config, &dir.join("templates/footer.md")).unwrap(),
+            [dir.join("guide/install.md"), dir.join("intro.md")]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use mdbook::errors::Result;
+
+/// A new template, created by `mdbook-template new component`, so every component of a book
+/// starts out the same way.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Component {
+    name: String,
+    args: Vec<String>,
+}
+
+impl Component {
+    pub fn new<S: AsRef<str>>(name: &str, args: &[S]) -> Result<Component> {
+        let args = args
+            .iter()
+            .map(|arg| arg.as_ref().trim().to_string())
+            .filter(|arg| !arg.is_empty())
+            .collect::<Vec<_>>();
+        for name in args.iter().map(String::as_str).chain([name]) {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!(
+                    "Invalid name \"{}\", only letters, digits, '-' and '_' are allowed",
+                    name
+                );
+            }
+        }
+        Ok(Component {
+            name: name.to_string(),
+            args,
+        })
+    }
+
+    /// Contents of the template file, with front matter and a placeholder for every argument.
+    pub fn template(&self) -> String {
+        let mut template = String::from(
+            "+++\n# CSS and JavaScript snippets the component depends on, injected once per chapter\ncss = []\njs = []\n+++\n",
+        );
+        template.push_str(&format!("<div class=\"{}\">\n", self.name));
+        for arg in &self.args {
+            template.push_str(&format!("\n[[#{}]]\n", arg));
+        }
+        template.push_str("\n</div>\n");
+        template
+    }
+
+    /// Invocation of the component from a chapter, given the `path` of its template relative to
+    /// the chapter.
+    pub fn invocation(&self, path: &Path) -> String {
+        let mut invocation = format!("{{{{#template {}", path.display());
+        for arg in &self.args {
+            invocation.push_str(&format!(" {}=", arg));
+        }
+        invocation.push_str("}}");
+        invocation
+    }
+
+    /// Writes the template into `dir`, refusing to overwrite an existing one.
+    pub fn create(&self, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(format!("{}.md", self.name));
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+        fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+        fs::write(&path, self.template())
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod scaffold_tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::front_matter;
+    use crate::links::extract_parameters;
+    use crate::scaffold::Component;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_component() {
+        let component = Component::new("card", &["title", " body", "link", ""]).unwrap();
+
+        let template = component.template();
+        let (_, rest) = front_matter::split(&template).unwrap();
+        assert_eq!(
+            extract_parameters(rest)
+                .iter()
+                .map(|parameter| parameter.name)
+                .collect::<Vec<_>>(),
+            vec!["title", "body", "link"]
+        );
+        assert_eq!(
+            component.invocation(Path::new("templates/card.md")),
+            "{{#template templates/card.md title= body= link=}}"
+        );
+        assert!(Component::new("my card", &["title"]).is_err());
+        assert!(Component::new("card", &["title}}"]).is_err());
+    }
+
+    #[test]
+    fn test_create() {
+        let book = Fixture::new("scaffold").write().unwrap();
+        let dir = book.root();
+        let component = Component::new("card", &["title"]).unwrap();
+
+        let path = component.create(&dir.join("templates")).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), component.template());
+        assert!(component.create(&dir.join("templates")).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::ops::Range;
+
+use crate::links::{CHANGELOG_DIRECTIVE, DATA_SUFFIX, DIAGRAM_DIRECTIVE};
+
+// Keywords expanded regardless of the configured directives
+const BUILTIN_KEYWORDS: &[&str] = &[CHANGELOG_DIRECTIVE, DIAGRAM_DIRECTIVE];
+
+/// Finds the next opening at or after `position`, walking `contents` once. An opening is either
+/// an invocation of one of the `keywords`, e.g. `{{#template `, or one of the escapes `\\`
+/// (before a `{{`), `\{{` and `{{\#`.
+pub(crate) fn find(contents: &str, position: usize, keywords: &[&str]) -> Option<Range<usize>> {
+    let mut index = position;
+    while let Some(offset) = contents[index..].find(['{', '\\']) {
+        let start = index + offset;
+        if let Some(end) = opening_at(contents, start, keywords) {
+            return Some(start..end);
+        }
+        index = start + 1;
+    }
+    None
+}
+
+/// Returns the end of the opening starting at `start`, if there is one.
+fn opening_at(contents: &str, start: usize, keywords: &[&str]) -> Option<usize> {
+    let rest = &contents[start..];
+    // This looks like \\{{, an escaped backslash before an opening parens
+    if let Some(after) = rest.strip_prefix(r"\\") {
+        return after
+            .trim_start_matches('\\')
+            .starts_with("{{")
+            .then_some(start + 2);
+    }
+    // This looks like \{{
+    if rest.starts_with(r"\{{") {
+        return Some(start + 3);
+    }
+
+    let inner = rest.strip_prefix("{{")?.trim_start();
+    // This looks like {{\#
+    if inner.starts_with(r"\#") {
+        return Some(end_of(contents, inner) + 2);
+    }
+    // This looks like {{#template followed by whitespace
+    let name = inner.strip_prefix('#')?;
+    let length = name
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(name.len());
+    if !is_keyword(&name[..length], keywords) {
+        return None;
+    }
+    let separator = &name[length..];
+    let args = separator.trim_start();
+    (args.len() < separator.len()).then(|| end_of(contents, args))
+}
+
+fn is_keyword(name: &str, keywords: &[&str]) -> bool {
+    keywords.contains(&name)
+        || BUILTIN_KEYWORDS.contains(&name)
+        || name
+            .strip_suffix(DATA_SUFFIX)
+            .is_some_and(|keyword| keywords.contains(&keyword))
+}
+
+/// Byte index within `contents` of the start of its suffix `rest`.
+fn end_of(contents: &str, rest: &str) -> usize {
+    contents.len() - rest.len()
+}
+
+#[cfg(test)]
+mod scanner_tests {
+    use crate::scanner::find;
+
+    fn openings<'a>(contents: &'a str, keywords: &[&str]) -> Vec<&'a str> {
+        let mut openings = Vec::new();
+        let mut position = 0;
+        while let Some(opening) = find(contents, position, keywords) {
+            position = opening.end;
+            openings.push(&contents[opening]);
+        }
+        openings
+    }
+
+    #[test]
+    fn test_find_invocations() {
+        let contents = "{{#template a.md}} {{ #template-data b.json}} {{#changelog c.md}} {{#diagram d.mmd}} {{#templates e.md}} {{#template}} {{#include f.md}}";
+
+        assert_eq!(
+            openings(contents, &["template"]),
+            vec![
+                "{{#template ",
+                "{{ #template-data ",
+                "{{#changelog ",
+                "{{#diagram "
+            ]
+        );
+        assert_eq!(
+            openings(contents, &["component"]),
+            vec!["{{#changelog ", "{{#diagram "]
+        );
+        assert_eq!(
+            openings(
+                "{{#component\n  a.md}} {{#component-data b.json}}",
+                &["component"]
+            ),
+            vec!["{{#component\n  ", "{{#component-data "]
+        );
+    }
+
+    #[test]
+    fn test_find_escapes() {
+        assert_eq!(
+            openings(
+                r"\{{#template a.md}} {{ \#template}} \\{{#template b.md}}",
+                &["template"]
+            ),
+            vec![r"\{{", r"{{ \#", r"\\", "{{#template "]
+        );
+        assert_eq!(
+            openings(r"\\\{{#template a.md}} C:\\path \\\\", &["template"]),
+            vec![r"\\", r"\{{"]
+        );
+    }
+
+    #[test]
+    fn test_find_multibyte() {
+        assert_eq!(
+            openings("héllo {{#template ü.md}}", &["template"]),
+            vec!["{{#template "]
+        );
+        assert_eq!(find("{{#template ü.md", 7, &["template"]), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use mdbook::utils::unique_id_from_content;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+struct Heading {
+    level: usize,
+    range: Range<usize>,
+    id: String,
+    text: String,
+}
+
+/// Returns the part of `contents` below the heading named `name`, up until the next heading of
+/// the same or a higher level. A heading is named by its anchor, as generated by mdbook, or by
+/// its text regardless of case.
+pub(crate) fn extract<'a>(contents: &'a str, name: &str) -> Option<&'a str> {
+    extract_by(contents, |heading| {
+        heading.id == name || heading.text.eq_ignore_ascii_case(name)
+    })
+}
+
+/// Returns the changes of the release `version` within a Keep a Changelog style `contents`,
+/// whose headings look like `## [1.2.0] - 2019-02-15` or `## [Unreleased]`.
+pub(crate) fn extract_release<'a>(contents: &'a str, version: &str) -> Option<&'a str> {
+    let version = version.trim_start_matches('v');
+    extract_by(contents, |heading| {
+        let release = heading
+            .text
+            .trim_start()
+            .trim_start_matches('[')
+            .trim_start_matches('v');
+        match release.get(..version.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(version) => release[version.len()..]
+                .chars()
+                .next()
+                .is_none_or(|next| matches!(next, ']' | ' ' | '-')),
+            _ => false,
+        }
+    })
+}
+
+fn extract_by<F>(contents: &str, matches: F) -> Option<&str>
+where
+    F: Fn(&Heading) -> bool,
+{
+    let headings = headings(contents);
+    let index = headings.iter().position(matches)?;
+    let heading = &headings[index];
+    let end = headings[index + 1..]
+        .iter()
+        .find(|next| next.level <= heading.level)
+        .map_or(contents.len(), |next| next.range.start);
+
+    Some(&contents[heading.range.end..end])
+}
+
+fn headings(contents: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    // Repeated headings get a numbered suffix, e.g. `example-1`, just like mdbook's anchors
+    let mut ids = HashMap::new();
+    let mut current: Option<(usize, Range<usize>, Option<String>, String)> = None;
+
+    // The same extensions mdbook renders chapters with
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    for (event, range) in Parser::new_ext(contents, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading(level, id, _)) => {
+                current = Some((level as usize, range, id.map(str::to_owned), String::new()))
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, _, _, ref mut heading)) = current {
+                    heading.push_str(&text);
+                }
+            }
+            Event::End(Tag::Heading(..)) => {
+                if let Some((level, range, id, text)) = current.take() {
+                    let unique_id = unique_id_from_content(&text, &mut ids);
+                    headings.push(Heading {
+                        level,
+                        range,
+                        // An explicit `{#id}` attribute replaces the generated anchor
+                        id: id.unwrap_or(unique_id),
+                        text,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+#[cfg(test)]
+mod sections_tests {
+    use crate::sections::{extract, extract_release};
+
+    const GUIDE: &str = "# Guide
+
+Intro
+
+## Install Rust
+
+Use rustup.
+
+```sh
+# not a heading
+```
+
+### On Windows
+
+Use the installer.
+
+## Configure
+
+Edit book.toml.
+
+## Install Rust
+
+Again.
+";
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(
+            extract(GUIDE, "install-rust"),
+            Some(
+                "\nUse rustup.\n\n```sh\n# not a heading\n```\n\n### On Windows\n\nUse the installer.\n\n"
+            )
+        );
+        assert_eq!(extract(GUIDE, "configure"), Some("\nEdit book.toml.\n\n"));
+        assert_eq!(extract(GUIDE, "install-rust-1"), Some("\nAgain.\n"));
+        assert_eq!(
+            extract(GUIDE, "on-windows"),
+            Some("\nUse the installer.\n\n")
+        );
+    }
+
+    #[test]
+    fn test_extract_by_text() {
+        let notes = "Troubleshooting\n===============\nRestart it.\n\n# `mdbook` Errors {#errors}\nRead the logs.\n";
+
+        assert_eq!(extract(notes, "troubleshooting"), Some("Restart it.\n\n"));
+        assert_eq!(extract(notes, "Troubleshooting"), Some("Restart it.\n\n"));
+        assert_eq!(extract(notes, "errors"), Some("Read the logs.\n"));
+        assert_eq!(extract(notes, "mdbook errors"), Some("Read the logs.\n"));
+    }
+
+    #[test]
+    fn test_extract_release() {
+        let changelog = "# Changelog
+
+## [Unreleased]
+
+- Trimming
+
+## [1.2.0] - 2023-02-15
+
+### Added
+
+- Presets
+
+## [1.2] - 2023-01-01
+
+- Tabs
+
+[1.2.0]: https://github.com/sgoudham/mdbook-template/releases/tag/v1.2.0
+";
+
+        assert_eq!(
+            extract_release(changelog, "1.2.0"),
+            Some("\n### Added\n\n- Presets\n\n")
+        );
+        assert_eq!(
+            extract_release(changelog, "v1.2.0"),
+            Some("\n### Added\n\n- Presets\n\n")
+        );
+        assert_eq!(
+            extract_release(changelog, "unreleased"),
+            Some("\n- Trimming\n\n")
+        );
+        assert_eq!(
+            extract_release(changelog, "1.2"),
+            Some(
+                "\n- Tabs\n\n[1.2.0]: https://github.com/sgoudham/mdbook-template/releases/tag/v1.2.0\n"
+            )
+        );
+        assert_eq!(extract_release(changelog, "1.1.0"), None);
+    }
+
+    #[test]
+    fn test_extract_missing() {
+        assert_eq!(extract(GUIDE, "not-a-heading"), None);
+        assert_eq!(extract(GUIDE, "uninstall"), None);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Every template expanded while processing the book.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Stats {
+    expansions: Vec<Expansion>,
+    sources: Vec<(PathBuf, Source)>,
+    failures: Vec<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) struct Expansion {
+    pub(crate) template: PathBuf,
+    pub(crate) chapter: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) duration: Duration,
+    /// How deeply the expansion is nested, 1 for templates invoked by the chapter itself
+    pub(crate) depth: usize,
+}
+
+/// Content from outside the book, such as the output of an external command.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(crate) struct Source {
+    pub(crate) provider: String,
+    pub(crate) input: PathBuf,
+    pub(crate) input_sha256: String,
+    pub(crate) output_sha256: String,
+}
+
+impl Stats {
+    pub(crate) fn record(&mut self, expansion: Expansion) {
+        self.expansions.push(expansion);
+    }
+
+    pub(crate) fn record_source<P: AsRef<Path>>(&mut self, chapter: P, source: Source) {
+        self.sources.push((chapter.as_ref().to_path_buf(), source));
+    }
+
+    pub(crate) fn record_failure(&mut self, failure: String) {
+        self.failures.push(failure);
+    }
+
+    /// Adds everything recorded in `other`, e.g. while expanding a single chapter.
+    pub(crate) fn extend(&mut self, other: Stats) {
+        self.expansions.extend(other.expansions);
+        self.sources.extend(other.sources);
+        self.failures.extend(other.failures);
+    }
+
+    /// Number of templates expanded.
+    pub(crate) fn expanded(&self) -> usize {
+        self.expansions.len()
+    }
+
+    /// Every template expanded within `chapter`, including nested ones.
+    pub fn templates<P: AsRef<Path>>(&self, chapter: P) -> BTreeSet<PathBuf> {
+        self.expansions
+            .iter()
+            .filter(|expansion| expansion.chapter == chapter.as_ref())
+            .map(|expansion| expansion.template.clone())
+            .collect()
+    }
+
+    /// Invocations that failed the build, such as templates that don't match their integrity
+    /// digest or those given with `on-error=fail`.
+    pub fn failures(&self) -> &[String] {
+        &self.failures
+    }
+
+    /// Renders a collapsed HTML block listing every expansion within `chapter` and how long
+    /// each one took, or `None` when the chapter doesn't use any template.
+    pub fn debug_overlay<P1, P2>(&self, chapter: P1, src_dir: P2) -> Option<String>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let expansions = self
+            .expansions
+            .iter()
+            .filter(|expansion| expansion.chapter == chapter.as_ref())
+            .collect::<Vec<_>>();
+        if expansions.is_empty() {
+            return None;
+        }
+
+        let total = expansions
+            .iter()
+            .map(|expansion| expansion.duration)
+            .sum::<Duration>();
+        let mut overlay = format!(
+            "<details class=\"mdbook-template-debug\">\n<summary>mdbook-template: {} expansion(s) in {}</summary>\n\n",
+            expansions.len(),
+            millis(total)
+        );
+        overlay.push_str("| Template | Arguments | Time |\n");
+        overlay.push_str("|----------|-----------|------|\n");
+        for expansion in expansions {
+            let template = expansion
+                .template
+                .strip_prefix(src_dir.as_ref())
+                .unwrap_or(&expansion.template);
+            let args = expansion
+                .args
+                .iter()
+                .map(|arg| format!("`{}`", arg))
+                .collect::<Vec<_>>();
+            overlay.push_str(&format!(
+                "| `{}` | {} | {} |\n",
+                template.display(),
+                args.join(", "),
+                millis(expansion.duration)
+            ));
+        }
+        overlay.push_str("\n</details>");
+        Some(overlay)
+    }
+
+    /// Renders the markdown of the appendix chapter summarising how often each template is used,
+    /// by which chapters and with which arguments. Templates are shown relative to `src_dir`.
+    pub fn usage_appendix<P: AsRef<Path>>(&self, src_dir: P) -> String {
+        #[derive(Default)]
+        struct Usage<'a> {
+            count: usize,
+            chapters: BTreeSet<&'a Path>,
+            args: BTreeSet<&'a str>,
+        }
+
+        let mut usages = BTreeMap::<&Path, Usage>::new();
+        for expansion in &self.expansions {
+            let template = expansion
+                .template
+                .strip_prefix(src_dir.as_ref())
+                .unwrap_or(&expansion.template);
+            let usage = usages.entry(template).or_default();
+            usage.count += 1;
+            usage.chapters.insert(&expansion.chapter);
+            usage.args.extend(expansion.args.iter().map(String::as_str));
+        }
+
+        let mut appendix = String::from("# Template Usage\n\n");
+        if usages.is_empty() {
+            appendix.push_str("No templates are used within this book.\n");
+            return appendix;
+        }
+
+        appendix.push_str("| Template | Uses | Chapters | Arguments |\n");
+        appendix.push_str("|----------|------|----------|-----------|\n");
+        for (template, usage) in usages {
+            let chapters = usage
+                .chapters
+                .iter()
+                .map(|chapter| format!("`{}`", chapter.display()))
+                .collect::<Vec<_>>();
+            let args = usage
+                .args
+                .iter()
+                .map(|arg| format!("`{}`", arg))
+                .collect::<Vec<_>>();
+            appendix.push_str(&format!(
+                "| `{}` | {} | {} | {} |\n",
+                template.display(),
+                usage.count,
+                chapters.join(", "),
+                args.join(", ")
+            ));
+        }
+        appendix
+    }
+
+    /// Renders the markdown of the appendix chapter listing every source from outside the book
+    /// with the hashes of what went into it and what came out, so published content can be audited.
+    pub fn provenance_report<P: AsRef<Path>>(&self, src_dir: P) -> String {
+        let mut report = String::from("# Provenance\n\n");
+        if self.sources.is_empty() {
+            report.push_str("Every template of this book is local.\n");
+            return report;
+        }
+
+        report.push_str("| Provider | Input | Chapter | Input SHA-256 | Output SHA-256 |\n");
+        report.push_str("|----------|-------|---------|---------------|----------------|\n");
+        for (chapter, source) in &self.sources {
+            let input = source
+                .input
+                .strip_prefix(src_dir.as_ref())
+                .unwrap_or(&source.input);
+            report.push_str(&format!(
+                "| `{}` | `{}` | `{}` | `{}` | `{}` |\n",
+                source.provider,
+                input.display(),
+                chapter.display(),
+                source.input_sha256,
+                source.output_sha256
+            ));
+        }
+        report
+    }
+
+    /// Renders the `metrics.toml` summarising the expansions of the build: how many there were,
+    /// how many arguments they were given on average and how deeply they were nested, in total and
+    /// per template. Templates are shown relative to `src_dir`.
+    pub fn metrics<P: AsRef<Path>>(&self, src_dir: P) -> String {
+        fn summary(expansions: &[&Expansion]) -> String {
+            let args = expansions
+                .iter()
+                .map(|expansion| expansion.args.len())
+                .sum::<usize>();
+            let average = match expansions.len() {
+                0 => 0.0,
+                count => args as f64 / count as f64,
+            };
+            let deepest = expansions
+                .iter()
+                .map(|expansion| expansion.depth)
+                .max()
+                .unwrap_or_default();
+            format!(
+                "expansions = {}\naverage-args = {:.2}\ndeepest-nesting = {}\n",
+                expansions.len(),
+                average,
+                deepest
+            )
+        }
+
+        let mut templates = BTreeMap::<&Path, Vec<&Expansion>>::new();
+        for expansion in &self.expansions {
+            let template = expansion
+                .template
+                .strip_prefix(src_dir.as_ref())
+                .unwrap_or(&expansion.template);
+            templates.entry(template).or_default().push(expansion);
+        }
+
+        let mut metrics = summary(&self.expansions.iter().collect::<Vec<_>>());
+        for (template, expansions) in templates {
+            metrics.push_str(&format!(
+                "\n[templates.{:?}]\n{}",
+                template.to_string_lossy(),
+                summary(&expansions)
+            ));
+        }
+        metrics
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::stats::{Expansion, Source, Stats};
+
+    #[test]
+    fn test_usage_appendix_empty() {
+        assert_eq!(
+            Stats::default().usage_appendix("/book/src"),
+            "# Template Usage\n\nNo templates are used within this book.\n"
+        );
+    }
+
+    #[test]
+    fn test_usage_appendix() {
+        let mut stats = Stats::default();
+        let footer = |chapter: &str, args: &[&str]| Expansion {
+            template: PathBuf::from("/book/src/templates/footer.md"),
+            chapter: PathBuf::from(chapter),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            duration: Duration::ZERO,
+            depth: 1,
+        };
+        stats.record(footer("rust.md", &["path", "authors"]));
+        stats.record(footer("friends/hazel.md", &["path"]));
+        stats.record(Expansion {
+            template: PathBuf::from("builtin:note"),
+            chapter: PathBuf::from("rust.md"),
+            args: vec!["text".to_string()],
+            duration: Duration::ZERO,
+            depth: 1,
+        });
+
+        assert_eq!(
+            stats.usage_appendix("/book/src"),
+            "# Template Usage
+
+| Template | Uses | Chapters | Arguments |
+|----------|------|----------|-----------|
+| `builtin:note` | 1 | `rust.md` | `text` |
+| `templates/footer.md` | 2 | `friends/hazel.md`, `rust.md` | `authors`, `path` |
+"
+        );
+    }
+
+    #[test]
+    fn test_debug_overlay() {
+        let mut stats = Stats::default();
+        stats.record(Expansion {
+            template: PathBuf::from("/book/src/templates/footer.md"),
+            chapter: PathBuf::from("rust.md"),
+            args: vec!["authors".to_string()],
+            duration: Duration::from_micros(1500),
+            depth: 1,
+        });
+        stats.record(Expansion {
+            template: PathBuf::from("builtin:note"),
+            chapter: PathBuf::from("rust.md"),
+            args: vec![],
+            duration: Duration::from_micros(250),
+            depth: 2,
+        });
+
+        assert_eq!(stats.debug_overlay("hazel.md", "/book/src"), None);
+        assert_eq!(
+            stats.debug_overlay("rust.md", "/book/src").unwrap(),
+            r#"<details class="mdbook-template-debug">
+<summary>mdbook-template: 2 expansion(s) in 1.75ms</summary>
+
+| Template | Arguments | Time |
+|----------|-----------|------|
+| `templates/footer.md` | `authors` | 1.50ms |
+| `builtin:note` |  | 0.25ms |
+
+</details>"#
+        );
+    }
+
+    #[test]
+    fn test_provenance_report() {
+        let mut stats = Stats::default();
+        assert_eq!(
+            stats.provenance_report("/book/src"),
+            "# Provenance\n\nEvery template of this book is local.\n"
+        );
+
+        stats.record_source(
+            "design.md",
+            Source {
+                provider: "plantuml -tsvg -pipe".to_string(),
+                input: PathBuf::from("/book/src/diagrams/seq.puml"),
+                input_sha256: "ab12".to_string(),
+                output_sha256: "cd34".to_string(),
+            },
+        );
+
+        assert_eq!(
+            stats.provenance_report("/book/src"),
+            "# Provenance
+
+| Provider | Input | Chapter | Input SHA-256 | Output SHA-256 |
+|----------|-------|---------|---------------|----------------|
+| `plantuml -tsvg -pipe` | `diagrams/seq.puml` | `design.md` | `ab12` | `cd34` |
+"
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        assert_eq!(
+            Stats::default().metrics("/book/src"),
+            "expansions = 0\naverage-args = 0.00\ndeepest-nesting = 0\n"
+        );
+
+        let mut stats = Stats::default();
+        let footer = |args: &[&str], depth: usize| Expansion {
+            template: PathBuf::from("/book/src/templates/footer.md"),
+            chapter: PathBuf::from("rust.md"),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            duration: Duration::ZERO,
+            depth,
+        };
+        stats.record(footer(&["path", "authors"], 1));
+        stats.record(footer(&[], 3));
+        stats.record(Expansion {
+            template: PathBuf::from("builtin:note"),
+            chapter: PathBuf::from("rust.md"),
+            args: vec!["text".to_string()],
+            duration: Duration::ZERO,
+            depth: 2,
+        });
+
+        let metrics = stats.metrics("/book/src");
+        assert_eq!(
+            metrics,
+            r#"expansions = 3
+average-args = 1.00
+deepest-nesting = 3
+
+[templates."builtin:note"]
+expansions = 1
+average-args = 1.00
+deepest-nesting = 2
+
+[templates."templates/footer.md"]
+expansions = 2
+average-args = 1.00
+deepest-nesting = 3
+"#
+        );
+        assert!(metrics.parse::<toml::Value>().is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::directives;
+use crate::links::{self, LinkType, ESCAPE_CHAR};
+
+/// Escapes every `{{` and every directive such as `[[@counter ...]]` within `text`, doubling the
+/// backslashes already before them, so that once placed within a chapter `text` is rendered
+/// verbatim.
+///
+/// For every `text`, `unescape(&escape(text)) == text`.
+pub fn escape(text: &str) -> String {
+    let escaped = links::escape(text, "{{", |_| Some(2));
+    links::escape(&escaped, "[[", directives::directive_length)
+}
+
+/// Reverses [`escape`]: every `{{` and directive preceded by an odd number of backslashes loses
+/// the one escaping it and has the others halved, as expanding the chapter would. Delimiters that
+/// aren't escaped, and the backslashes before them, are left untouched.
+pub fn unescape(text: &str) -> String {
+    let unescaped = unescape_delimiter(text, "[[", directives::directive_length);
+    unescape_delimiter(&unescaped, "{{", |_| Some(2))
+}
+
+fn unescape_delimiter<F>(text: &str, opening: &str, delimiter_length: F) -> String
+where
+    F: Fn(&str) -> Option<usize>,
+{
+    let mut previous_end_index = 0;
+    let mut delimiter_end_index = 0;
+    let mut unescaped = String::with_capacity(text.len());
+
+    for (index, _) in text.match_indices(opening) {
+        match delimiter_length(&text[index..]) {
+            Some(length) if index >= delimiter_end_index => delimiter_end_index = index + length,
+            _ => continue,
+        }
+        let gap = &text[previous_end_index..index];
+        let backslashes = gap.len() - gap.trim_end_matches(ESCAPE_CHAR).len();
+        if backslashes.is_multiple_of(2) {
+            continue;
+        }
+        unescaped.push_str(&gap[..gap.len() - backslashes]);
+        unescaped.extend(std::iter::repeat_n(ESCAPE_CHAR, backslashes / 2));
+        unescaped.push_str(opening);
+        previous_end_index = index + opening.len();
+    }
+
+    unescaped.push_str(&text[previous_end_index..]);
+    unescaped
+}
+
+/// What a [`Segment`] of a chapter is.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SegmentKind {
+    /// Text left as it is
+    Text,
+    /// An invocation such as `{{#template footer.md}}`, replaced by its expansion
+    Invocation,
+    /// An escaped delimiter such as `\{{`, replaced by the delimiter itself
+    Escape,
+}
+
+/// A part of a chapter, as split by [`parse`].
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub kind: SegmentKind,
+    pub text: &'a str,
+}
+
+/// Splits `contents` into the text, invocations of `{{#template ...}}` and escaped delimiters it
+/// consists of, without reading any template.
+///
+/// The split never loses anything: concatenating the text of every segment gives back `contents`,
+/// and no segment is empty.
+pub fn parse(contents: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut previous_end_index = 0;
+    for link in links::extract_template_links(contents) {
+        if link.start_index > previous_end_index {
+            segments.push(Segment {
+                kind: SegmentKind::Text,
+                text: &contents[previous_end_index..link.start_index],
+            });
+        }
+        segments.push(Segment {
+            kind: match link.link_type {
+                LinkType::Escaped => SegmentKind::Escape,
+                _ => SegmentKind::Invocation,
+            },
+            text: &contents[link.start_index..link.end_index],
+        });
+        previous_end_index = link.end_index;
+    }
+    if previous_end_index < contents.len() {
+        segments.push(Segment {
+            kind: SegmentKind::Text,
+            text: &contents[previous_end_index..],
+        });
+    }
+    segments
+}
+
+#[cfg(test)]
+mod syntax_tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use proptest::prelude::*;
+    use proptest::{collection, sample};
+
+    use crate::config::Config;
+    use crate::context::ExpansionContext;
+    use crate::directives::Registry;
+    use crate::front_matter::Assets;
+    use crate::replace_template;
+    use crate::syntax::{escape, parse, unescape, Segment, SegmentKind};
+    use crate::utils::TestFileReader;
+
+    // Fragments the generated inputs are made of, weighted towards the delimiters
+    const FRAGMENTS: &[&str] = &[
+        "{{",
+        "}}",
+        "{{#template ",
+        "{{#template-data ",
+        "[[",
+        "]]",
+        "[[#",
+        "[[@counter ",
+        "#",
+        "\\",
+        "\\\\",
+        "footer.md",
+        "missing.md",
+        "a=",
+        "b=1",
+        "+=",
+        " ",
+        "\n",
+        "\r\n",
+        "```\n",
+        "text",
+        "é",
+        "🦀",
+    ];
+
+    /// Chapters made of delimiters, paths and arguments, along with any other character, which
+    /// shrink towards fewer fragments.
+    fn chapters() -> impl Strategy<Value = String> {
+        let fragment = prop_oneof![
+            4 => sample::select(FRAGMENTS).prop_map(str::to_string),
+            1 => any::<char>().prop_map(String::from),
+        ];
+        collection::vec(fragment, 0..24).prop_map(|fragments| fragments.concat())
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r"{{#template a.md}} \{{x}} [[#title]] [[@counter figure]]"),
+            r"\{{#template a.md}} \\\{{x}} [[#title]] \[[@counter figure]]"
+        );
+        assert_eq!(
+            unescape(r"\{{#template a.md}} \\\{{x}} \\{{y}} \[[@counter figure]]"),
+            r"{{#template a.md}} \{{x}} \\{{y}} [[@counter figure]]"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(r"Intro {{#template footer.md a=1}} \{{"),
+            vec![
+                Segment {
+                    kind: SegmentKind::Text,
+                    text: "Intro "
+                },
+                Segment {
+                    kind: SegmentKind::Invocation,
+                    text: "{{#template footer.md a=1}}"
+                },
+                Segment {
+                    kind: SegmentKind::Text,
+                    text: " "
+                },
+                Segment {
+                    kind: SegmentKind::Escape,
+                    text: r"\{{"
+                },
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_escape_round_trips(input in chapters()) {
+            prop_assert_eq!(unescape(&escape(&input)), input);
+        }
+
+        #[test]
+        fn test_parse_loses_nothing(input in chapters()) {
+            let segments = parse(&input);
+            prop_assert!(segments.iter().all(|segment| !segment.text.is_empty()));
+            prop_assert_eq!(
+                segments
+                    .iter()
+                    .map(|segment| segment.text)
+                    .collect::<String>(),
+                input
+            );
+        }
+
+        #[test]
+        fn test_expansion_never_panics(input in chapters()) {
+            let file_reader = TestFileReader::from(HashMap::from([(
+                PathBuf::from("footer.md"),
+                "[[#a]] [[#b default]] {{#template footer.md}} \\[[#a]]".to_string(),
+            )]));
+            let (globals, config) = (HashMap::new(), Config::default());
+            let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+            replace_template(&input, "", "", 0, &mut Assets::default(), &ctx);
+        }
+
+        #[test]
+        fn test_escaped_text_expands_verbatim(input in chapters()) {
+            let file_reader = TestFileReader::default();
+            let (globals, config) = (HashMap::new(), Config::default());
+            let ctx = ExpansionContext::new(&file_reader, &globals, &config);
+            let expanded =
+                replace_template(&escape(&input), "", "", 0, &mut Assets::default(), &ctx);
+            let mut registry = Registry::default();
+            let collected = registry.collect(&expanded, "intro.md");
+            prop_assert_eq!(registry.resolve(&collected, "intro.md"), input);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+
+use crate::front_matter::Assets;
+
+const TABS_CSS: &str = include_str!("builtins/tabs.css");
+const TABS_JS: &str = include_str!("builtins/tabs.js");
+
+lazy_static! {
+    // r"(?xs)\\\\(?=\\*\{\{)|\\\{\{|\{\{\s*\#tabs\s*\}\}(.*?)\{\{\s*/tabs\s*\}\}"
+    static ref TABS: Regex = Regex::new(
+        r"(?xs)                                 # insignificant whitespace, `.` matches newlines
+
+        \\\\(?=\\*\{\{)                         # escaped backslash before an opening parens
+
+        |                                       # or
+
+        \\\{\{                                  # escaped opening parens
+
+        |                                       # or
+
+        \{\{\s*\#tabs\s*\}\}                    # tabs block opening
+        (.*?)                                   # all tabs within the block
+        \{\{\s*/tabs\s*\}\}                     # tabs block closing"
+    )
+    .unwrap();
+
+    // r"\{\{\s*\#tab\s+([^}]+?)\s*\}\}"
+    static ref TAB: Regex = Regex::new(r"\{\{\s*\#tab\s+([^}]+?)\s*\}\}").unwrap();
+}
+
+/// Expands every `{{#tabs}} {{#tab <title>}} ... {{/tabs}}` block into the HTML of a tabbed
+/// component, registering the CSS and JavaScript it needs within `assets`.
+pub(crate) fn replace(contents: &str, assets: &mut Assets) -> String {
+    // Must keep track of indices as they will not correspond after string substitution
+    let mut previous_end_index = 0;
+    let mut replaced = String::with_capacity(contents.len());
+
+    for cap in TABS.captures_iter(contents).filter_map(|cap| cap.ok()) {
+        let (mat, body) = match (cap.get(0), cap.get(1)) {
+            (Some(mat), Some(body)) => (mat, body.as_str()),
+            // Escaped blocks are left for the template links to unescape
+            _ => continue,
+        };
+
+        replaced.push_str(&contents[previous_end_index..mat.start()]);
+        replaced.push_str(&render(body));
+        assets.add_css(TABS_CSS);
+        assets.add_js(TABS_JS);
+        previous_end_index = mat.end();
+    }
+
+    replaced.push_str(&contents[previous_end_index..]);
+    replaced
+}
+
+fn render(body: &str) -> String {
+    let mut titles = vec![];
+    let mut panels = vec![];
+
+    let tabs = TAB
+        .captures_iter(body)
+        .filter_map(|cap| cap.ok())
+        .collect::<Vec<_>>();
+    for (index, cap) in tabs.iter().enumerate() {
+        let (Some(mat), Some(title)) = (cap.get(0), cap.get(1)) else {
+            continue;
+        };
+        let end = tabs
+            .get(index + 1)
+            .and_then(|next| next.get(0))
+            .map_or(body.len(), |next| next.start());
+
+        titles.push(title.as_str());
+        panels.push(body[mat.end()..end].trim_matches(['\n', '\r']));
+    }
+
+    let mut html = String::from("<div class=\"mdbook-tabs\">\n<div class=\"mdbook-tab-titles\">");
+    for (index, title) in titles.iter().enumerate() {
+        let active = if index == 0 { " class=\"active\"" } else { "" };
+        html.push_str(&format!("<button{}>{}</button>", active, title));
+    }
+    html.push_str("</div>\n");
+    for (index, panel) in panels.iter().enumerate() {
+        let active = if index == 0 { " active" } else { "" };
+        // Blank lines around the panel let markdown within it be rendered
+        html.push_str(&format!(
+            "<div class=\"mdbook-tab{}\">\n\n{}\n\n</div>\n",
+            active, panel
+        ));
+    }
+    html.push_str("</div>");
+    html
+}
+
+#[cfg(test)]
+mod tabs_tests {
+    use crate::front_matter::Assets;
+    use crate::tabs::replace;
+
+    #[test]
+    fn test_replace_tabs() {
+        let start = r#"Install with
+{{#tabs}}
+{{#tab Rust}}
+`cargo install mdbook-template`
+{{#tab Nix}}
+`nix profile install`
+{{/tabs}}
+Done!"#;
+        let end = r#"Install with
+<div class="mdbook-tabs">
+<div class="mdbook-tab-titles"><button class="active">Rust</button><button>Nix</button></div>
+<div class="mdbook-tab active">
+
+`cargo install mdbook-template`
+
+</div>
+<div class="mdbook-tab">
+
+`nix profile install`
+
+</div>
+</div>
+Done!"#;
+        let mut assets = Assets::default();
+
+        assert_eq!(replace(start, &mut assets), end);
+        assert!(assets.render().is_some());
+    }
+
+    #[test]
+    fn test_replace_tabs_escaped() {
+        let start = r"\{{#tabs}} {{#tab Rust}} {{/tabs}}";
+        let mut assets = Assets::default();
+
+        assert_eq!(replace(start, &mut assets), start);
+        assert_eq!(assets.render(), None);
+    }
+}
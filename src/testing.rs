@@ -0,0 +1,306 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+use mdbook::errors::Result;
+use mdbook::MDBook;
+
+use crate::embedded::Embedded;
+
+// Fixtures of the same name, e.g. within tests running in parallel, never share a directory
+static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// A book written to a temporary directory and built end-to-end with [`MDBook`], so tests can
+/// assert on the HTML that readers actually get.
+///
+/// ```no_run
+/// use mdbook_template::testing::Fixture;
+///
+/// let book = Fixture::new("footer")
+///     .book_toml("[preprocessor.template]\non-error = \"fail\"")
+///     .chapter("intro.md", "# Intro\n{{#template footer.md authors=Goudham}}")
+///     .file("footer.md", "Written by [[#authors]]")
+///     .build()
+///     .unwrap();
+/// assert!(book.content("intro.md").unwrap().contains("Written by Goudham"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    root: PathBuf,
+    book_toml: String,
+    chapters: Vec<String>,
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Fixture {
+    /// A fixture within its own directory of the temporary directory, named after `name`.
+    pub fn new(name: &str) -> Fixture {
+        Fixture {
+            root: std::env::temp_dir().join(format!(
+                "mdbook-template-fixture-{}-{}-{}",
+                name,
+                std::process::id(),
+                FIXTURES.fetch_add(1, Ordering::Relaxed)
+            )),
+            book_toml: String::new(),
+            chapters: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Contents of `book.toml`, the `[preprocessor.template]` table configures the preprocessor
+    /// as it would for `mdbook build`.
+    pub fn book_toml(mut self, contents: &str) -> Fixture {
+        self.book_toml = contents.to_string();
+        self
+    }
+
+    /// A chapter listed in `SUMMARY.md`, and named after its `path` relative to the `src` directory.
+    pub fn chapter(mut self, path: &str, contents: &str) -> Fixture {
+        self.chapters.push(path.to_string());
+        self.file(path, contents)
+    }
+
+    /// Any other file, such as a template, at `path` relative to the `src` directory.
+    pub fn file<P: AsRef<Path>, C: AsRef<[u8]>>(mut self, path: P, contents: C) -> Fixture {
+        self.files
+            .push((path.as_ref().to_path_buf(), contents.as_ref().to_vec()));
+        self
+    }
+
+    /// Writes the book, `book.toml` included, without building it, e.g. for the subcommands that
+    /// only read the book source.
+    pub fn write(self) -> Result<Rendered> {
+        let rendered = Rendered { root: self.root };
+        let src_dir = rendered.root.join("src");
+        let mut summary = String::from("# Summary\n\n");
+        for chapter in &self.chapters {
+            summary.push_str(&format!("- [{}]({})\n", chapter, chapter));
+        }
+        for (path, contents) in [
+            (src_dir.join("SUMMARY.md"), summary.into_bytes()),
+            (rendered.root.join("book.toml"), self.book_toml.into_bytes()),
+        ]
+        .into_iter()
+        .chain(
+            self.files
+                .into_iter()
+                .map(|(path, contents)| (src_dir.join(path), contents)),
+        ) {
+            let parent = path.parent().expect("Files have a parent");
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create {}", parent.display()))?;
+            fs::write(&path, contents)
+                .with_context(|| format!("Could not write {}", path.display()))?;
+        }
+        Ok(rendered)
+    }
+
+    /// Writes the book and renders it.
+    pub fn build(self) -> Result<Rendered> {
+        let mut config = self.book_toml.parse::<mdbook::Config>()?;
+        let rendered = self.write()?;
+        let preprocessor = Embedded::take(&mut config);
+        let mut book = MDBook::load_with_config(&rendered.root, config)?;
+        book.with_preprocessor(preprocessor);
+        book.build()?;
+        Ok(rendered)
+    }
+}
+
+/// A written, or built, [`Fixture`], removed once dropped.
+#[derive(Debug)]
+pub struct Rendered {
+    root: PathBuf,
+}
+
+impl Rendered {
+    /// Root directory of the book, holding `book.toml` and the `src` directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The whole page rendered for the `chapter` at `path` relative to the `src` directory.
+    pub fn html(&self, path: &str) -> Result<String> {
+        let page = self
+            .root
+            .join("book")
+            .join(Path::new(path).with_extension("html"));
+        fs::read_to_string(&page).with_context(|| format!("Could not read {}", page.display()))
+    }
+
+    /// Only the rendered chapter, without the navigation, scripts and styles around it.
+    pub fn content(&self, path: &str) -> Result<String> {
+        let html = self.html(path)?;
+        let start = html
+            .find("<main>")
+            .map_or(0, |start| start + "<main>".len());
+        let end = html[start..]
+            .find("</main>")
+            .map_or(html.len(), |end| start + end);
+        Ok(html[start..end].trim().to_string())
+    }
+}
+
+impl Drop for Rendered {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use crate::testing::Fixture;
+
+    #[test]
+    fn test_fixture() {
+        let book = Fixture::new("fixture")
+            .book_toml(
+                "[book]\ntitle = \"Fixture\"\n\n[preprocessor.template.variables]\nyear = \"2022\"",
+            )
+            .chapter(
+                "intro.md",
+                "# Intro\n\n{{#template templates/footer.md authors=Goudham}}",
+            )
+            .chapter(
+                "guide/install.md",
+                "# Install\n\n{{#template ../templates/footer.md#credits}}",
+            )
+            .file(
+                "templates/footer.md",
+                "Written by **[[#authors]]** in [[#year]]\n\n## Credits\n\nThanks!",
+            )
+            .build()
+            .unwrap();
+
+        assert!(book
+            .html("intro.md")
+            .unwrap()
+            .contains("<title>intro.md - Fixture</title>"));
+        assert_eq!(
+            book.content("intro.md").unwrap(),
+            "<h1 id=\"intro\"><a class=\"header\" href=\"#intro\">Intro</a></h1>\n<p>Written by <strong>Goudham</strong> in 2022</p>\n<h2 id=\"credits\"><a class=\"header\" href=\"#credits\">Credits</a></h2>\n<p>Thanks!</p>"
+        );
+        assert!(book
+            .content("guide/install.md")
+            .unwrap()
+            .ends_with("<p>Thanks!</p>"));
+        assert!(book.content("missing.md").is_err());
+    }
+
+    #[test]
+    fn test_fixture_renderer_variant() {
+        let book = Fixture::new("renderer-variant")
+            .chapter("intro.md", "{{#template footer.md}}")
+            .file("footer.md", "Printed")
+            .file("footer.html.md", "<em>Online</em>")
+            .build()
+            .unwrap();
+
+        assert_eq!(book.content("intro.md").unwrap(), "<p><em>Online</em></p>");
+    }
+
+    #[test]
+    fn test_fixture_blockquote() {
+        let book = Fixture::new("blockquote")
+            .chapter("intro.md", "> {{#template note.md}}")
+            .file("note.md", "First\n\nSecond")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            book.content("intro.md").unwrap(),
+            "<blockquote>\n<p>First</p>\n<p>Second</p>\n</blockquote>"
+        );
+    }
+
+    #[test]
+    fn test_fixture_fails_on_error() {
+        let result = Fixture::new("on-error")
+            .book_toml("[preprocessor.template]\non-error = \"fail\"")
+            .chapter("intro.md", "{{#template missing.md}}")
+            .build();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_fixture_pack() {
+        let book = Fixture::new("pack")
+            .chapter(
+                "intro.md",
+                "{{#template corp:header.md}}\n\nBack to [[@ref corp:top]]",
+            )
+            .file(
+                "packs/corp/template-pack.toml",
+                "[pack]\nname = \"corp\"\nversion = \"1.0.0\"\ntemplates = [\"header.md\"]\n\n[pack.variables]\nlogo = \"Corp\"\n",
+            )
+            .file("packs/corp/header.md", "[[@label top]]**[[#corp.logo]]**")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            book.content("intro.md").unwrap(),
+            "<p><a id=\"corp:top\"></a><strong>Corp</strong></p>\n<p>Back to <a href=\"#corp:top\">corp:top</a></p>"
+        );
+    }
+
+    #[test]
+    fn test_fixture_two_pass() {
+        let build = |book_toml: &str| {
+            Fixture::new("two-pass")
+                .book_toml(book_toml)
+                .chapter("intro.md", "See [[@ref usage]]")
+                .chapter("usage.md", "[[@label usage]]Usage")
+                .build()
+                .unwrap()
+                .content("intro.md")
+                .unwrap()
+        };
+
+        assert_eq!(build(""), "<p>See [[@ref usage]]</p>");
+        assert_eq!(
+            build("[preprocessor.template]\ntwo-pass = true"),
+            "<p>See <a href=\"usage.html#usage\">usage</a></p>"
+        );
+    }
+
+    #[test]
+    fn test_fixture_strict_args() {
+        let build = |book_toml: &str, front_matter: &str| {
+            Fixture::new("strict-args")
+                .book_toml(book_toml)
+                .chapter(
+                    "intro.md",
+                    &format!("{}{{{{#template title.md}}}}", front_matter),
+                )
+                .file("title.md", "# [[#title]]")
+                .build()
+        };
+        let strict = "+++\n[template]\nstrict-args = true\n+++\n";
+        let lenient = "+++\n[template]\nstrict-args = false\n+++\n";
+
+        assert!(build("", "").is_ok());
+        assert!(build("", strict).is_err());
+        assert!(build("[preprocessor.template]\nstrict-args = true", "").is_err());
+        assert!(build("[preprocessor.template]\nstrict-args = true", lenient).is_ok());
+    }
+
+    #[test]
+    fn test_fixture_raw() {
+        let book = Fixture::new("raw")
+            .chapter("intro.md", "{{#template syntax.md name=Ferris}}")
+            .file(
+                "syntax.md",
+                "Hi [[#name]], write `[[#raw]][[#width 200px]][[/raw]]` or `[[#raw]][[@counter figure]][[/raw]]`",
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            book.content("intro.md").unwrap(),
+            "<p>Hi Ferris, write <code>[[#width 200px]]</code> or <code>[[@counter figure]]</code></p>"
+        );
+    }
+}
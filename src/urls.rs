@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::{anyhow, bail, Error, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, Parser, Tag};
+
+lazy_static! {
+    // r#"(?i)\b(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)')"#
+    static ref ATTRIBUTE: Regex = Regex::new(
+        r#"(?xi)                                # insignificant whitespace, case insensitive
+        \b(?:href|src)\s*=\s*                   # attribute holding a url
+        (?:"([^"]*)"|'([^']*)')                 # its quoted value"#
+    )
+    .unwrap();
+
+    // Outcome of the HEAD request of every url, shared by every chapter of the build
+    static ref CHECKED: Mutex<HashMap<String, Option<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Destinations of the links and images within `contents`, along with the `href` and `src`
+/// attributes of its raw HTML, in the order they appear.
+pub(crate) fn urls(contents: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    for event in Parser::new(contents) {
+        match event {
+            Event::Start(Tag::Link(_, url, _) | Tag::Image(_, url, _)) => {
+                urls.push(url.into_string())
+            }
+            Event::Html(html) => urls.extend(
+                ATTRIBUTE
+                    .captures_iter(&html)
+                    .filter_map(|captures| captures.ok())
+                    .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+                    .map(|url| url.as_str().to_string()),
+            ),
+            _ => {}
+        }
+    }
+    urls
+}
+
+/// Every url within `contents` that is malformed or, with `head`, can't be requested, along with
+/// why. Only external urls are ever requested.
+pub(crate) fn check(contents: &str, head: bool) -> Vec<(String, Error)> {
+    urls(contents)
+        .into_iter()
+        .filter_map(|url| {
+            let checked = match url.is_empty() {
+                true => Err(anyhow!("it is empty")),
+                false => validate(&url).and_then(|_| match head && is_external(&url) {
+                    true => self::head(&url),
+                    false => Ok(()),
+                }),
+            };
+            checked.err().map(|err| (url, err))
+        })
+        .collect()
+}
+
+/// Returns true if `url` points outside of the book, e.g. `https://example.com`, rather than to
+/// a chapter or an anchor, which mdBook resolves itself.
+pub(crate) fn is_external(url: &str) -> bool {
+    url.starts_with("//") || scheme(url).is_some()
+}
+
+fn scheme(url: &str) -> Option<&str> {
+    let (scheme, _) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
+
+/// Checks the syntax of `url`, without ever sending a request. Beyond unexpanded placeholders,
+/// whitespace and escapes, only the host and port of external urls are checked.
+pub(crate) fn validate(url: &str) -> Result<()> {
+    if url.contains("[[") || url.contains("{{") {
+        bail!("it contains an unexpanded placeholder");
+    }
+    if url.contains(char::is_whitespace) {
+        bail!("it contains whitespace");
+    }
+    let bytes = url.as_bytes();
+    for (index, _) in url.match_indices('%') {
+        let escape = bytes.get(index + 1..index + 3);
+        if !escape.is_some_and(|escape| escape.iter().all(u8::is_ascii_hexdigit)) {
+            bail!("it contains a malformed escape at \"{}\"", &url[index..]);
+        }
+    }
+
+    let rest = match scheme(url) {
+        Some(scheme) => {
+            let scheme = scheme.to_ascii_lowercase();
+            let rest = &url[scheme.len() + 1..];
+            if !matches!(scheme.as_str(), "http" | "https" | "ftp" | "ws" | "wss") {
+                // Such as `mailto:` and `data:`, which have no host to check
+                return Ok(());
+            }
+            match rest.strip_prefix("//") {
+                Some(rest) => rest,
+                None => bail!("\"{}:\" isn't followed by \"//\"", scheme),
+            }
+        }
+        None => match url.strip_prefix("//") {
+            Some(rest) => rest,
+            None => return Ok(()),
+        },
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (host, port) = match host_port.strip_prefix('[') {
+        // IPv6 addresses, e.g. `[::1]:3000`
+        Some(address) => match address.split_once(']') {
+            Some((address, port)) => (address, port.strip_prefix(':')),
+            None => bail!("its IPv6 address isn't closed"),
+        },
+        None => match host_port.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("it has no host");
+    }
+    let valid = match host_port.starts_with('[') {
+        true => host
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || matches!(c, ':' | '.')),
+        false => host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_'))
+        }),
+    };
+    if !valid {
+        bail!("\"{}\" isn't a valid host", host);
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        bail!("\"{}\" isn't a valid port", port.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Sends a `HEAD` request for the `http` or `https` url through `curl`, following redirects.
+/// Every url is only ever requested once per build.
+pub(crate) fn head(url: &str) -> Result<()> {
+    let is_http = scheme(url).is_some_and(|scheme| {
+        scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+    });
+    if !is_http {
+        return Ok(());
+    }
+    let mut checked = CHECKED.lock().unwrap_or_else(PoisonError::into_inner);
+    let failure = checked
+        .entry(url.to_string())
+        .or_insert_with(|| request(url).err().map(|err| err.to_string()));
+    match failure {
+        Some(failure) => bail!("{}", failure),
+        None => Ok(()),
+    }
+}
+
+fn request(url: &str) -> Result<()> {
+    let output = match Command::new("curl")
+        .args(["--head", "--silent", "--location", "--max-time", "10"])
+        .args(["--write-out", "\n%{http_code}", url])
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => bail!(
+            "it couldn't be requested, curl is needed to check links: {}",
+            err
+        ),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let status = stdout.lines().last().unwrap_or_default().trim();
+    match status.parse::<u16>() {
+        Ok(200..=399) => Ok(()),
+        Ok(0) | Err(_) => bail!("it couldn't be reached"),
+        Ok(status) => bail!("requesting it responds with {}", status),
+    }
+}
+
+#[cfg(test)]
+mod urls_tests {
+    use crate::urls::{check, is_external, urls, validate};
+
+    #[test]
+    fn test_urls() {
+        let contents = "[Docs](https://docs.rs/mdbook) ![Logo](logo.png)\n\n`<a href=\"code\">`\n\n<a class=\"btn\" HREF='https://example.com'>Go</a> <img src=\"\">";
+        assert_eq!(
+            urls(contents),
+            vec![
+                "https://docs.rs/mdbook",
+                "logo.png",
+                "https://example.com",
+                ""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let contents =
+            "[Docs](https://docs.rs/mdbook) [Empty]() [Page](https://example.com/[[#page]])";
+        let failures = check(contents, false)
+            .into_iter()
+            .map(|(url, err)| format!("{}: {}", url, err))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failures,
+            vec![
+                ": it is empty",
+                "https://example.com/[[#page]]: it contains an unexpanded placeholder"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_external() {
+        assert!(is_external("https://example.com"));
+        assert!(is_external("//example.com/logo.png"));
+        assert!(is_external("mailto:ferris@example.com"));
+        assert!(!is_external("../intro.md#install"));
+        assert!(!is_external("#install"));
+        assert!(!is_external(""));
+    }
+
+    #[test]
+    fn test_validate() {
+        let error = |url: &str| validate(url).unwrap_err().to_string();
+
+        assert!(
+            validate("https://img.shields.io/badge/build-passing-green?style=flat%20square")
+                .is_ok()
+        );
+        assert!(validate("http://user@localhost:3000/path#top").is_ok());
+        assert!(validate("http://[::1]:8080/").is_ok());
+        assert!(validate("//cdn.example.com/app.js").is_ok());
+        assert!(validate("mailto:ferris@example.com").is_ok());
+        assert!(validate("../intro.md").is_ok());
+        assert_eq!(
+            error("https://example.com/[[#page]]"),
+            "it contains an unexpanded placeholder"
+        );
+        assert_eq!(error("https://example.com/a b"), "it contains whitespace");
+        assert_eq!(
+            error("https://example.com/%zz"),
+            "it contains a malformed escape at \"%zz\""
+        );
+        assert_eq!(
+            error("https:example.com"),
+            "\"https:\" isn't followed by \"//\""
+        );
+        assert_eq!(error("https:///path"), "it has no host");
+        assert_eq!(
+            error("https://exa$mple.com"),
+            "\"exa$mple.com\" isn't a valid host"
+        );
+        assert_eq!(
+            error("https://example..com"),
+            "\"example..com\" isn't a valid host"
+        );
+        assert_eq!(
+            error("https://example.com:http"),
+            "\"http\" isn't a valid port"
+        );
+    }
+}
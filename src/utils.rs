@@ -1,30 +1,600 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
-use anyhow::{Context, Error, Result};
+use anyhow::{bail, Context, Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use encoding_rs::{Encoding, UTF_8};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::config::Symlinks;
+use crate::diagnostics::{self, Code};
+use crate::front_matter::{self, DELIMITER};
+
+/// Prefix of template paths that refer to the templates bundled with the preprocessor
+pub const BUILTIN_PREFIX: &str = "builtin:";
+/// Prefix of templates reading the source of another chapter, relative to the book source
+pub const CHAPTER_PREFIX: &str = "chapter:";
+
+/// Default of the largest template file, in bytes, that is read from disk
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+// How many leading bytes are checked for a NUL byte to tell binary files apart
+const BINARY_SNIFF_LENGTH: usize = 8 * 1024;
+
+const BUILTINS: &[(&str, &str)] = &[
+    ("note", include_str!("builtins/note.md")),
+    ("tip", include_str!("builtins/tip.md")),
+    ("warning", include_str!("builtins/warning.md")),
+    ("danger", include_str!("builtins/danger.md")),
+    ("openapi", include_str!("builtins/openapi.md")),
+    ("diagram", include_str!("builtins/diagram.md")),
+];
 
 pub trait FileReader {
     fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String>;
 }
 
+/// Reads template files from disk, refusing binary files and files above `max_file_size` bytes.
+/// Files are UTF-8 unless their front matter or directory declares another encoding, invalid
+/// sequences are an error unless the reader is `lossy`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SystemFileReader {
+    max_file_size: u64,
+    lossy: bool,
+    encodings: Vec<(PathBuf, &'static Encoding)>,
+}
+
+/// Resolves `builtin:<name>` templates to the templates bundled with the preprocessor and
+/// delegates every other file to the wrapped reader.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct BuiltinFileReader<FR> {
+    inner: FR,
+}
+
+/// Prefers the variant of a template made for the active renderer, e.g. `footer.epub.md` over
+/// `footer.md` when building with the `epub` renderer, falling back to the template itself.
 #[derive(PartialEq, Eq, Debug, Clone, Default)]
-pub struct SystemFileReader;
+pub struct RendererFileReader<FR> {
+    inner: FR,
+    renderer: String,
+}
+
+/// Applies the [`Symlinks`] policy to template files before reading them through the wrapped
+/// reader, which is handed the canonical path of the file.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SymlinkFileReader<FR> {
+    inner: FR,
+    policy: Symlinks,
+    root: PathBuf,
+}
+
+/// Resolves templates given without an extension, e.g. `footer`, by trying each of the
+/// `extensions` in order before the file name itself.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ExtensionFileReader<FR> {
+    inner: FR,
+    extensions: Vec<String>,
+}
+
+/// Keeps the contents of every file read through the wrapped reader, so templates used by many
+/// chapters are only read, and decoded, once per build.
+#[derive(Debug, Default)]
+pub struct CachedFileReader<FR> {
+    inner: FR,
+    cache: Mutex<HashMap<PathBuf, String>>,
+}
 
 #[derive(PartialEq, Eq, Debug, Clone, Default)]
 pub struct TestFileReader {
     pub captured_contents: HashMap<PathBuf, String>,
 }
 
+/// Normalizes a template path written on any platform, so `templates\footer.md`,
+/// `templates/./footer.md` and `chapters/../templates/footer.md` all become
+/// `templates/footer.md`.
+pub fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref().to_string_lossy().replace('\\', "/");
+    let mut normalized = PathBuf::new();
+    for component in Path::new(&path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Hex encoded SHA-256 digest of `contents`.
+pub(crate) fn sha256<T: AsRef<[u8]>>(contents: T) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// The contents of a file don't match the integrity digest it is pinned to.
+#[derive(Debug)]
+pub struct IntegrityError {
+    pub file: PathBuf,
+    pub expected: String,
+    /// Digest of the contents, unless the algorithm of `expected` is unsupported
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.actual {
+            Some(ref actual) => write!(
+                f,
+                "{} doesn't match its integrity {}, its contents are {}",
+                self.file.display(),
+                self.expected,
+                actual
+            ),
+            None => write!(
+                f,
+                "Unsupported integrity {} for {}, expected sha256-, sha384- or sha512-<base64 digest>",
+                self.expected,
+                self.file.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Checks `contents` of `file` against a Subresource Integrity style digest such as
+/// `sha256-<base64 digest>`.
+pub(crate) fn verify_integrity(file: &Path, contents: &[u8], expected: &str) -> Result<()> {
+    let actual = match expected.split_once('-') {
+        Some(("sha256", _)) => Some(integrity::<Sha256>("sha256", contents)),
+        Some(("sha384", _)) => Some(integrity::<Sha384>("sha384", contents)),
+        Some(("sha512", _)) => Some(integrity::<Sha512>("sha512", contents)),
+        _ => None,
+    };
+    match actual {
+        Some(ref actual) if actual == expected => Ok(()),
+        _ => Err(IntegrityError {
+            file: file.to_path_buf(),
+            expected: expected.to_owned(),
+            actual,
+        }
+        .into()),
+    }
+}
+
+/// `<algorithm>-<base64 digest>` of `contents`, as written in integrity pins.
+fn integrity<D: Digest>(algorithm: &str, contents: &[u8]) -> String {
+    format!("{}-{}", algorithm, STANDARD.encode(D::digest(contents)))
+}
+
+/// Finds `file_name` by comparing each component case-insensitively, for books written on
+/// case-insensitive file systems.
+fn find_case_insensitive(file_name: &Path) -> Option<PathBuf> {
+    let mut found = PathBuf::new();
+    for component in file_name.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            other => {
+                found.push(other);
+                continue;
+            }
+        };
+        let dir = match found.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => found.as_path(),
+        };
+        let entry = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .eq_ignore_ascii_case(&name)
+            })?;
+        found.push(entry.file_name());
+    }
+    Some(found)
+}
+
+/// Every file within `dir` and its subdirectories, hidden directories aside. Symlinked
+/// directories are followed as the `symlinks` policy allows, `within-root` keeping to `dir`, and
+/// every directory is walked once however many links lead to it.
+pub(crate) fn walk(dir: &Path, symlinks: Symlinks) -> Result<Vec<PathBuf>> {
+    let root = dir
+        .canonicalize()
+        .with_context(|| format!("Could not read {}", dir.display()))?;
+    let mut visited = HashSet::from([root.clone()]);
+    let mut files = Vec::new();
+    walk_dir(dir, &root, symlinks, &mut visited, &mut files)?;
+    Ok(files)
+}
+
+fn walk_dir(
+    dir: &Path,
+    root: &Path,
+    symlinks: Symlinks,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let is_dir = match file_type.is_symlink() {
+            // Dangling symlinks are left for whoever reads them to report
+            true => fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir()),
+            false => file_type.is_dir(),
+        };
+        if !is_dir {
+            files.push(path);
+            continue;
+        }
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(_) => continue,
+        };
+        let allowed = !file_type.is_symlink()
+            || match symlinks {
+                Symlinks::Follow => true,
+                Symlinks::WithinRoot => canonical.starts_with(root),
+                Symlinks::Deny => false,
+            };
+        if !hidden && allowed && visited.insert(canonical) {
+            walk_dir(&path, root, symlinks, visited, files)?;
+        }
+    }
+    Ok(())
+}
+
+impl SystemFileReader {
+    pub fn new(max_file_size: u64) -> Self {
+        SystemFileReader {
+            max_file_size,
+            lossy: false,
+            encodings: vec![],
+        }
+    }
+
+    /// Decodes every template file within `dir` with `encoding`.
+    pub fn encoding<P: AsRef<Path>>(mut self, dir: P, encoding: &'static Encoding) -> Self {
+        let dir = dir.as_ref();
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        self.encodings.push((dir, encoding));
+        self
+    }
+
+    fn encoding_of(&self, file_name: &Path, bytes: &[u8]) -> Result<&'static Encoding> {
+        // Front matter is plain ASCII, so it can be read before the encoding is known
+        if bytes.starts_with(DELIMITER.as_bytes()) {
+            let declared = front_matter::split(&String::from_utf8_lossy(bytes))
+                .ok()
+                .and_then(|(front_matter, _)| front_matter.encoding);
+            if let Some(label) = declared {
+                return Encoding::for_label(label.as_bytes())
+                    .with_context(|| format!("Unknown encoding {}", label));
+            }
+        }
+
+        Ok(self
+            .encodings
+            .iter()
+            .filter(|(dir, _)| file_name.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+            .map_or(UTF_8, |(_, encoding)| *encoding))
+    }
+
+    pub fn lossy(mut self, lossy: bool) -> Self {
+        self.lossy = lossy;
+        self
+    }
+}
+
+impl Default for SystemFileReader {
+    fn default() -> Self {
+        SystemFileReader::new(DEFAULT_MAX_FILE_SIZE)
+    }
+}
+
 impl FileReader for SystemFileReader {
     fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
-        fs::read_to_string(file_name).with_context(|| {
+        let metadata = match fs::metadata(file_name) {
+            Err(err) if err.kind() == ErrorKind::NotFound => match find_case_insensitive(file_name)
+            {
+                Some(found) => {
+                    diagnostics::warn(
+                        Code::FileCasing,
+                        format_args!(
+                            "Template file {} only exists as {}, fix the casing so the book builds on case-sensitive file systems",
+                            file_name.display(),
+                            found.display()
+                        ),
+                    );
+                    return self.read_to_string(&found, template_text);
+                }
+                None => Err(err),
+            },
+            result => result,
+        };
+        let context = || {
             format!(
                 "Could not read template file {} ({})",
                 template_text,
                 file_name.display(),
             )
-        })
+        };
+
+        if metadata.with_context(context)?.len() > self.max_file_size {
+            bail!(
+                "Template file {} ({}) is larger than the max-file-size of {} bytes",
+                template_text,
+                file_name.display(),
+                self.max_file_size
+            );
+        }
+        let bytes = fs::read(file_name).with_context(context)?;
+        if bytes
+            .iter()
+            .take(BINARY_SNIFF_LENGTH)
+            .any(|byte| *byte == 0)
+        {
+            bail!(
+                "Template file {} ({}) looks like a binary file, only text files can be templates",
+                template_text,
+                file_name.display()
+            );
+        }
+        let encoding = self.encoding_of(file_name, &bytes).with_context(context)?;
+        if encoding != UTF_8 {
+            return match encoding.decode_without_bom_handling_and_without_replacement(&bytes) {
+                Some(contents) => Ok(contents.into_owned()),
+                None if self.lossy => {
+                    diagnostics::warn(
+                        Code::Encoding,
+                        format_args!(
+                            "Template file {} ({}) is not valid {}, invalid sequences were replaced with \u{FFFD}",
+                            template_text,
+                            file_name.display(),
+                            encoding.name()
+                        ),
+                    );
+                    Ok(encoding.decode_without_bom_handling(&bytes).0.into_owned())
+                }
+                None => bail!(
+                    "Template file {} ({}) is not valid {}",
+                    template_text,
+                    file_name.display(),
+                    encoding.name()
+                ),
+            };
+        }
+        match String::from_utf8(bytes) {
+            Ok(contents) => Ok(contents),
+            Err(err) if self.lossy => {
+                diagnostics::warn(
+                    Code::Encoding,
+                    format_args!(
+                        "Template file {} ({}) is not valid UTF-8, invalid sequences were replaced with \u{FFFD}",
+                        template_text,
+                        file_name.display()
+                    ),
+                );
+                Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            }
+            Err(err) => Err(err)
+                .with_context(|| format!("Template file {} is not valid UTF-8", template_text))
+                .with_context(context),
+        }
+    }
+}
+
+impl<FR: FileReader> CachedFileReader<FR> {
+    pub fn new(inner: FR) -> Self {
+        CachedFileReader {
+            inner,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<FR: FileReader> FileReader for CachedFileReader<FR> {
+    fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
+        let cache = |cache| -> MutexGuard<'_, HashMap<PathBuf, String>> {
+            Mutex::lock(cache).unwrap_or_else(PoisonError::into_inner)
+        };
+        if let Some(contents) = cache(&self.cache).get(file_name) {
+            return Ok(contents.to_owned());
+        }
+        let contents = self.inner.read_to_string(file_name, template_text)?;
+        cache(&self.cache).insert(file_name.to_path_buf(), contents.clone());
+        Ok(contents)
+    }
+}
+
+impl<FR: FileReader> BuiltinFileReader<FR> {
+    pub fn new(inner: FR) -> Self {
+        BuiltinFileReader { inner }
+    }
+}
+
+impl<FR: FileReader> FileReader for BuiltinFileReader<FR> {
+    fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
+        match file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(BUILTIN_PREFIX))
+        {
+            Some(builtin) => BUILTINS
+                .iter()
+                .find(|(name, _)| *name == builtin)
+                .map(|(_, contents)| contents.to_string())
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "Unknown builtin template {} ({}), expected one of: {}",
+                        template_text,
+                        builtin,
+                        BUILTINS
+                            .iter()
+                            .map(|(name, _)| *name)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                }),
+            None => self.inner.read_to_string(file_name, template_text),
+        }
+    }
+}
+
+impl<FR: FileReader> RendererFileReader<FR> {
+    pub fn new<S: Into<String>>(inner: FR, renderer: S) -> Self {
+        RendererFileReader {
+            inner,
+            renderer: renderer.into(),
+        }
+    }
+
+    fn variant(&self, file_name: &Path) -> Option<PathBuf> {
+        let stem = file_name.file_stem()?.to_string_lossy();
+        let variant = match file_name.extension() {
+            Some(extension) => {
+                format!("{}.{}.{}", stem, self.renderer, extension.to_string_lossy())
+            }
+            None => format!("{}.{}", stem, self.renderer),
+        };
+        Some(file_name.with_file_name(variant))
+    }
+}
+
+impl<FR: FileReader> FileReader for RendererFileReader<FR> {
+    fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
+        self.variant(file_name)
+            .and_then(|variant| self.inner.read_to_string(&variant, template_text).ok())
+            .map_or_else(|| self.inner.read_to_string(file_name, template_text), Ok)
+    }
+}
+
+impl<FR: FileReader> SymlinkFileReader<FR> {
+    pub fn new<P: AsRef<Path>>(inner: FR, policy: Symlinks, root: P) -> Self {
+        SymlinkFileReader {
+            inner,
+            policy,
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl<FR: FileReader> FileReader for SymlinkFileReader<FR> {
+    fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
+        // The policy applies to the file that is eventually read, even when it is only found
+        // with a different casing
+        let spelled = fs::symlink_metadata(file_name).is_ok();
+        let resolved = match spelled {
+            true => file_name.to_path_buf(),
+            false => match find_case_insensitive(file_name) {
+                Some(found) => found,
+                // Leave missing files for the wrapped reader to report
+                None => return self.inner.read_to_string(file_name, template_text),
+            },
+        };
+        let canonical = match resolved.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(_) => return self.inner.read_to_string(file_name, template_text),
+        };
+
+        match self.policy {
+            Symlinks::Follow => {}
+            Symlinks::Deny => {
+                // Symlinked directories within the root are refused as well as symlinked files
+                let root = normalize_path(&self.root);
+                let resolved = normalize_path(&resolved);
+                let symlink = resolved
+                    .ancestors()
+                    .filter(|ancestor| {
+                        *ancestor == resolved || *ancestor != root && ancestor.starts_with(&root)
+                    })
+                    .find(|ancestor| {
+                        fs::symlink_metadata(ancestor)
+                            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+                    });
+                if let Some(symlink) = symlink {
+                    bail!(
+                        "Template file {} ({}) is a symlink, or within the symlink {}, and symlinks = \"deny\"",
+                        template_text,
+                        file_name.display(),
+                        symlink.display()
+                    );
+                }
+            }
+            Symlinks::WithinRoot => {
+                // Only paths through a symlink are confined, plain relative paths such as
+                // `../../shared/footer.md` are read wherever they lead. Symlinks above the root,
+                // such as `/tmp` on macOS, don't count
+                let resolved = normalize_path(&resolved);
+                let through_symlink = resolved
+                    .ancestors()
+                    .filter(|ancestor| !self.root.starts_with(ancestor))
+                    .any(|ancestor| {
+                        fs::symlink_metadata(ancestor)
+                            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+                    });
+                let root = self
+                    .root
+                    .canonicalize()
+                    .unwrap_or_else(|_| self.root.clone());
+                if through_symlink && !canonical.starts_with(&root) {
+                    bail!(
+                        "Template file {} ({}) resolves to {} outside of the book root {}",
+                        template_text,
+                        file_name.display(),
+                        canonical.display(),
+                        root.display()
+                    );
+                }
+            }
+        }
+        match spelled {
+            true => self.inner.read_to_string(&canonical, template_text),
+            // The wrapped reader warns about the casing, finding the very same file
+            false => self.inner.read_to_string(file_name, template_text),
+        }
+    }
+}
+
+impl<FR: FileReader> ExtensionFileReader<FR> {
+    pub fn new<S: AsRef<str>>(inner: FR, extensions: &[S]) -> Self {
+        ExtensionFileReader {
+            inner,
+            extensions: extensions
+                .iter()
+                .map(|extension| extension.as_ref().trim_start_matches('.').to_owned())
+                .collect(),
+        }
+    }
+}
+
+impl<FR: FileReader> FileReader for ExtensionFileReader<FR> {
+    fn read_to_string(&self, file_name: &Path, template_text: &str) -> Result<String> {
+        if file_name.extension().is_none() {
+            for extension in &self.extensions {
+                if let Ok(contents) = self
+                    .inner
+                    .read_to_string(&file_name.with_extension(extension), template_text)
+                {
+                    return Ok(contents);
+                }
+            }
+        }
+        self.inner.read_to_string(file_name, template_text)
     }
 }
 
@@ -48,3 +618,192 @@ impl FileReader for TestFileReader {
         }
     }
 }
+
+#[cfg(test)]
+mod utils_tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use encoding_rs::SHIFT_JIS;
+
+    use crate::config::Symlinks;
+    use crate::testing::Fixture;
+    use crate::utils::{
+        normalize_path, CachedFileReader, FileReader, SymlinkFileReader, SystemFileReader,
+    };
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path(r"templates\footer.md"),
+            PathBuf::from("templates/footer.md")
+        );
+        assert_eq!(
+            normalize_path(r"..\shared\.\footer.md"),
+            PathBuf::from("../shared/footer.md")
+        );
+        assert_eq!(
+            normalize_path("./templates/footer.md"),
+            PathBuf::from("templates/footer.md")
+        );
+        assert_eq!(
+            normalize_path("/book/src/chapters/../templates/footer.md"),
+            PathBuf::from("/book/src/templates/footer.md")
+        );
+    }
+
+    #[test]
+    fn test_cached_file_reader() {
+        let book = Fixture::new("cached")
+            .file("footer.md", "[[#authors]]")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let file_reader = CachedFileReader::new(SystemFileReader::default());
+
+        let first = file_reader.read_to_string(&dir.join("footer.md"), "footer.md");
+        fs::remove_dir_all(&dir).unwrap();
+        let second = file_reader.read_to_string(&dir.join("footer.md"), "footer.md");
+
+        assert_eq!(first.unwrap(), "[[#authors]]");
+        assert_eq!(second.unwrap(), "[[#authors]]");
+        assert!(file_reader
+            .read_to_string(&dir.join("header.md"), "header.md")
+            .is_err());
+    }
+
+    #[test]
+    fn test_system_file_reader_case_insensitive() {
+        let book = Fixture::new("case-insensitive")
+            .file("Templates/Footer.md", "[[#authors]]")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+
+        let contents = SystemFileReader::default()
+            .read_to_string(&dir.join("templates/footer.md"), "footer.md");
+
+        assert_eq!(contents.unwrap(), "[[#authors]]");
+        assert!(SystemFileReader::default()
+            .read_to_string(&dir.join("templates/missing.md"), "missing.md")
+            .is_err());
+    }
+
+    #[test]
+    fn test_system_file_reader_refuses_binary_and_large_files() {
+        let book = Fixture::new("file-size")
+            .file("image.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")
+            .file("footer.md", "[[#authors]]")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+
+        assert!(SystemFileReader::default()
+            .read_to_string(&dir.join("image.png"), "image.png")
+            .is_err());
+        assert!(SystemFileReader::new(4)
+            .read_to_string(&dir.join("footer.md"), "footer.md")
+            .is_err());
+        assert_eq!(
+            SystemFileReader::new(12)
+                .read_to_string(&dir.join("footer.md"), "footer.md")
+                .unwrap(),
+            "[[#authors]]"
+        );
+    }
+
+    #[test]
+    fn test_system_file_reader_lossy() {
+        let book = Fixture::new("lossy")
+            .file("legacy.md", b"caf\xe9 [[#name]]")
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let read =
+            |reader: SystemFileReader| reader.read_to_string(&dir.join("legacy.md"), "legacy.md");
+
+        assert!(read(SystemFileReader::default()).is_err());
+        assert_eq!(
+            read(SystemFileReader::default().lossy(true)).unwrap(),
+            "caf\u{FFFD} [[#name]]"
+        );
+    }
+
+    #[test]
+    fn test_system_file_reader_encodings() {
+        // "日本" encoded as Shift_JIS
+        let book = Fixture::new("encodings")
+            .file("legacy/title.md", b"\x93\xfa\x96\x7b")
+            .file(
+                "declared.md",
+                b"+++\nencoding = \"shift_jis\"\n+++\n\x93\xfa\x96\x7b",
+            )
+            .write()
+            .unwrap();
+        let dir = book.root().join("src");
+        let reader = SystemFileReader::default().encoding(dir.join("legacy"), SHIFT_JIS);
+
+        assert_eq!(
+            reader
+                .read_to_string(&dir.join("legacy/title.md"), "title.md")
+                .unwrap(),
+            "日本"
+        );
+        assert_eq!(
+            SystemFileReader::default()
+                .read_to_string(&dir.join("declared.md"), "declared.md")
+                .unwrap(),
+            "+++\nencoding = \"shift_jis\"\n+++\n日本"
+        );
+        assert!(SystemFileReader::default()
+            .read_to_string(&dir.join("legacy/title.md"), "title.md")
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_file_reader() {
+        // The book source directory is the root, the files outside of it are shared
+        let fixture = Fixture::new("symlinks")
+            .file("../shared.md", "shared")
+            .file("../shared/nested.md", "nested")
+            .file("local.md", "local")
+            .file("parts/part.md", "part")
+            .write()
+            .unwrap();
+        let dir = fixture.root();
+        let book = dir.join("src");
+        std::os::unix::fs::symlink(dir.join("shared.md"), book.join("shared.md")).unwrap();
+        std::os::unix::fs::symlink(book.join("local.md"), book.join("alias.md")).unwrap();
+        std::os::unix::fs::symlink(dir.join("shared"), book.join("linked")).unwrap();
+        std::os::unix::fs::symlink(book.join("parts"), book.join("inner")).unwrap();
+        let read = |policy: Symlinks, file: &str| {
+            SymlinkFileReader::new(SystemFileReader::default(), policy, &book)
+                .read_to_string(&book.join(file), file)
+        };
+
+        assert_eq!(read(Symlinks::Follow, "shared.md").unwrap(), "shared");
+        assert!(read(Symlinks::WithinRoot, "shared.md").is_err());
+        assert_eq!(read(Symlinks::WithinRoot, "alias.md").unwrap(), "local");
+        assert!(read(Symlinks::Deny, "alias.md").is_err());
+        assert_eq!(read(Symlinks::Deny, "local.md").unwrap(), "local");
+        // Found through a different casing, or within a symlinked directory
+        assert!(read(Symlinks::WithinRoot, "SHARED.md").is_err());
+        assert!(read(Symlinks::Deny, "Alias.md").is_err());
+        assert_eq!(read(Symlinks::Deny, "LOCAL.md").unwrap(), "local");
+        assert!(read(Symlinks::WithinRoot, "linked/nested.md").is_err());
+        assert!(read(Symlinks::Deny, "linked/nested.md").is_err());
+        assert_eq!(read(Symlinks::WithinRoot, "inner/part.md").unwrap(), "part");
+        assert!(read(Symlinks::Deny, "inner/part.md").is_err());
+        assert_eq!(read(Symlinks::Deny, "parts/part.md").unwrap(), "part");
+        // Files outside of the root reached without any symlink
+        assert_eq!(
+            read(Symlinks::WithinRoot, "../shared.md").unwrap(),
+            "shared"
+        );
+        assert_eq!(
+            read(Symlinks::WithinRoot, "../shared/nested.md").unwrap(),
+            "nested"
+        );
+    }
+}